@@ -0,0 +1,295 @@
+export declare const MAX_RECONNECT_ATTEMPTS: number;
+
+export declare const CloseCode: {
+    readonly QUEUE_CLOSED: 4000;
+    readonly KEY_DELETED: 4001;
+    readonly DISCONNECTED: 4003;
+    readonly AUTH_EXPIRED: 4401;
+    readonly MOVED: 4307;
+    readonly DRAINING: 1001;
+    readonly SLOW_CONSUMER: 1008;
+    readonly MESSAGE_TOO_LARGE: 1009;
+    readonly INTERNAL_ERROR: 1011;
+};
+
+export declare function reconnectDelay(attempt: number): number;
+
+export type Key = string | number;
+
+export interface EventMessage<P = unknown> {
+    id: Key;
+    sequence?: number;
+    payload: P;
+    tombstone?: boolean;
+    reply_to?: string;
+    supersedes?: number;
+    ephemeral?: boolean;
+}
+
+export type ControlMessage =
+    | { control: "heartbeat" }
+    | { control: "lagged"; skipped: number }
+    | { control: "end_of_preload"; continuation?: number }
+    | { control: "queue_closed" }
+    | { control: "key_deleted" }
+    | { control: "revoked"; id: string; sequence: number }
+    | { control: "draining"; retry_after?: number }
+    | { control: "token_refreshed"; expiration: number }
+    | { control: "moved"; address: string }
+    | { control: "preload_failed" };
+
+export interface BaseResponse {
+    success: boolean;
+}
+
+export interface SendResponse extends BaseResponse {
+    /** Sequence of the stored event, absent for ephemeral events and deduplicated retries */
+    sequence?: number;
+}
+
+export interface BatchEventResult {
+    success: boolean;
+    /** Response code of the event if it was sent alone */
+    status: number;
+    error?: string;
+    sequence?: number;
+}
+
+export interface BatchResponse extends BaseResponse {
+    events: BatchEventResult[];
+}
+
+export interface BatchOptions {
+    /** Events sent with one request, 100 by default, the server accepts up to 1000 */
+    maxSize?: number;
+    /** Milliseconds the first event of the batch waits for others, 10 by default */
+    maxDelay?: number;
+}
+
+export interface LeaseResponse extends BaseResponse {
+    lease?: string;
+    expiration?: number;
+}
+
+export interface SubscribeOptions<P = unknown> {
+    /** Subscribes to the key, the whole queue otherwise */
+    key?: Key;
+    /** Keys of the queue subscription */
+    ids?: Key[];
+    /** Sequence of the first subscription, `first`, `last` or a number */
+    sequence?: number | "first" | "last";
+    maxPreload?: number;
+    identity?: string;
+    /** Client id listed by the admin api */
+    clientId?: string;
+    /** Client labels listed by the admin api, e.g. app name and version */
+    labels?: Record<string, string>;
+    /** Resumable session of the key subscription, continues after the last delivered sequence */
+    session?: string;
+    /** Jwt token or a function which returns a fresh one on every connect */
+    token?: string | (() => string | Promise<string>);
+    /** Reconnects when nothing was received in time, set it above the server heartbeat interval */
+    heartbeatTimeout?: number;
+    onEvent?: (event: EventMessage<P>) => void;
+    onControl?: (control: ControlMessage) => void;
+    onClose?: (close: { code: number; reason: string }) => void;
+}
+
+declare const payloadType: unique symbol;
+
+/** Queue name bound to the payload type of its events */
+export interface QueueBinding<P> {
+    readonly name: string;
+    /** Type marker only, bindings don't hold payloads */
+    readonly [payloadType]?: P;
+}
+
+export declare function queueBinding<P>(name: string): QueueBinding<P>;
+
+/** Methods of the queue which accept and return payloads of the bound type only */
+export declare class TypedQueue<P> {
+    constructor(client: SonyaClient, binding: QueueBinding<P>);
+    readonly name: string;
+    send(event: EventMessage<P>, options?: SendOptions): Promise<SendResponse>;
+    sendPayload(key: Key, payload: P, options?: SendOptions): Promise<SendResponse>;
+    sendBatch(events: EventMessage<P>[], options?: { lease?: string }): Promise<BatchResponse>;
+    publishAndWaitSubscribed(
+        event: EventMessage<P>,
+        options: WaitOptions,
+    ): Promise<{ sequence: number; receipt: DeliveryReceipt }>;
+    publishAndWaitAcked(
+        event: EventMessage<P>,
+        options: WaitOptions,
+    ): Promise<{ sequence: number; receipt: DeliveryReceipt }>;
+    peek(key: Key, options?: { n?: number }): Promise<BaseResponse & { events: EventMessage<P>[] }>;
+    replay(
+        key: Key,
+        options?: { from?: number; limit?: number },
+    ): Promise<BaseResponse & { events: EventMessage<P>[]; next?: number }>;
+    longpoll(key: Key, options?: LongpollOptions): Promise<EventMessage<P>[] | null>;
+    subscribe(options?: SubscribeOptions<P>): Subscription;
+}
+
+export declare class SonyaError extends Error {
+    status: number;
+}
+
+export declare class Subscription {
+    readonly queue: string;
+    readonly key?: Key;
+    /** Sequence of the last received event of the key subscription */
+    readonly lastSequence?: number;
+    refreshToken(accessToken: string): void;
+    close(): void;
+}
+
+export interface LongpollOptions {
+    sequence?: number | "first" | "last";
+    after?: number;
+    maxPreload?: number;
+    token?: string;
+    signal?: AbortSignal;
+}
+
+export interface SendOptions {
+    lease?: string;
+    /** Unique id of the event, retries with the same id are stored once */
+    dedupId?: string;
+}
+
+export interface WaitOptions {
+    /** Queue which receives delivery receipts of the event, it must exist */
+    replyTo: string;
+    /** Milliseconds to wait for the receipt, 30000 by default */
+    timeout?: number;
+    lease?: string;
+    /** Jwt token of the reply key, generated with the service token if unset */
+    token?: string;
+}
+
+export interface DeliveryReceipt {
+    queue: string;
+    id: string;
+    sequence: number;
+    acked: boolean;
+}
+
+export interface OutboxEntry<P = unknown> {
+    dedupId: string;
+    queue: string;
+    event?: EventMessage<P>;
+    /** Key of the payload sent with `sendPayload` */
+    key?: Key;
+    payload?: P;
+    lease?: string;
+}
+
+/** Persistence of buffered events, the whole buffer is saved after every change */
+export interface OutboxStorage {
+    load(): OutboxEntry[] | Promise<OutboxEntry[]>;
+    save(entries: OutboxEntry[]): void | Promise<void>;
+}
+
+export interface OutboxOptions {
+    /** In memory by default */
+    storage?: OutboxStorage;
+    /** Milliseconds between flushes, 5000 by default, 0 flushes only on sends and `online` events */
+    flushInterval?: number;
+    /** Events rejected by the server, they are dropped from the outbox */
+    onRejected?: (entry: OutboxEntry, error: Error) => void;
+}
+
+export declare class Outbox {
+    constructor(client: SonyaClient, options?: OutboxOptions);
+    /** Count of buffered events */
+    readonly pending: number;
+    /** Resolves with the dedup id once the event is stored locally */
+    send<P>(queue: string, event: EventMessage<P>, options?: { lease?: string }): Promise<string>;
+    sendPayload<P>(queue: string, key: Key, payload: P, options?: { lease?: string }): Promise<string>;
+    /** Sends buffered events in order, resolves with the count of events left */
+    flush(): Promise<number>;
+    close(): void;
+}
+
+export declare function memoryOutbox(): OutboxStorage;
+export declare function localStorageOutbox(name?: string, storage?: Storage): OutboxStorage;
+/** Node.js only */
+export declare function fileOutbox(path: string): OutboxStorage;
+
+export type ReadPreference = "primary" | "nearest";
+
+export interface Replica {
+    url: string;
+    /** Lower priorities are preferred regardless of latency, 0 by default */
+    priority?: number;
+}
+
+export interface ClientOptions {
+    serviceToken?: string;
+    WebSocket?: typeof WebSocket;
+    fetch?: typeof fetch;
+    /** Standby or delivery servers which replicate queues of the primary */
+    replicas?: (string | Replica)[];
+    /** Priority of the primary among replicas for `nearest` reads, 0 by default */
+    primaryPriority?: number;
+    /** Server of subscriptions and reads, `primary` by default, writes always go to the primary */
+    readPreference?: ReadPreference;
+    /** Read preferences of queues which differ from `readPreference` */
+    readPreferences?: Record<string, ReadPreference>;
+    /** Milliseconds between latency probes of replicas, probed once if unset */
+    probeInterval?: number;
+    /** Coalesces sends of one queue into batch requests, sends with dedup ids are not batched */
+    batch?: BatchOptions;
+}
+
+export declare class SonyaClient {
+    constructor(baseUrl: string, options?: ClientOptions);
+    /** Round trips in milliseconds by server address, `Infinity` for unreachable servers */
+    probe(): Promise<Record<string, number>>;
+    /** Address which serves subscriptions and reads of the queue */
+    readUrl(queue: string): string;
+    /** Stops latency probes */
+    close(): void;
+    createQueue(queue: string): Promise<BaseResponse>;
+    closeQueue(queue: string): Promise<BaseResponse>;
+    clearQueue(queue: string): Promise<BaseResponse>;
+    send<P>(queue: string, event: EventMessage<P>, options?: SendOptions): Promise<SendResponse>;
+    sendPayload<P>(queue: string, key: Key, payload: P, options?: SendOptions): Promise<SendResponse>;
+    sendBatch<P>(queue: string, events: EventMessage<P>[], options?: { lease?: string }): Promise<BatchResponse>;
+    /** Sends pending batches at once and waits for their results */
+    flush(): Promise<void>;
+    deleteKey(queue: string, key: Key, options?: { tombstone?: boolean }): Promise<BaseResponse>;
+    revoke(queue: string, key: Key, sequence: number): Promise<BaseResponse>;
+    acquireLease(queue: string, key: Key, options?: { ttl?: number; lease?: string }): Promise<LeaseResponse>;
+    releaseLease(queue: string, key: Key, lease: string): Promise<BaseResponse>;
+    count(queue: string, key: Key): Promise<BaseResponse & { count: number }>;
+    peek<P>(queue: string, key: Key, options?: { n?: number }): Promise<BaseResponse & { events: EventMessage<P>[] }>;
+    replay<P>(
+        queue: string,
+        key: Key,
+        options?: { from?: number; limit?: number },
+    ): Promise<BaseResponse & { events: EventMessage<P>[]; next?: number }>;
+    head(queue: string, key: Key): Promise<BaseResponse & { key?: object; queue?: object }>;
+    /** Confirms processing of the event to its publisher, subscribers pass their jwt token */
+    ack(
+        queue: string,
+        key: Key,
+        sequence: number,
+        options?: { token?: string; session?: string },
+    ): Promise<BaseResponse>;
+    generateJwt(queue: string, key: Key, options?: { identity?: string }): Promise<{ token: string; expiration: number }>;
+    /** Resolves when the event was delivered to a subscriber, rejects with `TimeoutError` */
+    publishAndWaitSubscribed<P>(
+        queue: string,
+        event: EventMessage<P>,
+        options: WaitOptions,
+    ): Promise<{ sequence: number; receipt: DeliveryReceipt }>;
+    /** Resolves when a subscriber acknowledged the event, rejects with `TimeoutError` */
+    publishAndWaitAcked<P>(
+        queue: string,
+        event: EventMessage<P>,
+        options: WaitOptions,
+    ): Promise<{ sequence: number; receipt: DeliveryReceipt }>;
+    longpoll<P>(queue: string, key: Key, options?: LongpollOptions): Promise<EventMessage<P>[] | null>;
+    subscribe<P>(queue: string, options?: SubscribeOptions<P>): Subscription;
+}
@@ -0,0 +1,729 @@
+// Thin client of the SonyaWQ web queue.
+// Keep in sync with the wire protocol in documentation/api/queue/websocket.md.
+
+export const MAX_RECONNECT_ATTEMPTS = 10;
+
+export const CloseCode = Object.freeze({
+    QUEUE_CLOSED: 4000,
+    KEY_DELETED: 4001,
+    DISCONNECTED: 4003,
+    AUTH_EXPIRED: 4401,
+    MOVED: 4307,
+    DRAINING: 1001,
+    SLOW_CONSUMER: 1008,
+    MESSAGE_TOO_LARGE: 1009,
+    INTERNAL_ERROR: 1011,
+});
+
+const LEASE_HEADER = "Sonya-Lease";
+
+const DEDUP_HEADER = "Sonya-Dedup-Id";
+
+// Small public document, its response headers measure the round trip to the server
+const PROBE_PATH = "/spec/asyncapi.json";
+
+// The same backoff as the proxy uses between reconnects to queue shards
+export function reconnectDelay(attempt) {
+    return Math.floor(Math.sqrt(1.5 * attempt)) * 1000;
+}
+
+export class SonyaError extends Error {
+    constructor(status, message) {
+        super(message || `request failed with ${status} code`);
+        this.status = status;
+    }
+}
+
+export class SonyaClient {
+    /**
+     * @param {string} baseUrl http(s) address of the queue or the proxy, the primary of replicas
+     * @param {object} options
+     */
+    constructor(baseUrl, {
+        serviceToken,
+        WebSocket: ws,
+        fetch: f,
+        replicas = [],
+        primaryPriority = 0,
+        readPreference = "primary",
+        readPreferences = {},
+        probeInterval,
+        batch,
+    } = {}) {
+        this.baseUrl = baseUrl.replace(/\/+$/, "");
+        this.serviceToken = serviceToken;
+        this.WebSocket = ws || globalThis.WebSocket;
+        this.fetch = f || globalThis.fetch.bind(globalThis);
+        this.replicas = replicas
+            .map((replica) => (typeof replica === "string" ? { url: replica } : replica))
+            .map(({ url, priority = 0 }) => ({ url: url.replace(/\/+$/, ""), priority }));
+        this.primaryPriority = primaryPriority;
+        this.readPreference = readPreference;
+        this.readPreferences = readPreferences;
+        this.latencies = new Map();
+        this.batch = batch && { maxSize: 100, maxDelay: 10, ...batch };
+        // pending and last sent batches by queue and lease, batches of one queue are sent in order
+        this.batches = new Map();
+        this.sending = new Map();
+        if (this.replicas.length) {
+            this.probe();
+            if (probeInterval) {
+                this.probeTimer = setInterval(() => this.probe(), probeInterval);
+                this.probeTimer.unref?.();
+            }
+        }
+    }
+
+    /**
+     * Measures round trips to the primary and replicas, unreachable servers get `Infinity`.
+     */
+    async probe() {
+        const urls = [this.baseUrl, ...this.replicas.map(({ url }) => url)];
+        await Promise.all(urls.map(async (url) => {
+            const started = Date.now();
+            try {
+                const response = await this.fetch(url + PROBE_PATH);
+                await response.body?.cancel();
+                this.latencies.set(url, Date.now() - started);
+            } catch {
+                this.latencies.set(url, Infinity);
+            }
+        }));
+        return Object.fromEntries(this.latencies);
+    }
+
+    /**
+     * Address for subscriptions and reads of the queue. Queues preferring `nearest` are read from
+     * the reachable server with the lowest priority and the lowest latency, writes go to the primary.
+     */
+    readUrl(queue) {
+        const preference = this.readPreferences[queue] || this.readPreference;
+        if (preference !== "nearest") {
+            return this.baseUrl;
+        }
+        const latency = ({ url }) => this.latencies.get(url) ?? Number.MAX_SAFE_INTEGER;
+        const [nearest] = [{ url: this.baseUrl, priority: this.primaryPriority }, ...this.replicas]
+            .filter((server) => latency(server) !== Infinity)
+            .sort((a, b) => a.priority - b.priority || latency(a) - latency(b));
+        return nearest ? nearest.url : this.baseUrl;
+    }
+
+    // failed connections skip the server until the next probe
+    unreachable(url) {
+        this.latencies.set(url, Infinity);
+    }
+
+    close() {
+        clearInterval(this.probeTimer);
+    }
+
+    createQueue(queue) {
+        return this.request("POST", `/queue/create/${enc(queue)}`);
+    }
+
+    closeQueue(queue) {
+        return this.request("POST", `/queue/close/${enc(queue)}`);
+    }
+
+    clearQueue(queue) {
+        return this.request("POST", `/queue/clear/${enc(queue)}`);
+    }
+
+    send(queue, event, { lease, dedupId } = {}) {
+        // dedup ids are sent in headers, so deduplicated events are sent alone
+        if (this.batch && !dedupId) {
+            return this.enqueue(queue, event, lease);
+        }
+        return this.request("POST", `/queue/send/${enc(queue)}`, { body: event, lease, dedupId });
+    }
+
+    sendPayload(queue, key, payload, { lease, dedupId } = {}) {
+        if (this.batch && !dedupId) {
+            return this.enqueue(queue, { id: key, payload }, lease);
+        }
+        const path = `/queue/send/${enc(queue)}/${enc(key)}`;
+        return this.request("POST", path, { body: payload, lease, dedupId });
+    }
+
+    /**
+     * Sends events in order with one request, results are returned in the same order.
+     */
+    sendBatch(queue, events, { lease } = {}) {
+        return this.request("POST", `/queue/batch/${enc(queue)}`, { body: events, lease });
+    }
+
+    /**
+     * Sends pending batches at once, resolves when all sent batches are answered.
+     */
+    async flush() {
+        [...this.batches.keys()].forEach((name) => this.flushBatch(name));
+        await Promise.all([...this.sending.values()]);
+    }
+
+    deleteKey(queue, key, { tombstone = false } = {}) {
+        return this.request("POST", `/queue/delete/${enc(queue)}/${enc(key)}`, { query: { tombstone } });
+    }
+
+    revoke(queue, key, sequence) {
+        return this.request("POST", `/queue/revoke/${enc(queue)}/${enc(key)}/${sequence}`);
+    }
+
+    acquireLease(queue, key, { ttl, lease } = {}) {
+        return this.request("POST", `/queue/lease/${enc(queue)}/${enc(key)}`, { query: { ttl }, lease });
+    }
+
+    releaseLease(queue, key, lease) {
+        return this.request("POST", `/queue/release/${enc(queue)}/${enc(key)}`, { lease });
+    }
+
+    count(queue, key) {
+        return this.request("GET", `/queue/count/${enc(queue)}/${enc(key)}`, { queue });
+    }
+
+    peek(queue, key, { n } = {}) {
+        return this.request("GET", `/queue/peek/${enc(queue)}/${enc(key)}`, { query: { n }, queue });
+    }
+
+    replay(queue, key, { from, limit } = {}) {
+        const path = `/queue/replay/${enc(queue)}/${enc(key)}`;
+        return this.request("GET", path, { query: { from, limit }, queue });
+    }
+
+    head(queue, key) {
+        return this.request("GET", `/queue/head/${enc(queue)}/${enc(key)}`, { queue });
+    }
+
+    /**
+     * Confirms processing of the event to its publisher, subscribers pass their jwt token
+     * and resumable session, which is listed in the message trace.
+     */
+    ack(queue, key, sequence, { token, session } = {}) {
+        const path = `/queue/ack/${enc(queue)}/${enc(key)}`;
+        return this.request("POST", path, { query: { sequence, session, access_token: token } });
+    }
+
+    generateJwt(queue, key, { identity } = {}) {
+        return this.request("POST", `/queue/generate_jwt/${enc(queue)}/${enc(key)}`, { query: { identity } });
+    }
+
+    /**
+     * Sends the event with `reply_to` and resolves when it was delivered to a subscriber of the queue.
+     * Rejects with `TimeoutError` if no receipt arrived in `timeout` milliseconds.
+     */
+    publishAndWaitSubscribed(queue, event, options) {
+        return this.publishAndWait(queue, event, false, options);
+    }
+
+    /**
+     * Sends the event with `reply_to` and resolves when a subscriber acknowledged it with `ack`.
+     */
+    publishAndWaitAcked(queue, event, options) {
+        return this.publishAndWait(queue, event, true, options);
+    }
+
+    // receipts are read after the current head of the reply key, so receipts of earlier events are skipped
+    async publishAndWait(queue, event, acked, { replyTo, timeout = 30000, lease, token } = {}) {
+        if (!replyTo) {
+            throw new TypeError("replyTo queue is required");
+        }
+        const signal = AbortSignal.timeout(timeout);
+        const key = String(event.id);
+        if (this.serviceToken && !token) {
+            ({ token } = await this.generateJwt(replyTo, key));
+        }
+        const { key: head } = await this.head(replyTo, key);
+        const body = { ...event, reply_to: replyTo };
+        const path = `/queue/send/${enc(queue)}`;
+        const { success, sequence } = await this.request("POST", path, { body, lease });
+        if (!success) {
+            throw new SonyaError(404, `queue ${queue} does not exist`);
+        }
+        if (sequence === null || sequence === undefined) {
+            throw new SonyaError(400, "events without sequences are not confirmed");
+        }
+        let next = (head?.sequence ?? 0) + 1;
+        for (;;) {
+            const receipts = await this.longpoll(replyTo, key, { sequence: next, token, signal });
+            for (const receipt of receipts || []) {
+                next = receipt.sequence + 1;
+                const { payload } = receipt;
+                if (payload.queue === queue && payload.sequence === sequence && (payload.acked || !acked)) {
+                    return { sequence, receipt: payload };
+                }
+            }
+        }
+    }
+
+    /**
+     * Waits for the next events of the key, `null` if nothing was published after the `after` sequence.
+     */
+    async longpoll(queue, key, { sequence, after, maxPreload, token, signal } = {}) {
+        const query = { sequence, after, max_preload: maxPreload, access_token: token };
+        const path = `/queue/listen/longpoll/${enc(queue)}/${enc(key)}`;
+        const url = this.url(path, query, undefined, this.readUrl(queue));
+        const response = await this.fetch(url, { signal });
+        if (response.status === 204) {
+            return null;
+        }
+        return parse(response);
+    }
+
+    /**
+     * Subscribes to the key or the whole queue over websocket.
+     * Key subscriptions resume from the last received sequence after reconnects.
+     *
+     * @returns {Subscription}
+     */
+    subscribe(queue, options = {}) {
+        return new Subscription(this, queue, options);
+    }
+
+    // sends are coalesced until the batch is full or its delay is over
+    enqueue(queue, event, lease) {
+        const name = JSON.stringify([queue, lease ?? null]);
+        let batch = this.batches.get(name);
+        if (!batch) {
+            batch = { queue, lease, entries: [] };
+            batch.timer = setTimeout(() => this.flushBatch(name), this.batch.maxDelay);
+            this.batches.set(name, batch);
+        }
+        return new Promise((resolve, reject) => {
+            batch.entries.push({ event, resolve, reject });
+            if (batch.entries.length >= this.batch.maxSize) {
+                this.flushBatch(name);
+            }
+        });
+    }
+
+    // the next batch of the queue is collected while the previous one is in flight
+    flushBatch(name) {
+        const batch = this.batches.get(name);
+        if (!batch) {
+            return;
+        }
+        this.batches.delete(name);
+        clearTimeout(batch.timer);
+        const previous = this.sending.get(name);
+        const sending = Promise.resolve(previous)
+            .then(() => this.sendBatch(batch.queue, batch.entries.map(({ event }) => event), batch))
+            .then(({ events }) => batch.entries.forEach(({ resolve, reject }, i) => {
+                const { success, status, error, sequence } = events[i];
+                if (status >= 400) {
+                    reject(new SonyaError(status, error));
+                } else {
+                    resolve({ success, sequence });
+                }
+            }))
+            .catch((error) => batch.entries.forEach(({ reject }) => reject(error)))
+            .finally(() => {
+                if (this.sending.get(name) === sending) {
+                    this.sending.delete(name);
+                }
+            });
+        this.sending.set(name, sending);
+    }
+
+    // reads pass the queue to be served by its preferred server
+    async request(method, path, { body, query, lease, dedupId, queue } = {}) {
+        const headers = {};
+        if (this.serviceToken) {
+            headers["Authorization"] = `Bearer ${this.serviceToken}`;
+        }
+        if (lease) {
+            headers[LEASE_HEADER] = lease;
+        }
+        if (dedupId) {
+            headers[DEDUP_HEADER] = dedupId;
+        }
+        if (body !== undefined) {
+            headers["Content-Type"] = "application/json";
+        }
+        const base = queue === undefined ? this.baseUrl : this.readUrl(queue);
+        const response = await this.fetch(this.url(path, query, undefined, base), {
+            method,
+            headers,
+            body: body === undefined ? undefined : JSON.stringify(body),
+        });
+        return parse(response);
+    }
+
+    url(path, query = {}, protocol, base = this.baseUrl) {
+        const url = new URL(base + path);
+        Object.entries(query)
+            .filter(([, value]) => value !== undefined && value !== null)
+            .forEach(([name, value]) => url.searchParams.set(name, String(value)));
+        if (protocol) {
+            url.protocol = url.protocol === "https:" ? "wss:" : "ws:";
+        }
+        return url.toString();
+    }
+}
+
+/**
+ * Persistent buffer of publishes for producers with flaky connectivity.
+ * Events are stored before sending and flushed in order with dedup ids,
+ * so retries of events which reached the server are not stored twice.
+ */
+/**
+ * Binds the queue name to the payload type of its events, so TypeScript rejects sends and handlers
+ * of other payloads: `const orders = queueBinding<OrderEvent>("orders")`.
+ */
+export function queueBinding(name) {
+    return Object.freeze({ name });
+}
+
+/**
+ * Methods of the bound queue, `new TypedQueue(client, orders)`.
+ */
+export class TypedQueue {
+    constructor(client, binding) {
+        this.client = client;
+        this.name = binding.name;
+    }
+
+    send(event, options) {
+        return this.client.send(this.name, event, options);
+    }
+
+    sendPayload(key, payload, options) {
+        return this.client.sendPayload(this.name, key, payload, options);
+    }
+
+    sendBatch(events, options) {
+        return this.client.sendBatch(this.name, events, options);
+    }
+
+    publishAndWaitSubscribed(event, options) {
+        return this.client.publishAndWaitSubscribed(this.name, event, options);
+    }
+
+    publishAndWaitAcked(event, options) {
+        return this.client.publishAndWaitAcked(this.name, event, options);
+    }
+
+    peek(key, options) {
+        return this.client.peek(this.name, key, options);
+    }
+
+    replay(key, options) {
+        return this.client.replay(this.name, key, options);
+    }
+
+    longpoll(key, options) {
+        return this.client.longpoll(this.name, key, options);
+    }
+
+    subscribe(options) {
+        return this.client.subscribe(this.name, options);
+    }
+}
+
+export class Outbox {
+    constructor(client, { storage = memoryOutbox(), flushInterval = 5000, onRejected } = {}) {
+        this.client = client;
+        this.storage = storage;
+        this.onRejected = onRejected;
+        this.entries = [];
+        this.flushing = undefined;
+        this.saving = Promise.resolve();
+        this.loaded = Promise.resolve(storage.load()).then((entries) => {
+            this.entries = entries || [];
+        });
+        if (flushInterval) {
+            this.flushTimer = setInterval(() => this.flush(), flushInterval);
+            this.flushTimer.unref?.();
+        }
+        globalThis.addEventListener?.("online", this.onOnline = () => this.flush());
+    }
+
+    /** Count of buffered events */
+    get pending() {
+        return this.entries.length;
+    }
+
+    /**
+     * Buffers the event and tries to send it, resolves with its dedup id once it's stored locally.
+     */
+    async send(queue, event, { lease } = {}) {
+        return this.push({ queue, event, lease });
+    }
+
+    async sendPayload(queue, key, payload, { lease } = {}) {
+        return this.push({ queue, key, payload, lease });
+    }
+
+    /**
+     * Sends buffered events in order until the server is unreachable.
+     * Events rejected by the server are dropped and passed to `onRejected`.
+     *
+     * @returns {Promise<number>} count of buffered events left
+     */
+    flush() {
+        if (!this.flushing) {
+            this.flushing = this.sendAll().finally(() => {
+                this.flushing = undefined;
+            });
+        }
+        return this.flushing;
+    }
+
+    close() {
+        clearInterval(this.flushTimer);
+        globalThis.removeEventListener?.("online", this.onOnline);
+    }
+
+    async push(entry) {
+        await this.loaded;
+        entry.dedupId = randomId();
+        this.entries.push(entry);
+        await this.persist();
+        this.flush();
+        return entry.dedupId;
+    }
+
+    async sendAll() {
+        await this.loaded;
+        while (this.entries.length) {
+            const [entry] = this.entries;
+            try {
+                const response = entry.payload === undefined
+                    ? await this.client.send(entry.queue, entry.event, entry)
+                    : await this.client.sendPayload(entry.queue, entry.key, entry.payload, entry);
+                if (!response.success) {
+                    this.reject(entry, new SonyaError(404, `queue ${entry.queue} does not exist`));
+                }
+            } catch (error) {
+                if (retriable(error)) {
+                    break;
+                }
+                this.reject(entry, error);
+            }
+            this.entries.shift();
+            await this.persist();
+        }
+        return this.entries.length;
+    }
+
+    // saves are queued, so the storage never writes an older snapshot over a newer one
+    persist() {
+        const entries = [...this.entries];
+        this.saving = this.saving.catch(() => {}).then(() => this.storage.save(entries));
+        return this.saving;
+    }
+
+    reject(entry, error) {
+        if (typeof this.onRejected === "function") {
+            this.onRejected(entry, error);
+        }
+    }
+}
+
+// network errors, timeouts and unavailable servers, the event is sent again with the same dedup id
+function retriable(error) {
+    return !(error instanceof SonyaError) || error.status >= 500 || error.status === 429;
+}
+
+function randomId() {
+    return globalThis.crypto.randomUUID();
+}
+
+/** Keeps the outbox in memory, buffered events are lost with the process */
+export function memoryOutbox() {
+    return { load: () => [], save: () => {} };
+}
+
+/** Keeps the outbox in the browser local storage */
+export function localStorageOutbox(name = "sonya-outbox", storage = globalThis.localStorage) {
+    return {
+        load: () => JSON.parse(storage.getItem(name) || "[]"),
+        save: (entries) => storage.setItem(name, JSON.stringify(entries)),
+    };
+}
+
+/** Keeps the outbox in the json file, Node.js only */
+export function fileOutbox(path) {
+    const fs = import("node:fs/promises");
+    return {
+        async load() {
+            try {
+                return JSON.parse(await (await fs).readFile(path, "utf8"));
+            } catch (error) {
+                if (error.code === "ENOENT") {
+                    return [];
+                }
+                throw error;
+            }
+        },
+        // written aside and renamed, so a crash never leaves a truncated file
+        async save(entries) {
+            const { writeFile, rename } = await fs;
+            await writeFile(`${path}.tmp`, JSON.stringify(entries));
+            await rename(`${path}.tmp`, path);
+        },
+    };
+}
+
+export class Subscription {
+    constructor(client, queue, options) {
+        this.client = client;
+        this.queue = queue;
+        this.key = options.key;
+        this.options = options;
+        this.sequence = options.sequence;
+        this.lastSequence = undefined;
+        this.retryAfter = undefined;
+        this.attempts = 0;
+        this.closed = false;
+        this.connect();
+    }
+
+    /**
+     * Sends a new jwt token before the current one expires, key subscriptions only.
+     */
+    refreshToken(accessToken) {
+        this.send({ control: "refresh_token", access_token: accessToken });
+    }
+
+    close() {
+        this.closed = true;
+        clearTimeout(this.heartbeatTimer);
+        clearTimeout(this.reconnectTimer);
+        if (this.socket) {
+            this.socket.close(1000);
+        }
+    }
+
+    send(message) {
+        if (this.socket && this.socket.readyState === 1) {
+            this.socket.send(JSON.stringify(message));
+        }
+    }
+
+    async connect() {
+        const { ids, maxPreload, identity, clientId, labels, session } = this.options;
+        let token = this.options.token;
+        if (typeof token === "function") {
+            token = await token();
+        }
+        if (this.closed) {
+            return;
+        }
+        const path = this.key === undefined
+            ? `/queue/listen/ws/${enc(this.queue)}`
+            : `/queue/listen/ws/${enc(this.queue)}/${enc(this.key)}`;
+        const query = {
+            ids: ids && ids.join(","),
+            sequence: this.resumeSequence(),
+            max_preload: maxPreload,
+            identity,
+            client_id: clientId,
+            labels: labels && Object.entries(labels).map(([name, value]) => `${name}:${value}`).join(","),
+            session,
+            access_token: token || this.client.serviceToken,
+        };
+
+        const endpoint = this.client.readUrl(this.queue);
+        const socket = new this.client.WebSocket(this.client.url(path, query, "ws", endpoint));
+        this.socket = socket;
+        let opened = false;
+        socket.onopen = () => {
+            opened = true;
+            this.attempts = 0;
+            this.watchHeartbeat();
+        };
+        socket.onmessage = (message) => {
+            this.watchHeartbeat();
+            this.receive(JSON.parse(message.data));
+        };
+        socket.onclose = (event) => {
+            // the next attempt connects to another replica
+            if (!opened && endpoint !== this.client.baseUrl) {
+                this.client.unreachable(endpoint);
+            }
+            this.closedBy(event.code, event.reason);
+        };
+    }
+
+    receive(message) {
+        if (message.control === undefined) {
+            if (message.sequence !== undefined && !message.ephemeral) {
+                this.lastSequence = message.sequence;
+            }
+            this.emit("onEvent", message);
+            return;
+        }
+        if (message.control === "draining") {
+            this.retryAfter = message.retry_after;
+        }
+        // the whole server failed over, so other subscriptions and requests follow it too
+        if (message.control === "moved") {
+            this.client.baseUrl = message.address.replace(/\/+$/, "");
+        }
+        if (message.control !== "heartbeat") {
+            this.emit("onControl", message);
+        }
+    }
+
+    // key histories have own sequences, so only key subscriptions are resumed precisely
+    resumeSequence() {
+        if (this.key !== undefined && this.lastSequence !== undefined) {
+            return this.lastSequence + 1;
+        }
+        return this.sequence;
+    }
+
+    watchHeartbeat() {
+        const { heartbeatTimeout } = this.options;
+        if (!heartbeatTimeout) {
+            return;
+        }
+        clearTimeout(this.heartbeatTimer);
+        this.heartbeatTimer = setTimeout(() => this.socket.close(4002, "heartbeat timeout"), heartbeatTimeout);
+    }
+
+    closedBy(code, reason) {
+        clearTimeout(this.heartbeatTimer);
+        if (this.closed) {
+            return;
+        }
+        const retry = code !== CloseCode.QUEUE_CLOSED
+            && code !== CloseCode.KEY_DELETED
+            && code !== CloseCode.DISCONNECTED
+            && this.attempts < MAX_RECONNECT_ATTEMPTS;
+        if (!retry) {
+            this.closed = true;
+            this.emit("onClose", { code, reason });
+            return;
+        }
+        this.attempts += 1;
+        // expired tokens are taken again from the token function and moved servers are ready,
+        // so reconnect without delay
+        let delay = code === CloseCode.AUTH_EXPIRED || code === CloseCode.MOVED
+            ? 0
+            : reconnectDelay(this.attempts);
+        // draining servers spread reconnects of their clients with jittered delays
+        if (code === CloseCode.DRAINING && this.retryAfter !== undefined) {
+            delay = this.retryAfter * 1000;
+        }
+        this.retryAfter = undefined;
+        this.reconnectTimer = setTimeout(() => this.connect(), delay);
+    }
+
+    emit(callback, value) {
+        if (typeof this.options[callback] === "function") {
+            this.options[callback](value);
+        }
+    }
+}
+
+function enc(value) {
+    return encodeURIComponent(String(value));
+}
+
+async function parse(response) {
+    const text = await response.text();
+    if (!response.ok) {
+        throw new SonyaError(response.status, text);
+    }
+    return text ? JSON.parse(text) : null;
+}
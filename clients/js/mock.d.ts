@@ -0,0 +1,13 @@
+import { ClientOptions, EventMessage, Key, SonyaClient } from "./index.js";
+
+/** In-process queues for tests of applications, served through the fetch and WebSocket of clients */
+export declare class MockServer {
+    readonly fetch: typeof fetch;
+    readonly WebSocket: typeof WebSocket;
+    /** Client of the mock, `fetch` and `WebSocket` options are replaced */
+    client(options?: ClientOptions): SonyaClient;
+    /** Stored events of the key in the order of sequences */
+    events<P>(queue: string, key: Key): EventMessage<P>[];
+    /** Closes all subscriptions with the code, e.g. to test reconnects */
+    disconnect(code?: number, reason?: string): void;
+}
@@ -0,0 +1,339 @@
+import { CloseCode, SonyaClient } from "./index.js";
+
+const BASE_URL = "http://sonya.mock";
+
+/**
+ * In-process queues for tests of applications. Clients of the mock send requests and subscribe
+ * through its fetch and WebSocket, so batching, outboxes and typed queues work as with a server.
+ * Secure mode, leases, replicas and the admin api are not mocked.
+ */
+export class MockServer {
+    constructor() {
+        this.queues = new Map();
+        this.sockets = new Set();
+        this.waiters = new Set();
+        this.dedupIds = new Set();
+        // delivery receipt marks of events with `reply_to`
+        this.receipts = new Map();
+        this.fetch = (url, init) => this.handle(new URL(url), init || {});
+        const server = this;
+        this.WebSocket = class extends MockSocket {
+            constructor(url) {
+                super(server, new URL(url));
+            }
+        };
+    }
+
+    client(options = {}) {
+        return new SonyaClient(BASE_URL, { ...options, fetch: this.fetch, WebSocket: this.WebSocket });
+    }
+
+    /**
+     * Stored events of the key in the order of sequences.
+     */
+    events(queue, key) {
+        return [...this.keyEvents(queue, String(key))];
+    }
+
+    /**
+     * Closes all subscriptions with the code, e.g. to test reconnects.
+     */
+    disconnect(code = CloseCode.INTERNAL_ERROR, reason = "") {
+        [...this.sockets].forEach((socket) => socket.close(code, reason));
+    }
+
+    async handle(url, { method = "GET", headers = {}, body, signal }) {
+        const [, scope, action, ...params] = url.pathname.split("/").map(decodeURIComponent);
+        const query = Object.fromEntries(url.searchParams);
+        const data = body === undefined ? undefined : JSON.parse(body);
+        if (scope !== "queue") {
+            return respond(404, "Not Found");
+        }
+        const [queue, key, sequence] = params;
+        switch (`${method} ${action}`) {
+            case "POST create":
+                if (!this.queues.has(queue)) {
+                    this.queues.set(queue, { keys: new Map(), counters: new Map() });
+                }
+                return respond(200, { success: true });
+            case "POST close":
+                return respond(200, { success: this.closeQueue(queue) });
+            case "POST clear":
+                this.queues.get(queue)?.keys.clear();
+                return respond(200, { success: this.queues.has(queue) });
+            case "POST send": {
+                const event = key === undefined ? data : { id: key, payload: data };
+                return respond(200, this.publish(queue, event, headers["Sonya-Dedup-Id"]));
+            }
+            case "POST batch": {
+                const events = data.map((event) => ({
+                    status: 200,
+                    error: null,
+                    ...this.publish(queue, event),
+                }));
+                return respond(200, { success: events.every((e) => e.success), events });
+            }
+            case "POST delete":
+                return respond(200, { success: this.deleteKey(queue, key, query.tombstone === "true") });
+            case "POST revoke":
+                return respond(200, { success: this.revoke(queue, key, Number(sequence)) });
+            case "POST ack":
+                return respond(200, { success: this.ack(queue, key, Number(query.sequence)) });
+            case "POST generate_jwt":
+                return respond(200, { token: "mock", expiration: Math.floor(Date.now() / 1000) + 3600 });
+            case "GET count": {
+                const count = this.keyEvents(queue, key).length;
+                return respond(200, { success: this.queues.has(queue), count });
+            }
+            case "GET peek": {
+                const events = this.keyEvents(queue, key).slice(-Number(query.n || 10)).reverse();
+                return respond(200, { success: this.queues.has(queue), events });
+            }
+            case "GET replay": {
+                const from = Number(query.from || 0);
+                const limit = Number(query.limit || 100);
+                const events = this.keyEvents(queue, key).filter((e) => e.sequence >= from);
+                const next = events[limit]?.sequence ?? null;
+                const page = events.slice(0, limit);
+                return respond(200, { success: this.queues.has(queue), events: page, next });
+            }
+            case "GET head": {
+                const last = this.keyEvents(queue, key).at(-1);
+                const head = last && { sequence: last.sequence, timestamp: Math.floor(Date.now() / 1000) };
+                return respond(200, { success: this.queues.has(queue), key: head || null, queue: null });
+            }
+            case "GET listen":
+                return this.longpoll(params[1], params[2], query, signal);
+            default:
+                return respond(404, "Not Found");
+        }
+    }
+
+    publish(queue, event, dedupId) {
+        const state = this.queues.get(queue);
+        if (!state) {
+            return { success: false, sequence: null };
+        }
+        if (dedupId !== undefined) {
+            const name = `${queue}/${dedupId}`;
+            if (this.dedupIds.has(name)) {
+                return { success: true, sequence: null };
+            }
+            this.dedupIds.add(name);
+        }
+        const id = String(event.id);
+        const stored = { ...event, id };
+        if (event.ephemeral) {
+            this.broadcast(queue, stored);
+            return { success: true, sequence: null };
+        }
+        const sequence = event.sequence ?? (state.counters.get(id) || 0) + 1;
+        state.counters.set(id, Math.max(state.counters.get(id) || 0, sequence));
+        stored.sequence = sequence;
+        const events = (state.keys.get(id) || []).filter((e) => e.sequence !== sequence);
+        state.keys.set(id, [...events, stored].sort((a, b) => a.sequence - b.sequence));
+        this.broadcast(queue, stored);
+        return { success: true, sequence };
+    }
+
+    broadcast(queue, event) {
+        [...this.sockets]
+            .filter((socket) => socket.queue === queue && socket.accepts(event.id))
+            .forEach((socket) => socket.deliver(event));
+        [...this.waiters]
+            .filter((waiter) => waiter.queue === queue && waiter.key === event.id)
+            .forEach((waiter) => waiter.resolve(event));
+    }
+
+    closeQueue(queue) {
+        if (!this.queues.delete(queue)) {
+            return false;
+        }
+        [...this.sockets]
+            .filter((socket) => socket.queue === queue)
+            .forEach((socket) => socket.end("queue_closed", CloseCode.QUEUE_CLOSED, "queue closed"));
+        return true;
+    }
+
+    deleteKey(queue, key, tombstone) {
+        const state = this.queues.get(queue);
+        if (!state) {
+            return false;
+        }
+        state.keys.delete(key);
+        [...this.sockets]
+            .filter((socket) => socket.queue === queue && socket.key === key)
+            .forEach((socket) => socket.end("key_deleted", CloseCode.KEY_DELETED, "key deleted"));
+        if (tombstone) {
+            this.publish(queue, { id: key, payload: null, tombstone: true });
+        }
+        return true;
+    }
+
+    revoke(queue, key, sequence) {
+        const events = this.keyEvents(queue, key);
+        if (!events.some((e) => e.sequence === sequence)) {
+            return false;
+        }
+        this.queues.get(queue).keys.set(key, events.filter((e) => e.sequence !== sequence));
+        [...this.sockets]
+            .filter((socket) => socket.queue === queue && socket.accepts(key))
+            .forEach((socket) => socket.message({ control: "revoked", id: key, sequence }));
+        return true;
+    }
+
+    ack(queue, key, sequence) {
+        const event = this.keyEvents(queue, key).find((e) => e.sequence === sequence);
+        if (!event) {
+            return false;
+        }
+        this.confirm(queue, event, true);
+        return true;
+    }
+
+    // events with `reply_to` are confirmed once after the first delivery and once after the ack
+    confirm(queue, event, acked = false) {
+        if (!event.reply_to || event.sequence === undefined) {
+            return;
+        }
+        const mark = JSON.stringify([queue, event.id, event.sequence]);
+        if (this.receipts.get(mark) === "acked" || (!acked && this.receipts.has(mark))) {
+            return;
+        }
+        this.receipts.set(mark, acked ? "acked" : "delivered");
+        const payload = { queue, id: event.id, sequence: event.sequence, acked };
+        this.publish(event.reply_to, { id: event.id, payload });
+    }
+
+    async longpoll(queue, key, { sequence, after }, signal) {
+        if (!this.queues.has(queue)) {
+            return respond(404, "Queue does not exist");
+        }
+        const events = this.keyEvents(queue, key);
+        let preload = [];
+        if (sequence !== undefined) {
+            preload = preloaded(events, sequence);
+        } else if (after !== undefined) {
+            preload = events.filter((e) => e.sequence > Number(after));
+            if (!preload.length) {
+                return new Response(null, { status: 204 });
+            }
+        }
+        if (!preload.length) {
+            preload = [await this.wait(queue, key, signal)];
+        }
+        preload.forEach((event) => this.confirm(queue, event));
+        return respond(200, preload);
+    }
+
+    wait(queue, key, signal) {
+        return new Promise((resolve, reject) => {
+            const waiter = {
+                queue,
+                key,
+                resolve: (event) => {
+                    this.waiters.delete(waiter);
+                    resolve(event);
+                },
+            };
+            this.waiters.add(waiter);
+            signal?.addEventListener("abort", () => {
+                this.waiters.delete(waiter);
+                reject(signal.reason);
+            });
+        });
+    }
+
+    keyEvents(queue, key) {
+        return this.queues.get(queue)?.keys.get(key) || [];
+    }
+}
+
+class MockSocket {
+    constructor(server, url) {
+        const [, , , , queue, key] = url.pathname.split("/").map(decodeURIComponent);
+        this.server = server;
+        this.queue = queue;
+        this.key = key;
+        this.ids = url.searchParams.get("ids")?.split(",");
+        this.sequence = url.searchParams.get("sequence") ?? undefined;
+        this.readyState = 0;
+        setTimeout(() => this.open());
+    }
+
+    accepts(id) {
+        if (this.key !== undefined) {
+            return this.key === id;
+        }
+        return !this.ids || this.ids.includes(id);
+    }
+
+    open() {
+        if (this.readyState !== 0) {
+            return;
+        }
+        if (!this.server.queues.has(this.queue)) {
+            this.close(CloseCode.QUEUE_CLOSED, "queue closed");
+            return;
+        }
+        this.readyState = 1;
+        this.server.sockets.add(this);
+        this.onopen?.();
+        if (this.sequence === undefined) {
+            return;
+        }
+        const keys = this.key === undefined
+            ? [...this.server.queues.get(this.queue).keys.keys()].filter((id) => this.accepts(id))
+            : [this.key];
+        keys.flatMap((id) => preloaded(this.server.keyEvents(this.queue, id), this.sequence))
+            .forEach((event) => this.deliver(event));
+        this.message({ control: "end_of_preload" });
+    }
+
+    send(data) {
+        const message = JSON.parse(data);
+        if (message.control === "refresh_token") {
+            this.message({ control: "token_refreshed", expiration: Math.floor(Date.now() / 1000) + 3600 });
+        }
+    }
+
+    deliver(event) {
+        this.server.confirm(this.queue, event);
+        this.message(event);
+    }
+
+    message(value) {
+        if (this.readyState === 1) {
+            this.onmessage?.({ data: JSON.stringify(value) });
+        }
+    }
+
+    end(control, code, reason) {
+        this.message({ control });
+        this.close(code, reason);
+    }
+
+    close(code = 1000, reason = "") {
+        if (this.readyState === 3) {
+            return;
+        }
+        this.readyState = 3;
+        this.server.sockets.delete(this);
+        setTimeout(() => this.onclose?.({ code, reason }));
+    }
+}
+
+function preloaded(events, sequence) {
+    if (sequence === "first") {
+        return events;
+    }
+    if (sequence === "last") {
+        return events.slice(-1);
+    }
+    return events.filter((e) => e.sequence >= Number(sequence));
+}
+
+function respond(status, body) {
+    const text = typeof body === "string" ? body : JSON.stringify(body);
+    return new Response(text, { status });
+}
@@ -0,0 +1,22 @@
+from .client import (
+    MAX_RECONNECT_ATTEMPTS,
+    SonyaClient,
+    SonyaError,
+    SubscriptionClosed,
+    reconnect_delay,
+)
+from .binding import Event, QueueBinding, TypedQueue, queue_binding
+from .outbox import Outbox
+
+__all__ = [
+    "MAX_RECONNECT_ATTEMPTS",
+    "Event",
+    "Outbox",
+    "QueueBinding",
+    "SonyaClient",
+    "SonyaError",
+    "SubscriptionClosed",
+    "TypedQueue",
+    "queue_binding",
+    "reconnect_delay",
+]
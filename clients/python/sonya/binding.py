@@ -0,0 +1,97 @@
+"""Queues bound to the payload type of their events, checked by mypy and other type checkers."""
+
+from dataclasses import dataclass
+from typing import Any, AsyncIterator, Dict, Generic, List, Optional, Type, TypeVar
+
+from .client import Key, SonyaClient
+
+P = TypeVar("P")
+
+
+@dataclass(frozen=True)
+class QueueBinding(Generic[P]):
+    name: str
+
+
+def queue_binding(name: str, payload: Type[P]) -> QueueBinding[P]:
+    """Binds the queue name to the payload type: `orders = queue_binding("orders", OrderEvent)`.
+
+    Payloads are not converted, use `TypedDict` or other json shaped types.
+    """
+    return QueueBinding(name)
+
+
+@dataclass(frozen=True)
+class Event(Generic[P]):
+    id: Key
+    payload: P
+    sequence: Optional[int] = None
+    reply_to: Optional[str] = None
+    supersedes: Optional[int] = None
+    ephemeral: bool = False
+
+    @classmethod
+    def from_message(cls, message: Dict[str, Any]) -> "Event[P]":
+        return cls(
+            id=message["id"],
+            payload=message.get("payload"),
+            sequence=message.get("sequence"),
+            reply_to=message.get("reply_to"),
+            supersedes=message.get("supersedes"),
+            ephemeral=message.get("ephemeral", False),
+        )
+
+    def to_message(self) -> Dict[str, Any]:
+        message = {"id": self.id, "payload": self.payload}
+        for name in ("sequence", "reply_to", "supersedes"):
+            if getattr(self, name) is not None:
+                message[name] = getattr(self, name)
+        if self.ephemeral:
+            message["ephemeral"] = True
+        return message
+
+
+class TypedQueue(Generic[P]):
+    def __init__(self, client: SonyaClient, binding: QueueBinding[P]):
+        """Methods of the bound queue which accept and return payloads of its type only."""
+        self.client = client
+        self.name = binding.name
+
+    async def send(
+        self, event: Event[P], lease: Optional[str] = None, dedup_id: Optional[str] = None
+    ) -> Dict[str, Any]:
+        return await self.client.send(self.name, event.to_message(), lease, dedup_id)
+
+    async def send_payload(
+        self,
+        key: Key,
+        payload: P,
+        lease: Optional[str] = None,
+        dedup_id: Optional[str] = None,
+    ) -> Dict[str, Any]:
+        return await self.client.send_payload(self.name, key, payload, lease, dedup_id)
+
+    async def publish_and_wait_subscribed(
+        self, event: Event[P], reply_to: str, timeout: float = 30, **kwargs: Any
+    ) -> Dict[str, Any]:
+        message = event.to_message()
+        return await self.client.publish_and_wait_subscribed(
+            self.name, message, reply_to, timeout, **kwargs
+        )
+
+    async def publish_and_wait_acked(
+        self, event: Event[P], reply_to: str, timeout: float = 30, **kwargs: Any
+    ) -> Dict[str, Any]:
+        message = event.to_message()
+        return await self.client.publish_and_wait_acked(
+            self.name, message, reply_to, timeout, **kwargs
+        )
+
+    async def peek(self, key: Key, n: Optional[int] = None) -> List[Event[P]]:
+        response = await self.client.peek(self.name, key, n)
+        return [Event.from_message(e) for e in response.get("events", [])]
+
+    async def subscribe(self, key: Optional[Key] = None, **kwargs: Any) -> AsyncIterator[Event[P]]:
+        """Yields events of the key or the whole queue, see `SonyaClient.subscribe` for options."""
+        async for message in self.client.subscribe(self.name, key=key, **kwargs):
+            yield Event.from_message(message)
@@ -0,0 +1,536 @@
+"""Thin client of the SonyaWQ web queue.
+
+Keep in sync with the wire protocol in documentation/api/queue/websocket.md.
+"""
+
+import asyncio
+import json
+import math
+import time
+from typing import Any, AsyncIterator, Awaitable, Callable, Dict, List, Optional, Tuple, Union
+from urllib.parse import quote
+
+import aiohttp
+
+MAX_RECONNECT_ATTEMPTS = 10
+
+CLOSE_QUEUE_CLOSED = 4000
+CLOSE_KEY_DELETED = 4001
+CLOSE_DISCONNECTED = 4003
+CLOSE_AUTH_EXPIRED = 4401
+CLOSE_DRAINING = 1001
+CLOSE_MOVED = 4307
+# subscriptions closed with these codes are not reopened
+CLOSE_FINAL = (CLOSE_QUEUE_CLOSED, CLOSE_KEY_DELETED, CLOSE_DISCONNECTED)
+
+LEASE_HEADER = "Sonya-Lease"
+
+DEDUP_HEADER = "Sonya-Dedup-Id"
+
+# small public document, its response headers measure the round trip to the server
+PROBE_PATH = "/spec/asyncapi.json"
+
+Key = Union[str, int]
+Token = Union[str, Callable[[], Awaitable[str]], None]
+# address or a dict with `url` and optional `priority`
+Replica = Union[str, Dict[str, Any]]
+# pending batches are collected by queue and lease
+BatchName = Tuple[str, Optional[str]]
+
+
+def reconnect_delay(attempt: int) -> int:
+    """The same backoff as the proxy uses between reconnects to queue shards, in seconds."""
+    return int(math.sqrt(1.5 * attempt))
+
+
+class SonyaError(Exception):
+    def __init__(self, status: int, message: str):
+        super().__init__(message or f"request failed with {status} code")
+        self.status = status
+
+
+class SubscriptionClosed(Exception):
+    """Subscription was closed by the server and can't be resumed."""
+
+    def __init__(self, code: int, reason: str):
+        super().__init__(f"subscription closed with {code} code: {reason}")
+        self.code = code
+        self.reason = reason
+
+
+class SonyaClient:
+    def __init__(
+        self,
+        base_url: str,
+        service_token: Optional[str] = None,
+        session: Optional[aiohttp.ClientSession] = None,
+        replicas: Optional[List[Replica]] = None,
+        primary_priority: int = 0,
+        read_preference: str = "primary",
+        read_preferences: Optional[Dict[str, str]] = None,
+        batch_max_size: Optional[int] = None,
+        batch_max_delay: float = 0.01,
+    ):
+        """`base_url` is the primary of `replicas`, which are standby or delivery servers.
+
+        Subscriptions and reads of queues preferring `nearest` go to the reachable server
+        with the lowest priority and the lowest latency measured by `probe`,
+        writes always go to the primary.
+
+        With `batch_max_size` sends of one queue are coalesced into batch requests,
+        which are sent when they are full or after `batch_max_delay` seconds.
+        """
+        self.base_url = base_url.rstrip("/")
+        self.service_token = service_token
+        self._session = session
+        self.replicas = [
+            {"url": r.rstrip("/"), "priority": 0}
+            if isinstance(r, str)
+            else {"url": r["url"].rstrip("/"), "priority": r.get("priority", 0)}
+            for r in replicas or []
+        ]
+        self.primary_priority = primary_priority
+        self.read_preference = read_preference
+        self.read_preferences = read_preferences or {}
+        self.latencies: Dict[str, float] = {}
+        self.batch_max_size = batch_max_size
+        self.batch_max_delay = batch_max_delay
+        self._batches: Dict[BatchName, List[Tuple[Dict[str, Any], asyncio.Future]]] = {}
+        self._sending: Dict[BatchName, asyncio.Task] = {}
+
+    async def __aenter__(self) -> "SonyaClient":
+        return self
+
+    async def __aexit__(self, *_) -> None:
+        await self.close()
+
+    async def close(self) -> None:
+        await self.flush()
+        if self._session is not None:
+            await self._session.close()
+            self._session = None
+
+    @property
+    def session(self) -> aiohttp.ClientSession:
+        if self._session is None:
+            self._session = aiohttp.ClientSession()
+        return self._session
+
+    async def probe(self) -> Dict[str, float]:
+        """Measures round trips to the primary and replicas in seconds, `inf` if unreachable."""
+
+        async def measure(url: str) -> None:
+            started = time.monotonic()
+            try:
+                async with self.session.get(url + PROBE_PATH):
+                    self.latencies[url] = time.monotonic() - started
+            except aiohttp.ClientError:
+                self.latencies[url] = math.inf
+
+        urls = [self.base_url] + [r["url"] for r in self.replicas]
+        await asyncio.gather(*(measure(url) for url in urls))
+        return dict(self.latencies)
+
+    def read_url(self, queue: str) -> str:
+        """Address which serves subscriptions and reads of the queue."""
+        if self.read_preferences.get(queue, self.read_preference) != "nearest":
+            return self.base_url
+        servers = [{"url": self.base_url, "priority": self.primary_priority}] + self.replicas
+        reachable = [s for s in servers if self.latencies.get(s["url"]) != math.inf]
+        if not reachable:
+            return self.base_url
+        # servers which were not probed yet follow the probed ones of the same priority
+        nearest = min(
+            reachable,
+            key=lambda s: (s["priority"], self.latencies.get(s["url"], math.inf)),
+        )
+        return nearest["url"]
+
+    async def create_queue(self, queue: str) -> Dict[str, Any]:
+        return await self._request("POST", f"/queue/create/{_enc(queue)}")
+
+    async def close_queue(self, queue: str) -> Dict[str, Any]:
+        return await self._request("POST", f"/queue/close/{_enc(queue)}")
+
+    async def clear_queue(self, queue: str) -> Dict[str, Any]:
+        return await self._request("POST", f"/queue/clear/{_enc(queue)}")
+
+    async def send(
+        self,
+        queue: str,
+        event: Dict[str, Any],
+        lease: Optional[str] = None,
+        dedup_id: Optional[str] = None,
+    ) -> Dict[str, Any]:
+        """Retries with the same `dedup_id` are stored once, such events are never batched."""
+        if self.batch_max_size and dedup_id is None:
+            return await self._enqueue(queue, event, lease)
+        path = f"/queue/send/{_enc(queue)}"
+        return await self._request("POST", path, body=event, lease=lease, dedup_id=dedup_id)
+
+    async def send_payload(
+        self,
+        queue: str,
+        key: Key,
+        payload: Any,
+        lease: Optional[str] = None,
+        dedup_id: Optional[str] = None,
+    ) -> Dict[str, Any]:
+        if self.batch_max_size and dedup_id is None:
+            return await self._enqueue(queue, {"id": key, "payload": payload}, lease)
+        path = f"/queue/send/{_enc(queue)}/{_enc(key)}"
+        return await self._request("POST", path, body=payload, lease=lease, dedup_id=dedup_id)
+
+    async def send_batch(
+        self, queue: str, events: List[Dict[str, Any]], lease: Optional[str] = None
+    ) -> Dict[str, Any]:
+        """Sends events in order with one request, results are returned in the same order."""
+        return await self._request("POST", f"/queue/batch/{_enc(queue)}", body=events, lease=lease)
+
+    async def flush(self) -> None:
+        """Sends pending batches at once and waits for their results."""
+        for name, batch in list(self._batches.items()):
+            self._flush_batch(name, batch)
+        await asyncio.gather(*self._sending.values(), return_exceptions=True)
+
+    async def delete_key(self, queue: str, key: Key, tombstone: bool = False) -> Dict[str, Any]:
+        path = f"/queue/delete/{_enc(queue)}/{_enc(key)}"
+        return await self._request("POST", path, query={"tombstone": tombstone})
+
+    async def revoke(self, queue: str, key: Key, sequence: int) -> Dict[str, Any]:
+        path = f"/queue/revoke/{_enc(queue)}/{_enc(key)}/{sequence}"
+        return await self._request("POST", path)
+
+    async def acquire_lease(
+        self, queue: str, key: Key, ttl: Optional[int] = None, lease: Optional[str] = None
+    ) -> Dict[str, Any]:
+        path = f"/queue/lease/{_enc(queue)}/{_enc(key)}"
+        return await self._request("POST", path, query={"ttl": ttl}, lease=lease)
+
+    async def release_lease(self, queue: str, key: Key, lease: str) -> Dict[str, Any]:
+        path = f"/queue/release/{_enc(queue)}/{_enc(key)}"
+        return await self._request("POST", path, lease=lease)
+
+    async def count(self, queue: str, key: Key) -> Dict[str, Any]:
+        path = f"/queue/count/{_enc(queue)}/{_enc(key)}"
+        return await self._request("GET", path, queue=queue)
+
+    async def peek(self, queue: str, key: Key, n: Optional[int] = None) -> Dict[str, Any]:
+        path = f"/queue/peek/{_enc(queue)}/{_enc(key)}"
+        return await self._request("GET", path, query={"n": n}, queue=queue)
+
+    async def replay(
+        self, queue: str, key: Key, start: Optional[int] = None, limit: Optional[int] = None
+    ) -> Dict[str, Any]:
+        path = f"/queue/replay/{_enc(queue)}/{_enc(key)}"
+        query = {"from": start, "limit": limit}
+        return await self._request("GET", path, query=query, queue=queue)
+
+    async def head(self, queue: str, key: Key) -> Dict[str, Any]:
+        path = f"/queue/head/{_enc(queue)}/{_enc(key)}"
+        return await self._request("GET", path, queue=queue)
+
+    async def ack(
+        self,
+        queue: str,
+        key: Key,
+        sequence: int,
+        token: Optional[str] = None,
+        session: Optional[str] = None,
+    ) -> Dict[str, Any]:
+        """Confirms processing of the event to its publisher, subscribers pass their jwt token
+        and resumable session, which is listed in the message trace."""
+        path = f"/queue/ack/{_enc(queue)}/{_enc(key)}"
+        query = {"sequence": sequence, "session": session, "access_token": token}
+        return await self._request("POST", path, query=query)
+
+    async def generate_jwt(
+        self, queue: str, key: Key, identity: Optional[str] = None
+    ) -> Dict[str, Any]:
+        path = f"/queue/generate_jwt/{_enc(queue)}/{_enc(key)}"
+        return await self._request("POST", path, query={"identity": identity})
+
+    async def publish_and_wait_subscribed(
+        self,
+        queue: str,
+        event: Dict[str, Any],
+        reply_to: str,
+        timeout: float = 30,
+        lease: Optional[str] = None,
+        token: Optional[str] = None,
+    ) -> Dict[str, Any]:
+        """Sends the event with `reply_to` and returns the receipt of its first delivery
+        to a subscriber of the queue. Raises `asyncio.TimeoutError` after `timeout` seconds."""
+        return await asyncio.wait_for(
+            self._publish_and_wait(queue, event, False, reply_to, lease, token), timeout
+        )
+
+    async def publish_and_wait_acked(
+        self,
+        queue: str,
+        event: Dict[str, Any],
+        reply_to: str,
+        timeout: float = 30,
+        lease: Optional[str] = None,
+        token: Optional[str] = None,
+    ) -> Dict[str, Any]:
+        """Sends the event with `reply_to` and returns the receipt of its acknowledgement."""
+        return await asyncio.wait_for(
+            self._publish_and_wait(queue, event, True, reply_to, lease, token), timeout
+        )
+
+    async def longpoll(
+        self,
+        queue: str,
+        key: Key,
+        sequence: Union[int, str, None] = None,
+        after: Optional[int] = None,
+        max_preload: Optional[int] = None,
+        token: Optional[str] = None,
+    ) -> Optional[List[Dict[str, Any]]]:
+        """Waits for the next events of the key, `None` if nothing was published after `after`."""
+        path = f"/queue/listen/longpoll/{_enc(queue)}/{_enc(key)}"
+        query = {
+            "sequence": sequence,
+            "after": after,
+            "max_preload": max_preload,
+            "access_token": token,
+        }
+        async with self.session.get(self._url(path, query, self.read_url(queue))) as response:
+            if response.status == 204:
+                return None
+            return await _parse(response)
+
+    async def subscribe(
+        self,
+        queue: str,
+        key: Optional[Key] = None,
+        ids: Optional[List[Key]] = None,
+        sequence: Union[int, str, None] = None,
+        max_preload: Optional[int] = None,
+        identity: Optional[str] = None,
+        client_id: Optional[str] = None,
+        labels: Optional[Dict[str, str]] = None,
+        session: Optional[str] = None,
+        token: Token = None,
+        heartbeat_timeout: Optional[float] = None,
+        on_control: Optional[Callable[[Dict[str, Any]], None]] = None,
+    ) -> AsyncIterator[Dict[str, Any]]:
+        """Yields events of the key or the whole queue received over websocket.
+
+        Key subscriptions resume from the last received sequence after reconnects.
+        Control events except heartbeats are passed to `on_control`.
+        `client_id` and `labels` are listed by the admin api, so lagging clients can be found.
+        Key subscriptions with `session` continue after the last sequence delivered to the session,
+        also after restarts of the process.
+        Raises `SubscriptionClosed` when the subscription can't be resumed.
+        """
+        if key is None:
+            path = f"/queue/listen/ws/{_enc(queue)}"
+        else:
+            path = f"/queue/listen/ws/{_enc(queue)}/{_enc(key)}"
+        last_sequence: Optional[int] = None
+        attempts = 0
+
+        while True:
+            access_token = await token() if callable(token) else token
+            retry_after: Optional[int] = None
+            # key histories have own sequences, so only key subscriptions are resumed precisely
+            resume = last_sequence + 1 if key is not None and last_sequence is not None else sequence
+            query = {
+                "ids": ",".join(str(i) for i in ids) if ids else None,
+                "sequence": resume,
+                "max_preload": max_preload,
+                "identity": identity,
+                "client_id": client_id,
+                "labels": ",".join(f"{n}:{v}" for n, v in labels.items()) if labels else None,
+                "session": session,
+                "access_token": access_token or self.service_token,
+            }
+            endpoint = self.read_url(queue)
+            opened = False
+            try:
+                async with self.session.ws_connect(self._url(path, query, endpoint)) as socket:
+                    opened = True
+                    attempts = 0
+                    while True:
+                        message = await socket.receive(timeout=heartbeat_timeout)
+                        if message.type != aiohttp.WSMsgType.TEXT:
+                            break
+                        event = json.loads(message.data)
+                        control = event.get("control")
+                        if control is None:
+                            if event.get("sequence") is not None and not event.get("ephemeral"):
+                                last_sequence = event["sequence"]
+                            yield event
+                        elif control != "heartbeat":
+                            if control == "draining":
+                                retry_after = event.get("retry_after")
+                            # the whole server failed over, so other subscriptions follow it too
+                            if control == "moved":
+                                self.base_url = event["address"].rstrip("/")
+                            if on_control is not None:
+                                on_control(event)
+                    code = socket.close_code or 1006
+                    reason = str(message.extra or "")
+            except asyncio.TimeoutError:
+                code, reason = 4002, "heartbeat timeout"
+            except aiohttp.ClientError as e:
+                code, reason = 1006, str(e)
+                # the next attempt connects to another replica until the next probe
+                if not opened and endpoint != self.base_url:
+                    self.latencies[endpoint] = math.inf
+
+            if code in CLOSE_FINAL or attempts >= MAX_RECONNECT_ATTEMPTS:
+                raise SubscriptionClosed(code, reason)
+            attempts += 1
+            # expired tokens are taken again from the token function and moved servers are ready,
+            # so reconnect without delay
+            if code == CLOSE_DRAINING and retry_after is not None:
+                # draining servers spread reconnects of their clients with jittered delays
+                await asyncio.sleep(retry_after)
+            elif code not in (CLOSE_AUTH_EXPIRED, CLOSE_MOVED):
+                await asyncio.sleep(reconnect_delay(attempts))
+
+    async def _enqueue(
+        self, queue: str, event: Dict[str, Any], lease: Optional[str]
+    ) -> Dict[str, Any]:
+        """Sends are coalesced until the batch is full or its delay is over."""
+        loop = asyncio.get_event_loop()
+        name = (queue, lease)
+        batch = self._batches.get(name)
+        if batch is None:
+            batch = self._batches[name] = []
+            loop.call_later(self.batch_max_delay, self._flush_batch, name, batch)
+        future = loop.create_future()
+        batch.append((event, future))
+        if len(batch) >= (self.batch_max_size or 1):
+            self._flush_batch(name, batch)
+        return await future
+
+    def _flush_batch(self, name: BatchName, batch: List[Tuple[Dict[str, Any], asyncio.Future]]):
+        """The next batch of the queue is collected while the previous one is in flight."""
+        if self._batches.get(name) is not batch:
+            return
+        del self._batches[name]
+        task = asyncio.ensure_future(self._send_pending(name, batch, self._sending.get(name)))
+        self._sending[name] = task
+
+    async def _send_pending(
+        self,
+        name: BatchName,
+        batch: List[Tuple[Dict[str, Any], asyncio.Future]],
+        previous: Optional[asyncio.Task],
+    ) -> None:
+        if previous is not None:
+            await asyncio.gather(previous, return_exceptions=True)
+        queue, lease = name
+        try:
+            response = await self.send_batch(queue, [event for event, _ in batch], lease=lease)
+            for (_, future), result in zip(batch, response["events"]):
+                if future.done():
+                    continue
+                if result["status"] >= 400:
+                    future.set_exception(SonyaError(result["status"], result.get("error")))
+                else:
+                    future.set_result(
+                        {"success": result["success"], "sequence": result.get("sequence")}
+                    )
+        except Exception as e:
+            for _, future in batch:
+                if not future.done():
+                    future.set_exception(e)
+        finally:
+            if self._sending.get(name) is asyncio.current_task():
+                del self._sending[name]
+
+    async def _publish_and_wait(
+        self,
+        queue: str,
+        event: Dict[str, Any],
+        acked: bool,
+        reply_to: str,
+        lease: Optional[str],
+        token: Optional[str],
+    ) -> Dict[str, Any]:
+        """Receipts are read after the current head of the reply key,
+        so receipts of earlier events are skipped."""
+        key = event["id"]
+        if self.service_token and token is None:
+            token = (await self.generate_jwt(reply_to, key))["token"]
+        head = (await self.head(reply_to, key)).get("key") or {}
+        body = {**event, "reply_to": reply_to}
+        response = await self._request("POST", f"/queue/send/{_enc(queue)}", body=body, lease=lease)
+        if not response["success"]:
+            raise SonyaError(404, f"queue {queue} does not exist")
+        sequence = response.get("sequence")
+        if sequence is None:
+            raise SonyaError(400, "events without sequences are not confirmed")
+        next_sequence = head.get("sequence", 0) + 1
+        while True:
+            receipts = await self.longpoll(reply_to, key, sequence=next_sequence, token=token)
+            for receipt in receipts or []:
+                next_sequence = receipt["sequence"] + 1
+                payload = receipt["payload"]
+                if (
+                    payload["queue"] == queue
+                    and payload["sequence"] == sequence
+                    and (payload["acked"] or not acked)
+                ):
+                    return payload
+
+    async def _request(
+        self,
+        method: str,
+        path: str,
+        body: Any = None,
+        query: Optional[Dict[str, Any]] = None,
+        lease: Optional[str] = None,
+        queue: Optional[str] = None,
+        dedup_id: Optional[str] = None,
+    ) -> Dict[str, Any]:
+        """Reads pass the queue to be served by its preferred server."""
+        headers = {}
+        if self.service_token:
+            headers["Authorization"] = f"Bearer {self.service_token}"
+        if lease:
+            headers[LEASE_HEADER] = lease
+        if dedup_id:
+            headers[DEDUP_HEADER] = dedup_id
+        kwargs: Dict[str, Any] = {"headers": headers}
+        if body is not None:
+            kwargs["json"] = body
+        base = self.base_url if queue is None else self.read_url(queue)
+        url = self._url(path, query, base)
+        async with self.session.request(method, url, **kwargs) as response:
+            return await _parse(response)
+
+    def _url(
+        self, path: str, query: Optional[Dict[str, Any]] = None, base: Optional[str] = None
+    ) -> str:
+        params = {
+            name: _query_value(value)
+            for name, value in (query or {}).items()
+            if value is not None
+        }
+        url = (base or self.base_url) + path
+        if params:
+            url += "?" + "&".join(f"{name}={quote(value)}" for name, value in params.items())
+        return url
+
+
+def _enc(value: Key) -> str:
+    return quote(str(value), safe="")
+
+
+def _query_value(value: Any) -> str:
+    if isinstance(value, bool):
+        return "true" if value else "false"
+    return str(value)
+
+
+async def _parse(response: aiohttp.ClientResponse) -> Any:
+    text = await response.text()
+    if response.status >= 400:
+        raise SonyaError(response.status, text)
+    return json.loads(text) if text else None
@@ -0,0 +1,321 @@
+"""In-process queues for tests of applications without a running server."""
+
+import asyncio
+import time
+from typing import Any, AsyncIterator, Callable, Dict, List, Optional, Set, Tuple, Union
+from urllib.parse import unquote
+
+from .client import (
+    CLOSE_KEY_DELETED,
+    CLOSE_QUEUE_CLOSED,
+    Key,
+    SonyaClient,
+    SonyaError,
+    SubscriptionClosed,
+    Token,
+)
+
+BASE_URL = "http://sonya.mock"
+
+Event = Dict[str, Any]
+
+
+class _Subscriber:
+    def __init__(self, queue: str, key: Optional[str], ids: Optional[List[str]]):
+        self.queue = queue
+        self.key = key
+        self.ids = ids
+        self.messages: "asyncio.Queue[Event]" = asyncio.Queue()
+
+    def accepts(self, id: str) -> bool:
+        if self.key is not None:
+            return self.key == id
+        return self.ids is None or id in self.ids
+
+
+class MockServer:
+    def __init__(self):
+        """Queues of the mock are served to its clients in process, so batching, outboxes
+        and typed queues work as with a server.
+        Secure mode, leases, replicas and the admin api are not mocked.
+        """
+        self.queues: Dict[str, Dict[str, List[Event]]] = {}
+        self.counters: Dict[Tuple[str, str], int] = {}
+        self.dedup_ids: Set[Tuple[str, str]] = set()
+        # delivery receipt marks of events with `reply_to`
+        self.receipts: Dict[Tuple[str, str, int], str] = {}
+        self.subscribers: Set[_Subscriber] = set()
+        self.waiters: Set[Tuple[str, str, asyncio.Future]] = set()
+
+    def client(self, **options: Any) -> "MockClient":
+        """Client of the mock, accepts options of `SonyaClient` except the session."""
+        return MockClient(self, **options)
+
+    def events(self, queue: str, key: Key) -> List[Event]:
+        """Stored events of the key in the order of sequences."""
+        return list(self._key_events(queue, str(key)))
+
+    def handle(
+        self,
+        method: str,
+        path: str,
+        body: Any = None,
+        query: Optional[Dict[str, Any]] = None,
+        dedup_id: Optional[str] = None,
+    ) -> Tuple[int, Any]:
+        _, scope, action, *params = [unquote(p) for p in path.split("/")]
+        query = {n: v for n, v in (query or {}).items() if v is not None}
+        if scope != "queue" or not params:
+            return 404, "Not Found"
+        queue, key, sequence = (params + [None, None])[:3]
+        exists = queue in self.queues
+        route = (method, action)
+        if route == ("POST", "create"):
+            self.queues.setdefault(queue, {})
+            return 200, {"success": True}
+        if route == ("POST", "close"):
+            return 200, {"success": self._close_queue(queue)}
+        if route == ("POST", "clear"):
+            if exists:
+                self.queues[queue].clear()
+            return 200, {"success": exists}
+        if route == ("POST", "send"):
+            event = body if key is None else {"id": key, "payload": body}
+            return 200, self.publish(queue, event, dedup_id)
+        if route == ("POST", "batch"):
+            events = [{"status": 200, "error": None, **self.publish(queue, e)} for e in body]
+            return 200, {"success": all(e["success"] for e in events), "events": events}
+        if route == ("POST", "delete"):
+            return 200, {"success": self._delete_key(queue, key, bool(query.get("tombstone")))}
+        if route == ("POST", "revoke"):
+            return 200, {"success": self._revoke(queue, key, int(sequence))}
+        if route == ("POST", "ack"):
+            return 200, {"success": self._ack(queue, key, int(query["sequence"]))}
+        if route == ("POST", "generate_jwt"):
+            return 200, {"token": "mock", "expiration": int(time.time()) + 3600}
+        events = self._key_events(queue, key)
+        if route == ("GET", "count"):
+            return 200, {"success": exists, "count": len(events)}
+        if route == ("GET", "peek"):
+            n = int(query.get("n", 10))
+            return 200, {"success": exists, "events": list(reversed(events[-n:]))}
+        if route == ("GET", "replay"):
+            start = int(query.get("from", 0))
+            limit = int(query.get("limit", 100))
+            events = [e for e in events if e["sequence"] >= start]
+            next_sequence = events[limit]["sequence"] if len(events) > limit else None
+            return 200, {"success": exists, "events": events[:limit], "next": next_sequence}
+        if route == ("GET", "head"):
+            head = None
+            if events:
+                head = {"sequence": events[-1]["sequence"], "timestamp": int(time.time())}
+            return 200, {"success": exists, "key": head, "queue": None}
+        return 404, "Not Found"
+
+    def publish(self, queue: str, event: Event, dedup_id: Optional[str] = None) -> Dict[str, Any]:
+        if queue not in self.queues:
+            return {"success": False, "sequence": None}
+        if dedup_id is not None:
+            if (queue, dedup_id) in self.dedup_ids:
+                return {"success": True, "sequence": None}
+            self.dedup_ids.add((queue, dedup_id))
+        id = str(event["id"])
+        stored = {**event, "id": id}
+        if event.get("ephemeral"):
+            self._broadcast(queue, stored)
+            return {"success": True, "sequence": None}
+        counter = self.counters.get((queue, id), 0)
+        sequence = event.get("sequence") or counter + 1
+        self.counters[(queue, id)] = max(counter, sequence)
+        stored["sequence"] = sequence
+        events = [e for e in self.queues[queue].get(id, []) if e["sequence"] != sequence]
+        self.queues[queue][id] = sorted(events + [stored], key=lambda e: e["sequence"])
+        self._broadcast(queue, stored)
+        return {"success": True, "sequence": sequence}
+
+    async def longpoll(
+        self,
+        queue: str,
+        key: str,
+        sequence: Union[int, str, None] = None,
+        after: Optional[int] = None,
+    ) -> Optional[List[Event]]:
+        if queue not in self.queues:
+            raise SonyaError(404, "Queue does not exist")
+        events = self._key_events(queue, key)
+        preload: List[Event] = []
+        if sequence is not None:
+            preload = _preloaded(events, sequence)
+        elif after is not None:
+            preload = [e for e in events if e["sequence"] > after]
+            if not preload:
+                return None
+        if not preload:
+            future = asyncio.get_running_loop().create_future()
+            waiter = (queue, key, future)
+            self.waiters.add(waiter)
+            try:
+                preload = [await future]
+            finally:
+                self.waiters.discard(waiter)
+        for event in preload:
+            self._confirm(queue, event)
+        return preload
+
+    def subscribe(
+        self, queue: str, key: Optional[str], ids: Optional[List[str]], sequence: Any
+    ) -> _Subscriber:
+        subscriber = _Subscriber(queue, key, ids)
+        if sequence is not None:
+            keys = [k for k in self.queues[queue] if subscriber.accepts(k)]
+            for k in keys:
+                for event in _preloaded(self._key_events(queue, k), sequence):
+                    self._deliver(subscriber, event)
+            subscriber.messages.put_nowait({"control": "end_of_preload"})
+        self.subscribers.add(subscriber)
+        return subscriber
+
+    def _broadcast(self, queue: str, event: Event) -> None:
+        for subscriber in list(self.subscribers):
+            if subscriber.queue == queue and subscriber.accepts(event["id"]):
+                self._deliver(subscriber, event)
+        for waiter_queue, key, future in list(self.waiters):
+            if waiter_queue == queue and key == event["id"] and not future.done():
+                future.set_result(event)
+
+    def _deliver(self, subscriber: _Subscriber, event: Event) -> None:
+        self._confirm(subscriber.queue, event)
+        subscriber.messages.put_nowait(event)
+
+    def _confirm(self, queue: str, event: Event, acked: bool = False) -> None:
+        """Events with `reply_to` are confirmed after the first delivery and after the ack."""
+        if not event.get("reply_to") or event.get("sequence") is None:
+            return
+        mark = (queue, event["id"], event["sequence"])
+        if self.receipts.get(mark) == "acked" or (not acked and mark in self.receipts):
+            return
+        self.receipts[mark] = "acked" if acked else "delivered"
+        payload = {"queue": queue, "id": event["id"], "sequence": event["sequence"], "acked": acked}
+        self.publish(event["reply_to"], {"id": event["id"], "payload": payload})
+
+    def _close_queue(self, queue: str) -> bool:
+        if self.queues.pop(queue, None) is None:
+            return False
+        for subscriber in list(self.subscribers):
+            if subscriber.queue == queue:
+                subscriber.messages.put_nowait({"control": "queue_closed"})
+        return True
+
+    def _delete_key(self, queue: str, key: str, tombstone: bool) -> bool:
+        if queue not in self.queues:
+            return False
+        self.queues[queue].pop(key, None)
+        for subscriber in list(self.subscribers):
+            if subscriber.queue == queue and subscriber.key == key:
+                subscriber.messages.put_nowait({"control": "key_deleted"})
+        if tombstone:
+            self.publish(queue, {"id": key, "payload": None, "tombstone": True})
+        return True
+
+    def _revoke(self, queue: str, key: str, sequence: int) -> bool:
+        events = self._key_events(queue, key)
+        if not any(e["sequence"] == sequence for e in events):
+            return False
+        self.queues[queue][key] = [e for e in events if e["sequence"] != sequence]
+        for subscriber in list(self.subscribers):
+            if subscriber.queue == queue and subscriber.accepts(key):
+                control = {"control": "revoked", "id": key, "sequence": sequence}
+                subscriber.messages.put_nowait(control)
+        return True
+
+    def _ack(self, queue: str, key: str, sequence: int) -> bool:
+        for event in self._key_events(queue, key):
+            if event["sequence"] == sequence:
+                self._confirm(queue, event, acked=True)
+                return True
+        return False
+
+    def _key_events(self, queue: str, key: Optional[str]) -> List[Event]:
+        return self.queues.get(queue, {}).get(key or "", [])
+
+
+class MockClient(SonyaClient):
+    def __init__(self, server: MockServer, **options: Any):
+        super().__init__(BASE_URL, **options)
+        self.server = server
+
+    async def close(self) -> None:
+        await self.flush()
+
+    async def longpoll(
+        self,
+        queue: str,
+        key: Key,
+        sequence: Union[int, str, None] = None,
+        after: Optional[int] = None,
+        max_preload: Optional[int] = None,
+        token: Optional[str] = None,
+    ) -> Optional[List[Dict[str, Any]]]:
+        return await self.server.longpoll(queue, str(key), sequence, after)
+
+    async def subscribe(
+        self,
+        queue: str,
+        key: Optional[Key] = None,
+        ids: Optional[List[Key]] = None,
+        sequence: Union[int, str, None] = None,
+        max_preload: Optional[int] = None,
+        identity: Optional[str] = None,
+        client_id: Optional[str] = None,
+        labels: Optional[Dict[str, str]] = None,
+        session: Optional[str] = None,
+        token: Token = None,
+        heartbeat_timeout: Optional[float] = None,
+        on_control: Optional[Callable[[Dict[str, Any]], None]] = None,
+    ) -> AsyncIterator[Dict[str, Any]]:
+        if queue not in self.server.queues:
+            raise SubscriptionClosed(CLOSE_QUEUE_CLOSED, "queue closed")
+        subscriber = self.server.subscribe(
+            queue,
+            None if key is None else str(key),
+            [str(i) for i in ids] if ids else None,
+            sequence,
+        )
+        try:
+            while True:
+                event = await subscriber.messages.get()
+                control = event.get("control")
+                if control is None:
+                    yield event
+                    continue
+                if on_control is not None:
+                    on_control(event)
+                if control == "queue_closed":
+                    raise SubscriptionClosed(CLOSE_QUEUE_CLOSED, "queue closed")
+                if control == "key_deleted":
+                    raise SubscriptionClosed(CLOSE_KEY_DELETED, "key deleted")
+        finally:
+            self.server.subscribers.discard(subscriber)
+
+    async def _request(
+        self,
+        method: str,
+        path: str,
+        body: Any = None,
+        query: Optional[Dict[str, Any]] = None,
+        lease: Optional[str] = None,
+        queue: Optional[str] = None,
+        dedup_id: Optional[str] = None,
+    ) -> Dict[str, Any]:
+        status, data = self.server.handle(method, path, body, query, dedup_id)
+        if status >= 400:
+            raise SonyaError(status, data)
+        return data
+
+
+def _preloaded(events: List[Event], sequence: Union[int, str]) -> List[Event]:
+    if sequence == "first":
+        return list(events)
+    if sequence == "last":
+        return events[-1:]
+    return [e for e in events if e["sequence"] >= int(sequence)]
@@ -0,0 +1,119 @@
+"""Persistent buffer of publishes for producers with flaky connectivity."""
+
+import asyncio
+import json
+import os
+import uuid
+from typing import Any, Callable, Dict, List, Optional
+
+import aiohttp
+
+from .client import Key, SonyaClient, SonyaError
+
+Entry = Dict[str, Any]
+
+
+class Outbox:
+    def __init__(
+        self,
+        client: SonyaClient,
+        path: Optional[str] = None,
+        on_rejected: Optional[Callable[[Entry, Exception], None]] = None,
+    ):
+        """Events are stored in the `path` json file before sending and flushed in order
+        with dedup ids, so retries of events which reached the server are not stored twice.
+        Without `path` buffered events are lost with the process.
+
+        Events rejected by the server are dropped and passed to `on_rejected`.
+        """
+        self.client = client
+        self.path = path
+        self.on_rejected = on_rejected
+        self.entries: List[Entry] = self._load()
+        self._flushing = asyncio.Lock()
+
+    @property
+    def pending(self) -> int:
+        """Count of buffered events."""
+        return len(self.entries)
+
+    async def send(self, queue: str, event: Dict[str, Any], lease: Optional[str] = None) -> str:
+        """Buffers the event and tries to send it, returns its dedup id."""
+        return await self._push({"queue": queue, "event": event, "lease": lease})
+
+    async def send_payload(
+        self, queue: str, key: Key, payload: Any, lease: Optional[str] = None
+    ) -> str:
+        return await self._push({"queue": queue, "key": key, "payload": payload, "lease": lease})
+
+    async def flush(self) -> int:
+        """Sends buffered events in order until the server is unreachable.
+
+        Returns count of buffered events left.
+        """
+        async with self._flushing:
+            while self.entries:
+                entry = self.entries[0]
+                try:
+                    response = await self._send(entry)
+                    if not response.get("success"):
+                        missing = f"queue {entry['queue']} does not exist"
+                        self._reject(entry, SonyaError(404, missing))
+                except (aiohttp.ClientError, asyncio.TimeoutError):
+                    break
+                except SonyaError as e:
+                    if _retriable(e):
+                        break
+                    self._reject(entry, e)
+                self.entries.pop(0)
+                self._save()
+            return len(self.entries)
+
+    async def run(self, interval: float = 5.0) -> None:
+        """Flushes the outbox every `interval` seconds until canceled."""
+        while True:
+            await self.flush()
+            await asyncio.sleep(interval)
+
+    async def _push(self, entry: Entry) -> str:
+        entry["dedup_id"] = str(uuid.uuid4())
+        self.entries.append(entry)
+        self._save()
+        await self.flush()
+        return entry["dedup_id"]
+
+    async def _send(self, entry: Entry) -> Dict[str, Any]:
+        if "payload" in entry:
+            return await self.client.send_payload(
+                entry["queue"],
+                entry["key"],
+                entry["payload"],
+                lease=entry["lease"],
+                dedup_id=entry["dedup_id"],
+            )
+        return await self.client.send(
+            entry["queue"], entry["event"], lease=entry["lease"], dedup_id=entry["dedup_id"]
+        )
+
+    def _reject(self, entry: Entry, error: Exception) -> None:
+        if self.on_rejected:
+            self.on_rejected(entry, error)
+
+    def _load(self) -> List[Entry]:
+        if self.path is None or not os.path.exists(self.path):
+            return []
+        with open(self.path) as f:
+            return json.load(f)
+
+    def _save(self) -> None:
+        # written aside and renamed, so a crash never leaves a truncated file
+        if self.path is None:
+            return
+        with open(self.path + ".tmp", "w") as f:
+            json.dump(self.entries, f)
+        os.replace(self.path + ".tmp", self.path)
+
+
+def _retriable(error: SonyaError) -> bool:
+    """Unavailable servers and timeouts, the event is sent again with the same dedup id."""
+    return error.status >= 500 or error.status == 429
@@ -49,10 +49,12 @@ publish_docker_package() {
   docker push $DOCKER_LATEST_TAG
 }
 
+validate_crates_package "sonya-meta-derive" "$VERSION"
 validate_crates_package "sonya-meta" "$VERSION"
 validate_crates_package "sonya" "$VERSION"
 validate_crates_package "sonya-proxy" "$VERSION"
 
+publish_crates_package "sonya-meta-derive" "$VERSION"
 publish_crates_package "sonya-meta" "$VERSION"
 publish_crates_package "sonya" "$VERSION"
 publish_crates_package "sonya-proxy" "$VERSION"
@@ -0,0 +1,140 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Ident};
+
+/// Implements `sonya_meta::message::UniqId` for structs with named fields.
+///
+/// The key field is marked with `#[event(id)]` and must deref to `str`,
+/// the sequence field is marked with `#[event(sequence)]` and must be `sonya_meta::message::Sequence`.
+/// Optional `#[event(supersedes)]` field is a `sonya_meta::message::Sequence`
+/// of the corrected event, optional `#[event(ephemeral)]` field is a `bool`
+/// which marks events delivered to live subscribers only, optional `#[event(timestamp)]` field
+/// is an `Option<sonya_meta::message::HlcTimestamp>` assigned by the server.
+///
+/// ```ignore
+/// #[derive(UniqId)]
+/// struct Order {
+///     #[event(id)]
+///     order_id: String,
+///     #[event(sequence)]
+///     sequence: Sequence,
+///     amount: u64,
+/// }
+/// ```
+#[proc_macro_derive(UniqId, attributes(event))]
+pub fn derive_uniq_id(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_uniq_id(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand_uniq_id(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input,
+                    "UniqId can be derived only for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &input,
+                "UniqId can be derived only for structs",
+            ))
+        }
+    };
+
+    let mut id = None;
+    let mut sequence = None;
+    let mut supersedes = None;
+    let mut ephemeral = None;
+    let mut timestamp = None;
+    for field in fields {
+        for attr in field.attrs.iter().filter(|a| a.path.is_ident("event")) {
+            let kind: Ident = attr.parse_args()?;
+            let target = match kind.to_string().as_str() {
+                "id" => &mut id,
+                "sequence" => &mut sequence,
+                "supersedes" => &mut supersedes,
+                "ephemeral" => &mut ephemeral,
+                "timestamp" => &mut timestamp,
+                _ => {
+                    return Err(Error::new_spanned(
+                        kind,
+                        "expected `#[event(id)]`, `#[event(sequence)]`, \
+                        `#[event(supersedes)]`, `#[event(ephemeral)]` or `#[event(timestamp)]`",
+                    ))
+                }
+            };
+            if target.is_some() {
+                return Err(Error::new_spanned(attr, "duplicated event field"));
+            }
+            *target = field.ident.clone();
+        }
+    }
+
+    let id = id.ok_or_else(|| Error::new(Span::call_site(), "missing `#[event(id)]` field"))?;
+    let sequence = sequence
+        .ok_or_else(|| Error::new(Span::call_site(), "missing `#[event(sequence)]` field"))?;
+
+    let get_supersedes = supersedes.map(|supersedes| {
+        quote! {
+            fn get_supersedes(&self) -> ::sonya_meta::message::Sequence {
+                self.#supersedes
+            }
+        }
+    });
+
+    let is_ephemeral = ephemeral.map(|ephemeral| {
+        quote! {
+            fn is_ephemeral(&self) -> bool {
+                self.#ephemeral
+            }
+        }
+    });
+
+    let timestamp = timestamp.map(|timestamp| {
+        quote! {
+            fn get_timestamp(&self) -> ::std::option::Option<::sonya_meta::message::HlcTimestamp> {
+                self.#timestamp
+            }
+
+            fn set_timestamp(&mut self, timestamp: ::sonya_meta::message::HlcTimestamp) {
+                self.#timestamp = ::std::option::Option::Some(timestamp);
+            }
+        }
+    });
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::sonya_meta::message::UniqId for #name #ty_generics #where_clause {
+            fn get_id(&self) -> &str {
+                &self.#id
+            }
+
+            fn get_sequence(&self) -> ::sonya_meta::message::Sequence {
+                self.#sequence
+            }
+
+            fn set_sequence(
+                &mut self,
+                sequence: ::sonya_meta::message::SequenceId,
+            ) -> ::sonya_meta::message::Sequence {
+                self.#sequence.replace(sequence)
+            }
+
+            #get_supersedes
+
+            #is_ephemeral
+
+            #timestamp
+        }
+    })
+}
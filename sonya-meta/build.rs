@@ -0,0 +1,15 @@
+fn main() {
+    // protobuf types are generated only when they are requested
+    #[cfg(feature = "proto")]
+    compile_protos().expect("protobuf schema compilation error");
+}
+
+#[cfg(feature = "proto")]
+fn compile_protos() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto/event.proto");
+    if std::env::var_os("PROTOC").is_none() {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+    prost_build::compile_protos(&["proto/event.proto"], &["proto"])?;
+    Ok(())
+}
@@ -1,39 +1,161 @@
 use crate::config::Secure;
+use crate::response::ServerResponse;
 use actix_web::dev::{HttpServiceFactory, RequestHead};
 use actix_web::guard::Guard;
 use actix_web::rt::time::sleep;
 use actix_web::web::Data;
 use actix_web::{web, HttpResponse};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use log::error;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use schemars::JsonSchema;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::future::Future;
 use std::time::{Duration, SystemTime};
+use tracing::error;
 
 const BEARER: &str = "Bearer ";
 
+/// Context of the key which signs subscription urls, it is derived from the service token,
+/// so signatures are never valid for other uses of the token
+const SIGNATURE_KEY_CONTEXT: &[u8] = b"sonya subscription signature";
+
+#[macro_export]
+macro_rules! admin_scope_factory {
+    (   $sequence_gaps:ident,
+        $message_trace:ident,
+        $broadcasts:ident,
+        $sizes:ident,
+        $subscribers:ident,
+        $disconnect_subscriber:ident,
+        $secure:expr,
+        $server_info:expr,
+    ) => {
+        match $secure {
+            None => web::scope("/admin")
+                .route(
+                    "/gaps/{queue_name}/{uniq_id}",
+                    web::get().to($sequence_gaps),
+                )
+                .route(
+                    "/trace/{queue_name}/{uniq_id}/{sequence}",
+                    web::get().to($message_trace),
+                )
+                .route("/broadcasts/{queue_name}", web::get().to($broadcasts))
+                .route("/sizes/{queue_name}", web::get().to($sizes))
+                // queue name for listing, connection id for disconnecting
+                .service(
+                    web::resource("/subscribers/{id}")
+                        .route(web::get().to($subscribers))
+                        .route(web::delete().to($disconnect_subscriber)),
+                ),
+            Some(st) => web::scope("/admin")
+                .route(
+                    "/gaps/{queue_name}/{uniq_id}",
+                    web::get()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($sequence_gaps),
+                )
+                .route(
+                    "/trace/{queue_name}/{uniq_id}/{sequence}",
+                    web::get()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($message_trace),
+                )
+                .route(
+                    "/broadcasts/{queue_name}",
+                    web::get()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($broadcasts),
+                )
+                .route(
+                    "/sizes/{queue_name}",
+                    web::get()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($sizes),
+                )
+                .service(
+                    web::resource("/subscribers/{id}")
+                        .route(
+                            web::get()
+                                .guard($crate::api::service_token_guard(st))
+                                .to($subscribers),
+                        )
+                        .route(
+                            web::delete()
+                                .guard($crate::api::service_token_guard(st))
+                                .to($disconnect_subscriber),
+                        ),
+                ),
+        }
+        .service($crate::api::server_info_method_factory(
+            $secure,
+            $server_info,
+        ))
+    };
+}
+
 #[macro_export]
 macro_rules! queue_scope_factory {
     (   $create_queue:ident,
-        $delete_from_queue:ident,
+        $set_queue_metadata:ident,
+        $delete_key_history:ident,
+        $revoke_event:ident,
+        $acquire_lease:ident,
+        $release_lease:ident,
         $send_to_queue:ident,
-        $close_queue:ident,
+        $send_payload_to_queue:ident,
+        $send_batch_to_queue:ident,
+        $ack_event:ident,
+        $drop_queue:ident,
+        $clear_queue:ident,
+        $count_key:ident,
+        $peek_key:ident,
+        $replay_key:ident,
+        $head_key:ident,
         $subscribe_queue_by_id_ws:ident,
         $subscribe_queue_by_id_longpoll:ident,
         $subscribe_queue_ws:ident,
         $subscribe_queue_longpoll:ident,
+        $subscribe_queue_by_id_ndjson:ident,
+        $subscribe_queue_ndjson:ident,
         $secure:expr,
     ) => {
         match $secure {
             None => web::scope("/queue")
                 .route("/create/{queue_name}", web::post().to($create_queue))
+                .route("/meta/{queue_name}", web::post().to($set_queue_metadata))
                 .route(
                     "/delete/{queue_name}/{uniq_id}",
-                    web::post().to($delete_from_queue),
+                    web::post().to($delete_key_history),
+                )
+                .route(
+                    "/revoke/{queue_name}/{uniq_id}/{sequence}",
+                    web::post().to($revoke_event),
+                )
+                .route(
+                    "/lease/{queue_name}/{uniq_id}",
+                    web::post().to($acquire_lease),
+                )
+                .route(
+                    "/release/{queue_name}/{uniq_id}",
+                    web::post().to($release_lease),
                 )
                 .route("/send/{queue_name}", web::post().to($send_to_queue))
-                .route("/close/{queue_name}", web::post().to($close_queue))
+                .route(
+                    "/send/{queue_name}/{uniq_id}",
+                    web::post().to($send_payload_to_queue),
+                )
+                .route("/batch/{queue_name}", web::post().to($send_batch_to_queue))
+                .route("/ack/{queue_name}/{uniq_id}", web::post().to($ack_event))
+                .route("/close/{queue_name}", web::post().to($drop_queue))
+                .route("/clear/{queue_name}", web::post().to($clear_queue))
+                .route("/count/{queue_name}/{uniq_id}", web::get().to($count_key))
+                .route("/peek/{queue_name}/{uniq_id}", web::get().to($peek_key))
+                .route("/replay/{queue_name}/{uniq_id}", web::get().to($replay_key))
+                .route("/head/{queue_name}/{uniq_id}", web::get().to($head_key))
                 .service(
                     web::scope("/listen")
                         .route(
@@ -48,6 +170,14 @@ macro_rules! queue_scope_factory {
                         .service(
                             web::resource("/ws/{queue_name}/{uniq_id}")
                                 .to($subscribe_queue_by_id_ws),
+                        )
+                        .route(
+                            "/ndjson/{queue_name}",
+                            web::get().to($subscribe_queue_ndjson),
+                        )
+                        .route(
+                            "/ndjson/{queue_name}/{uniq_id}",
+                            web::get().to($subscribe_queue_by_id_ndjson),
                         ),
                 ),
             Some(st) => web::scope("/queue")
@@ -57,11 +187,35 @@ macro_rules! queue_scope_factory {
                         .guard($crate::api::service_token_guard(st))
                         .to($create_queue),
                 )
+                .route(
+                    "/meta/{queue_name}",
+                    web::post()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($set_queue_metadata),
+                )
                 .route(
                     "/delete/{queue_name}/{uniq_id}",
                     web::post()
                         .guard($crate::api::service_token_guard(st))
-                        .to($delete_from_queue),
+                        .to($delete_key_history),
+                )
+                .route(
+                    "/revoke/{queue_name}/{uniq_id}/{sequence}",
+                    web::post()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($revoke_event),
+                )
+                .route(
+                    "/lease/{queue_name}/{uniq_id}",
+                    web::post()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($acquire_lease),
+                )
+                .route(
+                    "/release/{queue_name}/{uniq_id}",
+                    web::post()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($release_lease),
                 )
                 .route(
                     "/send/{queue_name}",
@@ -69,13 +223,65 @@ macro_rules! queue_scope_factory {
                         .guard($crate::api::service_token_guard(st))
                         .to($send_to_queue),
                 )
+                .route(
+                    "/send/{queue_name}/{uniq_id}",
+                    web::post()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($send_payload_to_queue),
+                )
+                .route(
+                    "/batch/{queue_name}",
+                    web::post()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($send_batch_to_queue),
+                )
+                .route(
+                    "/ack/{queue_name}/{uniq_id}",
+                    web::post()
+                        .guard(
+                            actix_web::guard::Any($crate::api::service_token_guard(st))
+                                .or($crate::api::jwt_token_guard(st)),
+                        )
+                        .to($ack_event),
+                )
                 .route(
                     "/close/{queue_name}",
                     web::post()
                         .guard($crate::api::service_token_guard(st))
-                        .to($close_queue),
+                        .to($drop_queue),
+                )
+                .route(
+                    "/clear/{queue_name}",
+                    web::post()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($clear_queue),
+                )
+                .route(
+                    "/count/{queue_name}/{uniq_id}",
+                    web::get()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($count_key),
+                )
+                .route(
+                    "/peek/{queue_name}/{uniq_id}",
+                    web::get()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($peek_key),
+                )
+                .route(
+                    "/replay/{queue_name}/{uniq_id}",
+                    web::get()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($replay_key),
+                )
+                .route(
+                    "/head/{queue_name}/{uniq_id}",
+                    web::get()
+                        .guard($crate::api::service_token_guard(st))
+                        .to($head_key),
                 )
                 .service($crate::api::generate_jwt_method_factory(st.clone()))
+                .service($crate::api::generate_signature_method_factory(st.clone()))
                 .service(
                     web::scope("/listen")
                         .route(
@@ -92,13 +298,34 @@ macro_rules! queue_scope_factory {
                         .route(
                             "/longpoll/{queue_name}/{uniq_id}",
                             web::get()
-                                .guard($crate::api::jwt_token_guard(st))
+                                .guard(
+                                    actix_web::guard::Any($crate::api::jwt_token_guard(st))
+                                        .or($crate::api::signed_url_guard(st)),
+                                )
                                 .to($subscribe_queue_by_id_longpoll),
                         )
                         .service(
                             web::resource("/ws/{queue_name}/{uniq_id}")
-                                .guard($crate::api::jwt_token_guard(st))
+                                .guard(
+                                    actix_web::guard::Any($crate::api::jwt_token_guard(st))
+                                        .or($crate::api::signed_url_guard(st)),
+                                )
                                 .to($subscribe_queue_by_id_ws),
+                        )
+                        .route(
+                            "/ndjson/{queue_name}",
+                            web::get()
+                                .guard($crate::api::service_token_guard(st))
+                                .to($subscribe_queue_ndjson),
+                        )
+                        .route(
+                            "/ndjson/{queue_name}/{uniq_id}",
+                            web::get()
+                                .guard(
+                                    actix_web::guard::Any($crate::api::jwt_token_guard(st))
+                                        .or($crate::api::signed_url_guard(st)),
+                                )
+                                .to($subscribe_queue_by_id_ndjson),
                         ),
                 ),
         }
@@ -136,7 +363,156 @@ pub fn jwt_token_guard(secure: &Secure) -> impl Guard {
     })
 }
 
-fn extract_access_token(head: &RequestHead) -> Option<String> {
+/// Jwt authorization of long-lived key subscriptions.
+/// Clients may refresh it with new tokens before expiration.
+#[derive(Debug, Clone)]
+pub struct JwtSession {
+    secure: Secure,
+    pub expiration: SystemTime,
+    pub identity: Option<String>,
+}
+
+impl JwtSession {
+    pub fn from_request(head: &RequestHead, secure: &Secure) -> Option<Self> {
+        let claims = extract_access_token(head).and_then(|token| {
+            decode::<Claims>(
+                &token,
+                &DecodingKey::from_secret(secure.service_token.as_bytes()),
+                &Validation::default(),
+            )
+            .ok()
+        })?;
+
+        Some(Self {
+            secure: secure.clone(),
+            expiration: SystemTime::UNIX_EPOCH + Duration::from_secs(claims.claims.exp as u64),
+            identity: claims.claims.identity,
+        })
+    }
+
+    /// Validates the new token for the same queue, key and identity and extends the session
+    pub fn refresh(&mut self, token: &str, queue_name: &str, id: &str) -> Option<SystemTime> {
+        let claims = decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(self.secure.service_token.as_bytes()),
+            &Validation::default(),
+        )
+        .ok()
+        .filter(|c| {
+            c.claims.iss == queue_name && c.claims.sub == id && c.claims.identity == self.identity
+        })?
+        .claims;
+
+        self.expiration = SystemTime::UNIX_EPOCH + Duration::from_secs(claims.exp as u64);
+        Some(self.expiration)
+    }
+}
+
+/// Authorizes the key subscription with the service token or the jwt token of the key,
+/// returns the identity of the jwt token. `None` if the subscription is not authorized.
+pub fn authorize_key_subscription(
+    secure: &Secure,
+    token: &str,
+    queue_name: &str,
+    id: &str,
+) -> Option<Option<String>> {
+    if token == secure.service_token {
+        return Some(None);
+    }
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secure.service_token.as_bytes()),
+        &Validation::default(),
+    )
+    .ok()
+    .filter(|c| c.claims.iss == queue_name && c.claims.sub == id)
+    .map(|c| c.claims.identity)
+}
+
+/// Authorizes the queue listing with the service token or the jwt token of a key,
+/// returns the queue of the jwt token. `None` if the listing is not authorized.
+pub fn authorize_listing(secure: &Secure, token: &str) -> Option<Option<String>> {
+    if token == secure.service_token {
+        return Some(None);
+    }
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secure.service_token.as_bytes()),
+        &Validation::default(),
+    )
+    .ok()
+    .map(|c| Some(c.claims.iss))
+}
+
+/// Accepts short-lived signed urls of key subscriptions.
+/// Queue name and key are taken from the last path segments, signatures are made of decoded ones.
+pub fn signed_url_guard(secure: &Secure) -> impl Guard {
+    let secure = secure.clone();
+    actix_web::guard::fn_guard(move |ctx| {
+        let head = ctx.head();
+        let mut segments = head.uri.path().rsplit('/').map(percent_decode);
+        let (id, queue_name) = match (segments.next(), segments.next()) {
+            (Some(Some(id)), Some(Some(queue_name))) => (id, queue_name),
+            _ => return false,
+        };
+
+        extract_any_data_from_query::<SignatureQuery>(head)
+            .filter(|q| SystemTime::UNIX_EPOCH + Duration::from_secs(q.expires) > SystemTime::now())
+            .and_then(|q| {
+                let expected = sign_subscription(&secure, &queue_name, &id, q.expires)?;
+                Some(
+                    expected.len() == q.signature.len()
+                        && openssl::memcmp::eq(expected.as_bytes(), q.signature.as_bytes()),
+                )
+            })
+            .unwrap_or_default()
+    })
+}
+
+/// Decodes percent encoded path segment, `None` if it is malformed or not utf-8
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(segment.len());
+    let mut bytes = segment.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            decoded.push(b);
+            continue;
+        }
+        let hex = [bytes.next()?, bytes.next()?];
+        decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Calculates hex encoded HMAC-SHA256 signature of the key subscription
+pub fn sign_subscription(
+    secure: &Secure,
+    queue_name: &str,
+    id: &str,
+    expires: u64,
+) -> Option<String> {
+    let key = hmac_sha256(secure.service_token.as_bytes(), SIGNATURE_KEY_CONTEXT)?;
+
+    // fields are prefixed with their lengths, so a part of the key can't be moved to the queue name
+    let mut message = Vec::new();
+    for field in [queue_name.as_bytes(), id.as_bytes()] {
+        message.extend_from_slice(&(field.len() as u64).to_be_bytes());
+        message.extend_from_slice(field);
+    }
+    message.extend_from_slice(&expires.to_be_bytes());
+
+    let signature = hmac_sha256(&key, &message)?;
+    Some(signature.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Option<Vec<u8>> {
+    let key = PKey::hmac(key).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).ok()?;
+    signer.update(message).ok()?;
+    signer.sign_to_vec().ok()
+}
+
+pub fn extract_access_token(head: &RequestHead) -> Option<String> {
     extract_access_token_from_header(head).or_else(|| extract_access_token_from_query(head))
 }
 
@@ -148,7 +524,7 @@ pub fn extract_any_data_from_query<T: DeserializeOwned>(head: &RequestHead) -> O
     head.uri.query().and_then(|v| {
         serde_urlencoded::from_str(v)
             .map_err(|e| {
-                error!("extracting sequence error: {}", e);
+                error!(error = %e, "extracting sequence error");
                 e
             })
             .ok()
@@ -171,11 +547,24 @@ pub struct AccessTokenQuery {
     pub access_token: String,
 }
 
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SignatureQuery {
+    pub expires: u64,
+    pub signature: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
     sub: String,
     exp: usize,
     iss: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    identity: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct IdentityQuery {
+    pub identity: Option<String>,
 }
 
 pub fn generate_jwt_method_factory(secure: Secure) -> impl HttpServiceFactory {
@@ -183,7 +572,9 @@ pub fn generate_jwt_method_factory(secure: Secure) -> impl HttpServiceFactory {
         .guard(service_token_guard(&secure))
         .app_data(Data::new(secure))
         .route(web::post().to(
-            move |secure: Data<Secure>, info: web::Path<(String, String)>| async move {
+            move |secure: Data<Secure>,
+                  info: web::Path<(String, String)>,
+                  query: web::Query<IdentityQuery>| async move {
                 let (queue_name, id) = info.into_inner();
                 let expiration_res = SystemTime::now()
                     .checked_add(Duration::from_secs(secure.jwt_token_expiration))
@@ -199,6 +590,7 @@ pub fn generate_jwt_method_factory(secure: Secure) -> impl HttpServiceFactory {
                     sub: id,
                     iss: queue_name,
                     exp: expiration,
+                    identity: query.into_inner().identity,
                 };
                 let token = encode(
                     &Header::default(),
@@ -215,16 +607,120 @@ pub fn generate_jwt_method_factory(secure: Secure) -> impl HttpServiceFactory {
         ))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct JwtTokenResponse {
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct JwtTokenResponse {
     token: String,
     expiration: usize,
 }
 
+pub fn generate_signature_method_factory(secure: Secure) -> impl HttpServiceFactory {
+    web::resource("/generate_signature/{queue}/{uniq_id}")
+        .guard(service_token_guard(&secure))
+        .app_data(Data::new(secure))
+        .route(web::post().to(
+            move |secure: Data<Secure>, info: web::Path<(String, String)>| async move {
+                let (queue_name, id) = info.into_inner();
+                let expires_res = SystemTime::now()
+                    .checked_add(Duration::from_secs(secure.jwt_token_expiration))
+                    .expect("could not to add minute to current time")
+                    .duration_since(SystemTime::UNIX_EPOCH);
+
+                let expires = match expires_res {
+                    Ok(e) => e.as_secs(),
+                    Err(e) => return Err(actix_web::error::ErrorInternalServerError(e)),
+                };
+
+                match sign_subscription(&secure, &queue_name, &id, expires) {
+                    Some(signature) => {
+                        Ok(HttpResponse::Ok().json(SignatureQuery { expires, signature }))
+                    }
+                    None => Err(actix_web::error::ErrorInternalServerError("signing error")),
+                }
+            },
+        ))
+}
+
+pub fn server_info_method_factory(
+    secure: &Option<Secure>,
+    info: ServerResponse,
+) -> impl HttpServiceFactory {
+    let route = web::get()
+        .to(|info: Data<ServerResponse>| async move { HttpResponse::Ok().json(info.get_ref()) });
+    web::resource("/server")
+        .app_data(Data::new(info))
+        .route(match secure {
+            None => route,
+            Some(s) => route.guard(service_token_guard(s)),
+        })
+}
+
 pub const MAX_RECONNECT_ATTEMPTS: u8 = 10;
 
+/// Maximum count of events sent with one batch request
+pub const MAX_BATCH_SIZE: usize = 1000;
+
 /// Calculate sleep time with formula `seconds = 1.5 * sqrt(attempts)`
 /// To getting increasing time intervals between reconnections.
 pub fn sleep_between_reconnects(attempt: u8) -> impl Future<Output = ()> {
     sleep(Duration::from_secs((1.5 * (attempt as f32)).sqrt() as u64))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn signed_url_of_encoded_key_is_accepted() {
+        let secure = Secure::from("secret".to_string());
+        let expires = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let signature = sign_subscription(&secure, "my queue", "a/b ключ", expires).unwrap();
+
+        let req = TestRequest::get()
+            .uri(&format!(
+                "/queue/listen/ws/my%20queue/a%2Fb%20%D0%BA%D0%BB%D1%8E%D1%87?expires={}&signature={}",
+                expires, signature
+            ))
+            .to_srv_request();
+        assert!(signed_url_guard(&secure).check(&req.guard_ctx()));
+
+        let req = TestRequest::get()
+            .uri(&format!(
+                "/queue/listen/ws/my%20queue/a%2Fc?expires={}&signature={}",
+                expires, signature
+            ))
+            .to_srv_request();
+        assert!(!signed_url_guard(&secure).check(&req.guard_ctx()));
+    }
+
+    #[test]
+    fn signed_url_is_rejected_for_other_split_of_queue_and_key() {
+        let secure = Secure::from("secret".to_string());
+        let expires = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let signature = sign_subscription(&secure, "a", "b/c", expires).unwrap();
+
+        let req = TestRequest::get()
+            .uri(&format!(
+                "/queue/listen/ws/a/b%2Fc?expires={}&signature={}",
+                expires, signature
+            ))
+            .to_srv_request();
+        assert!(signed_url_guard(&secure).check(&req.guard_ctx()));
+
+        let req = TestRequest::get()
+            .uri(&format!(
+                "/queue/listen/ws/a%2Fb/c?expires={}&signature={}",
+                expires, signature
+            ))
+            .to_srv_request();
+        assert!(!signed_url_guard(&secure).check(&req.guard_ctx()));
+    }
+}
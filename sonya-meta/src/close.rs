@@ -0,0 +1,62 @@
+use std::fmt::{Display, Formatter};
+
+/// Reasons of closing subscriptions by the server.
+/// Every reason has own websocket close code, so clients can choose
+/// between resubscribing, refreshing tokens or giving up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QueueCloseReason {
+    /// Queue was dropped, resubscribing is useless
+    QueueClosed,
+    /// Key history was deleted
+    KeyDeleted,
+    /// Access token expired, client should resubscribe with a new token
+    AuthExpired,
+    /// Server is shutting down, client should reconnect with backoff
+    Draining,
+    /// Client sent too large frame
+    MessageTooLarge,
+    /// Client skipped too many messages
+    SlowConsumer,
+    /// Subscriber was disconnected by the admin api
+    Disconnected,
+    /// Server failed over, client should reconnect to the address from the `moved` event
+    Moved,
+    /// History preload failed or timed out, client should resubscribe with backoff
+    PreloadFailed,
+    /// Unexpected server error
+    InternalError,
+}
+
+impl QueueCloseReason {
+    pub fn code(&self) -> u16 {
+        match self {
+            QueueCloseReason::QueueClosed => 4000,
+            QueueCloseReason::KeyDeleted => 4001,
+            QueueCloseReason::AuthExpired => 4401,
+            QueueCloseReason::Draining => 1001,
+            QueueCloseReason::MessageTooLarge => 1009,
+            QueueCloseReason::SlowConsumer => 1008,
+            QueueCloseReason::Disconnected => 4003,
+            QueueCloseReason::Moved => 4307,
+            QueueCloseReason::PreloadFailed => 1013,
+            QueueCloseReason::InternalError => 1011,
+        }
+    }
+}
+
+impl Display for QueueCloseReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueCloseReason::QueueClosed => write!(f, "queue closed"),
+            QueueCloseReason::KeyDeleted => write!(f, "key deleted"),
+            QueueCloseReason::AuthExpired => write!(f, "auth expired"),
+            QueueCloseReason::Draining => write!(f, "server draining"),
+            QueueCloseReason::MessageTooLarge => write!(f, "message too large"),
+            QueueCloseReason::SlowConsumer => write!(f, "slow consumer"),
+            QueueCloseReason::Disconnected => write!(f, "disconnected by admin"),
+            QueueCloseReason::Moved => write!(f, "moved"),
+            QueueCloseReason::PreloadFailed => write!(f, "preload failed"),
+            QueueCloseReason::InternalError => write!(f, "internal error"),
+        }
+    }
+}
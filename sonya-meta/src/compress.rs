@@ -0,0 +1,119 @@
+use crate::config::Compression;
+use actix_web::dev::RequestHead;
+use actix_web::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, VARY};
+use actix_web::web::Bytes;
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use flate2::write::{DeflateEncoder, GzEncoder};
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+
+/// Content coding of compressed http responses
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ContentCoding {
+    Gzip,
+    Deflate,
+}
+
+impl ContentCoding {
+    /// Coding accepted by the client, gzip is preferred
+    pub fn from_accept_encoding(head: &RequestHead) -> Option<Self> {
+        let accepted: Vec<_> = head
+            .headers
+            .get_all(ACCEPT_ENCODING)
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .filter_map(|coding| {
+                let mut params = coding.split(';').map(str::trim);
+                let name = params.next()?;
+                // codings with zero quality are refused by the client
+                let refused = params.any(|p| matches!(p, "q=0" | "q=0.0" | "q=0.00" | "q=0.000"));
+                (!refused).then_some(name)
+            })
+            .collect();
+
+        [ContentCoding::Gzip, ContentCoding::Deflate]
+            .into_iter()
+            .find(|coding| accepted.contains(&coding.as_str()))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(&self, body: &[u8], level: u32) -> std::io::Result<Vec<u8>> {
+        let level = flate2::Compression::new(level);
+        match self {
+            ContentCoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 2), level);
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            ContentCoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::with_capacity(body.len() / 2), level);
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+impl Display for ContentCoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Response body after the content coding negotiation
+#[derive(Debug)]
+pub struct Negotiated {
+    pub coding: Option<ContentCoding>,
+    /// Size of the body before compression
+    pub original_len: usize,
+    pub body: Bytes,
+}
+
+impl Negotiated {
+    /// Compresses bodies of the threshold size and more, if compression is enabled
+    /// and the client accepts it. Bodies are sent as is if compression fails.
+    pub fn new(head: &RequestHead, compression: Option<&Compression>, body: Bytes) -> Self {
+        let original_len = body.len();
+        let coding = compression
+            .filter(|c| original_len >= c.min_size)
+            .zip(ContentCoding::from_accept_encoding(head));
+        if let Some((compression, coding)) = coding {
+            match coding.encode(&body, compression.level) {
+                Ok(compressed) if compressed.len() < original_len => {
+                    return Self {
+                        coding: Some(coding),
+                        original_len,
+                        body: Bytes::from(compressed),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!(error = %e, coding = %coding, "compression error"),
+            }
+        }
+
+        Self {
+            coding: None,
+            original_len,
+            body,
+        }
+    }
+
+    /// Bytes saved by compression
+    pub fn saved(&self) -> usize {
+        self.original_len.saturating_sub(self.body.len())
+    }
+
+    pub fn respond(self, mut builder: HttpResponseBuilder) -> HttpResponse {
+        builder.insert_header((VARY, "Accept-Encoding"));
+        if let Some(coding) = self.coding {
+            builder.insert_header((CONTENT_ENCODING, coding.as_str()));
+        }
+        builder.body(self.body)
+    }
+}
@@ -0,0 +1,40 @@
+use crate::config::Cors;
+use actix_web::http::header;
+use actix_web::http::Method;
+
+/// Builds CORS middleware, origins of queue methods are checked against
+/// the queue specific list and fall back to the global list
+pub fn get_cors_from_config(opts: Cors) -> actix_cors::Cors {
+    let max_age = opts.max_age;
+
+    actix_cors::Cors::default()
+        .allowed_origin_fn(move |origin, head| {
+            let origin = match origin.to_str() {
+                Ok(o) => o,
+                Err(_) => return false,
+            };
+
+            let allowed = extract_queue_name(head.uri.path())
+                .and_then(|q| opts.queues.get(q))
+                .unwrap_or(&opts.allowed_origins);
+
+            allowed.iter().any(|a| a == "*" || a == origin)
+        })
+        .allowed_methods([Method::GET, Method::POST])
+        .allowed_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+        .max_age(max_age)
+}
+
+/// Extracts queue name from `/queue/{method}/{queue_name}` and
+/// `/queue/listen/{type}/{queue_name}` paths
+fn extract_queue_name(path: &str) -> Option<&str> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    if segments.next()? != "queue" {
+        return None;
+    }
+
+    match segments.next()? {
+        "listen" => segments.nth(1),
+        _ => segments.next(),
+    }
+}
@@ -0,0 +1,301 @@
+use actix_web::dev::RequestHead;
+use actix_web::http::header::{ACCEPT, SEC_WEBSOCKET_PROTOCOL};
+use actix_web::web::Bytes;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+/// Websocket subprotocol of json deliveries
+pub const JSON_PROTOCOL: &str = "sonya.json";
+
+/// Websocket subprotocol of MessagePack deliveries
+pub const MSGPACK_PROTOCOL: &str = "sonya.msgpack";
+
+/// Websocket subprotocol of binary frames with the compact header and MessagePack payloads
+pub const BINARY_PROTOCOL: &str = "sonya.binary";
+
+/// Subprotocols supported by websocket subscriptions
+pub const PROTOCOLS: &[&str] = &[JSON_PROTOCOL, MSGPACK_PROTOCOL, BINARY_PROTOCOL];
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+/// Content type of streaming subscriptions, one json event per line
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Kind of the binary frame with the event
+const EVENT_FRAME: u8 = 1;
+
+/// Kind of the binary frame with the MessagePack control event
+const CONTROL_FRAME: u8 = 2;
+
+const SEQUENCE_FLAG: u8 = 0x01;
+const SUPERSEDES_FLAG: u8 = 0x02;
+const TOMBSTONE_FLAG: u8 = 0x04;
+const EPHEMERAL_FLAG: u8 = 0x08;
+const REPLY_TO_FLAG: u8 = 0x10;
+const NUMERIC_KEY_FLAG: u8 = 0x20;
+
+/// Count of recently transcoded events kept for other subscribers
+const TRANSCODED_CACHE_SIZE: usize = 1024;
+
+/// Encoding of events delivered to the subscriber, json by default
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum DeliveryFormat {
+    #[default]
+    Json,
+    MessagePack,
+    /// Websocket only, longpoll responses have no frames
+    Binary,
+}
+
+impl DeliveryFormat {
+    /// Format of the websocket subscription, the first supported subprotocol requested
+    /// by the client is chosen
+    pub fn from_protocols(head: &RequestHead) -> Self {
+        head.headers
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .find_map(|protocol| match protocol.trim() {
+                JSON_PROTOCOL => Some(DeliveryFormat::Json),
+                MSGPACK_PROTOCOL => Some(DeliveryFormat::MessagePack),
+                BINARY_PROTOCOL => Some(DeliveryFormat::Binary),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// Format of the longpoll subscription, the first supported media type accepted
+    /// by the client is chosen
+    pub fn from_accept(head: &RequestHead) -> Self {
+        head.headers
+            .get_all(ACCEPT)
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .find_map(|media| match media.split(';').next().map(str::trim) {
+                Some(JSON_CONTENT_TYPE) => Some(DeliveryFormat::Json),
+                Some(MSGPACK_CONTENT_TYPE | "application/x-msgpack") => {
+                    Some(DeliveryFormat::MessagePack)
+                }
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            DeliveryFormat::Json => JSON_PROTOCOL,
+            DeliveryFormat::MessagePack => MSGPACK_PROTOCOL,
+            DeliveryFormat::Binary => BINARY_PROTOCOL,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            DeliveryFormat::Json => JSON_CONTENT_TYPE,
+            DeliveryFormat::MessagePack | DeliveryFormat::Binary => MSGPACK_CONTENT_TYPE,
+        }
+    }
+}
+
+impl Display for DeliveryFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.protocol())
+    }
+}
+
+/// Transcodes serialized json events to other delivery formats.
+/// Subscribers of one event share its encoding, so every event is transcoded
+/// once per requested format instead of once per subscriber.
+#[derive(Debug, Default)]
+pub struct Transcoder {
+    recent: Mutex<HashMap<DeliveryFormat, RecentEncodings>>,
+}
+
+#[derive(Debug, Default)]
+struct RecentEncodings {
+    encoded: HashMap<Arc<str>, Bytes>,
+    order: VecDeque<Arc<str>>,
+}
+
+impl Transcoder {
+    /// Encodes the json event with the format
+    pub fn transcode(&self, json: &str, format: DeliveryFormat) -> Result<Bytes, TranscodeError> {
+        if format == DeliveryFormat::Json {
+            return Ok(Bytes::copy_from_slice(json.as_bytes()));
+        }
+
+        let cached = self
+            .recent
+            .lock()
+            .unwrap()
+            .get(&format)
+            .and_then(|recent| recent.encoded.get(json).cloned());
+        if let Some(encoded) = cached {
+            return Ok(encoded);
+        }
+
+        let value = serde_json::from_str::<Value>(json)?;
+        let encoded = Bytes::from(match format {
+            DeliveryFormat::Binary => encode_frame(value)?,
+            _ => rmp_serde::to_vec_named(&value)?,
+        });
+
+        let mut recent = self.recent.lock().unwrap();
+        let recent = recent.entry(format).or_default();
+        if recent.order.len() >= TRANSCODED_CACHE_SIZE {
+            if let Some(oldest) = recent.order.pop_front() {
+                recent.encoded.remove(&oldest);
+            }
+        }
+        let json = Arc::<str>::from(json);
+        let previous = recent.encoded.insert(json.clone(), encoded.clone());
+        if previous.is_none() {
+            recent.order.push_back(json);
+        }
+
+        Ok(encoded)
+    }
+
+    /// Encodes the list of json events with the format, e.g. longpoll responses
+    pub fn transcode_all<'a>(
+        &self,
+        events: impl ExactSizeIterator<Item = &'a str>,
+        format: DeliveryFormat,
+    ) -> Result<Bytes, TranscodeError> {
+        // lists aren't framed, so binary subscribers get MessagePack arrays
+        let format = match format {
+            DeliveryFormat::Binary => DeliveryFormat::MessagePack,
+            format => format,
+        };
+        let mut data = Vec::new();
+        match format {
+            DeliveryFormat::Json => data.push(b'['),
+            _ => write_msgpack_array_len(&mut data, events.len()),
+        }
+        for (i, event) in events.enumerate() {
+            if i > 0 && format == DeliveryFormat::Json {
+                data.push(b',');
+            }
+            data.extend_from_slice(&self.transcode(event, format)?);
+        }
+        if format == DeliveryFormat::Json {
+            data.push(b']');
+        }
+
+        Ok(Bytes::from(data))
+    }
+}
+
+/// Encodes the binary frame. Event frames are `1`, flags, big endian `u64` sequence
+/// and supersedes if flagged, the key as big endian `u64` if numeric or `u16` length and utf8,
+/// `u16` length and utf8 of `reply_to` if flagged, then the MessagePack payload.
+/// Control frames are `2` and the MessagePack control event.
+fn encode_frame(value: Value) -> Result<Vec<u8>, TranscodeError> {
+    let mut event = match value {
+        Value::Object(event) if event.contains_key("control") => {
+            let mut frame = vec![CONTROL_FRAME];
+            rmp_serde::encode::write_named(&mut frame, &event)?;
+            return Ok(frame);
+        }
+        Value::Object(event) => event,
+        _ => return Err(TranscodeError::Frame("event is not an object")),
+    };
+
+    let sequence = event.get("sequence").and_then(Value::as_u64);
+    let supersedes = event.get("supersedes").and_then(Value::as_u64);
+    let reply_to = event.get("reply_to").and_then(Value::as_str);
+    let flag = |name: &str, flag: u8| match event.get(name).and_then(Value::as_bool) {
+        Some(true) => flag,
+        _ => 0,
+    };
+    let mut flags = flag("tombstone", TOMBSTONE_FLAG) | flag("ephemeral", EPHEMERAL_FLAG);
+    if sequence.is_some() {
+        flags |= SEQUENCE_FLAG;
+    }
+    if supersedes.is_some() {
+        flags |= SUPERSEDES_FLAG;
+    }
+    if reply_to.is_some() {
+        flags |= REPLY_TO_FLAG;
+    }
+    if matches!(event.get("id"), Some(Value::Number(_))) {
+        flags |= NUMERIC_KEY_FLAG;
+    }
+
+    let mut frame = vec![EVENT_FRAME, flags];
+    for value in [sequence, supersedes].into_iter().flatten() {
+        frame.extend_from_slice(&value.to_be_bytes());
+    }
+    match event.get("id") {
+        Some(Value::Number(id)) => {
+            let id = id.as_u64().ok_or(TranscodeError::Frame("key is not u64"))?;
+            frame.extend_from_slice(&id.to_be_bytes());
+        }
+        Some(Value::String(id)) => write_frame_str(&mut frame, id)?,
+        _ => return Err(TranscodeError::Frame("event has no key")),
+    }
+    if let Some(reply_to) = reply_to {
+        write_frame_str(&mut frame, reply_to)?;
+    }
+
+    let payload = event.remove("payload").unwrap_or_default();
+    rmp_serde::encode::write_named(&mut frame, &payload)?;
+    Ok(frame)
+}
+
+fn write_frame_str(frame: &mut Vec<u8>, value: &str) -> Result<(), TranscodeError> {
+    let len = u16::try_from(value.len()).map_err(|_| TranscodeError::Frame("too long string"))?;
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+fn write_msgpack_array_len(data: &mut Vec<u8>, len: usize) {
+    match len {
+        0..=15 => data.push(0x90 | len as u8),
+        16..=0xffff => {
+            data.push(0xdc);
+            data.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            data.push(0xdd);
+            data.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TranscodeError {
+    Decode(serde_json::Error),
+    Encode(rmp_serde::encode::Error),
+    /// Event can't be put into the binary frame
+    Frame(&'static str),
+}
+
+impl Display for TranscodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TranscodeError::Decode(e) => write!(f, "decoding json event error: {}", e),
+            TranscodeError::Encode(e) => write!(f, "encoding event error: {}", e),
+            TranscodeError::Frame(e) => write!(f, "encoding binary frame error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TranscodeError {}
+
+impl From<serde_json::Error> for TranscodeError {
+    fn from(e: serde_json::Error) -> Self {
+        TranscodeError::Decode(e)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for TranscodeError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        TranscodeError::Encode(e)
+    }
+}
@@ -1,5 +1,18 @@
+// allows derive macros to refer the crate by name inside the crate
+extern crate self as sonya_meta;
+
 pub mod api;
+pub mod close;
+pub mod compress;
 pub mod config;
+pub mod cors;
+pub mod encoding;
+pub mod limit;
+pub mod logger;
 pub mod message;
+#[cfg(feature = "proto")]
+pub mod proto;
 pub mod response;
+pub mod server;
+pub mod spec;
 pub mod tls;
@@ -0,0 +1,142 @@
+use crate::config::ConnectionLimits;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Counts opened subscriptions and rejects new ones over configured limits
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    limits: ConnectionLimits,
+    counters: Mutex<ConnectionCounters>,
+}
+
+#[derive(Debug, Default)]
+struct ConnectionCounters {
+    total: usize,
+    queues: HashMap<String, usize>,
+    ips: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            counters: Default::default(),
+        }
+    }
+
+    /// Registers new connection, it will be released when returned guard is dropped
+    pub fn acquire(
+        self: Arc<Self>,
+        queue_name: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<ConnectionGuard, LimitError> {
+        let mut counters = self.counters.lock().unwrap();
+
+        if matches!(self.limits.max_connections, Some(m) if counters.total >= m) {
+            return Err(LimitError::Total);
+        }
+
+        if let Some(m) = self.limits.max_connections_per_queue {
+            if counters.queues.get(queue_name).copied().unwrap_or_default() >= m {
+                return Err(LimitError::Queue);
+            }
+        }
+
+        if let (Some(m), Some(ip)) = (self.limits.max_connections_per_ip, ip) {
+            if counters.ips.get(&ip).copied().unwrap_or_default() >= m {
+                return Err(LimitError::Ip);
+            }
+        }
+
+        counters.total += 1;
+        *counters.queues.entry(queue_name.to_string()).or_default() += 1;
+        if let Some(ip) = ip {
+            *counters.ips.entry(ip).or_default() += 1;
+        }
+        drop(counters);
+
+        Ok(ConnectionGuard {
+            limiter: self,
+            queue_name: queue_name.to_string(),
+            ip,
+        })
+    }
+
+    fn release(&self, queue_name: &str, ip: Option<IpAddr>) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.total = counters.total.saturating_sub(1);
+
+        if let Some(count) = counters.queues.get_mut(queue_name) {
+            *count -= 1;
+            if *count == 0 {
+                counters.queues.remove(queue_name);
+            }
+        }
+
+        if let Some(ip) = ip {
+            if let Some(count) = counters.ips.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    counters.ips.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// Holds a connection slot until dropped
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    limiter: Arc<ConnectionLimiter>,
+    queue_name: String,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.limiter.release(&self.queue_name, self.ip)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum LimitError {
+    Total,
+    Queue,
+    Ip,
+}
+
+impl Display for LimitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitError::Total => write!(f, "too many connections"),
+            LimitError::Queue => write!(f, "too many connections to queue"),
+            LimitError::Ip => write!(f, "too many connections from ip"),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+impl ResponseError for LimitError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::TOO_MANY_REQUESTS
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(LimitErrorResponse {
+            success: false,
+            reason: self.to_string(),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct LimitErrorResponse {
+    success: bool,
+    reason: String,
+}
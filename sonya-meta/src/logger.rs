@@ -0,0 +1,12 @@
+use crate::config::{Log, LogFormat};
+use tracing_subscriber::EnvFilter;
+
+/// Initializes global logger, log levels are configured with `RUST_LOG` env
+pub fn init_logger(config: &Log) {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env());
+
+    match config.format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(true).init(),
+    }
+}
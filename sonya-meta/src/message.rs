@@ -1,14 +1,127 @@
+use schemars::JsonSchema;
 use serde::de::Unexpected;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
+pub use sonya_meta_derive::UniqId;
 use std::fmt::{Debug, Display, Formatter};
-use std::num::NonZeroU64;
+use std::num::{NonZeroU64, ParseIntError};
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, UniqId, JsonSchema)]
 pub struct EventMessage {
+    #[event(id)]
+    #[serde(deserialize_with = "deserialize_key")]
     pub id: String,
+    #[event(sequence)]
     pub sequence: Sequence,
     pub payload: Value,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tombstone: bool,
+    /// Queue which receives the receipt of the first delivery to a subscriber
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    #[event(supersedes)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supersedes: Sequence,
+    /// Delivered to live subscribers only, without storing
+    #[event(ephemeral)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ephemeral: bool,
+    /// Hybrid logical clock timestamp assigned by the server on publish.
+    /// Published timestamps are observed by the server clock, so the event is ordered after them.
+    #[event(timestamp)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<HlcTimestamp>,
+}
+
+impl EventMessage {
+    pub fn new(id: String, payload: Value) -> Self {
+        Self {
+            id,
+            sequence: None,
+            payload,
+            tombstone: false,
+            reply_to: None,
+            supersedes: None,
+            ephemeral: false,
+            timestamp: None,
+        }
+    }
+}
+
+/// Control events of the wire protocol.
+/// Serialized with the `control` tag, so clients can distinguish them from data events.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "control", rename_all = "snake_case")]
+pub enum ControlMessage {
+    Heartbeat,
+    Lagged {
+        skipped: u64,
+    },
+    EndOfPreload {
+        /// Sequence to continue the preload from, set when the preload was limited
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        continuation: Option<SequenceId>,
+    },
+    QueueClosed,
+    KeyDeleted,
+    /// Stored event of the key was revoked, clients should retract it
+    Revoked {
+        id: String,
+        sequence: SequenceId,
+    },
+    /// Server is shutting down, clients should reconnect after `retry_after` seconds
+    Draining {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+    },
+    TokenRefreshed {
+        expiration: u64,
+    },
+    /// Server failed over, clients should reconnect to the new address
+    Moved {
+        address: String,
+    },
+    /// History couldn't be preloaded, clients should resubscribe from the last received sequence
+    PreloadFailed,
+}
+
+/// Control events sent by clients over websocket subscriptions
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "control", rename_all = "snake_case")]
+pub enum ClientControlMessage {
+    RefreshToken { access_token: String },
+}
+
+/// Hybrid logical clock timestamp, ordered by the wall time and then by the logical counter.
+/// Timestamps of causally related events are ordered even if wall clocks of servers skew.
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, JsonSchema,
+)]
+pub struct HlcTimestamp {
+    /// Unix time in milliseconds
+    pub wall: u64,
+    /// Counter of events with the same wall time
+    pub logical: u32,
+}
+
+/// Parses `{wall}` or `{wall}.{logical}`
+impl FromStr for HlcTimestamp {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (wall, logical) = s.split_once('.').unwrap_or((s, "0"));
+        Ok(Self {
+            wall: wall.parse()?,
+            logical: logical.parse()?,
+        })
+    }
+}
+
+impl Display for HlcTimestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.wall, self.logical)
+    }
 }
 
 pub type Sequence = Option<SequenceId>;
@@ -95,21 +208,67 @@ impl Display for RequestSequenceId {
     }
 }
 
-impl UniqId for EventMessage {
-    fn get_id(&self) -> &str {
-        &self.id
-    }
-    fn get_sequence(&self) -> Sequence {
-        self.sequence
+/// Deserializes keys of events, unsigned integer keys are stored in their decimal form,
+/// so `42` and `"42"` are the same key
+fn deserialize_key<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Key {
+        Number(u64),
+        String(String),
     }
 
-    fn set_sequence(&mut self, sequence: SequenceId) -> Sequence {
-        self.sequence.replace(sequence)
-    }
+    Ok(match Key::deserialize(deserializer)? {
+        Key::Number(n) => n.to_string(),
+        Key::String(s) => s,
+    })
 }
 
+/// Key and sequence of events, may be derived with `#[derive(UniqId)]`
 pub trait UniqId {
     fn get_id(&self) -> &str;
     fn get_sequence(&self) -> Sequence;
     fn set_sequence(&mut self, sequence: SequenceId) -> Sequence;
+    /// Sequence of the earlier event of the key which this event corrects
+    fn get_supersedes(&self) -> Sequence {
+        None
+    }
+    /// Ephemeral events are broadcasted to live subscribers only, without storing and sequencing
+    fn is_ephemeral(&self) -> bool {
+        false
+    }
+    fn get_timestamp(&self) -> Option<HlcTimestamp> {
+        None
+    }
+    /// Events without a timestamp field aren't stamped
+    fn set_timestamp(&mut self, _timestamp: HlcTimestamp) {}
+}
+
+impl Tombstone for EventMessage {
+    fn tombstone(id: String) -> Self {
+        Self {
+            id,
+            sequence: None,
+            payload: Value::Null,
+            tombstone: true,
+            reply_to: None,
+            supersedes: None,
+            ephemeral: false,
+            timestamp: None,
+        }
+    }
+
+    fn is_tombstone(&self) -> bool {
+        self.tombstone
+    }
+}
+
+/// Marker events which are written instead of deleted key history,
+/// so subscribers may invalidate their caches.
+pub trait Tombstone {
+    fn tombstone(id: String) -> Self;
+    fn is_tombstone(&self) -> bool;
 }
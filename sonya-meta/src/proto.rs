@@ -0,0 +1,50 @@
+use crate::message::{EventMessage, HlcTimestamp, SequenceId};
+use serde_json::Value;
+
+// generated from `proto/event.proto`
+include!(concat!(env!("OUT_DIR"), "/sonya.event.rs"));
+
+impl TryFrom<&EventMessage> for Event {
+    type Error = serde_json::Error;
+
+    /// Envelope has no headers, they are set by the sender
+    fn try_from(event: &EventMessage) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: event.id.clone(),
+            sequence: event.sequence.map_or(0, SequenceId::get),
+            timestamp: event.timestamp.map_or(0, |t| t.wall),
+            headers: Default::default(),
+            payload: serde_json::to_vec(&event.payload)?,
+            tombstone: event.tombstone,
+            reply_to: event.reply_to.clone().unwrap_or_default(),
+            supersedes: event.supersedes.map_or(0, SequenceId::get),
+            ephemeral: event.ephemeral,
+            logical: event.timestamp.map_or(0, |t| t.logical),
+        })
+    }
+}
+
+impl TryFrom<Event> for EventMessage {
+    type Error = serde_json::Error;
+
+    fn try_from(event: Event) -> Result<Self, Self::Error> {
+        let payload = match event.payload.is_empty() {
+            true => Value::Null,
+            false => serde_json::from_slice(&event.payload)?,
+        };
+        Ok(Self {
+            id: event.id.into(),
+            sequence: SequenceId::new(event.sequence),
+            payload,
+            tombstone: event.tombstone,
+            reply_to: Some(event.reply_to).filter(|r| !r.is_empty()),
+            supersedes: SequenceId::new(event.supersedes),
+            ephemeral: event.ephemeral,
+            timestamp: Some(HlcTimestamp {
+                wall: event.timestamp,
+                logical: event.logical,
+            })
+            .filter(|t| t.wall > 0),
+        })
+    }
+}
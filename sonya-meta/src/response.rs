@@ -1,6 +1,364 @@
+use crate::config::{Config, ConnectionLimits};
+use crate::message::SequenceId;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct BaseQueueResponse {
     pub success: bool,
 }
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SendResponse {
+    pub success: bool,
+    /// Sequence of the stored event, absent for ephemeral events and duplicates
+    pub sequence: Option<SequenceId>,
+}
+
+/// Results of the batch events in the order of the request
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct BatchResponse {
+    /// All events were sent
+    pub success: bool,
+    pub events: Vec<BatchEventResult>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct BatchEventResult {
+    pub success: bool,
+    /// Response code of the event if it was sent alone
+    pub status: u16,
+    pub error: Option<String>,
+    /// Sequence of the stored event
+    pub sequence: Option<SequenceId>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct CountResponse {
+    pub success: bool,
+    pub count: usize,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct PeekResponse<T> {
+    pub success: bool,
+    pub events: Vec<T>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ReplayResponse<T> {
+    pub success: bool,
+    pub events: Vec<T>,
+    pub next: Option<SequenceId>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct LeaseResponse {
+    pub success: bool,
+    /// Token of the lease holder, passed with the `Sonya-Lease` header
+    pub lease: Option<String>,
+    /// Unix time in seconds
+    pub expiration: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct HeadResponse {
+    pub success: bool,
+    /// Latest stored sequence of the key
+    pub key: Option<KeyHead>,
+    /// Latest stored offset of the queue
+    pub queue: Option<QueueHead>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct KeyHead {
+    pub sequence: SequenceId,
+    /// Unix time in milliseconds
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct QueueHead {
+    pub offset: u64,
+    /// Unix time in milliseconds
+    pub timestamp: u64,
+}
+
+/// Queues which the caller may subscribe to, sorted by name
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct QueuesResponse {
+    pub success: bool,
+    pub queues: Vec<QueueListing>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct QueueListing {
+    pub name: String,
+    /// Latest stored offset of the queue
+    pub head: Option<QueueHead>,
+    #[serde(flatten)]
+    pub metadata: QueueMetadata,
+}
+
+/// Human metadata of the queue, so large deployments stay navigable
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueMetadata {
+    pub description: Option<String>,
+    /// Team owning the queue
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct GapsResponse {
+    pub success: bool,
+    pub published: u64,
+    pub stored: usize,
+    pub first: Option<SequenceId>,
+    pub last: Option<SequenceId>,
+    pub gaps: Vec<SequenceGap>,
+}
+
+/// Lifecycle of the stored event, `trace` is `None` if the event was not traced
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct TraceResponse {
+    pub success: bool,
+    pub trace: Option<MessageTrace>,
+}
+
+/// Lifecycle of the stored event, timestamps are milliseconds since the unix epoch
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct MessageTrace {
+    pub stored_at: Option<u64>,
+    pub removed_at: Option<u64>,
+    pub removal: Option<TraceRemoval>,
+    /// Count of deliveries to subscriptions, reconnects and several subscriptions count apart
+    pub deliveries: u64,
+    pub first_delivered_at: Option<u64>,
+    pub last_delivered_at: Option<u64>,
+    #[serde(default)]
+    pub acks: Vec<TraceAck>,
+}
+
+/// Why the event is not stored anymore
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceRemoval {
+    /// Trimmed by `max_key_updates`
+    Trimmed,
+    /// Collapsed by the superseding event
+    Superseded,
+    /// Dropped with its expired segment
+    Expired,
+    Revoked,
+    /// Removed with the key history
+    Deleted,
+    /// Removed with the queue messages
+    Cleared,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct TraceAck {
+    /// Resumable session of the subscriber, `None` for anonymous subscribers
+    pub session: Option<String>,
+    pub at: u64,
+}
+
+/// Delivery metrics of the queue broadcast channel and its key channels
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct BroadcastsResponse {
+    pub success: bool,
+    pub queue: ChannelMetrics,
+    pub keys: BTreeMap<String, ChannelMetrics>,
+}
+
+/// Counters of the broadcast channel since it was created
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct ChannelMetrics {
+    /// Live receivers of the channel
+    pub receivers: usize,
+    /// Messages sent to live receivers
+    pub sent: u64,
+    /// Messages sent when the channel had no receivers
+    pub unsent: u64,
+    /// Messages skipped by lagging receivers
+    pub dropped: u64,
+    /// Times receivers lagged behind and skipped messages
+    #[serde(default)]
+    pub lagged: u64,
+    /// Messages kept for slow receivers
+    pub capacity: usize,
+}
+
+/// Size estimates of stored entries of the queue and its keys, the queue size sums its keys
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SizesResponse {
+    pub success: bool,
+    pub queue: StoredSize,
+    pub keys: BTreeMap<String, StoredSize>,
+    pub metadata: QueueMetadata,
+}
+
+/// Stored entries and their approximate size in bytes, cold history is not counted
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default)]
+pub struct StoredSize {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// Open websocket subscriptions of the queue
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SubscribersResponse {
+    pub success: bool,
+    pub subscribers: Vec<SubscriberResponse>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct SubscriberResponse {
+    /// Id of the connection, used to disconnect the subscriber
+    pub connection_id: String,
+    /// Key of the subscription, `None` for queue subscriptions
+    pub key: Option<String>,
+    pub client_id: Option<String>,
+    /// Labels attached by the client at connect time, e.g. app name and version
+    pub labels: BTreeMap<String, String>,
+    pub ip: Option<String>,
+    /// Websocket subprotocol negotiated for deliveries, e.g. `sonya.json`
+    #[serde(default)]
+    pub format: String,
+    /// Connection time in seconds since the unix epoch
+    pub connected: u64,
+    /// Messages skipped by the subscriber since connected
+    pub skipped: u64,
+}
+
+/// Inclusive range of missing sequences
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SequenceGap {
+    pub from: SequenceId,
+    pub to: SequenceId,
+}
+
+/// Checksums of the queue keys, replicas compare them to find diverged keys
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct DigestResponse {
+    pub success: bool,
+    pub keys: BTreeMap<String, KeyDigest>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct KeyDigest {
+    /// Latest stored sequence of the key
+    pub head: u64,
+    pub count: usize,
+    /// Checksum of the range checksums
+    pub checksum: u64,
+}
+
+/// Checksums of the key entries over aligned sequence ranges, ranges without entries are omitted
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct RangeDigestResponse {
+    pub success: bool,
+    /// Latest stored sequence of the key
+    pub head: u64,
+    pub ranges: Vec<RangeDigest>,
+}
+
+/// Checksum of the stored entries with sequences from `from` to `to` inclusive
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct RangeDigest {
+    pub from: u64,
+    pub to: u64,
+    pub count: usize,
+    pub checksum: u64,
+}
+
+/// Last rebalancing of the queue, `None` if the proxy never rebalanced it
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct RebalanceResponse {
+    pub success: bool,
+    pub progress: Option<RebalanceProgress>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct RebalanceProgress {
+    pub state: RebalanceState,
+    /// Keys stored by shards which don't own them
+    pub keys: usize,
+    pub moved: usize,
+    pub failed: usize,
+    /// Copied entries of the moved keys
+    pub entries: usize,
+    /// Errors of the failed keys, the keys are left on their shards
+    pub errors: Vec<String>,
+    /// Start time in seconds since the unix epoch
+    pub started: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RebalanceState {
+    #[default]
+    Running,
+    Finished,
+    Failed,
+}
+
+/// Runtime capabilities of the service, so clients can adapt without out-of-band knowledge
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+pub struct ServerResponse {
+    pub success: bool,
+    pub version: String,
+    pub role: ServerRole,
+    pub auth: AuthMode,
+    pub storage: Option<String>,
+    pub service_discovery: Option<String>,
+    pub features: Vec<String>,
+    pub limits: ConnectionLimits,
+}
+
+impl ServerResponse {
+    pub fn new(role: ServerRole, version: &str, config: &Config) -> Self {
+        Self {
+            success: true,
+            version: version.to_string(),
+            role,
+            auth: match config.secure {
+                Some(_) => AuthMode::ServiceToken,
+                None => AuthMode::None,
+            },
+            storage: None,
+            service_discovery: config.service_discovery.as_ref().map(|s| s.to_string()),
+            features: vec![],
+            limits: config.limits.clone(),
+        }
+    }
+
+    pub fn with_storage(mut self, storage: &str) -> Self {
+        self.storage = Some(storage.to_string());
+        self
+    }
+
+    pub fn with_features<'a>(mut self, features: impl IntoIterator<Item = &'a str>) -> Self {
+        self.features.extend(features.into_iter().map(String::from));
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerRole {
+    Queue,
+    Proxy,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    /// All methods are open
+    None,
+    /// Service token for management methods, jwt tokens and signed urls for subscriptions
+    ServiceToken,
+}
@@ -0,0 +1,24 @@
+/// Applies [`crate::config::Server`] options to the actix `HttpServer`
+#[macro_export]
+macro_rules! configure_server {
+    ($server:expr, $options:expr) => {{
+        let mut server = $server;
+        let options: &$crate::config::Server = $options;
+        if let Some(workers) = options.workers {
+            server = server.workers(workers);
+        }
+        if let Some(max_blocking_threads) = options.max_blocking_threads {
+            server = server.worker_max_blocking_threads(max_blocking_threads);
+        }
+        if let Some(keep_alive) = options.keep_alive {
+            server = server.keep_alive(std::time::Duration::from_secs(keep_alive));
+        }
+        if let Some(timeout) = options.client_request_timeout {
+            server = server.client_request_timeout(std::time::Duration::from_millis(timeout));
+        }
+        if let Some(timeout) = options.client_disconnect_timeout {
+            server = server.client_disconnect_timeout(std::time::Duration::from_millis(timeout));
+        }
+        server
+    }};
+}
@@ -0,0 +1,903 @@
+use crate::api::{JwtTokenResponse, SignatureQuery};
+use crate::message::{ClientControlMessage, ControlMessage, EventMessage};
+use crate::response::{
+    BaseQueueResponse, BatchResponse, BroadcastsResponse, CountResponse, DigestResponse,
+    GapsResponse, HeadResponse, LeaseResponse, PeekResponse, QueueMetadata, QueuesResponse,
+    RangeDigestResponse, RebalanceResponse, ReplayResponse, SendResponse, ServerResponse,
+    SizesResponse, SubscribersResponse, TraceResponse,
+};
+use actix_web::dev::HttpServiceFactory;
+use actix_web::web::Data;
+use actix_web::{web, HttpResponse};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use serde_json::{json, Map, Value};
+
+/// Produces the schema reference of the type and registers its definition in the generator
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+/// Http method of the api with its parameters and response
+struct Endpoint {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    auth: Auth,
+    query: &'static [Param],
+    headers: &'static [Param],
+    body: Option<SchemaFn>,
+    status: u16,
+    response: SchemaFn,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Auth {
+    /// Service token is required in secure mode
+    Service,
+    /// Jwt token or signed url of the key is required in secure mode
+    Subscription,
+    /// Service token or jwt token of the key is required in secure mode
+    Key,
+    /// Registered in secure mode only
+    SecureOnly,
+}
+
+struct Param {
+    name: &'static str,
+    kind: &'static str,
+    description: &'static str,
+}
+
+const fn param(name: &'static str, kind: &'static str, description: &'static str) -> Param {
+    Param {
+        name,
+        kind,
+        description,
+    }
+}
+
+const LEASE: Param = param("Sonya-Lease", "string", "Lease token of the key writer");
+
+const DEDUP: Param = param(
+    "Sonya-Dedup-Id",
+    "string",
+    "Publisher id of the event, retries with the same id are stored once",
+);
+
+const ACCEPT: Param = param(
+    "Accept",
+    "string",
+    "`application/msgpack` to receive events as a MessagePack array",
+);
+
+const SUBSCRIPTION_QUERY: &[Param] = &[
+    param(
+        "sequence",
+        "string",
+        "Sequence to preload history from, `first`, `last` or a positive number",
+    ),
+    param(
+        "max_preload",
+        "integer",
+        "Maximum count of preloaded events",
+    ),
+    param("identity", "string", "Identity of the subscriber"),
+    param("access_token", "string", "Jwt token of the subscriber"),
+    param("expires", "integer", "Expiration of the signed url"),
+    param("signature", "string", "Signature of the signed url"),
+    param("client_id", "string", "Client id listed by the admin api"),
+    param(
+        "labels",
+        "string",
+        "Client labels listed by the admin api, `name:value` pairs separated with commas",
+    ),
+    param(
+        "session",
+        "string",
+        "Resumable session, continues after the last delivered sequence if `sequence` is not set",
+    ),
+];
+
+const KEY_LONGPOLL_QUERY: &[Param] = &[
+    param(
+        "sequence",
+        "string",
+        "Sequence to preload history from, `first`, `last` or a positive number",
+    ),
+    param(
+        "after",
+        "integer",
+        "Known sequence, responds with 204 if nothing was published after it",
+    ),
+    param(
+        "max_preload",
+        "integer",
+        "Maximum count of preloaded events",
+    ),
+    param("identity", "string", "Identity of the subscriber"),
+    param("access_token", "string", "Jwt token of the subscriber"),
+    param("expires", "integer", "Expiration of the signed url"),
+    param("signature", "string", "Signature of the signed url"),
+    param(
+        "buffer",
+        "boolean",
+        "Opens the poll session, its token is returned with `Sonya-Poll-Session` header",
+    ),
+    param(
+        "poll_session",
+        "string",
+        "Token of the poll session, returns events buffered since the previous poll",
+    ),
+];
+
+const QUEUE_LONGPOLL_QUERY: &[Param] = &[
+    param(
+        "ids",
+        "string",
+        "Keys of the subscription separated with commas",
+    ),
+    param(
+        "sequence",
+        "string",
+        "Sequence to preload history from, `first`, `last` or a positive number",
+    ),
+    param(
+        "max_preload",
+        "integer",
+        "Maximum count of preloaded events",
+    ),
+    param("identity", "string", "Identity of the subscriber"),
+    param(
+        "buffer",
+        "boolean",
+        "Opens the poll session, its token is returned with `Sonya-Poll-Session` header",
+    ),
+    param(
+        "poll_session",
+        "string",
+        "Token of the poll session, returns events buffered since the previous poll",
+    ),
+];
+
+const KEY_NDJSON_QUERY: &[Param] = &[
+    param(
+        "sequence",
+        "string",
+        "Sequence to preload history from, `first`, `last` or a positive number",
+    ),
+    param(
+        "max_preload",
+        "integer",
+        "Maximum count of preloaded events",
+    ),
+    param("identity", "string", "Identity of the subscriber"),
+    param("access_token", "string", "Jwt token of the subscriber"),
+    param("expires", "integer", "Expiration of the signed url"),
+    param("signature", "string", "Signature of the signed url"),
+    param("client_id", "string", "Client id listed by the admin api"),
+    param(
+        "labels",
+        "string",
+        "Client labels listed by the admin api, `name:value` pairs separated with commas",
+    ),
+];
+
+const QUEUE_WS_QUERY: &[Param] = &[
+    param(
+        "ids",
+        "string",
+        "Keys of the subscription separated with commas",
+    ),
+    param(
+        "sequence",
+        "string",
+        "Sequence to preload history from, `first`, `last` or a positive number",
+    ),
+    param(
+        "max_preload",
+        "integer",
+        "Maximum count of preloaded events",
+    ),
+    param("identity", "string", "Identity of the subscriber"),
+    param("client_id", "string", "Client id listed by the admin api"),
+    param(
+        "labels",
+        "string",
+        "Client labels listed by the admin api, `name:value` pairs separated with commas",
+    ),
+];
+
+const ENDPOINTS: &[Endpoint] = &[
+    Endpoint {
+        method: "post",
+        path: "/queue/create/{queue_name}",
+        summary: "Create queue",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 201,
+        response: SchemaGenerator::subschema_for::<BaseQueueResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/queue/meta/{queue_name}",
+        summary: "Set queue description, owner and tags",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: Some(SchemaGenerator::subschema_for::<QueueMetadata>),
+        status: 200,
+        response: SchemaGenerator::subschema_for::<BaseQueueResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/queue/delete/{queue_name}/{uniq_id}",
+        summary: "Delete key history",
+        auth: Auth::Service,
+        query: &[param(
+            "tombstone",
+            "boolean",
+            "Keep subscribers and publish a tombstone event",
+        )],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<BaseQueueResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/queue/revoke/{queue_name}/{uniq_id}/{sequence}",
+        summary: "Revoke event",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<BaseQueueResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/queue/lease/{queue_name}/{uniq_id}",
+        summary: "Acquire key lease",
+        auth: Auth::Service,
+        query: &[param("ttl", "integer", "Lease duration in seconds")],
+        headers: &[LEASE],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<LeaseResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/queue/release/{queue_name}/{uniq_id}",
+        summary: "Release key lease",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[LEASE],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<BaseQueueResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/queue/send/{queue_name}",
+        summary: "Send to queue",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[LEASE, DEDUP],
+        body: Some(SchemaGenerator::subschema_for::<EventMessage>),
+        status: 200,
+        response: SchemaGenerator::subschema_for::<SendResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/queue/send/{queue_name}/{uniq_id}",
+        summary: "Send payload to the key",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[LEASE, DEDUP],
+        body: Some(SchemaGenerator::subschema_for::<Value>),
+        status: 200,
+        response: SchemaGenerator::subschema_for::<SendResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/queue/batch/{queue_name}",
+        summary: "Send events in order with one request",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[LEASE],
+        body: Some(SchemaGenerator::subschema_for::<Vec<EventMessage>>),
+        status: 200,
+        response: SchemaGenerator::subschema_for::<BatchResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/queue/ack/{queue_name}/{uniq_id}",
+        summary: "Acknowledge processing of the event",
+        auth: Auth::Key,
+        query: &[
+            param("sequence", "integer", "Sequence of the event"),
+            param(
+                "session",
+                "string",
+                "Resumable session of the subscriber, recorded in the message trace",
+            ),
+        ],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<BaseQueueResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/queue/close/{queue_name}",
+        summary: "Close queue",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<BaseQueueResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/queue/clear/{queue_name}",
+        summary: "Clear queue",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<BaseQueueResponse>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/queue/count/{queue_name}/{uniq_id}",
+        summary: "Count key events",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<CountResponse>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/queue/peek/{queue_name}/{uniq_id}",
+        summary: "Peek latest key events",
+        auth: Auth::Service,
+        query: &[param("n", "integer", "Count of the latest events")],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<PeekResponse<EventMessage>>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/queue/replay/{queue_name}/{uniq_id}",
+        summary: "Replay key history",
+        auth: Auth::Service,
+        query: &[
+            param("from", "integer", "Sequence to replay from"),
+            param("since", "string", "Timestamp to replay from"),
+            param("limit", "integer", "Maximum count of events"),
+        ],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<ReplayResponse<EventMessage>>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/queue/head/{queue_name}/{uniq_id}",
+        summary: "Key and queue head",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<HeadResponse>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/queues",
+        summary: "Queues which the caller may subscribe to with their heads and metadata",
+        auth: Auth::Key,
+        query: &[
+            param("owner", "string", "Lists only queues of the owner"),
+            param("tag", "string", "Lists only queues with the tag"),
+        ],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<QueuesResponse>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/queue/listen/longpoll/{queue_name}",
+        summary: "Longpoll queue",
+        auth: Auth::Service,
+        query: QUEUE_LONGPOLL_QUERY,
+        headers: &[ACCEPT],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<Vec<EventMessage>>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/queue/listen/longpoll/{queue_name}/{uniq_id}",
+        summary: "Longpoll key",
+        auth: Auth::Subscription,
+        query: KEY_LONGPOLL_QUERY,
+        headers: &[ACCEPT],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<Vec<EventMessage>>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/queue/listen/ndjson/{queue_name}",
+        summary: "Stream queue events, one json event per line of `application/x-ndjson` body",
+        auth: Auth::Service,
+        query: QUEUE_WS_QUERY,
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<EventMessage>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/queue/listen/ndjson/{queue_name}/{uniq_id}",
+        summary: "Stream key events, one json event per line of `application/x-ndjson` body",
+        auth: Auth::Subscription,
+        query: KEY_NDJSON_QUERY,
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<EventMessage>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/queue/generate_jwt/{queue}/{uniq_id}",
+        summary: "Generate jwt token of the key subscriber",
+        auth: Auth::SecureOnly,
+        query: &[param("identity", "string", "Identity of the subscriber")],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<JwtTokenResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/queue/generate_signature/{queue}/{uniq_id}",
+        summary: "Generate signed url of the key subscription",
+        auth: Auth::SecureOnly,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<SignatureQuery>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/admin/gaps/{queue_name}/{uniq_id}",
+        summary: "Sequence gaps of the key",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<GapsResponse>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/admin/trace/{queue_name}/{uniq_id}/{sequence}",
+        summary: "Lifecycle of the stored event, if message tracing is enabled",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<TraceResponse>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/admin/broadcasts/{queue_name}",
+        summary: "Delivery metrics of the queue broadcast channels",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<BroadcastsResponse>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/admin/sizes/{queue_name}",
+        summary: "Estimated count and size of stored entries of the queue and its keys",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<SizesResponse>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/admin/subscribers/{queue_name}",
+        summary: "Open websocket subscriptions of the queue with client ids and labels",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<SubscribersResponse>,
+    },
+    Endpoint {
+        method: "delete",
+        path: "/admin/subscribers/{connection_id}",
+        summary: "Close the websocket subscription",
+        auth: Auth::Service,
+        query: &[param(
+            "reason",
+            "string",
+            "Sent to the client in the close frame",
+        )],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<BaseQueueResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/admin/failover",
+        summary: "Redirect clients of the queue server to the new primary",
+        auth: Auth::Service,
+        query: &[param("address", "string", "Address of the new primary")],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<BaseQueueResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/admin/promote",
+        summary: "Make the standby or failed over queue server primary",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<BaseQueueResponse>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/admin/digest/{queue_name}",
+        summary: "Checksums of the queue keys compared by standbys",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<DigestResponse>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/admin/digest/{queue_name}/{uniq_id}",
+        summary: "Checksums of the key entries over aligned sequence ranges",
+        auth: Auth::Service,
+        query: &[
+            param(
+                "range",
+                "integer",
+                "Sequences covered by one checksum, 1024 by default",
+            ),
+            param("to", "integer", "Entries after the sequence are skipped"),
+        ],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<RangeDigestResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/admin/move/{queue_name}/{uniq_id}",
+        summary: "Delete the key moved by rebalancing and move its subscribers to the owner",
+        auth: Auth::Service,
+        query: &[param(
+            "to",
+            "string",
+            "Address of the shard which owns the key",
+        )],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<BaseQueueResponse>,
+    },
+    Endpoint {
+        method: "post",
+        path: "/admin/rebalance/{queue_name}",
+        summary: "Move keys of the queue to the shards which own them, proxies only",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 202,
+        response: SchemaGenerator::subschema_for::<BaseQueueResponse>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/admin/rebalance/{queue_name}",
+        summary: "Progress of the last rebalancing of the queue, proxies only",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<RebalanceResponse>,
+    },
+    Endpoint {
+        method: "get",
+        path: "/admin/server",
+        summary: "Server capabilities",
+        auth: Auth::Service,
+        query: &[],
+        headers: &[],
+        body: None,
+        status: 200,
+        response: SchemaGenerator::subschema_for::<ServerResponse>,
+    },
+];
+
+/// Websocket subscriptions of the wire protocol
+const CHANNELS: &[(&str, &str, &[Param])] = &[
+    (
+        "/queue/listen/ws/{queue_name}",
+        "Events of all keys of the queue",
+        QUEUE_WS_QUERY,
+    ),
+    (
+        "/queue/listen/ws/{queue_name}/{uniq_id}",
+        "Events of the key",
+        SUBSCRIPTION_QUERY,
+    ),
+];
+
+/// Builds OpenAPI document of the http methods.
+/// Methods which exist only in secure mode are skipped if `secure` is not set.
+pub fn openapi(version: &str, secure: bool) -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let mut paths = Map::new();
+
+    for endpoint in ENDPOINTS {
+        if endpoint.auth == Auth::SecureOnly && !secure {
+            continue;
+        }
+
+        let mut parameters = path_params(endpoint.path);
+        parameters.extend(endpoint.query.iter().map(|p| param_spec(p, "query")));
+        parameters.extend(endpoint.headers.iter().map(|p| param_spec(p, "header")));
+
+        let mut operation = json!({
+            "summary": endpoint.summary,
+            "parameters": parameters,
+            "responses": {
+                (endpoint.status.to_string()): {
+                    "description": endpoint.summary,
+                    "content": {
+                        "application/json": {
+                            "schema": (endpoint.response)(&mut gen),
+                        }
+                    }
+                }
+            }
+        });
+        if let Some(body) = endpoint.body {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": {
+                    "application/json": {
+                        "schema": body(&mut gen),
+                    }
+                }
+            });
+        }
+        if secure {
+            operation["security"] = security(endpoint.auth);
+        }
+
+        paths
+            .entry(endpoint.path)
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .expect("path item is an object")
+            .insert(endpoint.method.to_string(), operation);
+    }
+
+    let mut components = json!({ "schemas": gen.take_definitions() });
+    if secure {
+        components["securitySchemes"] = security_schemes();
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Sonya web queue",
+            "version": version,
+        },
+        "paths": paths,
+        "components": components,
+    })
+}
+
+/// Builds AsyncAPI document of the websocket protocol
+pub fn asyncapi(version: &str) -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let event = gen.subschema_for::<EventMessage>();
+    let control = gen.subschema_for::<ControlMessage>();
+    let client_control = gen.subschema_for::<ClientControlMessage>();
+
+    let channels: Map<String, Value> = CHANNELS
+        .iter()
+        .map(|(path, description, query)| {
+            let parameters: Map<String, Value> = path_names(path)
+                .map(|name| (name.to_string(), json!({ "schema": { "type": "string" } })))
+                .collect();
+            let bindings = query
+                .iter()
+                .map(|p| {
+                    (
+                        p.name.to_string(),
+                        json!({ "type": p.kind, "description": p.description }),
+                    )
+                })
+                .collect::<Map<String, Value>>();
+            let channel = json!({
+                "description": description,
+                "parameters": parameters,
+                "bindings": {
+                    "ws": {
+                        "method": "GET",
+                        "bindingVersion": "0.1.0",
+                        "query": {
+                            "type": "object",
+                            "properties": bindings,
+                        }
+                    }
+                },
+                "subscribe": {
+                    "message": {
+                        "oneOf": [
+                            { "$ref": "#/components/messages/event" },
+                            { "$ref": "#/components/messages/control" },
+                        ]
+                    }
+                },
+                "publish": {
+                    "message": { "$ref": "#/components/messages/client_control" }
+                }
+            });
+            (path.to_string(), channel)
+        })
+        .collect();
+
+    let components = json!({
+        "messages": {
+            "event": {
+                "summary": "Published event",
+                "payload": event,
+            },
+            "control": {
+                "summary": "Control event of the subscription, tagged with the `control` field",
+                "payload": control,
+            },
+            "client_control": {
+                "summary": "Control event sent by the client",
+                "payload": client_control,
+            }
+        },
+        "schemas": gen.take_definitions(),
+    });
+
+    json!({
+        "asyncapi": "2.6.0",
+        "info": {
+            "title": "Sonya web queue",
+            "version": version,
+        },
+        "defaultContentType": "application/json",
+        "channels": channels,
+        "components": components,
+    })
+}
+
+/// Serves `/spec/openapi.json` and `/spec/asyncapi.json` documents
+pub fn spec_scope_factory(version: &str, secure: bool) -> impl HttpServiceFactory {
+    let openapi = Data::new(SpecDocument(openapi(version, secure)));
+    let asyncapi = Data::new(SpecDocument(asyncapi(version)));
+    web::scope("/spec")
+        .service(
+            web::resource("/openapi.json")
+                .app_data(openapi)
+                .route(web::get().to(spec_document)),
+        )
+        .service(
+            web::resource("/asyncapi.json")
+                .app_data(asyncapi)
+                .route(web::get().to(spec_document)),
+        )
+}
+
+struct SpecDocument(Value);
+
+async fn spec_document(document: Data<SpecDocument>) -> HttpResponse {
+    HttpResponse::Ok().json(&document.0)
+}
+
+fn path_names(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter_map(|s| s.strip_prefix('{').and_then(|s| s.strip_suffix('}')))
+}
+
+fn path_params(path: &str) -> Vec<Value> {
+    path_names(path)
+        .map(|name| {
+            let kind = match name {
+                "sequence" => "integer",
+                _ => "string",
+            };
+            json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": { "type": kind },
+            })
+        })
+        .collect()
+}
+
+fn param_spec(param: &Param, location: &str) -> Value {
+    json!({
+        "name": param.name,
+        "in": location,
+        "required": false,
+        "description": param.description,
+        "schema": { "type": param.kind },
+    })
+}
+
+fn security(auth: Auth) -> Value {
+    match auth {
+        Auth::Service | Auth::SecureOnly => json!([{ "service_token": [] }]),
+        Auth::Subscription => json!([
+            { "service_token": [] },
+            { "jwt_token": [] },
+            { "signed_url": [] },
+        ]),
+        Auth::Key => json!([{ "service_token": [] }, { "jwt_token": [] }]),
+    }
+}
+
+fn security_schemes() -> Value {
+    json!({
+        "service_token": {
+            "type": "http",
+            "scheme": "bearer",
+            "description": "Service token, also accepted with the `access_token` query param",
+        },
+        "jwt_token": {
+            "type": "http",
+            "scheme": "bearer",
+            "bearerFormat": "JWT",
+            "description": "Key token from `/queue/generate_jwt`, \
+                also accepted with the `access_token` query param",
+        },
+        "signed_url": {
+            "type": "apiKey",
+            "in": "query",
+            "name": "signature",
+            "description": "Signed url from `/queue/generate_signature`, \
+                passed with `expires` and `signature` query params",
+        }
+    })
+}
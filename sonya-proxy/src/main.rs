@@ -1,9 +1,11 @@
+mod rebalance;
 mod registry;
 mod service_discovery;
 mod websocket_proxy;
 mod websocket_proxy_client;
 
 use crate::{
+    rebalance::{rebalance, Rebalances},
     registry::{get_address, get_all_addresses, RegistryActor, RegistryList},
     service_discovery::ServiceDiscoveryActor,
     websocket_proxy::WebSocketProxyActor,
@@ -13,33 +15,53 @@ use crate::{
 };
 use actix::Addr;
 use actix_web::{
-    dev::RequestHead, http::header::HeaderMap, middleware::Logger, web, App, Error, HttpRequest,
-    HttpResponse, HttpServer, Responder,
+    dev::{HttpServiceFactory, RequestHead},
+    http::header::{HeaderMap, ACCEPT, AUTHORIZATION},
+    middleware::Condition,
+    web::{self, Bytes, BytesMut},
+    App, Error, HttpRequest, HttpResponse, HttpServer, Responder, Route,
 };
 use actix_web_actors::ws;
 use awc::{
     http::header::{HeaderName, HeaderValue},
-    Client,
+    http::StatusCode,
+    Client, ClientRequest,
 };
-use futures::{future::Either, SinkExt, TryStreamExt};
-use log::{error, info};
+use futures::{future::Either, SinkExt, Stream, StreamExt, TryStreamExt};
 use serde::Deserialize;
 use serde_json::Value;
 use sonya_meta::message::RequestSequence;
 use sonya_meta::{
+    admin_scope_factory,
     api::extract_any_data_from_query,
     api::service_token_guard,
-    config::{get_config, Config, ServiceDiscovery},
+    api::JwtSession,
+    api::MAX_BATCH_SIZE,
+    compress::Negotiated,
+    config::{get_config, Config, Secure, ServiceDiscovery},
+    configure_server,
+    cors::get_cors_from_config,
+    encoding::{DeliveryFormat, Transcoder, NDJSON_CONTENT_TYPE, PROTOCOLS},
+    limit::ConnectionLimiter,
     message::EventMessage,
     queue_scope_factory,
-    response::BaseQueueResponse,
+    response::{
+        BaseQueueResponse, BatchEventResult, BatchResponse, BroadcastsResponse, QueueHead,
+        QueueListing, QueueMetadata, QueuesResponse, RebalanceResponse, ServerResponse, ServerRole,
+        SizesResponse, SubscribersResponse,
+    },
+    spec::spec_scope_factory,
     tls::get_options_from_config,
 };
 use std::{
+    collections::{BTreeMap, HashMap},
     net::{IpAddr, Ipv4Addr, SocketAddr},
     time::Duration,
 };
+use tracing::{error, info, instrument, Span};
+use tracing_actix_web::TracingLogger;
 
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
 async fn subscribe_queue_by_id_ws(
     req: HttpRequest,
     stream: web::Payload,
@@ -48,8 +70,13 @@ async fn subscribe_queue_by_id_ws(
     info: web::Path<(String, String)>,
     proxies_storage: web::Data<WebSocketProxyClientsStorage>,
     config: web::Data<Config>,
+    limiter: web::Data<ConnectionLimiter>,
+    transcoder: web::Data<Transcoder>,
 ) -> Result<HttpResponse, Error> {
     let (queue_name, id) = info.into_inner();
+    let connection_guard = limiter
+        .into_inner()
+        .acquire(&queue_name, req.peer_addr().map(|a| a.ip()))?;
     let receiver = create_receiver(
         queue_name,
         id.into(),
@@ -62,13 +89,24 @@ async fn subscribe_queue_by_id_ws(
     )
     .await;
 
-    ws::start(
-        WebSocketProxyActor::new(receiver, req.peer_addr().unwrap()),
+    ws::start_with_protocols(
+        WebSocketProxyActor::new(
+            receiver,
+            req.peer_addr().unwrap(),
+            connection_guard,
+            Span::current(),
+        )
+        .with_format(
+            DeliveryFormat::from_protocols(req.head()),
+            transcoder.into_inner(),
+        ),
+        PROTOCOLS,
         &req,
         stream,
     )
 }
 
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
 async fn subscribe_queue_by_id_longpoll(
     req: HttpRequest,
     registry: web::Data<Addr<RegistryActor>>,
@@ -79,8 +117,10 @@ async fn subscribe_queue_by_id_longpoll(
 
     let client = Client::default();
 
+    // compressed shard responses are passed with their content encoding
     let response = client
         .request_from(address + prepare_path(&req).as_str(), req.head())
+        .no_decompress()
         .timeout(Duration::from_secs(10000))
         .send()
         .await;
@@ -107,6 +147,7 @@ async fn subscribe_queue_by_id_longpoll(
     }
 }
 
+#[instrument(skip_all, fields(queue = %info.0))]
 async fn subscribe_queue_ws(
     req: HttpRequest,
     stream: web::Payload,
@@ -115,8 +156,13 @@ async fn subscribe_queue_ws(
     info: web::Path<(String,)>,
     proxies_storage: web::Data<WebSocketProxyClientsStorage>,
     config: web::Data<Config>,
+    limiter: web::Data<ConnectionLimiter>,
+    transcoder: web::Data<Transcoder>,
 ) -> Result<HttpResponse, Error> {
     let queue_name = info.into_inner().0;
+    let connection_guard = limiter
+        .into_inner()
+        .acquire(&queue_name, req.peer_addr().map(|a| a.ip()))?;
     let receiver = create_receiver(
         queue_name,
         None,
@@ -129,8 +175,18 @@ async fn subscribe_queue_ws(
     )
     .await;
 
-    ws::start(
-        WebSocketProxyActor::new(receiver, req.peer_addr().unwrap()),
+    ws::start_with_protocols(
+        WebSocketProxyActor::new(
+            receiver,
+            req.peer_addr().unwrap(),
+            connection_guard,
+            Span::current(),
+        )
+        .with_format(
+            DeliveryFormat::from_protocols(req.head()),
+            transcoder.into_inner(),
+        ),
+        PROTOCOLS,
         &req,
         stream,
     )
@@ -139,7 +195,20 @@ async fn subscribe_queue_ws(
 async fn subscribe_queue_longpoll(
     req: HttpRequest,
     registry: web::Data<Addr<RegistryActor>>,
+    transcoder: web::Data<Transcoder>,
+    config: web::Data<Config>,
 ) -> Result<HttpResponse, Error> {
+    // every shard would buffer its own part of the subscription
+    let PollSessionQuery {
+        buffer,
+        poll_session,
+    } = extract_any_data_from_query(req.head()).unwrap_or_default();
+    if buffer || poll_session.is_some() {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Poll sessions are supported by key subscriptions only",
+        ));
+    }
+
     let addresses = get_all_addresses(registry.get_ref()).await;
 
     let client = Client::default();
@@ -149,6 +218,8 @@ async fn subscribe_queue_longpoll(
         .map(|address| {
             client
                 .request_from(address + prepare_path(&req).as_str(), req.head())
+                // shard responses are merged as json and encoded for the client at once
+                .insert_header((ACCEPT, DeliveryFormat::Json.content_type()))
                 .timeout(Duration::from_secs(10000))
                 .send()
         })
@@ -207,7 +278,137 @@ async fn subscribe_queue_longpoll(
         }
     }
 
-    Ok(HttpResponse::Ok().json(results))
+    let format = DeliveryFormat::from_accept(req.head());
+    let body = match format {
+        DeliveryFormat::Json => serde_json::to_vec(&results)
+            .map(Bytes::from)
+            .map_err(actix_web::error::ErrorInternalServerError)?,
+        format => {
+            let events = results
+                .iter()
+                .map(serde_json::to_string)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(actix_web::error::ErrorInternalServerError)?;
+            transcoder
+                .transcode_all(events.iter().map(String::as_str), format)
+                .map_err(actix_web::error::ErrorInternalServerError)?
+        }
+    };
+    let mut response = HttpResponse::Ok();
+    response.content_type(format.content_type());
+    Ok(Negotiated::new(req.head(), config.compression.as_ref(), body).respond(response))
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn subscribe_queue_by_id_ndjson(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    limiter: web::Data<ConnectionLimiter>,
+    info: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (queue_name, id) = info.into_inner();
+    let connection_guard = limiter
+        .into_inner()
+        .acquire(&queue_name, req.peer_addr().map(|a| a.ip()))?;
+    let address = get_address(registry.get_ref(), queue_name, id).await;
+
+    // streams are open until the client or the shard closes them
+    let client = Client::builder().disable_timeout().finish();
+
+    let response = client
+        .request_from(address.clone() + prepare_path(&req).as_str(), req.head())
+        .send()
+        .await;
+
+    match response {
+        Ok(r) => {
+            let mut back_rsp = HttpResponse::build(r.status());
+            for (key, value) in r.headers() {
+                back_rsp.insert_header((key.clone(), value.clone()));
+            }
+
+            let mut body = r.into_stream();
+            Ok(back_rsp.streaming(async_stream::stream! {
+                let _connection_guard = connection_guard;
+                while let Some(chunk) = body.next().await {
+                    yield chunk;
+                }
+            }))
+        }
+        Err(e) => {
+            error!(shard = %address, error = ?e, "ndjson subscription proxy error");
+            Err(actix_web::error::ErrorGone(
+                "One of shards is not responding",
+            ))
+        }
+    }
+}
+
+#[instrument(skip_all, fields(queue = %info.0))]
+async fn subscribe_queue_ndjson(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    limiter: web::Data<ConnectionLimiter>,
+    info: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    let queue_name = info.into_inner().0;
+    let connection_guard = limiter
+        .into_inner()
+        .acquire(&queue_name, req.peer_addr().map(|a| a.ip()))?;
+    let addresses = get_all_addresses(registry.get_ref()).await;
+
+    // streams are open until the client or the shard closes them
+    let client = Client::builder().disable_timeout().finish();
+
+    let requests = addresses.into_iter().map(|address| {
+        client
+            .request_from(address + prepare_path(&req).as_str(), req.head())
+            .send()
+    });
+
+    let mut shards = Vec::new();
+    for response in futures::future::join_all(requests).await {
+        let mut response = response.map_err(actix_web::error::ErrorGone)?;
+        if !response.status().is_success() {
+            let b = response.body().await.map_err(actix_web::error::ErrorGone)?;
+            return Ok(HttpResponse::build(response.status()).body(b));
+        }
+        shards.push(Box::pin(ndjson_lines(response.into_stream())));
+    }
+
+    let mut lines = futures::stream::select_all(shards);
+    Ok(HttpResponse::Ok()
+        .content_type(NDJSON_CONTENT_TYPE)
+        .streaming(async_stream::stream! {
+            let _connection_guard = connection_guard;
+            while let Some(line) = lines.next().await {
+                yield line;
+            }
+        }))
+}
+
+/// Splits the shard stream into whole lines, so lines of different shards aren't interleaved
+fn ndjson_lines<S, E>(mut body: S) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    async_stream::stream! {
+        let mut buffer = BytesMut::new();
+        while let Some(chunk) = body.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    buffer.extend_from_slice(&chunk);
+                    if let Some(end) = buffer.iter().rposition(|b| *b == b'\n') {
+                        yield Ok(buffer.split_to(end + 1).freeze());
+                    }
+                }
+                Err(e) => {
+                    yield Err(e);
+                    break;
+                }
+            }
+        }
+    }
 }
 
 fn get_sequence_from_req(req: &HttpRequest) -> SequenceQuery {
@@ -247,30 +448,62 @@ async fn create_receiver(
     head: &RequestHead,
     registry: &Addr<RegistryActor>,
     service_discovery: &Addr<ServiceDiscoveryActor>,
-    SequenceQuery {
-        access_token,
-        sequence,
-    }: SequenceQuery,
+    query: SequenceQuery,
 ) -> Option<tokio::sync::broadcast::Receiver<WebSocketActorResponse>> {
+    let identity = match &config.secure {
+        Some(secure) if id.is_some() => {
+            JwtSession::from_request(head, secure).and_then(|session| session.identity)
+        }
+        _ => query.identity.clone(),
+    };
+    let forward_query = query.forward_query();
     proxies_storage
         .subscribe(
-            WebSocketProxyClientsStorageKey::new(queue_name, id),
+            WebSocketProxyClientsStorageKey::new(queue_name, id, identity),
             create_ws_header_map(config, head),
             Addr::clone(registry),
             Addr::clone(service_discovery),
             config.garbage_collector.interval,
-            access_token,
-            sequence,
+            forward_query,
+            query.sequence,
         )
         .await
 }
 
+#[derive(Deserialize, Default)]
+struct PollSessionQuery {
+    #[serde(default)]
+    buffer: bool,
+    poll_session: Option<String>,
+}
+
 #[derive(Deserialize, Default)]
 struct SequenceQuery {
     sequence: RequestSequence,
     access_token: Option<String>,
+    expires: Option<u64>,
+    signature: Option<String>,
+    identity: Option<String>,
+}
+
+impl SequenceQuery {
+    /// Query string part which is forwarded to shards
+    fn forward_query(&self) -> Option<String> {
+        let auth = match (&self.access_token, self.expires, &self.signature) {
+            (Some(at), _, _) => Some(format!("access_token={}", at)),
+            (None, Some(e), Some(s)) => Some(format!("expires={}&signature={}", e, s)),
+            _ => None,
+        };
+
+        match (auth, &self.identity) {
+            (Some(a), Some(i)) => Some(format!("{}&identity={}", a, i)),
+            (None, Some(i)) => Some(format!("identity={}", i)),
+            (a, None) => a,
+        }
+    }
 }
 
+#[instrument(skip_all, fields(queue = %info.as_str(), key = %message.id))]
 async fn send_to_queue(
     req: HttpRequest,
     registry: web::Data<Addr<RegistryActor>>,
@@ -299,7 +532,42 @@ async fn send_to_queue(
             Ok(back_rsp)
         }
         Err(e) => {
-            error!("send to queue proxy error ({}): {:#?}", address, e);
+            error!(shard = %address, error = ?e, "send to queue proxy error");
+            Err(actix_web::error::ErrorGone(
+                "One of shards is not responding",
+            ))
+        }
+    }
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn send_payload_to_queue(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String, String)>,
+    payload: web::Bytes,
+) -> impl Responder {
+    let (queue_name, id) = info.into_inner();
+    let address = get_address(registry.get_ref(), queue_name, id).await;
+
+    let client = Client::default();
+
+    let response = client
+        .request_from(address.clone() + prepare_path(&req).as_str(), req.head())
+        .send_body(payload)
+        .await;
+
+    match response {
+        Ok(r) => {
+            let mut back_rsp = HttpResponse::build(r.status());
+            for (key, value) in r.headers() {
+                back_rsp.insert_header((key.clone(), value.clone()));
+            }
+
+            Ok(back_rsp.streaming(r.into_stream()))
+        }
+        Err(e) => {
+            error!(shard = %address, error = ?e, "send to queue proxy error");
             Err(actix_web::error::ErrorGone(
                 "One of shards is not responding",
             ))
@@ -307,6 +575,96 @@ async fn send_to_queue(
     }
 }
 
+/// Splits the batch by shards of the event keys, results are merged in the request order
+#[instrument(skip_all, fields(queue = %info.as_str(), events = messages.len()))]
+async fn send_batch_to_queue(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<String>,
+    messages: web::Json<Vec<EventMessage>>,
+) -> Result<HttpResponse, Error> {
+    let queue_name = info.into_inner();
+    let messages = messages.into_inner();
+    if messages.len() > MAX_BATCH_SIZE {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "batch must contain at most {} events",
+            MAX_BATCH_SIZE
+        )));
+    }
+
+    let count = messages.len();
+    let mut batches: HashMap<String, (Vec<usize>, Vec<EventMessage>)> = HashMap::new();
+    for (index, message) in messages.into_iter().enumerate() {
+        let id = message.id.clone();
+        let address = get_address(registry.get_ref(), queue_name.clone(), id).await;
+        let (indexes, events) = batches.entry(address).or_default();
+        indexes.push(index);
+        events.push(message);
+    }
+
+    let client = Client::default();
+    let requests = batches.into_iter().map(|(address, (indexes, events))| {
+        let request =
+            client.request_from(address.clone() + prepare_path(&req).as_str(), req.head());
+        async move {
+            let results = match send_shard_batch(request, &events).await {
+                Ok(results) if results.len() == indexes.len() => results,
+                Ok(_) => {
+                    error!(shard = %address, "batch results don't match events");
+                    shard_batch_error(&indexes, StatusCode::BAD_GATEWAY, "Invalid shard response")
+                }
+                Err(status) => {
+                    error!(shard = %address, %status, "send batch proxy error");
+                    shard_batch_error(&indexes, status, "One of shards is not responding")
+                }
+            };
+            indexes.into_iter().zip(results).collect::<Vec<_>>()
+        }
+    });
+
+    let results = futures::future::join_all(requests).await;
+    let mut events: Vec<Option<BatchEventResult>> = vec![None; count];
+    for (index, result) in results.into_iter().flatten() {
+        events[index] = Some(result);
+    }
+    let events: Vec<_> = events.into_iter().flatten().collect();
+
+    Ok(HttpResponse::Ok().json(BatchResponse {
+        success: events.iter().all(|e| e.success),
+        events,
+    }))
+}
+
+async fn send_shard_batch(
+    request: ClientRequest,
+    events: &[EventMessage],
+) -> Result<Vec<BatchEventResult>, StatusCode> {
+    let mut response = request
+        .send_json(&events)
+        .await
+        .map_err(|_| StatusCode::GONE)?;
+    if !response.status().is_success() {
+        return Err(response.status());
+    }
+
+    response
+        .json::<BatchResponse>()
+        .await
+        .map(|b| b.events)
+        .map_err(|_| StatusCode::BAD_GATEWAY)
+}
+
+/// Results of the events of the shard which didn't send them
+fn shard_batch_error(indexes: &[usize], status: StatusCode, error: &str) -> Vec<BatchEventResult> {
+    let result = BatchEventResult {
+        success: false,
+        status: status.as_u16(),
+        error: Some(error.to_string()),
+        sequence: None,
+    };
+    vec![result; indexes.len()]
+}
+
 async fn create_queue(
     req: HttpRequest,
     registry: web::Data<Addr<RegistryActor>>,
@@ -314,17 +672,473 @@ async fn create_queue(
     base_diagonal_proxy(req, registry).await
 }
 
-async fn delete_from_queue(
+/// Sets metadata of the queue on all shards
+async fn set_queue_metadata(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    metadata: web::Json<QueueMetadata>,
+) -> Result<HttpResponse, Error> {
+    let addresses = get_all_addresses(registry.get_ref()).await;
+
+    let client = Client::default();
+
+    let requests = addresses.into_iter().map(|address| {
+        let request = client
+            .request_from(address + prepare_path(&req).as_str(), req.head())
+            .send_json(&*metadata);
+        async move {
+            request
+                .await
+                .map_err(actix_web::error::ErrorGone)?
+                .json::<BaseQueueResponse>()
+                .await
+                .map_err(actix_web::error::ErrorGone)
+        }
+    });
+
+    let mut success = false;
+    for response in futures::future::join_all(requests).await {
+        success |= response
+            .map_err(|e| {
+                error!(error = %e, "queue metadata proxy error");
+                e
+            })?
+            .success;
+    }
+
+    Ok(HttpResponse::Ok().json(BaseQueueResponse { success }))
+}
+
+async fn delete_key_history(
     req: HttpRequest,
     registry: web::Data<Addr<RegistryActor>>,
 ) -> impl Responder {
     base_diagonal_proxy(req, registry).await
 }
 
-async fn close_queue(req: HttpRequest, registry: web::Data<Addr<RegistryActor>>) -> impl Responder {
+async fn drop_queue(req: HttpRequest, registry: web::Data<Addr<RegistryActor>>) -> impl Responder {
     base_diagonal_proxy(req, registry).await
 }
 
+async fn clear_queue(req: HttpRequest, registry: web::Data<Addr<RegistryActor>>) -> impl Responder {
+    base_diagonal_proxy(req, registry).await
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1, sequence = %info.2))]
+async fn revoke_event(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String, String, u64)>,
+) -> impl Responder {
+    let (queue_name, id, _) = info.into_inner();
+    base_key_proxy(req, registry, queue_name, id).await
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn ack_event(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String, String)>,
+) -> impl Responder {
+    let (queue_name, id) = info.into_inner();
+    base_key_proxy(req, registry, queue_name, id).await
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn acquire_lease(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String, String)>,
+) -> impl Responder {
+    let (queue_name, id) = info.into_inner();
+    base_key_proxy(req, registry, queue_name, id).await
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn release_lease(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String, String)>,
+) -> impl Responder {
+    let (queue_name, id) = info.into_inner();
+    base_key_proxy(req, registry, queue_name, id).await
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn count_key(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String, String)>,
+) -> impl Responder {
+    let (queue_name, id) = info.into_inner();
+    base_key_proxy(req, registry, queue_name, id).await
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn peek_key(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String, String)>,
+) -> impl Responder {
+    let (queue_name, id) = info.into_inner();
+    base_key_proxy(req, registry, queue_name, id).await
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn replay_key(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String, String)>,
+) -> impl Responder {
+    let (queue_name, id) = info.into_inner();
+    base_key_proxy(req, registry, queue_name, id).await
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn head_key(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String, String)>,
+) -> impl Responder {
+    let (queue_name, id) = info.into_inner();
+    base_key_proxy(req, registry, queue_name, id).await
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn sequence_gaps(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String, String)>,
+) -> impl Responder {
+    let (queue_name, id) = info.into_inner();
+    base_key_proxy(req, registry, queue_name, id).await
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1, sequence = %info.2))]
+async fn message_trace(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String, String, u64)>,
+) -> impl Responder {
+    let (queue_name, id, _) = info.into_inner();
+    base_key_proxy(req, registry, queue_name, id).await
+}
+
+/// Sums channel metrics of all shards, keys are stored by one shard each
+#[instrument(skip_all, fields(queue = %info.0))]
+async fn broadcasts(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    let addresses = get_all_addresses(registry.get_ref()).await;
+
+    let client = Client::default();
+
+    let requests = addresses.into_iter().map(|address| {
+        let request = client
+            .request_from(address + prepare_path(&req).as_str(), req.head())
+            .send();
+        async move {
+            request
+                .await
+                .map_err(actix_web::error::ErrorGone)?
+                .json::<BroadcastsResponse>()
+                .await
+                .map_err(actix_web::error::ErrorGone)
+        }
+    });
+
+    let mut result = BroadcastsResponse {
+        success: false,
+        queue: Default::default(),
+        keys: Default::default(),
+    };
+    for response in futures::future::join_all(requests).await {
+        let response = response.map_err(|e| {
+            error!(queue = %info.0, error = %e, "broadcasts proxy error");
+            e
+        })?;
+        if !response.success {
+            continue;
+        }
+
+        result.success = true;
+        result.queue.receivers += response.queue.receivers;
+        result.queue.sent += response.queue.sent;
+        result.queue.unsent += response.queue.unsent;
+        result.queue.dropped += response.queue.dropped;
+        result.queue.lagged += response.queue.lagged;
+        result.queue.capacity = response.queue.capacity;
+        result.keys.extend(response.keys);
+    }
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Sums size estimates of all shards, keys are stored by one shard each
+#[instrument(skip_all, fields(queue = %info.0))]
+async fn sizes(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    let addresses = get_all_addresses(registry.get_ref()).await;
+
+    let client = Client::default();
+
+    let requests = addresses.into_iter().map(|address| {
+        let request = client
+            .request_from(address + prepare_path(&req).as_str(), req.head())
+            .send();
+        async move {
+            request
+                .await
+                .map_err(actix_web::error::ErrorGone)?
+                .json::<SizesResponse>()
+                .await
+                .map_err(actix_web::error::ErrorGone)
+        }
+    });
+
+    let mut result = SizesResponse {
+        success: false,
+        queue: Default::default(),
+        keys: Default::default(),
+        metadata: Default::default(),
+    };
+    for response in futures::future::join_all(requests).await {
+        let response = response.map_err(|e| {
+            error!(queue = %info.0, error = %e, "sizes proxy error");
+            e
+        })?;
+        if !response.success {
+            continue;
+        }
+
+        result.success = true;
+        result.queue.entries += response.queue.entries;
+        result.queue.bytes += response.queue.bytes;
+        result.keys.extend(response.keys);
+        result.metadata = response.metadata;
+    }
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Lists queues of all shards which the caller may subscribe to, shards check the token.
+/// Queue heads are local to shards, the latest one is listed with metadata of any shard
+async fn list_queues(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+) -> Result<HttpResponse, Error> {
+    let addresses = get_all_addresses(registry.get_ref()).await;
+
+    let client = Client::default();
+
+    let requests = addresses.into_iter().map(|address| {
+        let request = client
+            .request_from(address + prepare_path(&req).as_str(), req.head())
+            .send();
+        async move {
+            let mut response = request.await.map_err(actix_web::error::ErrorGone)?;
+            if response.status() == StatusCode::UNAUTHORIZED {
+                return Err(actix_web::error::ErrorUnauthorized("Token is not valid"));
+            }
+            response
+                .json::<QueuesResponse>()
+                .await
+                .map_err(actix_web::error::ErrorGone)
+        }
+    });
+
+    let mut queues: BTreeMap<String, (Option<QueueHead>, QueueMetadata)> = BTreeMap::new();
+    for response in futures::future::join_all(requests).await {
+        let response = response.map_err(|e| {
+            error!(error = %e, "queues proxy error");
+            e
+        })?;
+
+        for listing in response.queues {
+            let (head, metadata) = queues.entry(listing.name).or_default();
+            let latest = head.as_ref().map(|h| h.timestamp);
+            if listing.head.as_ref().map(|h| h.timestamp) > latest {
+                *head = listing.head;
+            }
+            if *metadata == QueueMetadata::default() {
+                *metadata = listing.metadata;
+            }
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(QueuesResponse {
+        success: true,
+        queues: queues
+            .into_iter()
+            .map(|(name, (head, metadata))| QueueListing {
+                name,
+                head,
+                metadata,
+            })
+            .collect(),
+    }))
+}
+
+/// Lists subscribers of all shards
+#[instrument(skip_all, fields(queue = %info.0))]
+async fn subscribers(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    let addresses = get_all_addresses(registry.get_ref()).await;
+
+    let client = Client::default();
+
+    let requests = addresses.into_iter().map(|address| {
+        let request = client
+            .request_from(address + prepare_path(&req).as_str(), req.head())
+            .send();
+        async move {
+            request
+                .await
+                .map_err(actix_web::error::ErrorGone)?
+                .json::<SubscribersResponse>()
+                .await
+                .map_err(actix_web::error::ErrorGone)
+        }
+    });
+
+    let mut result = SubscribersResponse {
+        success: false,
+        subscribers: vec![],
+    };
+    for response in futures::future::join_all(requests).await {
+        let response = response.map_err(|e| {
+            error!(queue = %info.0, error = %e, "subscribers proxy error");
+            e
+        })?;
+        result.success |= response.success;
+        result.subscribers.extend(response.subscribers);
+    }
+    result.subscribers.sort_by_key(|s| s.connected);
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Connection ids are unique over shards, so the connection is looked for on every shard
+async fn disconnect_subscriber(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    info: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    let addresses = get_all_addresses(registry.get_ref()).await;
+
+    let client = Client::default();
+
+    let requests = addresses.into_iter().map(|address| {
+        let request = client
+            .request_from(address + prepare_path(&req).as_str(), req.head())
+            .send();
+        async move {
+            request
+                .await
+                .map_err(actix_web::error::ErrorGone)?
+                .json::<BaseQueueResponse>()
+                .await
+                .map_err(actix_web::error::ErrorGone)
+        }
+    });
+
+    let mut success = false;
+    for response in futures::future::join_all(requests).await {
+        let response = response.map_err(|e| {
+            error!(connection_id = %info.0, error = %e, "disconnect subscriber proxy error");
+            e
+        })?;
+        success |= response.success;
+    }
+
+    Ok(HttpResponse::Ok().json(BaseQueueResponse { success }))
+}
+
+/// Rebalancing methods of proxies, shards don't know the shard map
+fn rebalance_methods_factory(secure: &Option<Secure>) -> impl HttpServiceFactory {
+    let guarded = |route: Route| match secure {
+        None => route,
+        Some(s) => route.guard(service_token_guard(s)),
+    };
+    web::resource("/rebalance/{queue_name}")
+        .route(guarded(web::post().to(start_rebalance)))
+        .route(guarded(web::get().to(rebalance_progress)))
+}
+
+/// Starts moving keys of the queue to the shards which own them, the progress is polled
+#[instrument(skip_all, fields(queue = %info.0))]
+async fn start_rebalance(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    rebalances: web::Data<Rebalances>,
+    info: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    let queue_name = info.into_inner().0;
+    if !rebalances.start(&queue_name) {
+        return Ok(HttpResponse::Ok().json(BaseQueueResponse { success: false }));
+    }
+
+    info!("starting rebalancing");
+    // shards are requested with the token of the service which started the rebalancing
+    let authorization = req.headers().get(AUTHORIZATION).cloned();
+    actix::spawn(async move {
+        rebalance(&rebalances, &registry, queue_name, authorization).await;
+    });
+    Ok(HttpResponse::Accepted().json(BaseQueueResponse { success: true }))
+}
+
+async fn rebalance_progress(
+    rebalances: web::Data<Rebalances>,
+    info: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    let progress = rebalances.progress(&info.into_inner().0);
+    Ok(HttpResponse::Ok().json(RebalanceResponse {
+        success: progress.is_some(),
+        progress,
+    }))
+}
+
+async fn base_key_proxy(
+    req: HttpRequest,
+    registry: web::Data<Addr<RegistryActor>>,
+    queue_name: String,
+    id: String,
+) -> impl Responder {
+    let address = get_address(registry.get_ref(), queue_name, id).await;
+
+    let client = Client::default();
+
+    let response = client
+        .request_from(address.clone() + prepare_path(&req).as_str(), req.head())
+        .send()
+        .await;
+
+    match response {
+        Ok(r) => {
+            let mut back_rsp = HttpResponse::build(r.status());
+            for (key, value) in r.headers() {
+                back_rsp.insert_header((key.clone(), value.clone()));
+            }
+
+            Ok(back_rsp.streaming(r.into_stream()))
+        }
+        Err(e) => {
+            error!(shard = %address, error = ?e, "queue key proxy error");
+            Err(actix_web::error::ErrorGone(
+                "One of shards is not responding",
+            ))
+        }
+    }
+}
+
 async fn base_diagonal_proxy(
     req: HttpRequest,
     registry: web::Data<Addr<RegistryActor>>,
@@ -347,7 +1161,7 @@ async fn base_diagonal_proxy(
     match result {
         Ok(_) => Ok(HttpResponse::Ok().json(BaseQueueResponse { success: true })),
         Err(e) => {
-            error!("queue proxy error: {:#?}", e);
+            error!(error = ?e, "queue proxy error");
             Err(actix_web::error::ErrorGone(
                 "One of shards is not responding",
             ))
@@ -367,13 +1181,33 @@ fn prepare_path(req: &HttpRequest) -> String {
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let config = get_config();
+    if let Err(e) = config.validate() {
+        e.exit()
+    }
     let shared_config = web::Data::new(config.clone());
+    let server_info = ServerResponse::new(ServerRole::Proxy, env!("CARGO_PKG_VERSION"), &config)
+        .with_features([
+            "websocket",
+            "longpoll",
+            "replay",
+            "head",
+            "leases",
+            "ephemeral",
+            "spec",
+            "send_payload",
+            "send_batch",
+            "broadcast_metrics",
+            "subscriber_labels",
+            "rebalance",
+            "acks",
+        ]);
 
     let address = config
         .addr
         .unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8081));
 
     let secure = config.secure;
+    let cors = config.cors;
 
     let registry = web::Data::new(RegistryActor::new(
         match &config.service_discovery {
@@ -418,6 +1252,9 @@ async fn main() -> std::io::Result<()> {
     };
 
     let web_socket_proxies = web::Data::new(WebSocketProxyClientsStorage::default());
+    let rebalances = web::Data::new(Rebalances::default());
+    let limiter = web::Data::new(ConnectionLimiter::new(config.limits.clone()));
+    let transcoder = web::Data::new(Transcoder::default());
     let wsp = web_socket_proxies.clone();
 
     let garbage_interval = config.garbage_collector.interval;
@@ -433,21 +1270,60 @@ async fn main() -> std::io::Result<()> {
 
     let server = HttpServer::new(move || {
         let mut app = App::new()
-            .wrap(Logger::default())
+            .wrap(Condition::new(
+                cors.is_some(),
+                get_cors_from_config(cors.clone().unwrap_or_default()),
+            ))
+            .wrap(TracingLogger::default())
             .app_data(registry.clone())
             .app_data(service_discovery.clone())
             .app_data(web_socket_proxies.clone())
             .app_data(shared_config.clone())
+            .app_data(limiter.clone())
+            .app_data(transcoder.clone())
+            .app_data(rebalances.clone())
             .service(queue_scope_factory!(
                 create_queue,
-                delete_from_queue,
+                set_queue_metadata,
+                delete_key_history,
+                revoke_event,
+                acquire_lease,
+                release_lease,
                 send_to_queue,
-                close_queue,
+                send_payload_to_queue,
+                send_batch_to_queue,
+                ack_event,
+                drop_queue,
+                clear_queue,
+                count_key,
+                peek_key,
+                replay_key,
+                head_key,
                 subscribe_queue_by_id_ws,
                 subscribe_queue_by_id_longpoll,
                 subscribe_queue_ws,
                 subscribe_queue_longpoll,
+                subscribe_queue_by_id_ndjson,
+                subscribe_queue_ndjson,
                 &secure,
+            ))
+            .service(
+                admin_scope_factory!(
+                    sequence_gaps,
+                    message_trace,
+                    broadcasts,
+                    sizes,
+                    subscribers,
+                    disconnect_subscriber,
+                    &secure,
+                    server_info.clone(),
+                )
+                .service(rebalance_methods_factory(&secure)),
+            )
+            .route("/queues", web::get().to(list_queues))
+            .service(spec_scope_factory(
+                env!("CARGO_PKG_VERSION"),
+                secure.is_some(),
             ));
 
         #[cfg(feature = "api")]
@@ -466,6 +1342,8 @@ async fn main() -> std::io::Result<()> {
         app
     });
 
+    let server = configure_server!(server, &config.server);
+
     let result = futures::future::select(rx, {
         match config.tls {
             None => server.bind(address)?,
@@ -0,0 +1,343 @@
+use crate::registry::{get_all_addresses, get_owner, pin_key, RegistryActor};
+use actix::Addr;
+use actix_web::http::header::{HeaderValue, AUTHORIZATION};
+use actix_web::http::{Method, StatusCode};
+use awc::error::{JsonPayloadError, SendRequestError};
+use awc::{Client, ClientRequest};
+use derive_more::{Display, From};
+use parking_lot::Mutex;
+use percent_encoding::{utf8_percent_encode, PercentEncode, NON_ALPHANUMERIC};
+use serde::de::DeserializeOwned;
+use sonya_meta::message::EventMessage;
+use sonya_meta::response::{
+    DigestResponse, RangeDigest, RangeDigestResponse, RebalanceProgress, RebalanceState,
+    ReplayResponse,
+};
+use std::collections::HashMap;
+use std::time::SystemTime;
+use tracing::{error, info, warn};
+
+/// Limit of digest and replay responses of shards, digests of large queues exceed defaults
+const SHARD_PAYLOAD_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Events copied by one replay request
+const COPY_PAGE: usize = 500;
+
+/// Copies of the key repeated while checksums of the owner differ, e.g. because of new writes
+const VERIFY_ATTEMPTS: usize = 3;
+
+/// Errors kept in the progress, the rest are only logged
+const MAX_ERRORS: usize = 100;
+
+/// Rebalancing progress of queues, one rebalancing of the queue runs at once
+#[derive(Default)]
+pub struct Rebalances {
+    jobs: Mutex<HashMap<String, RebalanceProgress>>,
+}
+
+impl Rebalances {
+    /// Registers the rebalancing, returns `false` if the queue is being rebalanced already
+    pub fn start(&self, queue_name: &str) -> bool {
+        let mut jobs = self.jobs.lock();
+        if matches!(jobs.get(queue_name), Some(p) if p.state == RebalanceState::Running) {
+            return false;
+        }
+
+        let started = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        jobs.insert(
+            queue_name.to_string(),
+            RebalanceProgress {
+                started,
+                ..Default::default()
+            },
+        );
+        true
+    }
+
+    pub fn progress(&self, queue_name: &str) -> Option<RebalanceProgress> {
+        self.jobs.lock().get(queue_name).cloned()
+    }
+
+    fn update(&self, queue_name: &str, f: impl FnOnce(&mut RebalanceProgress)) {
+        if let Some(progress) = self.jobs.lock().get_mut(queue_name) {
+            f(progress)
+        }
+    }
+}
+
+#[derive(Debug, Display, From)]
+enum RebalanceError {
+    Request(SendRequestError),
+    Payload(JsonPayloadError),
+    #[display(fmt = "shard responded with {}", _0)]
+    Status(StatusCode),
+    #[display(fmt = "checksums of {} differ after {} copies", _0, _1)]
+    #[from(ignore)]
+    Diverged(String, usize),
+}
+
+/// Key stored by the shard which doesn't own it
+struct Migration {
+    id: String,
+    source: String,
+    target: String,
+}
+
+/// Moves keys of the queue to the shards which own them by the current shard list.
+/// Every key is pinned to its source while it's copied and verified,
+/// then routed to the owner, and deleted from the source.
+/// Keys which failed before the switch stay pinned to their sources.
+pub async fn rebalance(
+    rebalances: &Rebalances,
+    registry: &Addr<RegistryActor>,
+    queue_name: String,
+    authorization: Option<HeaderValue>,
+) {
+    let shards = Shards {
+        client: Client::default(),
+        authorization,
+        queue_name: queue_name.clone(),
+    };
+
+    let migrations = match plan(&shards, registry).await {
+        Ok(migrations) => migrations,
+        Err(e) => {
+            error!(queue = %queue_name, error = %e, "rebalance planning error");
+            rebalances.update(&queue_name, |p| {
+                p.state = RebalanceState::Failed;
+                p.errors.push(e.to_string());
+            });
+            return;
+        }
+    };
+
+    info!(queue = %queue_name, keys = migrations.len(), "rebalancing queue");
+    rebalances.update(&queue_name, |p| p.keys = migrations.len());
+    for m in &migrations {
+        let shard = Some(m.source.clone());
+        pin_key(registry, queue_name.clone(), m.id.clone(), shard).await;
+    }
+
+    for m in migrations {
+        // the next rebalancing finds failed keys again, they are planned by owners regardless of pins
+        match migrate(&shards, registry, &m).await {
+            Ok(entries) => rebalances.update(&queue_name, |p| {
+                p.moved += 1;
+                p.entries += entries;
+            }),
+            Err(e) => {
+                warn!(queue = %queue_name, key = %m.id, error = %e, "moving key error");
+                rebalances.update(&queue_name, |p| {
+                    p.failed += 1;
+                    if p.errors.len() < MAX_ERRORS {
+                        p.errors.push(format!("{}: {}", m.id, e));
+                    }
+                });
+            }
+        }
+    }
+
+    info!(queue = %queue_name, "rebalancing finished");
+    rebalances.update(&queue_name, |p| p.state = RebalanceState::Finished);
+}
+
+/// Finds keys stored by shards which don't own them
+async fn plan(
+    shards: &Shards,
+    registry: &Addr<RegistryActor>,
+) -> Result<Vec<Migration>, RebalanceError> {
+    let mut migrations = Vec::new();
+    for source in get_all_addresses(registry).await {
+        let digest: DigestResponse = shards
+            .get(
+                &source,
+                format!("/admin/digest/{}", encode(&shards.queue_name)),
+            )
+            .await?;
+        for id in digest.keys.into_keys() {
+            let target = get_owner(registry, shards.queue_name.clone(), id.clone()).await;
+            if target != source {
+                migrations.push(Migration {
+                    id,
+                    source: source.clone(),
+                    target,
+                });
+            }
+        }
+    }
+
+    Ok(migrations)
+}
+
+/// Copies the key to the owner until checksums match, switches it and deletes it from the source.
+/// Subscribers of the source are moved to the owner after the key is deleted.
+/// Returns count of copied entries.
+async fn migrate(
+    shards: &Shards,
+    registry: &Addr<RegistryActor>,
+    m: &Migration,
+) -> Result<usize, RebalanceError> {
+    shards
+        .post(
+            &m.target,
+            format!("/queue/create/{}", encode(&shards.queue_name)),
+        )
+        .await?;
+
+    let mut entries = copy_diverged(shards, m).await?;
+
+    pin_key(registry, shards.queue_name.clone(), m.id.clone(), None).await;
+    // writes which reached the source before the switch
+    entries += copy_diverged(shards, m).await?;
+
+    shards
+        .post(
+            &m.source,
+            format!(
+                "/admin/move/{}/{}?to={}",
+                encode(&shards.queue_name),
+                encode(&m.id),
+                encode(&m.target)
+            ),
+        )
+        .await?;
+
+    Ok(entries)
+}
+
+/// Copies ranges of the key which differ on the owner, until they match.
+/// Entries published to the owner after the source head are not compared.
+async fn copy_diverged(shards: &Shards, m: &Migration) -> Result<usize, RebalanceError> {
+    let path = format!(
+        "/admin/digest/{}/{}",
+        encode(&shards.queue_name),
+        encode(&m.id)
+    );
+
+    let mut entries = 0;
+    for _ in 0..VERIFY_ATTEMPTS {
+        let source: RangeDigestResponse = shards.get(&m.source, path.clone()).await?;
+        let target: RangeDigestResponse = shards
+            .get(&m.target, format!("{}?to={}", path, source.head))
+            .await?;
+        let target: HashMap<u64, RangeDigest> =
+            target.ranges.into_iter().map(|r| (r.from, r)).collect();
+
+        let diverged: Vec<_> = source
+            .ranges
+            .into_iter()
+            .filter(|r| target.get(&r.from) != Some(r))
+            .collect();
+        if diverged.is_empty() {
+            return Ok(entries);
+        }
+
+        for range in diverged {
+            entries += copy_range(shards, m, &range).await?;
+        }
+    }
+
+    Err(RebalanceError::Diverged(m.id.clone(), VERIFY_ATTEMPTS))
+}
+
+/// Replays the range from the source and publishes its events to the owner with their sequences
+async fn copy_range(
+    shards: &Shards,
+    m: &Migration,
+    range: &RangeDigest,
+) -> Result<usize, RebalanceError> {
+    let mut entries = 0;
+    let mut from = Some(range.from);
+    while let Some(sequence) = from.filter(|s| *s <= range.to) {
+        let page: ReplayResponse<EventMessage> = shards
+            .get(
+                &m.source,
+                format!(
+                    "/queue/replay/{}/{}?from={}&limit={}",
+                    encode(&shards.queue_name),
+                    encode(&m.id),
+                    sequence,
+                    COPY_PAGE
+                ),
+            )
+            .await?;
+        from = page.next.map(|s| s.get());
+
+        for event in page.events {
+            if !matches!(event.sequence, Some(s) if s.get() <= range.to) {
+                continue;
+            }
+            shards
+                .send(
+                    &m.target,
+                    format!("/queue/send/{}", encode(&shards.queue_name)),
+                    &event,
+                )
+                .await?;
+            entries += 1;
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Encodes queue names, keys and addresses, so they stay one path segment or query value
+fn encode(value: &str) -> PercentEncode<'_> {
+    utf8_percent_encode(value, NON_ALPHANUMERIC)
+}
+
+/// Requests to shards on behalf of the service which started the rebalancing
+struct Shards {
+    client: Client,
+    authorization: Option<HeaderValue>,
+    queue_name: String,
+}
+
+impl Shards {
+    fn request(&self, method: Method, shard: &str, path: String) -> ClientRequest {
+        let request = self
+            .client
+            .request(method, shard.to_string() + path.as_str());
+        match &self.authorization {
+            None => request,
+            Some(authorization) => request.insert_header((AUTHORIZATION, authorization.clone())),
+        }
+    }
+
+    async fn get<R: DeserializeOwned>(
+        &self,
+        shard: &str,
+        path: String,
+    ) -> Result<R, RebalanceError> {
+        let mut response = self.request(Method::GET, shard, path).send().await?;
+        if !response.status().is_success() {
+            return Err(RebalanceError::Status(response.status()));
+        }
+        Ok(response.json().limit(SHARD_PAYLOAD_LIMIT).await?)
+    }
+
+    async fn post(&self, shard: &str, path: String) -> Result<(), RebalanceError> {
+        let response = self.request(Method::POST, shard, path).send().await?;
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(RebalanceError::Status(response.status())),
+        }
+    }
+
+    async fn send(
+        &self,
+        shard: &str,
+        path: String,
+        event: &EventMessage,
+    ) -> Result<(), RebalanceError> {
+        let request = self.request(Method::POST, shard, path);
+        let response = request.send_json(event).await?;
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(RebalanceError::Status(response.status())),
+        }
+    }
+}
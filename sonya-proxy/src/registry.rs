@@ -1,18 +1,22 @@
 use actix::prelude::*;
-use log::{error, info};
 use maglev::{ConsistentHasher, Maglev};
 use parking_lot::RwLock;
 use sonya_meta::config::Shards;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::hash::{BuildHasherDefault, Hash};
 use std::sync::Arc;
+use tracing::{error, info};
 
 type RegistryStore = Arc<RwLock<RegistryConsistentList>>;
+/// Keys routed to their previous shards until rebalancing moves them
+type PinsStore = Arc<RwLock<HashMap<(String, String), String>>>;
 pub type RegistryList = Shards;
 pub type RegistryConsistentList = Maglev<String, BuildHasherDefault<DefaultHasher>>;
 
 pub struct RegistryActor {
     registry: RegistryStore,
+    pins: PinsStore,
 }
 
 impl Actor for RegistryActor {
@@ -23,28 +27,48 @@ impl RegistryActor {
     pub fn new(registry: RegistryList) -> Addr<Self> {
         let list: RegistryConsistentList = RegistryConsistentList::new(registry);
         let registry = RegistryStore::new(RwLock::new(list));
+        let pins = PinsStore::default();
         SyncArbiter::start(num_cpus::get(), move || Self {
             registry: registry.clone(),
+            pins: pins.clone(),
         })
     }
+
+    /// Shard which owns the key by the consistent hashing
+    fn owner(&self, key: &GetAddress) -> Option<String> {
+        let registry = self.registry.read();
+        if registry.nodes().is_empty() {
+            error!("no one queue service is not registered in service discovery");
+            return None;
+        }
+
+        let shard = registry.get(key).cloned();
+        info!(queue = %key.0, key = %key.1, shard = ?shard, "chosen new shard");
+        shard
+    }
 }
 
 impl Handler<GetAddress> for RegistryActor {
     type Result = MessageResult<GetAddress>;
 
     fn handle(&mut self, msg: GetAddress, _ctx: &mut Self::Context) -> Self::Result {
-        let registry = self.registry.read();
-        if registry.nodes().is_empty() {
-            error!("no one queue service is not registered in service discovery");
-            return MessageResult(None);
+        if let Some(shard) = self.pins.read().get(&(msg.0.clone(), msg.1.clone())) {
+            return MessageResult(Some(shard.clone()));
         }
 
-        let shard = registry.get(&msg).cloned();
-        info!(
-            "chosen new shard for queue: {} and id: {}, shard: {:#?}",
-            msg.0, msg.1, shard
-        );
-        MessageResult(shard)
+        MessageResult(self.owner(&msg))
+    }
+}
+
+impl Handler<GetOwner> for RegistryActor {
+    type Result = MessageResult<GetOwner>;
+
+    fn handle(
+        &mut self,
+        GetOwner(queue_name, id): GetOwner,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        MessageResult(self.owner(&GetAddress(queue_name, id)))
     }
 }
 
@@ -72,20 +96,34 @@ impl Handler<UpdateRegistry> for RegistryActor {
     ) -> Self::Result {
         let mut registry = self.registry.write();
         let n = new_registry.len();
-        info!(
-            "updated registry, old: {:#?}, new: {:#?}",
-            registry.nodes(),
-            new_registry
-        );
+        info!(old = ?registry.nodes(), new = ?new_registry, "updated registry");
         *registry = Maglev::with_capacity(new_registry, registry.capacity().max(n));
         MessageResult(())
     }
 }
 
+impl Handler<PinKey> for RegistryActor {
+    type Result = MessageResult<PinKey>;
+
+    fn handle(&mut self, msg: PinKey, _ctx: &mut Self::Context) -> Self::Result {
+        let mut pins = self.pins.write();
+        match msg.2 {
+            Some(shard) => pins.insert((msg.0, msg.1), shard),
+            None => pins.remove(&(msg.0, msg.1)),
+        };
+        MessageResult(())
+    }
+}
+
 #[derive(Message, Hash, Debug, Eq, PartialEq)]
 #[rtype(result = "Option<String>")]
 pub struct GetAddress(String, String);
 
+/// Owner of the key, pins of keys which are being moved are ignored
+#[derive(Message)]
+#[rtype(result = "Option<String>")]
+pub struct GetOwner(String, String);
+
 #[derive(Message, Hash)]
 #[rtype(result = "Option<RegistryList>")]
 pub struct GetAllAddresses;
@@ -98,6 +136,26 @@ pub async fn get_address(registry: &Addr<RegistryActor>, queue_name: String, id:
         .expect("registry empty")
 }
 
+pub async fn get_owner(registry: &Addr<RegistryActor>, queue_name: String, id: String) -> String {
+    registry
+        .send(GetOwner(queue_name, id))
+        .await
+        .expect("registry failed")
+        .expect("registry empty")
+}
+
+pub async fn pin_key(
+    registry: &Addr<RegistryActor>,
+    queue_name: String,
+    id: String,
+    shard: Option<String>,
+) {
+    registry
+        .send(PinKey(queue_name, id, shard))
+        .await
+        .expect("registry failed")
+}
+
 pub async fn get_all_addresses(registry: &Addr<RegistryActor>) -> RegistryList {
     registry
         .send(GetAllAddresses)
@@ -109,3 +167,8 @@ pub async fn get_all_addresses(registry: &Addr<RegistryActor>) -> RegistryList {
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct UpdateRegistry(pub RegistryList);
+
+/// Routes the key to the shard, `None` returns it to the consistent hashing
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PinKey(pub String, pub String, pub Option<String>);
@@ -1,7 +1,7 @@
 use crate::service_discovery::ServiceDiscoveryStreamFactory;
 use etcd_client::*;
-use log::error;
 use std::collections::HashMap;
+use tracing::error;
 
 pub fn factory(uris: Vec<String>, prefix: String) -> ServiceDiscoveryStreamFactory {
     Box::new(move || {
@@ -15,7 +15,7 @@ pub fn factory(uris: Vec<String>, prefix: String) -> ServiceDiscoveryStreamFacto
             .await {
                 Ok(c) => c,
                 Err(e) => {
-                    error!("connection error {}", e);
+                    error!(error = %e, "connection error");
                     return
                 }
             };
@@ -25,7 +25,7 @@ pub fn factory(uris: Vec<String>, prefix: String) -> ServiceDiscoveryStreamFacto
                 .await {
                 Ok(c) => c,
                 Err(e) => {
-                    error!("getting list error {}", e);
+                    error!(error = %e, "getting list error");
                     return
                 }
             };
@@ -46,7 +46,7 @@ pub fn factory(uris: Vec<String>, prefix: String) -> ServiceDiscoveryStreamFacto
                 .await {
                 Ok(c) => c,
                 Err(e) => {
-                    error!("watching error {}", e);
+                    error!(error = %e, "watching error");
                     return
                 }
             };
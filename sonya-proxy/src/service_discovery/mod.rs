@@ -7,7 +7,6 @@ use crate::registry::{RegistryActor, RegistryList, UpdateRegistry};
 use actix::prelude::*;
 use futures::stream::BoxStream;
 use futures::{Future, StreamExt};
-use log::{error, info};
 use parking_lot::Mutex;
 use pin_project_lite::pin_project;
 use sonya_meta::api::{sleep_between_reconnects, MAX_RECONNECT_ATTEMPTS};
@@ -15,6 +14,7 @@ use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::task::{Poll, Waker};
+use tracing::{error, info};
 
 pub type ServiceDiscoveryStreamFactory = Box<dyn Fn() -> BoxStream<'static, RegistryList>>;
 
@@ -57,17 +57,11 @@ impl StreamHandler<UpdateRegistry> for ServiceDiscoveryActor {
     }
 
     fn started(&mut self, _ctx: &mut Self::Context) {
-        info!(
-            "service discovery stream started attempt: {}",
-            self.attempts
-        )
+        info!(attempt = self.attempts, "service discovery stream started")
     }
 
     fn finished(&mut self, ctx: &mut Self::Context) {
-        error!(
-            "service discovery stream closed, attempt: {}",
-            self.attempts
-        );
+        error!(attempt = self.attempts, "service discovery stream closed");
         if self.attempts == MAX_RECONNECT_ATTEMPTS {
             return;
         }
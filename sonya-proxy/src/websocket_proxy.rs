@@ -2,20 +2,31 @@ use crate::websocket_proxy_client::WebSocketActorResponse;
 use actix::prelude::*;
 use actix_web_actors::ws;
 use actix_web_actors::ws::{CloseCode, CloseReason, Frame};
-use log::{info, warn};
+use sonya_meta::close::QueueCloseReason;
+use sonya_meta::encoding::{DeliveryFormat, Transcoder};
+use sonya_meta::limit::ConnectionGuard;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::sync::broadcast;
+use tracing::{info, warn, Span};
+
+/// Prefix of serialized `moved` control events, the tag is always serialized first
+const MOVED_CONTROL: &[u8] = br#"{"control":"moved""#;
 
 pub struct WebSocketProxyActor {
     receiver: Option<broadcast::Receiver<WebSocketActorResponse>>,
     ip: SocketAddr,
+    _connection_guard: ConnectionGuard,
+    format: DeliveryFormat,
+    transcoder: Arc<Transcoder>,
+    span: Span,
 }
 
 impl Actor for WebSocketProxyActor {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        info!("new client {} connected", self.ip);
+        info!(parent: &self.span, ip = %self.ip, "new client connected");
         match self.receiver.take() {
             Some(mut receiver) => {
                 let stream = async_stream::stream! {
@@ -26,7 +37,7 @@ impl Actor for WebSocketProxyActor {
                 ctx.add_stream(stream);
             }
             None => {
-                warn!("client {} was aborted, empty receiver", self.ip);
+                warn!(parent: &self.span, ip = %self.ip, "client was aborted, empty receiver");
                 ctx.close(Some(CloseReason::from(CloseCode::Error)));
                 ctx.stop()
             }
@@ -38,8 +49,41 @@ impl WebSocketProxyActor {
     pub fn new(
         receiver: Option<broadcast::Receiver<WebSocketActorResponse>>,
         ip: SocketAddr,
+        connection_guard: ConnectionGuard,
+        span: Span,
     ) -> Self {
-        Self { receiver, ip }
+        Self {
+            receiver,
+            ip,
+            _connection_guard: connection_guard,
+            format: DeliveryFormat::Json,
+            transcoder: Default::default(),
+            span,
+        }
+    }
+
+    /// Transcodes json events of shards to the format negotiated with the client
+    pub fn with_format(mut self, format: DeliveryFormat, transcoder: Arc<Transcoder>) -> Self {
+        self.format = format;
+        self.transcoder = transcoder;
+        self
+    }
+
+    fn send(&self, text: String, ctx: &mut <Self as Actor>::Context) {
+        if self.format == DeliveryFormat::Json {
+            return ctx.text(text);
+        }
+
+        match self.transcoder.transcode(&text, self.format) {
+            Ok(encoded) => ctx.binary(encoded),
+            Err(e) => warn!(
+                parent: &self.span,
+                ip = %self.ip,
+                error = %e,
+                format = %self.format,
+                "transcoding error"
+            ),
+        }
     }
 }
 
@@ -47,28 +91,44 @@ impl StreamHandler<WebSocketActorResponse> for WebSocketProxyActor {
     fn handle(&mut self, response: WebSocketActorResponse, ctx: &mut Self::Context) {
         match response {
             WebSocketActorResponse::Message(f) => match f.as_ref() {
-                Frame::Text(b) => ctx.text(
-                    std::str::from_utf8(b)
+                // shards moved after failover are found again with service discovery,
+                // so proxy clients must not follow them
+                Frame::Text(b) if b.starts_with(MOVED_CONTROL) => {}
+                Frame::Text(b) => {
+                    let text = std::str::from_utf8(b)
                         .map(|r| r.to_string())
                         .unwrap_or_else(|e| {
                             warn!(
-                                "invalid utf message sent from queue, error: {}, client ip: {}",
-                                e, self.ip
+                                parent: &self.span,
+                                ip = %self.ip,
+                                error = %e,
+                                "invalid utf message sent from queue"
                             );
                             e.to_string()
-                        }),
-                ),
+                        });
+                    self.send(text, ctx)
+                }
                 Frame::Binary(b) => ctx.binary(b.clone()),
                 Frame::Continuation(_) => {}
                 Frame::Ping(p) => ctx.ping(p),
                 Frame::Pong(p) => ctx.pong(p),
                 Frame::Close(r) => {
-                    ctx.close(r.clone());
+                    let moved = QueueCloseReason::Moved.code();
+                    match r {
+                        Some(r) if u16::from(r.code) == moved => {
+                            let reason = QueueCloseReason::Draining;
+                            ctx.close(Some(CloseReason {
+                                code: CloseCode::from(reason.code()),
+                                description: Some(reason.to_string()),
+                            }))
+                        }
+                        r => ctx.close(r.clone()),
+                    }
                     ctx.stop()
                 }
             },
             WebSocketActorResponse::Stopped => {
-                warn!("closed connection {}", self.ip);
+                warn!(parent: &self.span, ip = %self.ip, "closed connection");
                 ctx.close(Some(CloseReason::from(CloseCode::Error)));
                 ctx.stop()
             }
@@ -84,6 +144,14 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketProxyAct
                 ctx.close(reason);
                 ctx.stop();
             }
+            Err(ws::ProtocolError::Overflow) => {
+                let reason = QueueCloseReason::MessageTooLarge;
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::from(reason.code()),
+                    description: Some(reason.to_string()),
+                }));
+                ctx.stop();
+            }
             Err(_) => {
                 ctx.close(Some(CloseReason::from(CloseCode::Error)));
                 ctx.stop();
@@ -9,18 +9,20 @@ use awc::{
 };
 use derive_more::{Display, Error, From};
 use futures::StreamExt;
-use log::{error, info};
 use sonya_meta::api::{sleep_between_reconnects, MAX_RECONNECT_ATTEMPTS};
 use sonya_meta::message::RequestSequence;
 use std::{collections::HashMap, pin::Pin, sync::Arc, task::Poll, time::Duration};
 use tokio::sync::{broadcast, RwLock};
+use tracing::{error, info};
 
 #[derive(Debug, Clone, Hash, PartialOrd, PartialEq, Eq)]
-pub struct WebSocketProxyClientsStorageKey(String, Option<String>);
+pub struct WebSocketProxyClientsStorageKey(String, Option<String>, Option<String>);
 
 impl WebSocketProxyClientsStorageKey {
-    pub fn new(queue: String, id: Option<String>) -> Self {
-        Self(queue, id)
+    /// Connections are shared between subscribers with the same identity only,
+    /// because shards filter events by it
+    pub fn new(queue: String, id: Option<String>, identity: Option<String>) -> Self {
+        Self(queue, id, identity)
     }
 }
 
@@ -37,7 +39,7 @@ impl WebSocketProxyClientsStorage {
         registry: Addr<RegistryActor>,
         service_discovery: Addr<ServiceDiscoveryActor>,
         garbage_interval: u64,
-        access_token: Option<String>,
+        forward_query: Option<String>,
         sequence: RequestSequence,
     ) -> Addr<WebSocketProxyClient> {
         if sequence.is_none() {
@@ -50,7 +52,7 @@ impl WebSocketProxyClientsStorage {
                 Some(a) => a,
                 None => {
                     let mut guard = self.0.write().await;
-                    let WebSocketProxyClientsStorageKey(queue_name, id) = key.clone();
+                    let WebSocketProxyClientsStorageKey(queue_name, id, _) = key.clone();
                     let addr = WebSocketProxyClient::new(
                         headers,
                         registry,
@@ -58,7 +60,7 @@ impl WebSocketProxyClientsStorage {
                         queue_name,
                         id,
                         garbage_interval,
-                        access_token,
+                        forward_query,
                         sequence,
                     );
 
@@ -68,7 +70,7 @@ impl WebSocketProxyClientsStorage {
                 }
             }
         } else {
-            let WebSocketProxyClientsStorageKey(queue_name, id) = key;
+            let WebSocketProxyClientsStorageKey(queue_name, id, _) = key;
 
             WebSocketProxyClient::new(
                 headers,
@@ -77,7 +79,7 @@ impl WebSocketProxyClientsStorage {
                 queue_name,
                 id,
                 garbage_interval,
-                access_token,
+                forward_query,
                 sequence,
             )
         }
@@ -90,7 +92,7 @@ impl WebSocketProxyClientsStorage {
         registry: Addr<RegistryActor>,
         service_discovery: Addr<ServiceDiscoveryActor>,
         garbage_interval: u64,
-        access_token: Option<String>,
+        forward_query: Option<String>,
         sequence: RequestSequence,
     ) -> Option<broadcast::Receiver<WebSocketActorResponse>> {
         let addr = self
@@ -100,7 +102,7 @@ impl WebSocketProxyClientsStorage {
                 registry,
                 service_discovery,
                 garbage_interval,
-                access_token,
+                forward_query,
                 sequence,
             )
             .await;
@@ -125,7 +127,7 @@ pub struct WebSocketProxyClient {
     garbage_interval: u64,
     attempts: u8,
     sender: broadcast::Sender<WebSocketActorResponse>,
-    access_token: Option<String>,
+    forward_query: Option<String>,
     sequence: RequestSequence,
 }
 
@@ -133,11 +135,7 @@ impl Actor for WebSocketProxyClient {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        info!(
-            "creating connection to queue {}, id {}",
-            self.queue_name,
-            self.id.clone().unwrap_or_else(|| String::from("None")),
-        );
+        info!(queue = %self.queue_name, key = ?self.id, "creating connection to queue");
         match &self.id {
             Some(_) => ctx.add_stream(self.add_stream()),
             None => ctx.add_stream(self.add_stream_all()),
@@ -163,17 +161,13 @@ impl Actor for WebSocketProxyClient {
     fn stopped(&mut self, _ctx: &mut Self::Context) {
         if self.attempts >= MAX_RECONNECT_ATTEMPTS {
             error!(
-                "close connection after {} attempts to reconnect queue {}, id {}",
-                self.attempts,
-                self.queue_name,
-                self.id.clone().unwrap_or_else(|| String::from("None")),
+                queue = %self.queue_name,
+                key = ?self.id,
+                attempts = self.attempts,
+                "close connection after attempts to reconnect queue"
             )
         } else {
-            info!(
-                "stopping connection to proxy, queue: {}, id: {}",
-                self.queue_name,
-                self.id.clone().unwrap_or_else(|| String::from("None"))
-            )
+            info!(queue = %self.queue_name, key = ?self.id, "stopping connection to proxy")
         }
     }
 }
@@ -189,11 +183,7 @@ impl Handler<Subscribe> for WebSocketProxyClient {
 impl StreamHandler<CloseEmptyConnection> for WebSocketProxyClient {
     fn handle(&mut self, _item: CloseEmptyConnection, ctx: &mut Self::Context) {
         if self.sender.receiver_count() == 0 {
-            info!(
-                "clearing unused connection queue: {}, id: {}",
-                self.queue_name,
-                self.id.clone().unwrap_or_else(|| String::from("None"))
-            );
+            info!(queue = %self.queue_name, key = ?self.id, "clearing unused connection");
             ctx.stop()
         }
     }
@@ -210,20 +200,20 @@ impl StreamHandler<WebSocketClientResponse> for WebSocketProxyClient {
                     .send(WebSocketActorResponse::Message(Arc::new(frame)))
                 {
                     error!(
-                        "message was not received, error {}, queue: {}, id: {}",
-                        e,
-                        self.queue_name,
-                        self.id.clone().unwrap_or_else(|| String::from("None")),
+                        queue = %self.queue_name,
+                        key = ?self.id,
+                        error = %e,
+                        "message was not received"
                     );
                     ctx.stop();
                 }
             }
             Err(e) => {
                 error!(
-                    "proxy error {}, queue: {}, id: {}",
-                    e,
-                    self.queue_name,
-                    self.id.clone().unwrap_or_else(|| String::from("None")),
+                    queue = %self.queue_name,
+                    key = ?self.id,
+                    error = %e,
+                    "proxy error"
                 );
                 ctx.stop();
             }
@@ -232,10 +222,10 @@ impl StreamHandler<WebSocketClientResponse> for WebSocketProxyClient {
 
     fn started(&mut self, _ctx: &mut Self::Context) {
         info!(
-            "connected to queue {}, id {}, attempt: {}",
-            self.queue_name,
-            self.id.clone().unwrap_or_else(|| String::from("None")),
-            self.attempts
+            queue = %self.queue_name,
+            key = ?self.id,
+            attempt = self.attempts,
+            "connected to queue"
         )
     }
 
@@ -256,7 +246,7 @@ impl WebSocketProxyClient {
         queue_name: String,
         id: Option<String>,
         garbage_interval: u64,
-        access_token: Option<String>,
+        forward_query: Option<String>,
         sequence: RequestSequence,
     ) -> Addr<Self> {
         Self {
@@ -268,7 +258,7 @@ impl WebSocketProxyClient {
             garbage_interval,
             attempts: 0,
             sender: broadcast::channel(8).0,
-            access_token,
+            forward_query,
             sequence,
         }
         .start()
@@ -283,13 +273,13 @@ impl WebSocketProxyClient {
         let attempt = self.attempts;
         let client = Client::default();
 
-        let path = match (self.access_token.as_ref(), self.sequence) {
+        let path = match (self.forward_query.as_ref(), self.sequence) {
             (Some(at), Some(s)) => format!(
-                "/queue/listen/ws/{}/{}?access_token={}&sequence={}",
+                "/queue/listen/ws/{}/{}?{}&sequence={}",
                 queue_name, id, at, s
             ),
             (Some(at), None) => {
-                format!("/queue/listen/ws/{}/{}?access_token={}", queue_name, id, at)
+                format!("/queue/listen/ws/{}/{}?{}", queue_name, id, at)
             }
             (None, Some(s)) => format!("/queue/listen/ws/{}/{}?&sequence={}", queue_name, id, s),
             (None, None) => format!("/queue/listen/ws/{}/{}", queue_name, id),
@@ -323,13 +313,12 @@ impl WebSocketProxyClient {
         let attempt = self.attempts;
         let client = Client::default();
 
-        let path = match (self.access_token.as_ref(), self.sequence) {
-            (Some(at), Some(s)) => format!(
-                "/queue/listen/ws/{}?access_token={}&sequence={}",
-                self.queue_name, at, s
-            ),
+        let path = match (self.forward_query.as_ref(), self.sequence) {
+            (Some(at), Some(s)) => {
+                format!("/queue/listen/ws/{}?{}&sequence={}", self.queue_name, at, s)
+            }
             (Some(at), None) => {
-                format!("/queue/listen/ws/{}?access_token={}", self.queue_name, at)
+                format!("/queue/listen/ws/{}?{}", self.queue_name, at)
             }
             (None, Some(s)) => format!("/queue/listen/ws/{}?&sequence={}", self.queue_name, s),
             (None, None) => format!("/queue/listen/ws/{}", self.queue_name),
@@ -0,0 +1,237 @@
+//! Ephemeral web queue servers for integration tests.
+//!
+//! Every server listens on a random local port and stores queues in a temporary directory,
+//! which is removed with the server.
+//!
+//! ```ignore
+//! let server = TestServer::builder().queue("orders").start()?;
+//! let url = format!("{}/queue/send/orders/1", server.base_url());
+//! ```
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Environment variable with the path of the server binary
+pub const BINARY_ENV: &str = "SONYA_BIN";
+
+/// Config overrides of the environment are not inherited by test servers
+const OVERRIDE_PREFIX: &str = "SONYA_";
+
+const STARTUP_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone)]
+pub struct TestServerBuilder {
+    binary: Option<PathBuf>,
+    queues: Vec<String>,
+    service_token: Option<String>,
+    envs: Vec<(String, String)>,
+    startup_timeout: Duration,
+    logs: bool,
+}
+
+impl Default for TestServerBuilder {
+    fn default() -> Self {
+        Self {
+            binary: None,
+            queues: Vec::new(),
+            service_token: None,
+            envs: Vec::new(),
+            startup_timeout: Duration::from_secs(10),
+            logs: false,
+        }
+    }
+}
+
+impl TestServerBuilder {
+    /// Path of the server binary, `SONYA_BIN` or `sonya` next to the test executable by default.
+    /// Tests of the `sonya` crate pass `env!("CARGO_BIN_EXE_sonya")`.
+    pub fn binary(mut self, path: impl Into<PathBuf>) -> Self {
+        self.binary = Some(path.into());
+        self
+    }
+
+    /// Queue created on start
+    pub fn queue(mut self, name: impl Into<String>) -> Self {
+        self.queues.push(name.into());
+        self
+    }
+
+    /// Enables secure mode with the service token
+    pub fn service_token(mut self, token: impl Into<String>) -> Self {
+        self.service_token = Some(token.into());
+        self
+    }
+
+    /// Config variable of the server, e.g. `QUEUE_MAX_KEY_UPDATES`
+    pub fn env(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.envs.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn startup_timeout(mut self, timeout: Duration) -> Self {
+        self.startup_timeout = timeout;
+        self
+    }
+
+    /// Passes server logs to the output of tests
+    pub fn logs(mut self, enabled: bool) -> Self {
+        self.logs = enabled;
+        self
+    }
+
+    /// Starts the server and waits until it accepts connections
+    pub fn start(self) -> io::Result<TestServer> {
+        let dir = tempfile::tempdir()?;
+        let addr = free_addr()?;
+        let child = self.spawn(addr, dir.path())?;
+
+        let mut server = TestServer {
+            child,
+            addr,
+            dir,
+            options: self,
+        };
+        server.wait_ready()?;
+
+        Ok(server)
+    }
+
+    fn spawn(&self, addr: SocketAddr, db_path: &Path) -> io::Result<Child> {
+        let mut command = Command::new(self.binary.clone().unwrap_or_else(default_binary));
+
+        std::env::vars_os()
+            .map(|(name, _)| name)
+            .filter(|name| name.to_string_lossy().starts_with(OVERRIDE_PREFIX))
+            .for_each(|name| {
+                command.env_remove(name);
+            });
+
+        command
+            .env("CONFIG", "ENV")
+            .env("ADDR", addr.to_string())
+            .env("QUEUE_DB_PATH", db_path);
+        if !self.queues.is_empty() {
+            command.env("QUEUE_DEFAULT", self.queues.join(";"));
+        }
+        if let Some(token) = &self.service_token {
+            command.env("SECURE_SERVICE_TOKEN", token);
+        }
+        command.envs(self.envs.iter().cloned());
+
+        if !self.logs {
+            command.stdout(Stdio::null()).stderr(Stdio::null());
+        }
+
+        command.spawn()
+    }
+}
+
+/// Running server, it's killed and its storage is removed on drop
+#[derive(Debug)]
+pub struct TestServer {
+    child: Child,
+    addr: SocketAddr,
+    dir: TempDir,
+    options: TestServerBuilder,
+}
+
+impl TestServer {
+    /// Starts the server with default options
+    pub fn start() -> io::Result<Self> {
+        Self::builder().start()
+    }
+
+    pub fn builder() -> TestServerBuilder {
+        TestServerBuilder::default()
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Http address of the server without a trailing slash
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Websocket address of the server without a trailing slash
+    pub fn ws_url(&self) -> String {
+        format!("ws://{}", self.addr)
+    }
+
+    /// Storage directory of the server
+    pub fn db_path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Kills the server and starts it again with the same port and storage,
+    /// so tests check what survives a crash
+    pub fn restart(&mut self) -> io::Result<()> {
+        self.kill()?;
+        self.child = self.options.spawn(self.addr, self.dir.path())?;
+        self.wait_ready()
+    }
+
+    /// Kills the server and removes its storage
+    pub fn stop(mut self) -> io::Result<()> {
+        self.kill()
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        if self.child.try_wait()?.is_none() {
+            self.child.kill()?;
+            self.child.wait()?;
+        }
+        Ok(())
+    }
+
+    fn wait_ready(&mut self) -> io::Result<()> {
+        let started = Instant::now();
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("server exited with {}", status),
+                ));
+            }
+            if TcpStream::connect(self.addr).is_ok() {
+                return Ok(());
+            }
+            if started.elapsed() > self.options.startup_timeout {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "server did not start in time",
+                ));
+            }
+            sleep(STARTUP_POLL_INTERVAL);
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.kill();
+    }
+}
+
+/// The port is released before the server binds it, other processes rarely take it in between
+fn free_addr() -> io::Result<SocketAddr> {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()
+}
+
+/// Test executables are built to `target/{profile}/deps`, binaries of the workspace next to it
+fn default_binary() -> PathBuf {
+    let name = format!("sonya{}", std::env::consts::EXE_SUFFIX);
+    std::env::var_os(BINARY_ENV)
+        .map(PathBuf::from)
+        .or_else(|| {
+            let exe = std::env::current_exe().ok()?;
+            Some(exe.parent()?.parent()?.join(&name)).filter(|p| p.exists())
+        })
+        .unwrap_or_else(|| PathBuf::from(name))
+}
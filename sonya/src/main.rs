@@ -1,44 +1,245 @@
+use crate::queue::admission::Admission;
+use crate::queue::blob::{BlobInterceptor, FsBlobStore};
 use crate::queue::connection::{BroadcastMessage, QueueConnection};
-use crate::queue::map::{Queue, QueueResult, Subscription};
-use actix_web::middleware::Logger;
-use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder};
+use crate::queue::digest::DEFAULT_DIGEST_RANGE;
+use crate::queue::executor::StorageExecutor;
+use crate::queue::failover::{replicate, sync_replica, Failover};
+use crate::queue::filter::PayloadFieldFilter;
+use crate::queue::interceptor::{InterceptorError, ScrubFieldsInterceptor};
+use crate::queue::lifecycle::{LifecycleEvent, LifecycleEvents};
+use crate::queue::map::{Queue, QueueError, QueueResult, Subscription};
+use crate::queue::ndjson::NdjsonConnection;
+use crate::queue::poll::{PollSessionRegistry, Polled};
+use crate::queue::receipt::{DeliveryReceipt, ReceiptInterceptor};
+use crate::queue::route::RouteRules;
+use crate::queue::schema::SchemaRegistry;
+use crate::queue::session::SessionCursor;
+use crate::queue::sink::{run_sink, sink_writer};
+use crate::queue::socketio::{connect_socket_io, SocketIoState};
+use crate::queue::stats::StatsReporter;
+use crate::queue::subscribers::{ClientLabels, SubscriberRegistry};
+use crate::queue::trace::{TraceInterceptor, TracedDelivery};
+use actix_web::dev::{HttpServiceFactory, Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Condition;
+use actix_web::web::Bytes;
+use actix_web::{
+    web, App, Error, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Route,
+};
 use actix_web_actors::ws;
+use futures::channel::mpsc::UnboundedReceiver;
 use futures::future::Either;
-use futures::{FutureExt, StreamExt, TryStreamExt};
-use log::{error, info};
+use futures::{FutureExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use sonya_meta::api::extract_any_data_from_query;
-use sonya_meta::config::{get_config, ServiceDiscovery, ServiceDiscoveryInstanceOptions};
-use sonya_meta::message::{EventMessage, RequestSequence, UniqId};
-use sonya_meta::queue_scope_factory;
-use sonya_meta::response::BaseQueueResponse;
+use serde_json::Value;
+use sonya_meta::api::{
+    authorize_listing, extract_access_token, extract_any_data_from_query, service_token_guard,
+    IdentityQuery, JwtSession, MAX_BATCH_SIZE,
+};
+use sonya_meta::compress::Negotiated;
+use sonya_meta::config::{
+    get_config, load_config, Config, ConfigErrors, NodeRole, QueueOrdering, Secure,
+    ServiceDiscovery, ServiceDiscoveryInstanceOptions, SinkTarget,
+};
+use sonya_meta::cors::get_cors_from_config;
+use sonya_meta::encoding::{DeliveryFormat, Transcoder, NDJSON_CONTENT_TYPE, PROTOCOLS};
+use sonya_meta::limit::{ConnectionGuard, ConnectionLimiter};
+use sonya_meta::message::{
+    EventMessage, HlcTimestamp, RequestSequence, RequestSequenceId, SequenceId, UniqId,
+};
+use sonya_meta::response::{
+    BaseQueueResponse, BatchEventResult, BatchResponse, BroadcastsResponse, CountResponse,
+    DigestResponse, GapsResponse, HeadResponse, KeyHead, LeaseResponse, PeekResponse, QueueHead,
+    QueueListing, QueueMetadata, QueuesResponse, RangeDigestResponse, ReplayResponse, SendResponse,
+    SequenceGap, ServerResponse, ServerRole, SizesResponse, SubscribersResponse, TraceResponse,
+};
+use sonya_meta::spec::spec_scope_factory;
 use sonya_meta::tls::get_options_from_config;
+use sonya_meta::{admin_scope_factory, configure_server, queue_scope_factory};
+use std::fmt::Display;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info, instrument, warn, Span};
+use tracing_actix_web::TracingLogger;
 
 pub mod queue;
 mod service_discovery;
 
+const STATS_EVENT_ID: &str = "stats";
+
+/// Longpoll response header with the sequence to continue the limited preload from
+const CONTINUATION_HEADER: &str = "Sonya-Continuation";
+
+const LEASE_HEADER: &str = "Sonya-Lease";
+
+/// Token of the longpoll session which buffers events between polls
+const POLL_SESSION_HEADER: &str = "Sonya-Poll-Session";
+
+/// Count of events dropped by the longpoll session since the previous poll
+const SKIPPED_HEADER: &str = "Sonya-Skipped";
+
+/// Publisher id of the event, retried publishes with the same id are stored once
+const DEDUP_HEADER: &str = "Sonya-Dedup-Id";
+
+/// Time in seconds between removals of expired dedup ids
+const DEDUP_PURGE_INTERVAL: u64 = 60;
+
+/// Time in seconds between removals of expired message traces
+const TRACE_PURGE_INTERVAL: u64 = 60;
+
+/// Pause between checks of subscriptions left open while draining
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Pause before the failed apply of the publish journal is retried
+const JOURNAL_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
 async fn subscribe_queue_by_id_ws(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    admission: web::Data<Admission>,
+    config: web::Data<Config>,
+    limiter: web::Data<ConnectionLimiter>,
     info: web::Path<(String, String)>,
 ) -> Result<HttpResponse, Error> {
     let (queue_name, id) = info.into_inner();
-    let sequence = get_sequence_from_req(&req);
-    let queue_connection = srv.subscribe_queue_by_id(queue_name.clone(), id.clone(), sequence);
-    ws_response_factory(queue_connection, queue_name, Some(id), &req, stream).await
+    let connection_guard = limiter
+        .into_inner()
+        .acquire(&queue_name, req.peer_addr().map(|a| a.ip()))?;
+    let session = match get_session_from_req(&req) {
+        None => None,
+        Some(session) => {
+            let cursor = executor
+                .run({
+                    let (srv, queue_name, id) = (srv.clone(), queue_name.clone(), id.clone());
+                    move || srv.session_cursor(&queue_name, &id, &session)
+                })
+                .await?;
+            match cursor {
+                Ok(cursor) => Some(cursor),
+                Err(e) => {
+                    error!(error = %e, "loading session cursor error");
+                    return Err(actix_web::error::ErrorInternalServerError(
+                        "Session was not loaded",
+                    ));
+                }
+            }
+        }
+    };
+    // explicit sequences take precedence over the stored session cursor
+    let sequence =
+        get_sequence_from_req(&req).or_else(|| session.as_ref().and_then(|s| s.resume_sequence()));
+    if let Some(session) = session {
+        req.extensions_mut().insert(session);
+    }
+    let max_preload = get_max_preload_from_req(&req);
+    let identity = get_identity_from_req(&req, config.as_ref(), true);
+    let permit = admission.admit().await?;
+    let queue_connection = executor
+        .run_subscribe({
+            let (queue_name, id) = (queue_name.clone(), id.clone());
+            move || srv.subscribe_queue_by_id(queue_name, id, sequence, max_preload, identity)
+        })
+        .await?
+        .map(|s| permit.hold(s));
+    ws_response_factory(
+        queue_connection,
+        queue_name,
+        Some(id),
+        config.as_ref(),
+        connection_guard,
+        &req,
+        stream,
+    )
+    .await
 }
 
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
 async fn subscribe_queue_by_id_longpoll(
     req: HttpRequest,
     srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    admission: web::Data<Admission>,
+    config: web::Data<Config>,
+    info: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (queue_name, id) = info.into_inner();
+    if let Some(token) = get_poll_session_from_req(&req).poll_session {
+        return poll_session_response::<EventMessage>(&req, token, &queue_name, Some(&id)).await;
+    }
+    let after = get_after_from_req(&req);
+    if let Some(after) = after {
+        let has_updates = executor
+            .run({
+                let (srv, queue_name, id) = (srv.clone(), queue_name.clone(), id.clone());
+                move || srv.has_updates_after(&queue_name, &id, after)
+            })
+            .await?;
+        match has_updates {
+            Ok(true) => {}
+            Ok(false) => return Ok(HttpResponse::NoContent().finish()),
+            Err(e) => {
+                error!(error = %e, "checking key updates error");
+                return Err(actix_web::error::ErrorInternalServerError(
+                    "Key updates were not checked",
+                ));
+            }
+        }
+    }
+    // conditional subscriptions continue right after the known sequence
+    let sequence = get_sequence_from_req(&req).or_else(|| {
+        after
+            .and_then(|a| a.checked_add(1))
+            .map(RequestSequenceId::Id)
+    });
+    let max_preload = get_max_preload_from_req(&req);
+    let identity = get_identity_from_req(&req, config.as_ref(), true);
+    let permit = admission.admit().await?;
+    let queue_connection = executor
+        .run_subscribe({
+            let (queue_name, id) = (queue_name.clone(), id.clone());
+            move || srv.subscribe_queue_by_id(queue_name, id, sequence, max_preload, identity)
+        })
+        .await?
+        .map(|s| permit.hold(s));
+    longpoll_response_factory(queue_connection, queue_name, Some(id), &req).await
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn subscribe_queue_by_id_ndjson(
+    req: HttpRequest,
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    admission: web::Data<Admission>,
+    config: web::Data<Config>,
+    limiter: web::Data<ConnectionLimiter>,
     info: web::Path<(String, String)>,
 ) -> Result<HttpResponse, Error> {
     let (queue_name, id) = info.into_inner();
+    let connection_guard = limiter
+        .into_inner()
+        .acquire(&queue_name, req.peer_addr().map(|a| a.ip()))?;
     let sequence = get_sequence_from_req(&req);
-    let queue_connection = srv.subscribe_queue_by_id(queue_name, id, sequence);
-    longpoll_response_factory(queue_connection).await
+    let max_preload = get_max_preload_from_req(&req);
+    let identity = get_identity_from_req(&req, config.as_ref(), true);
+    let permit = admission.admit().await?;
+    let queue_connection = executor
+        .run_subscribe({
+            let (queue_name, id) = (queue_name.clone(), id.clone());
+            move || srv.subscribe_queue_by_id(queue_name, id, sequence, max_preload, identity)
+        })
+        .await?
+        .map(|s| permit.hold(s));
+    ndjson_response_factory(
+        queue_connection,
+        queue_name,
+        Some(id),
+        config.as_ref(),
+        connection_guard,
+        &req,
+    )
 }
 
 fn get_sequence_from_req(req: &HttpRequest) -> RequestSequence {
@@ -46,50 +247,264 @@ fn get_sequence_from_req(req: &HttpRequest) -> RequestSequence {
     sequence
 }
 
+/// Last sequence known by the client of the conditional subscription
+fn get_after_from_req(req: &HttpRequest) -> Option<SequenceId> {
+    let AfterQuery { after } = extract_any_data_from_query(req.head()).unwrap_or_default();
+    after
+}
+
+/// Name of the resumable key session, chosen by the client
+fn get_session_from_req(req: &HttpRequest) -> Option<String> {
+    let SessionQuery { session } = extract_any_data_from_query(req.head()).unwrap_or_default();
+    session.filter(|session| !session.is_empty())
+}
+
+/// Token of the longpoll session or the request to open one
+fn get_poll_session_from_req(req: &HttpRequest) -> PollSessionQuery {
+    let mut query: PollSessionQuery = extract_any_data_from_query(req.head()).unwrap_or_default();
+    query.poll_session = query.poll_session.filter(|token| !token.is_empty());
+    query
+}
+
+fn get_max_preload_from_req(req: &HttpRequest) -> Option<usize> {
+    let PreloadQuery { max_preload } = extract_any_data_from_query(req.head()).unwrap_or_default();
+    max_preload
+}
+
+/// Keys of the composite subscription, splits by `,`
+fn get_ids_from_req(req: &HttpRequest) -> Option<Vec<String>> {
+    let IdsQuery { ids } = extract_any_data_from_query(req.head()).unwrap_or_default();
+    ids.map(|ids| {
+        ids.split(',')
+            .filter(|id| !id.is_empty())
+            .map(String::from)
+            .collect()
+    })
+}
+
+/// Client id and labels of the subscriber, labels are `name:value` pairs split by `,`
+fn get_client_from_req(req: &HttpRequest) -> ClientLabels {
+    let ClientQuery { client_id, labels } =
+        extract_any_data_from_query(req.head()).unwrap_or_default();
+    ClientLabels {
+        client_id,
+        labels: labels
+            .iter()
+            .flat_map(|labels| labels.split(','))
+            .filter_map(|label| label.split_once(':'))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+    }
+}
+
+/// Identity of secured key subscriptions is taken from jwt tokens only. Secured queue subscriptions
+/// are made with the service token, so its holders may set any identity with `identity` query parameter.
+/// Without `secure` the parameter is not authenticated, so the identity filter is advisory
+fn get_identity_from_req(req: &HttpRequest, config: &Config, by_id: bool) -> Option<String> {
+    match (&config.secure, by_id) {
+        (Some(secure), true) => {
+            JwtSession::from_request(req.head(), secure).and_then(|session| session.identity)
+        }
+        _ => extract_any_data_from_query::<IdentityQuery>(req.head())
+            .and_then(|query| query.identity),
+    }
+}
+
+#[instrument(skip_all, fields(queue = %info.0))]
 async fn subscribe_queue_ws(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    admission: web::Data<Admission>,
+    config: web::Data<Config>,
+    limiter: web::Data<ConnectionLimiter>,
     info: web::Path<(String,)>,
 ) -> Result<HttpResponse, Error> {
     let queue_name = info.into_inner().0;
+    let connection_guard = limiter
+        .into_inner()
+        .acquire(&queue_name, req.peer_addr().map(|a| a.ip()))?;
     let sequence = get_sequence_from_req(&req);
-    let queue_connection = srv.subscribe_queue(queue_name.clone(), sequence);
-    ws_response_factory(queue_connection, queue_name, None, &req, stream).await
+    let max_preload = get_max_preload_from_req(&req);
+    let identity = get_identity_from_req(&req, config.as_ref(), false);
+    let ids = get_ids_from_req(&req);
+    let permit = admission.admit().await?;
+    let queue_connection = executor
+        .run_subscribe({
+            let queue_name = queue_name.clone();
+            move || match ids {
+                Some(ids) => {
+                    srv.subscribe_queue_by_ids(queue_name, ids, sequence, max_preload, identity)
+                }
+                None => srv.subscribe_queue(queue_name, sequence, max_preload, identity),
+            }
+        })
+        .await?
+        .map(|s| permit.hold(s));
+    ws_response_factory(
+        queue_connection,
+        queue_name,
+        None,
+        config.as_ref(),
+        connection_guard,
+        &req,
+        stream,
+    )
+    .await
 }
 
+#[instrument(skip_all, fields(queue = %info.0))]
 async fn subscribe_queue_longpoll(
     req: HttpRequest,
     srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    admission: web::Data<Admission>,
+    config: web::Data<Config>,
+    info: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    let queue_name = info.into_inner().0;
+    if let Some(token) = get_poll_session_from_req(&req).poll_session {
+        return poll_session_response::<EventMessage>(&req, token, &queue_name, None).await;
+    }
+    let sequence = get_sequence_from_req(&req);
+    let max_preload = get_max_preload_from_req(&req);
+    let identity = get_identity_from_req(&req, config.as_ref(), false);
+    let ids = get_ids_from_req(&req);
+    let permit = admission.admit().await?;
+    let queue_connection = executor
+        .run_subscribe({
+            let queue_name = queue_name.clone();
+            move || match ids {
+                Some(ids) => {
+                    srv.subscribe_queue_by_ids(queue_name, ids, sequence, max_preload, identity)
+                }
+                None => srv.subscribe_queue(queue_name, sequence, max_preload, identity),
+            }
+        })
+        .await?
+        .map(|s| permit.hold(s));
+    longpoll_response_factory(queue_connection, queue_name, None, &req).await
+}
+
+#[instrument(skip_all, fields(queue = %info.0))]
+async fn subscribe_queue_ndjson(
+    req: HttpRequest,
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    admission: web::Data<Admission>,
+    config: web::Data<Config>,
+    limiter: web::Data<ConnectionLimiter>,
     info: web::Path<(String,)>,
 ) -> Result<HttpResponse, Error> {
     let queue_name = info.into_inner().0;
+    let connection_guard = limiter
+        .into_inner()
+        .acquire(&queue_name, req.peer_addr().map(|a| a.ip()))?;
     let sequence = get_sequence_from_req(&req);
-    let queue_connection = srv.subscribe_queue(queue_name, sequence);
-    longpoll_response_factory(queue_connection).await
+    let max_preload = get_max_preload_from_req(&req);
+    let identity = get_identity_from_req(&req, config.as_ref(), false);
+    let ids = get_ids_from_req(&req);
+    let permit = admission.admit().await?;
+    let queue_connection = executor
+        .run_subscribe({
+            let queue_name = queue_name.clone();
+            move || match ids {
+                Some(ids) => {
+                    srv.subscribe_queue_by_ids(queue_name, ids, sequence, max_preload, identity)
+                }
+                None => srv.subscribe_queue(queue_name, sequence, max_preload, identity),
+            }
+        })
+        .await?
+        .map(|s| permit.hold(s));
+    ndjson_response_factory(
+        queue_connection,
+        queue_name,
+        None,
+        config.as_ref(),
+        connection_guard,
+        &req,
+    )
 }
 
 async fn ws_response_factory<T>(
     queue: QueueResult<Subscription<'static, T>>,
     queue_name: String,
     id: Option<String>,
+    config: &Config,
+    connection_guard: ConnectionGuard,
     req: &HttpRequest,
     stream: web::Payload,
 ) -> Result<HttpResponse, Error>
 where
     T: 'static + Serialize + UniqId,
 {
+    // key subscriptions are authorized with jwt tokens
+    let jwt_session = config
+        .secure
+        .as_ref()
+        .filter(|_| id.is_some())
+        .and_then(|secure| JwtSession::from_request(req.head(), secure));
+    let retry_hint = req
+        .app_data::<web::Data<Admission>>()
+        .and_then(|admission| admission.retry_hint());
+    let format = DeliveryFormat::from_protocols(req.head());
+    let subscriber = req
+        .app_data::<web::Data<SubscriberRegistry>>()
+        .map(|registry| {
+            registry.clone().into_inner().register(
+                queue_name.clone(),
+                id.clone(),
+                get_client_from_req(req),
+                req.peer_addr().map(|a| a.ip()),
+                format,
+            )
+        });
+    let session = req.extensions_mut().remove::<SessionCursor>();
+    let transcoder = req
+        .app_data::<web::Data<Transcoder>>()
+        .map(|transcoder| transcoder.clone().into_inner())
+        .unwrap_or_default();
+    let reaper = config.queue.reap_timeout.and_then(|timeout| {
+        req.app_data::<web::Data<Queue<EventMessage>>>()
+            .map(|queue| queue.reaper(Duration::from_secs(timeout)))
+    });
+
     match queue {
         Ok(Subscription {
             stream: Some(q),
-            preloaded_count: _,
-        }) => ws::start(QueueConnection::new(id, queue_name, q), req, stream),
+            preload: _,
+        }) => ws::start_with_protocols(
+            QueueConnection::new(
+                id,
+                queue_name,
+                q,
+                config.queue.heartbeat_interval.map(Duration::from_secs),
+                config.queue.max_skipped_messages,
+                connection_guard,
+                jwt_session,
+                Span::current(),
+            )
+            .with_retry_hint(retry_hint)
+            .with_subscriber(subscriber)
+            .with_session(session)
+            .with_format(format, transcoder)
+            .with_reaper(reaper),
+            PROTOCOLS,
+            req,
+            stream,
+        ),
         Ok(Subscription {
             stream: None,
-            preloaded_count: _,
+            preload: _,
         }) => Err(actix_web::error::ErrorNotFound("Queue Not Found")),
+        Err(QueueError::PreloadTimeout) => {
+            warn!("websocket preload timed out");
+            Err(actix_web::error::ErrorGatewayTimeout("Preload timed out"))
+        }
         Err(e) => {
-            error!("websocket subscribe error {}", e);
+            error!(error = %e, "websocket subscribe error");
             Err(actix_web::error::ErrorInternalServerError(
                 "Subscription error",
             ))
@@ -97,34 +512,136 @@ where
     }
 }
 
-async fn longpoll_response_factory<T>(
+fn ndjson_response_factory<T>(
     queue: QueueResult<Subscription<'static, T>>,
+    queue_name: String,
+    id: Option<String>,
+    config: &Config,
+    connection_guard: ConnectionGuard,
+    req: &HttpRequest,
 ) -> Result<HttpResponse, Error>
 where
-    T: 'static + Serialize,
+    T: 'static + Send + Serialize,
 {
+    // key subscriptions are authorized with jwt tokens
+    let jwt_session = config
+        .secure
+        .as_ref()
+        .filter(|_| id.is_some())
+        .and_then(|secure| JwtSession::from_request(req.head(), secure));
+    let retry_hint = req
+        .app_data::<web::Data<Admission>>()
+        .and_then(|admission| admission.retry_hint());
+
     match queue {
         Ok(Subscription {
             stream: Some(q),
-            preloaded_count: prev_len,
+            preload: _,
         }) => {
-            let messages: Result<Vec<_>, _> = q
-                .take(prev_len.unwrap_or(1).max(1))
-                .map(|m| match m {
-                    BroadcastMessage::Message(s) => Ok(s),
-                    _ => Err(actix_web::error::ErrorGone("Queue was closed")),
-                })
-                .try_collect()
-                .await;
+            let subscriber = req
+                .app_data::<web::Data<SubscriberRegistry>>()
+                .map(|registry| {
+                    registry.clone().into_inner().register(
+                        queue_name,
+                        id,
+                        get_client_from_req(req),
+                        req.peer_addr().map(|a| a.ip()),
+                        DeliveryFormat::Json,
+                    )
+                });
+            let connection = NdjsonConnection::new(
+                q,
+                config.queue.heartbeat_interval.map(Duration::from_secs),
+                config.queue.max_skipped_messages,
+                connection_guard,
+                jwt_session,
+                Span::current(),
+            )
+            .with_retry_hint(retry_hint)
+            .with_subscriber(subscriber);
+            Ok(HttpResponse::Ok()
+                .content_type(NDJSON_CONTENT_TYPE)
+                .streaming(connection.into_stream()))
+        }
+        Ok(Subscription {
+            stream: None,
+            preload: _,
+        }) => Err(actix_web::error::ErrorNotFound("Queue Not Found")),
+        Err(QueueError::PreloadTimeout) => {
+            warn!("ndjson preload timed out");
+            Err(actix_web::error::ErrorGatewayTimeout("Preload timed out"))
+        }
+        Err(e) => {
+            error!(error = %e, "ndjson subscribe error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Subscription error",
+            ))
+        }
+    }
+}
+
+async fn longpoll_response_factory<T>(
+    queue: QueueResult<Subscription<'static, T>>,
+    queue_name: String,
+    id: Option<String>,
+    req: &HttpRequest,
+) -> Result<HttpResponse, Error>
+where
+    T: 'static + Send + Serialize,
+{
+    match queue {
+        Ok(Subscription {
+            stream: Some(mut q),
+            preload,
+        }) => {
+            // preloaded history is returned whole, otherwise the first live event is awaited
+            let mut preloading = preload;
+            let mut continuation = None;
+            let mut messages = Vec::new();
+            while let Some(m) = q.next().await {
+                match m {
+                    BroadcastMessage::Message(s) => {
+                        messages.push(s);
+                        if !preloading {
+                            break;
+                        }
+                    }
+                    BroadcastMessage::EndOfPreload(c) if !messages.is_empty() => {
+                        continuation = c;
+                        break;
+                    }
+                    BroadcastMessage::EndOfPreload(_) => preloading = false,
+                    m => {
+                        if let Some(e) = terminal_error(&m) {
+                            return Err(e);
+                        }
+                    }
+                }
+            }
 
-            messages.map(|m| HttpResponse::Ok().json(m))
+            let mut response = HttpResponse::Ok();
+            if let Some(continuation) = continuation {
+                response.insert_header((CONTINUATION_HEADER, continuation.to_string()));
+            }
+            // the rest of the subscription is buffered until the next poll of the session
+            let sessions = req
+                .app_data::<web::Data<PollSessionRegistry<T>>>()
+                .filter(|_| get_poll_session_from_req(req).buffer);
+            if let Some(Ok(token)) = sessions.map(|sessions| sessions.open(queue_name, id, q)) {
+                response.insert_header((POLL_SESSION_HEADER, token));
+            }
+            longpoll_events_response(req, response, &messages)
         }
         Ok(Subscription {
             stream: None,
-            preloaded_count: _,
+            preload: _,
         }) => Err(actix_web::error::ErrorNotFound("Queue Not Found")),
+        Err(QueueError::PreloadTimeout) => {
+            warn!("longpoll preload timed out");
+            Err(actix_web::error::ErrorGatewayTimeout("Preload timed out"))
+        }
         Err(e) => {
-            error!("longpoll subscribe error {}", e);
+            error!(error = %e, "longpoll subscribe error");
             Err(actix_web::error::ErrorInternalServerError(
                 "Subscription error",
             ))
@@ -132,14 +649,127 @@ where
     }
 }
 
+/// Returns events buffered by the longpoll session since the previous poll
+async fn poll_session_response<T>(
+    req: &HttpRequest,
+    token: String,
+    queue_name: &str,
+    id: Option<&str>,
+) -> Result<HttpResponse, Error>
+where
+    T: 'static + Send + Serialize,
+{
+    let sessions = req.app_data::<web::Data<PollSessionRegistry<T>>>();
+    let session = sessions
+        .and_then(|sessions| sessions.get(&token, queue_name, id))
+        // expired sessions are reopened by the client from its last sequence
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Poll session not found"))?;
+    let Polled {
+        events,
+        skipped,
+        terminal,
+    } = session.poll().await;
+    drop(session);
+
+    if let Some(terminal) = terminal {
+        if let Some(sessions) = sessions {
+            sessions.close(&token);
+        }
+        return Err(terminal_error(&terminal)
+            .unwrap_or_else(|| actix_web::error::ErrorGone("Poll session was closed")));
+    }
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header((POLL_SESSION_HEADER, token));
+    if skipped > 0 {
+        response.insert_header((SKIPPED_HEADER, skipped.to_string()));
+    }
+    longpoll_events_response(req, response, &events)
+}
+
+/// Error of the longpoll ended by the terminal event, `None` for other events
+fn terminal_error<T>(message: &BroadcastMessage<T>) -> Option<Error> {
+    match message {
+        BroadcastMessage::QueueClosed => Some(actix_web::error::ErrorGone("Queue was closed")),
+        BroadcastMessage::KeyDeleted => Some(actix_web::error::ErrorGone("Key was deleted")),
+        BroadcastMessage::Draining => Some(actix_web::error::ErrorServiceUnavailable(
+            "Server is draining",
+        )),
+        // the retried request is redirected to the new primary
+        BroadcastMessage::Moved(_) => Some(actix_web::error::ErrorServiceUnavailable(
+            "Server has moved",
+        )),
+        BroadcastMessage::PreloadFailed => {
+            Some(actix_web::error::ErrorServiceUnavailable("Preload failed"))
+        }
+        _ => None,
+    }
+}
+
+/// Encodes longpoll events with the format accepted by the client and compresses them
+fn longpoll_events_response<T: Serialize>(
+    req: &HttpRequest,
+    mut response: HttpResponseBuilder,
+    messages: &[T],
+) -> Result<HttpResponse, Error> {
+    let format = DeliveryFormat::from_accept(req.head());
+    let body = match format {
+        DeliveryFormat::Json => serde_json::to_vec(messages)
+            .map(Bytes::from)
+            .map_err(|e| e.to_string()),
+        format => {
+            let transcoder = req
+                .app_data::<web::Data<Transcoder>>()
+                .map(|transcoder| transcoder.clone().into_inner())
+                .unwrap_or_default();
+            messages
+                .iter()
+                .map(serde_json::to_string)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| e.to_string())
+                .and_then(|events| {
+                    transcoder
+                        .transcode_all(events.iter().map(String::as_str), format)
+                        .map_err(|e| e.to_string())
+                })
+        }
+    };
+    match body {
+        Ok(body) => {
+            let compression = req
+                .app_data::<web::Data<Config>>()
+                .and_then(|config| config.compression.as_ref());
+            let negotiated = Negotiated::new(req.head(), compression, body);
+            if negotiated.coding.is_some() {
+                if let Some(queue) = req.app_data::<web::Data<Queue<EventMessage>>>() {
+                    queue.record_compression(negotiated.saved());
+                }
+            }
+            response.content_type(format.content_type());
+            Ok(negotiated.respond(response))
+        }
+        Err(e) => {
+            error!(error = %e, format = %format, "longpoll encoding error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Events were not encoded",
+            ))
+        }
+    }
+}
+
+#[instrument(skip_all, fields(queue = %info.as_str()))]
 async fn create_queue(
     srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
     info: web::Path<String>,
-) -> impl Responder {
+) -> Result<HttpResponse, Error> {
     let queue_name = info.into_inner();
-    match srv.create_queue(queue_name) {
+    match executor.run(move || srv.create_queue(queue_name)).await? {
+        Err(e @ QueueError::InvalidMode { .. }) => {
+            Err(actix_web::error::ErrorBadRequest(e.to_string()))
+        }
         Err(e) => {
-            error!("creating queue error {}", e);
+            error!(error = %e, "creating queue error");
             Err(actix_web::error::ErrorInternalServerError(
                 "Queue was not created",
             ))
@@ -148,54 +778,395 @@ async fn create_queue(
     }
 }
 
-async fn delete_from_queue(
+/// Replaces description, owner and tags of the queue
+#[instrument(skip_all, fields(queue = %info.as_str()))]
+async fn set_queue_metadata(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<String>,
+    metadata: web::Json<QueueMetadata>,
+) -> Result<HttpResponse, Error> {
+    let queue_name = info.into_inner();
+    let metadata = metadata.into_inner();
+    match executor
+        .run(move || srv.set_metadata(&queue_name, &metadata))
+        .await?
+    {
+        Ok(success) => Ok(HttpResponse::Ok().json(BaseQueueResponse { success })),
+        Err(e) => {
+            error!(error = %e, "setting queue metadata error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Metadata was not set",
+            ))
+        }
+    }
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn delete_key_history(
     srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
     info: web::Path<(String, String)>,
-) -> impl Responder {
+    query: web::Query<DeleteQuery>,
+) -> Result<HttpResponse, Error> {
     let (queue_name, id) = info.into_inner();
-    match srv.delete_queue(queue_name, id) {
+    let tombstone = query.tombstone;
+    match executor
+        .run(move || srv.delete_key_history(queue_name, id, tombstone))
+        .await?
+    {
         Err(e) => {
-            error!("deleting queue error {}", e);
+            error!(error = %e, "deleting key history error");
             Err(actix_web::error::ErrorInternalServerError(
-                "Queue was not created",
+                "Key history was not deleted",
             ))
         }
         Ok(_) => Ok(HttpResponse::Ok().json(BaseQueueResponse { success: true })),
     }
 }
 
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1, sequence = %info.2))]
+async fn revoke_event(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<(String, String, SequenceId)>,
+) -> Result<HttpResponse, Error> {
+    let (queue_name, id, sequence) = info.into_inner();
+    match executor
+        .run(move || srv.revoke_event(queue_name, id, sequence))
+        .await?
+    {
+        Ok(revoked) => Ok(HttpResponse::Ok().json(BaseQueueResponse {
+            success: revoked.unwrap_or_default(),
+        })),
+        Err(e) => {
+            error!(error = %e, "revoking event error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Event was not revoked",
+            ))
+        }
+    }
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn acquire_lease(
+    req: HttpRequest,
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<(String, String)>,
+    query: web::Query<LeaseQuery>,
+) -> Result<HttpResponse, Error> {
+    let (queue_name, id) = info.into_inner();
+    let ttl = Duration::from_secs(query.ttl);
+    let token = get_lease_from_req(&req);
+    match executor
+        .run(move || srv.acquire_lease(queue_name, id, ttl, token))
+        .await?
+    {
+        Ok(lease) => Ok(HttpResponse::Ok().json(LeaseResponse {
+            success: lease.is_some(),
+            expiration: lease.as_ref().map(|l| {
+                l.expires
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+            lease: lease.map(|l| l.token),
+        })),
+        Err(QueueError::LeaseConflict(e)) => Err(actix_web::error::ErrorConflict(e.to_string())),
+        Err(e) => {
+            error!(error = %e, "acquiring lease error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Lease was not acquired",
+            ))
+        }
+    }
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn release_lease(
+    req: HttpRequest,
+    srv: web::Data<Queue<EventMessage>>,
+    info: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (queue_name, id) = info.into_inner();
+    let success = match get_lease_from_req(&req) {
+        Some(token) => srv.release_lease(queue_name, id, token),
+        None => false,
+    };
+    HttpResponse::Ok().json(BaseQueueResponse { success })
+}
+
+/// Lease token of the key writer
+fn get_lease_from_req(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(LEASE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+fn get_dedup_id_from_req(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(DEDUP_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+}
+
+#[derive(Deserialize)]
+struct LeaseQuery {
+    #[serde(default = "default_lease_ttl")]
+    ttl: u64,
+}
+
+fn default_lease_ttl() -> u64 {
+    30
+}
+
 #[derive(Deserialize, Default)]
 struct SequenceQuery {
     sequence: RequestSequence,
 }
 
+#[derive(Deserialize, Default)]
+struct AfterQuery {
+    after: Option<SequenceId>,
+}
+
+#[derive(Deserialize, Default)]
+struct IdsQuery {
+    ids: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct ClientQuery {
+    client_id: Option<String>,
+    labels: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct SessionQuery {
+    session: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct PollSessionQuery {
+    #[serde(default)]
+    buffer: bool,
+    poll_session: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct PreloadQuery {
+    max_preload: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+struct DeleteQuery {
+    #[serde(default)]
+    tombstone: bool,
+}
+
+#[instrument(skip_all, fields(queue = %info.as_str(), key = %message.id))]
 async fn send_to_queue(
+    req: HttpRequest,
     srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
     info: web::Path<String>,
     message: web::Json<EventMessage>,
-) -> impl Responder {
-    let queue_name = info.into_inner();
-    let message = message.into_inner();
-    match srv.send_to_queue(queue_name, message) {
+) -> Result<HttpResponse, Error> {
+    let lease = get_lease_from_req(&req);
+    let dedup_id = get_dedup_id_from_req(&req);
+    publish_event(
+        srv,
+        executor,
+        info.into_inner(),
+        message.into_inner(),
+        lease,
+        dedup_id,
+    )
+    .await
+}
+
+/// Sends the raw json payload with the key taken from the path
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn send_payload_to_queue(
+    req: HttpRequest,
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<(String, String)>,
+    payload: web::Json<Value>,
+) -> Result<HttpResponse, Error> {
+    let (queue_name, id) = info.into_inner();
+    let message = EventMessage::new(id, payload.into_inner());
+    let lease = get_lease_from_req(&req);
+    let dedup_id = get_dedup_id_from_req(&req);
+    publish_event(srv, executor, queue_name, message, lease, dedup_id).await
+}
+
+async fn publish_event(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    queue_name: String,
+    message: EventMessage,
+    lease: Option<String>,
+    dedup_id: Option<String>,
+) -> Result<HttpResponse, Error> {
+    match executor
+        .run_publish(move || {
+            srv.check_lease(&queue_name, &message.get_id(), lease.as_deref())?;
+            if srv.is_journaled() {
+                // journaled events get sequences when they are applied
+                return srv
+                    .append_journal(queue_name, vec![(message, dedup_id)])?
+                    .map(|results| results.into_iter().collect::<QueueResult<()>>())
+                    .transpose()
+                    .map(|appended| appended.map(|_| Vec::new()));
+            }
+            match dedup_id {
+                Some(dedup_id) => srv.send_deduplicated(queue_name, message, &dedup_id),
+                None => srv.publish(queue_name, message),
+            }
+        })
+        .await?
+    {
+        Err(QueueError::Rejected(e)) => Err(actix_web::error::ErrorBadRequest(e.to_string())),
+        Err(QueueError::LeaseConflict(e)) => Err(actix_web::error::ErrorConflict(e.to_string())),
+        Err(e @ QueueError::DiskFull) => {
+            Err(actix_web::error::ErrorInsufficientStorage(e.to_string()))
+        }
         Err(e) => {
-            error!("sending message error {}", e);
+            error!(error = %e, "sending message error");
             Err(actix_web::error::ErrorInternalServerError(
                 "Message was not sent",
             ))
         }
-        Ok(success) => Ok(HttpResponse::Ok().json(BaseQueueResponse { success })),
+        Ok(sequences) => Ok(HttpResponse::Ok().json(SendResponse {
+            success: sequences.is_some(),
+            sequence: sequences.and_then(|s| s.last().copied()),
+        })),
+    }
+}
+
+/// Sends events in order with one storage operation, failed events don't stop the batch
+#[instrument(skip_all, fields(queue = %info.as_str(), events = messages.len()))]
+async fn send_batch_to_queue(
+    req: HttpRequest,
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<String>,
+    messages: web::Json<Vec<EventMessage>>,
+) -> Result<HttpResponse, Error> {
+    let queue_name = info.into_inner();
+    let messages = messages.into_inner();
+    if messages.len() > MAX_BATCH_SIZE {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "batch must contain at most {} events",
+            MAX_BATCH_SIZE
+        )));
     }
+
+    let lease = get_lease_from_req(&req);
+    let results = executor
+        .run_publish(move || {
+            if srv.is_journaled() {
+                return append_batch_to_journal(&srv, queue_name, messages, lease);
+            }
+            messages
+                .into_iter()
+                .map(|message| {
+                    srv.check_lease(&queue_name, &message.get_id(), lease.as_deref())?;
+                    srv.publish(queue_name.clone(), message)
+                })
+                .collect::<Vec<_>>()
+        })
+        .await?;
+
+    let events: Vec<_> = results
+        .into_iter()
+        .map(|result| match result {
+            Ok(sequences) => BatchEventResult {
+                success: sequences.is_some(),
+                status: StatusCode::OK.as_u16(),
+                error: None,
+                sequence: sequences.and_then(|s| s.last().copied()),
+            },
+            Err(e) => {
+                let (status, error) = match e {
+                    QueueError::Rejected(e) => (StatusCode::BAD_REQUEST, e.to_string()),
+                    QueueError::LeaseConflict(e) => (StatusCode::CONFLICT, e.to_string()),
+                    e @ QueueError::DiskFull => (StatusCode::INSUFFICIENT_STORAGE, e.to_string()),
+                    e => {
+                        error!(error = %e, "sending batch event error");
+                        let error = String::from("Message was not sent");
+                        (StatusCode::INTERNAL_SERVER_ERROR, error)
+                    }
+                };
+                BatchEventResult {
+                    success: false,
+                    status: status.as_u16(),
+                    error: Some(error),
+                    sequence: None,
+                }
+            }
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(BatchResponse {
+        success: events.iter().all(|e| e.success),
+        events,
+    }))
+}
+
+/// Appends events of the batch which hold the lease and pass checks to the journal with one sync
+fn append_batch_to_journal(
+    srv: &Queue<EventMessage>,
+    queue_name: String,
+    messages: Vec<EventMessage>,
+    lease: Option<String>,
+) -> Vec<QueueResult<Option<Vec<SequenceId>>>> {
+    let checks: Vec<_> = messages
+        .iter()
+        .map(|message| srv.check_lease(&queue_name, &message.get_id(), lease.as_deref()))
+        .collect();
+    let accepted = messages
+        .into_iter()
+        .zip(&checks)
+        .filter(|(_, check)| check.is_ok())
+        .map(|(message, _)| (message, None))
+        .collect();
+
+    let (mut appended, error) = match srv.append_journal(queue_name, accepted) {
+        Ok(results) => (results.map(Vec::into_iter), None),
+        Err(e) => (None, Some(e)),
+    };
+    checks
+        .into_iter()
+        .map(|check| {
+            check?;
+            match (&mut appended, &error) {
+                (Some(results), _) => results.next().unwrap_or(Ok(())).map(|_| Some(Vec::new())),
+                (None, None) => Ok(None),
+                (None, Some(QueueError::Rejected(e))) => Err(InterceptorError(e.0.clone()).into()),
+                (None, Some(QueueError::DiskFull)) => Err(QueueError::DiskFull),
+                // the batch shares one append, so other errors are repeated as io errors
+                (None, Some(e)) => Err(std::io::Error::other(e.to_string()).into()),
+            }
+        })
+        .collect()
 }
 
-async fn close_queue(
+#[instrument(skip_all, fields(queue = %info.as_str()))]
+async fn drop_queue(
     srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
     info: web::Path<String>,
-) -> impl Responder {
+) -> Result<HttpResponse, Error> {
     let queue_name = info.into_inner();
-    match srv.close_queue(queue_name) {
+    match executor.run(move || srv.drop_queue(queue_name)).await? {
         Ok(success) => Ok(HttpResponse::Ok().json(BaseQueueResponse { success })),
         Err(e) => {
-            error!("close queue error {}", e);
+            error!(error = %e, "drop queue error");
             Err(actix_web::error::ErrorInternalServerError(
                 "Queue was not closed",
             ))
@@ -203,19 +1174,976 @@ async fn close_queue(
     }
 }
 
-#[actix_web::main]
-async fn main() -> tokio::io::Result<()> {
-    let config = get_config();
-
-    let address = config
-        .addr
-        .unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080));
-    let secure = config.secure;
-    let queue_options = config.queue;
+#[instrument(skip_all, fields(queue = %info.as_str()))]
+async fn clear_queue(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let queue_name = info.into_inner();
+    match executor.run(move || srv.clear_queue(queue_name)).await? {
+        Ok(success) => Ok(HttpResponse::Ok().json(BaseQueueResponse { success })),
+        Err(e) => {
+            error!(error = %e, "clear queue error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Queue was not cleared",
+            ))
+        }
+    }
+}
 
-    let (cx, rx) = futures::channel::oneshot::channel();
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn count_key(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (queue_name, id) = info.into_inner();
+    match executor.run(move || srv.count_key(queue_name, id)).await? {
+        Ok(count) => Ok(HttpResponse::Ok().json(CountResponse {
+            success: count.is_some(),
+            count: count.unwrap_or_default(),
+        })),
+        Err(e) => {
+            error!(error = %e, "count key error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Key was not counted",
+            ))
+        }
+    }
+}
 
-    match config.service_discovery {
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn head_key(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (queue_name, id) = info.into_inner();
+    match executor.run(move || srv.head(queue_name, id)).await? {
+        Ok(head) => Ok(HttpResponse::Ok().json(HeadResponse {
+            success: head.is_some(),
+            key: head.as_ref().and_then(|h| h.key).and_then(|m| {
+                Some(KeyHead {
+                    sequence: SequenceId::new(m.position)?,
+                    timestamp: m.timestamp,
+                })
+            }),
+            queue: head.and_then(|h| h.queue).map(|m| QueueHead {
+                offset: m.position,
+                timestamp: m.timestamp,
+            }),
+        })),
+        Err(e) => {
+            error!(error = %e, "head key error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Head was not read",
+            ))
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct ListQueuesQuery {
+    owner: Option<String>,
+    tag: Option<String>,
+}
+
+/// Lists queues which the caller may subscribe to with their heads and metadata.
+/// Jwt tokens of keys list only their queue
+async fn list_queues(
+    req: HttpRequest,
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    config: web::Data<Config>,
+    query: web::Query<ListQueuesQuery>,
+) -> Result<HttpResponse, Error> {
+    let only = match &config.secure {
+        None => None,
+        Some(secure) => extract_access_token(req.head())
+            .and_then(|token| authorize_listing(secure, &token))
+            .ok_or_else(|| actix_web::error::ErrorUnauthorized("Token is not valid"))?,
+    };
+
+    match executor
+        .run(move || srv.list_queues(only.as_deref()))
+        .await?
+    {
+        Ok(queues) => Ok(HttpResponse::Ok().json(QueuesResponse {
+            success: true,
+            queues: queues
+                .into_iter()
+                .filter(|q| {
+                    let owner = &query.owner;
+                    owner.is_none() || &q.metadata.owner == owner
+                })
+                .filter(|q| {
+                    query
+                        .tag
+                        .as_ref()
+                        .map_or(true, |t| q.metadata.tags.contains(t))
+                })
+                .map(|q| QueueListing {
+                    name: q.name,
+                    head: q.head.map(|m| QueueHead {
+                        offset: m.position,
+                        timestamp: m.timestamp,
+                    }),
+                    metadata: q.metadata,
+                })
+                .collect(),
+        })),
+        Err(e) => {
+            error!(error = %e, "listing queues error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Queues were not listed",
+            ))
+        }
+    }
+}
+
+/// Lifecycle of the stored event, traced if message tracing is enabled
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1, sequence = %info.2))]
+async fn message_trace(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<(String, String, SequenceId)>,
+) -> Result<HttpResponse, Error> {
+    let (queue_name, id, sequence) = info.into_inner();
+    match executor
+        .run(move || srv.message_trace(queue_name, id, sequence))
+        .await?
+    {
+        Ok(trace) => Ok(HttpResponse::Ok().json(TraceResponse {
+            success: trace.is_some(),
+            trace: trace.flatten(),
+        })),
+        Err(e) => {
+            error!(error = %e, "message trace error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Message trace was not loaded",
+            ))
+        }
+    }
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn sequence_gaps(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (queue_name, id) = info.into_inner();
+    match executor
+        .run(move || srv.sequence_gaps(queue_name, id))
+        .await?
+    {
+        Ok(None) => Ok(HttpResponse::Ok().json(GapsResponse {
+            success: false,
+            published: 0,
+            stored: 0,
+            first: None,
+            last: None,
+            gaps: vec![],
+        })),
+        Ok(Some(report)) => Ok(HttpResponse::Ok().json(GapsResponse {
+            success: true,
+            published: report.published,
+            stored: report.stored,
+            first: report.first.and_then(SequenceId::new),
+            last: report.last.and_then(SequenceId::new),
+            gaps: report
+                .gaps
+                .into_iter()
+                .filter_map(|gap| {
+                    Some(SequenceGap {
+                        from: SequenceId::new(*gap.start())?,
+                        to: SequenceId::new(*gap.end())?,
+                    })
+                })
+                .collect(),
+        })),
+        Err(e) => {
+            error!(error = %e, "sequence gaps error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Sequences were not scanned",
+            ))
+        }
+    }
+}
+
+#[instrument(skip_all, fields(queue = %info.0))]
+async fn broadcasts(
+    srv: web::Data<Queue<EventMessage>>,
+    info: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    let queue_name = info.into_inner().0;
+    match srv.broadcasts(queue_name) {
+        None => Ok(HttpResponse::Ok().json(BroadcastsResponse {
+            success: false,
+            queue: Default::default(),
+            keys: Default::default(),
+        })),
+        Some(broadcasts) => Ok(HttpResponse::Ok().json(BroadcastsResponse {
+            success: true,
+            queue: broadcasts.queue,
+            keys: broadcasts.keys,
+        })),
+    }
+}
+
+/// Size estimates of the queue and its keys, maintained on writes
+#[instrument(skip_all, fields(queue = %info.0))]
+async fn sizes(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    let queue_name = info.into_inner().0;
+    let sizes = move || Ok::<_, QueueError>((srv.sizes(&queue_name)?, srv.metadata(&queue_name)?));
+    match executor.run(sizes).await? {
+        Ok((None, _)) => Ok(HttpResponse::Ok().json(SizesResponse {
+            success: false,
+            queue: Default::default(),
+            keys: Default::default(),
+            metadata: Default::default(),
+        })),
+        Ok((Some(sizes), metadata)) => Ok(HttpResponse::Ok().json(SizesResponse {
+            success: true,
+            queue: sizes.queue,
+            keys: sizes.keys,
+            metadata,
+        })),
+        Err(e) => {
+            error!(error = %e, "sizes error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Sizes were not read",
+            ))
+        }
+    }
+}
+
+#[instrument(skip_all, fields(queue = %info.0))]
+async fn subscribers(
+    srv: web::Data<Queue<EventMessage>>,
+    registry: web::Data<SubscriberRegistry>,
+    info: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    let queue_name = info.into_inner().0;
+    let exists = srv.check_tree_exists(&queue_name);
+    Ok(HttpResponse::Ok().json(SubscribersResponse {
+        success: exists,
+        subscribers: match exists {
+            true => registry.list(&queue_name),
+            false => vec![],
+        },
+    }))
+}
+
+#[derive(Deserialize)]
+struct DisconnectQuery {
+    reason: Option<String>,
+}
+
+#[instrument(skip_all, fields(connection_id = %info.0))]
+async fn disconnect_subscriber(
+    registry: web::Data<SubscriberRegistry>,
+    info: web::Path<(String,)>,
+    query: web::Query<DisconnectQuery>,
+) -> Result<HttpResponse, Error> {
+    let connection_id = info.into_inner().0;
+    let success = registry.disconnect(&connection_id, query.into_inner().reason);
+    if success {
+        info!("disconnecting subscriber");
+    }
+    Ok(HttpResponse::Ok().json(BaseQueueResponse { success }))
+}
+
+/// Failover and replication methods of queue servers, proxies have no standbys
+fn failover_methods_factory(secure: &Option<Secure>) -> impl HttpServiceFactory {
+    let guarded = |route: Route| match secure {
+        None => route,
+        Some(s) => route.guard(service_token_guard(s)),
+    };
+    (
+        web::resource("/failover").route(guarded(web::post().to(failover))),
+        web::resource("/promote").route(guarded(web::post().to(promote))),
+        web::resource("/digest/{queue_name}").route(guarded(web::get().to(digest_queue))),
+        web::resource("/digest/{queue_name}/{uniq_id}").route(guarded(web::get().to(digest_key))),
+        web::resource("/move/{queue_name}/{uniq_id}").route(guarded(web::post().to(move_key))),
+    )
+}
+
+#[derive(Deserialize)]
+struct MoveQuery {
+    /// Address of the shard which owns the key after rebalancing
+    to: String,
+}
+
+/// Deletes the key copied to its owner by the rebalancing, subscribers are moved
+/// to the owner once the key is deleted
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn move_key(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<(String, String)>,
+    query: web::Query<MoveQuery>,
+) -> Result<HttpResponse, Error> {
+    let (queue_name, id) = info.into_inner();
+    let address = query.into_inner().to;
+    match executor
+        .run(move || srv.move_key(&queue_name, &id, &address))
+        .await?
+    {
+        Ok(()) => Ok(HttpResponse::Ok().json(BaseQueueResponse { success: true })),
+        Err(e) => {
+            error!(error = %e, "moving key error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Key was not moved",
+            ))
+        }
+    }
+}
+
+#[instrument(skip_all, fields(queue = %info.0))]
+async fn digest_queue(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    let queue_name = info.into_inner().0;
+    match executor.run(move || srv.digest_queue(queue_name)).await? {
+        Ok(keys) => Ok(HttpResponse::Ok().json(DigestResponse {
+            success: keys.is_some(),
+            keys: keys.unwrap_or_default(),
+        })),
+        Err(e) => {
+            error!(error = %e, "digest queue error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Queue digest was not calculated",
+            ))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DigestQuery {
+    #[serde(default = "default_digest_range")]
+    range: u64,
+    to: Option<u64>,
+}
+
+fn default_digest_range() -> u64 {
+    DEFAULT_DIGEST_RANGE
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn digest_key(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<(String, String)>,
+    query: web::Query<DigestQuery>,
+) -> Result<HttpResponse, Error> {
+    let (queue_name, id) = info.into_inner();
+    let DigestQuery { range, to } = query.into_inner();
+    match executor
+        .run(move || srv.digest_key(queue_name, id, range, to))
+        .await?
+    {
+        Ok(None) => Ok(HttpResponse::Ok().json(RangeDigestResponse {
+            success: false,
+            head: 0,
+            ranges: vec![],
+        })),
+        Ok(Some(digest)) => Ok(HttpResponse::Ok().json(RangeDigestResponse {
+            success: true,
+            head: digest.key().head,
+            ranges: digest.into_ranges(),
+        })),
+        Err(e) => {
+            error!(error = %e, "digest key error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Key digest was not calculated",
+            ))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct FailoverQuery {
+    address: String,
+}
+
+#[instrument(skip_all, fields(address = %query.address))]
+async fn failover(
+    srv: web::Data<Queue<EventMessage>>,
+    failover: web::Data<Failover>,
+    query: web::Query<FailoverQuery>,
+) -> Result<HttpResponse, Error> {
+    // standbys have no subscribers to move, they are promoted instead
+    if failover.is_standby() {
+        return Ok(HttpResponse::Ok().json(BaseQueueResponse { success: false }));
+    }
+
+    let address = query.into_inner().address;
+    info!("failing over");
+    failover.move_to(address.clone());
+    srv.move_subscribers(&address);
+    Ok(HttpResponse::Ok().json(BaseQueueResponse { success: true }))
+}
+
+#[instrument(skip_all)]
+async fn promote(failover: web::Data<Failover>) -> Result<HttpResponse, Error> {
+    let success = failover.promote();
+    if success {
+        info!("promoted to primary");
+    }
+    Ok(HttpResponse::Ok().json(BaseQueueResponse { success }))
+}
+
+#[derive(Deserialize)]
+struct PeekQuery {
+    #[serde(default = "default_peek_n")]
+    n: usize,
+}
+
+fn default_peek_n() -> usize {
+    10
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn peek_key(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<(String, String)>,
+    query: web::Query<PeekQuery>,
+) -> Result<HttpResponse, Error> {
+    let (queue_name, id) = info.into_inner();
+    let n = query.n;
+    match executor
+        .run(move || srv.peek_key(queue_name, id, n))
+        .await?
+    {
+        Ok(events) => Ok(HttpResponse::Ok().json(PeekResponse {
+            success: events.is_some(),
+            events: events.unwrap_or_default(),
+        })),
+        Err(e) => {
+            error!(error = %e, "peek key error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Key was not peeked",
+            ))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AckQuery {
+    sequence: SequenceId,
+    /// Resumable session of the subscriber, recorded in the message trace
+    session: Option<String>,
+}
+
+/// Confirms processing of the event to its publisher with an acked delivery receipt
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1, sequence = %query.sequence))]
+async fn ack_event(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<(String, String)>,
+    query: web::Query<AckQuery>,
+) -> Result<HttpResponse, Error> {
+    let (queue_name, id) = info.into_inner();
+    let AckQuery { sequence, session } = query.into_inner();
+    let result = executor
+        .run_publish(move || -> QueueResult<bool> {
+            let event = srv
+                .replay_key(queue_name.clone(), id.clone(), Some(sequence), None, 1)?
+                .and_then(|r| r.events.into_iter().next())
+                .filter(|e| e.sequence == Some(sequence));
+            let event = match event {
+                Some(event) => event,
+                None => return Ok(false),
+            };
+            srv.trace_ack(&queue_name, &id, sequence, session.as_deref())?;
+
+            if let Some(reply_to) = event.reply_to {
+                if srv.mark_acked(&queue_name, &id, sequence)? {
+                    let receipt = DeliveryReceipt {
+                        queue: queue_name,
+                        id,
+                        sequence,
+                        acked: true,
+                        reply_to,
+                    };
+                    if !srv.send_to_queue(receipt.reply_to.clone(), receipt.to_event())? {
+                        warn!(queue = %receipt.reply_to, "reply queue does not exist");
+                    }
+                }
+            }
+            Ok(true)
+        })
+        .await?;
+
+    match result {
+        Ok(success) => Ok(HttpResponse::Ok().json(BaseQueueResponse { success })),
+        Err(e) => {
+            error!(error = %e, "ack event error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Event was not acked",
+            ))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ReplayQuery {
+    from: Option<SequenceId>,
+    /// Hybrid logical clock timestamp as `{wall}` or `{wall}.{logical}`
+    since: Option<String>,
+    #[serde(default = "default_replay_limit")]
+    limit: usize,
+}
+
+fn default_replay_limit() -> usize {
+    100
+}
+
+#[instrument(skip_all, fields(queue = %info.0, key = %info.1))]
+async fn replay_key(
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    info: web::Path<(String, String)>,
+    query: web::Query<ReplayQuery>,
+) -> Result<HttpResponse, Error> {
+    let (queue_name, id) = info.into_inner();
+    let ReplayQuery { from, since, limit } = query.into_inner();
+    let since = since
+        .map(|s| s.parse::<HlcTimestamp>())
+        .transpose()
+        .map_err(|e| {
+            actix_web::error::ErrorBadRequest(format!("invalid since timestamp: {}", e))
+        })?;
+    match executor
+        .run(move || srv.replay_key(queue_name, id, from, since, limit))
+        .await?
+    {
+        Ok(replay) => Ok(HttpResponse::Ok().json(ReplayResponse {
+            success: replay.is_some(),
+            next: replay.as_ref().and_then(|r| r.next),
+            events: replay.map(|r| r.events).unwrap_or_default(),
+        })),
+        Err(e) => {
+            error!(error = %e, "replay key error");
+            Err(actix_web::error::ErrorInternalServerError(
+                "Key was not replayed",
+            ))
+        }
+    }
+}
+
+async fn report_stats(
+    queue: web::Data<Queue<EventMessage>>,
+    registry: web::Data<SubscriberRegistry>,
+    interval: u64,
+) {
+    let mut reporter = StatsReporter::new(interval);
+    let mut ticker = actix_web::rt::time::interval(Duration::from_secs(interval));
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        let (snapshot, subscribers) = queue.stats();
+        let report = reporter.report(
+            snapshot,
+            subscribers,
+            registry.skipped_by_clients(),
+            registry.formats(),
+        );
+
+        let event = EventMessage {
+            id: STATS_EVENT_ID.into(),
+            sequence: None,
+            payload: serde_json::to_value(report).expect("stats are serializable"),
+            tombstone: false,
+            reply_to: None,
+            supersedes: None,
+            ephemeral: false,
+            timestamp: None,
+        };
+
+        if let Err(e) = queue.send_stats(event) {
+            error!(error = %e, "sending stats error");
+        }
+    }
+}
+
+/// Publishes lifecycle events to the system queue with event names as keys
+async fn publish_lifecycle_events(
+    queue: web::Data<Queue<EventMessage>>,
+    mut events: UnboundedReceiver<LifecycleEvent>,
+) {
+    while let Some(event) = events.next().await {
+        let event = EventMessage {
+            id: event.name().into(),
+            sequence: None,
+            payload: serde_json::to_value(event).expect("lifecycle events are serializable"),
+            tombstone: false,
+            reply_to: None,
+            supersedes: None,
+            ephemeral: false,
+            timestamp: None,
+        };
+
+        if let Err(e) = queue.send_lifecycle(event) {
+            error!(error = %e, "sending lifecycle event error");
+        }
+    }
+}
+
+/// Publishes receipts of the first deliveries to the reply queues with keys of the delivered events
+async fn send_receipts(
+    queue: web::Data<Queue<EventMessage>>,
+    mut receipts: UnboundedReceiver<DeliveryReceipt>,
+) {
+    while let Some(receipt) = receipts.next().await {
+        match queue.mark_delivered(&receipt.queue, &receipt.id, receipt.sequence) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                error!(error = %e, "marking delivery error");
+                continue;
+            }
+        }
+
+        match queue.send_to_queue(receipt.reply_to.clone(), receipt.to_event()) {
+            Ok(true) => {}
+            Ok(false) => warn!(queue = %receipt.reply_to, "reply queue does not exist"),
+            Err(e) => error!(error = %e, "sending delivery receipt error"),
+        }
+    }
+}
+
+/// Counts deliveries of stored events in their traces
+async fn record_traces(
+    queue: web::Data<Queue<EventMessage>>,
+    mut deliveries: UnboundedReceiver<TracedDelivery>,
+) {
+    while let Some(delivery) = deliveries.next().await {
+        if let Err(e) = queue.trace_delivery(&delivery.queue, &delivery.id, delivery.sequence) {
+            error!(error = %e, "tracing delivery error");
+        }
+    }
+}
+
+async fn drop_expired_traces(queue: web::Data<Queue<EventMessage>>) {
+    let mut ticker = actix_web::rt::time::interval(Duration::from_secs(TRACE_PURGE_INTERVAL));
+
+    loop {
+        ticker.tick().await;
+
+        let queue = queue.clone();
+        match web::block(move || queue.drop_expired_traces()).await {
+            Ok(Ok(dropped)) if dropped > 0 => info!(dropped, "dropped expired message traces"),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!(error = %e, "dropping expired message traces error"),
+            Err(e) => error!(error = %e, "dropping expired message traces was canceled"),
+        }
+    }
+}
+
+async fn drop_expired_poll_sessions(
+    sessions: web::Data<PollSessionRegistry<EventMessage>>,
+    interval: Duration,
+) {
+    let mut ticker = actix_web::rt::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let dropped = sessions.drop_expired();
+        if dropped > 0 {
+            info!(dropped, "dropped expired poll sessions");
+        }
+    }
+}
+
+async fn tier_history(queue: web::Data<Queue<EventMessage>>, interval: u64) {
+    let mut ticker = actix_web::rt::time::interval(Duration::from_secs(interval));
+
+    loop {
+        ticker.tick().await;
+
+        let queue = queue.clone();
+        match web::block(move || queue.tier_history()).await {
+            Ok(Ok(moved)) if moved > 0 => info!(moved, "moved history to cold tier"),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!(error = %e, "moving history to cold tier error"),
+            Err(e) => error!(error = %e, "moving history to cold tier was canceled"),
+        }
+    }
+}
+
+async fn drop_expired_segments(queue: web::Data<Queue<EventMessage>>, interval: u64) {
+    let mut ticker = actix_web::rt::time::interval(Duration::from_secs(interval));
+
+    loop {
+        ticker.tick().await;
+
+        let queue = queue.clone();
+        match web::block(move || queue.drop_expired_segments()).await {
+            Ok(Ok(dropped)) if dropped > 0 => info!(dropped, "dropped expired segments"),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!(error = %e, "dropping expired segments error"),
+            Err(e) => error!(error = %e, "dropping expired segments was canceled"),
+        }
+    }
+}
+
+async fn watch_disk(queue: web::Data<Queue<EventMessage>>, interval: u64) {
+    let mut ticker = actix_web::rt::time::interval(Duration::from_secs(interval));
+
+    loop {
+        ticker.tick().await;
+
+        let queue = queue.clone();
+        match web::block(move || queue.watch_disk()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!(error = %e, "watching disk usage error"),
+            Err(e) => error!(error = %e, "watching disk usage was canceled"),
+        }
+    }
+}
+
+/// Publishes journaled events after every append, failed runs are retried
+async fn apply_journal(queue: web::Data<Queue<EventMessage>>) {
+    loop {
+        let applying = queue.clone();
+        match web::block(move || applying.apply_journal()).await {
+            Ok(Ok(_)) => queue.journal_appended().await,
+            Ok(Err(e)) => {
+                error!(error = %e, "applying publish journal error");
+                actix_web::rt::time::sleep(JOURNAL_RETRY_INTERVAL).await;
+            }
+            Err(e) => {
+                error!(error = %e, "applying publish journal was canceled");
+                actix_web::rt::time::sleep(JOURNAL_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+async fn drop_expired_dedup_ids(queue: web::Data<Queue<EventMessage>>) {
+    let mut ticker = actix_web::rt::time::interval(Duration::from_secs(DEDUP_PURGE_INTERVAL));
+
+    loop {
+        ticker.tick().await;
+
+        let queue = queue.clone();
+        match web::block(move || queue.drop_expired_dedup_ids()).await {
+            Ok(Ok(dropped)) if dropped > 0 => info!(dropped, "dropped expired dedup ids"),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!(error = %e, "dropping expired dedup ids error"),
+            Err(e) => error!(error = %e, "dropping expired dedup ids was canceled"),
+        }
+    }
+}
+
+/// GraphQL subscriptions gateway, served when the queue is built with the graphql feature
+#[cfg(feature = "graphql")]
+fn configure_graphql(cfg: &mut web::ServiceConfig) {
+    use crate::queue::graphql::{graphql_schema, subscribe_graphql};
+
+    cfg.app_data(web::Data::new(graphql_schema()))
+        .service(web::resource("/graphql").route(web::get().to(subscribe_graphql)));
+}
+
+#[cfg(not(feature = "graphql"))]
+fn configure_graphql(_cfg: &mut web::ServiceConfig) {}
+
+/// Socket.IO compatibility layer, served when the socket_io options are set
+fn configure_socket_io(cfg: &mut web::ServiceConfig, state: Option<web::Data<SocketIoState>>) {
+    if let Some(state) = state {
+        cfg.app_data(state).service(
+            web::resource(["/socket.io", "/socket.io/"]).route(web::get().to(connect_socket_io)),
+        );
+    }
+}
+
+/// Capabilities of the queue service depending on its config
+fn queue_features(queue: &sonya_meta::config::Queue) -> Vec<&'static str> {
+    let optional = [
+        (queue.identity_field.is_some(), "identity_filter"),
+        (!queue.scrub_fields.is_empty(), "scrub_fields"),
+        (!queue.schemas.is_empty(), "schemas"),
+        (!queue.routes.is_empty(), "routes"),
+        (queue.hierarchy, "hierarchy"),
+        (queue.ordered_preload, "ordered_preload"),
+        (!queue.modes.is_empty(), "queue_modes"),
+        (
+            queue.ordering.values().any(|o| *o == QueueOrdering::Strict),
+            "strict_ordering",
+        ),
+        (queue.blobs.is_some(), "blobs"),
+        (queue.tiering.is_some(), "tiering"),
+        (queue.segments.is_some(), "segments"),
+        (queue.disk.is_some(), "disk_watchdog"),
+        (queue.journal.is_some(), "journal"),
+        (queue.tracing.is_some(), "tracing"),
+        (queue.admission.is_some(), "admission"),
+        (queue.standby.is_some(), "standby"),
+        (!queue.sinks.is_empty(), "sinks"),
+        (!queue.sources.is_empty(), "sources"),
+        (queue.socket_io.is_some(), "socket_io"),
+        (queue.role == NodeRole::Ingest, "ingest_role"),
+        (queue.role == NodeRole::Delivery, "delivery_role"),
+        (queue.role == NodeRole::Replica, "replica_role"),
+        (
+            cfg!(feature = "scripting") && queue.scripts.is_some(),
+            "scripting",
+        ),
+        (cfg!(feature = "graphql"), "graphql"),
+    ];
+    [
+        "websocket",
+        "longpoll",
+        "replay",
+        "head",
+        "leases",
+        "ephemeral",
+        "spec",
+        "send_payload",
+        "send_batch",
+        "broadcast_metrics",
+        "sizes",
+        "subscriber_labels",
+        "multi_key_subscriptions",
+        "sessions",
+        "failover",
+        "backfill",
+        "dedup",
+        "acks",
+    ]
+    .into_iter()
+    .chain(
+        optional
+            .into_iter()
+            .filter_map(|(enabled, f)| enabled.then_some(f)),
+    )
+    .collect()
+}
+
+/// Checks options of the queue server together with options which depend on its features
+fn validate_config(config: &Config) -> Result<(), ConfigErrors> {
+    let mut errors = match config.validate_queue_server() {
+        Ok(()) => Vec::new(),
+        Err(e) => e.0,
+    };
+
+    if !cfg!(feature = "postgres") {
+        if !config.queue.sources.is_empty() {
+            errors.push("postgres sources require the postgres feature".into());
+        }
+        for sink in &config.queue.sinks {
+            if let SinkTarget::Postgres { .. } = sink.target {
+                errors.push(format!("sinks.{} requires the postgres feature", sink.name));
+            }
+        }
+    }
+
+    #[cfg(feature = "scripting")]
+    if let Some(scripts) = &config.queue.scripts {
+        if let Err(e) = crate::queue::script::ScriptInterceptor::new(scripts.clone()) {
+            errors.push(format!("invalid queue scripts: {}", e));
+        }
+    }
+    #[cfg(not(feature = "scripting"))]
+    if config.queue.scripts.is_some() {
+        errors.push("scripts require the scripting feature".into());
+    }
+
+    ConfigErrors::check(errors)
+}
+
+/// Exits like the failed config validation when the configured resource can't be opened
+fn exit_on_error<T, E: Display>(option: &str, result: Result<T, E>) -> T {
+    result
+        .unwrap_or_else(|e| ConfigErrors(vec![format!("{} can't be opened: {}", option, e)]).exit())
+}
+
+/// Parses and validates config without starting the server, prints effective config on success
+fn check_config(config_path: &str) -> ! {
+    let config = match load_config(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid config: {}", e);
+            std::process::exit(1)
+        }
+    };
+    if let Err(e) = validate_config(&config) {
+        e.exit()
+    }
+    match config.to_yaml() {
+        Ok(yaml) => print!("{}", yaml),
+        Err(e) => {
+            eprintln!("config rendering error: {}", e);
+            std::process::exit(1)
+        }
+    }
+    std::process::exit(0)
+}
+
+#[actix_web::main]
+async fn main() -> tokio::io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    if let Some("--check-config") = args.next().as_deref() {
+        match args.next() {
+            Some(config_path) => check_config(&config_path),
+            None => {
+                eprintln!("usage: sonya --check-config <file>");
+                std::process::exit(2)
+            }
+        }
+    }
+
+    let config = get_config();
+    if let Err(e) = validate_config(&config) {
+        e.exit()
+    }
+    let shared_config = web::Data::new(config.clone());
+    let server_info = ServerResponse::new(ServerRole::Queue, env!("CARGO_PKG_VERSION"), &config)
+        .with_storage("sled")
+        .with_features(queue_features(&config.queue));
+    let limiter = web::Data::new(ConnectionLimiter::new(config.limits.clone()));
+    let admission = web::Data::new(Admission::new(config.queue.admission.as_ref()));
+    let subscriber_registry = web::Data::new(SubscriberRegistry::default());
+    let transcoder = web::Data::new(Transcoder::default());
+    let poll_sessions = web::Data::new(PollSessionRegistry::<EventMessage>::new(
+        config.queue.poll_sessions.as_ref(),
+    ));
+    let failover = web::Data::new(Failover::new(
+        config.queue.role,
+        config.queue.standby.is_some(),
+    ));
+    let executor = web::Data::new(
+        StorageExecutor::new(
+            config.queue.max_pending_operations,
+            config.queue.operation_timeout.map(Duration::from_millis),
+        )
+        .with_publish_timeout(config.queue.publish_timeout.map(Duration::from_millis))
+        .with_subscribe_timeout(config.queue.subscribe_timeout.map(Duration::from_millis)),
+    );
+
+    let address = config
+        .addr
+        .unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080));
+    let secure = config.secure;
+    let cors = config.cors;
+    let queue_options = config.queue;
+
+    let (cx, rx) = futures::channel::oneshot::channel();
+
+    match config.service_discovery {
         #[cfg(feature = "api")]
         None | Some(ServiceDiscovery::Api { .. }) => {}
         #[cfg(feature = "etcd")]
@@ -227,46 +2155,323 @@ async fn main() -> tokio::io::Result<()> {
         }) => {
             info!("chosen etcd service discovery");
 
-            let ServiceDiscoveryInstanceOptions {
+            // instance options are required by the config validation
+            if let Some(ServiceDiscoveryInstanceOptions {
                 instance_addr,
                 instance_id,
-            } = instance_opts.expect("expected instance addr in config");
-
-            actix::spawn(
-                service_discovery::etcd::register_instance(
-                    hosts,
-                    prefix,
-                    instance_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
-                    instance_addr,
-                )
-                .inspect(|_| {
-                    let _ = cx.send(());
-                }),
-            );
+            }) = instance_opts
+            {
+                actix::spawn(
+                    service_discovery::etcd::register_instance(
+                        hosts,
+                        prefix,
+                        instance_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+                        instance_addr,
+                    )
+                    .inspect(|_| {
+                        let _ = cx.send(());
+                    }),
+                );
+            }
         }
         #[cfg(not(feature = "api"))]
         t => panic!("Invalid service discovery type accepted: {}", t.unwrap()),
     };
 
-    let queue = web::Data::new(Queue::<EventMessage>::new(queue_options).unwrap());
+    let identity_field = queue_options.identity_field.clone();
+    let schemas = SchemaRegistry::new(queue_options.schemas.clone());
+    let scrub_fields = queue_options.scrub_fields.clone();
+    let routes = queue_options.routes.clone();
+    let stats = queue_options.stats.clone();
+    let blobs = queue_options.blobs.clone();
+    let tiering = queue_options.tiering.clone();
+    let segments = queue_options.segments.clone();
+    let disk = queue_options.disk.clone();
+    let tracing = queue_options.tracing.is_some();
+    let standby = queue_options.standby.clone();
+    let role = queue_options.role;
+    let max_drain_time = Duration::from_secs(queue_options.max_drain_time);
+    let sinks = queue_options.sinks.clone();
+    #[cfg(feature = "postgres")]
+    let sources = queue_options.sources.clone();
+    let socket_io = queue_options.socket_io.clone();
+    let lifecycle_queue = queue_options.lifecycle.is_some();
+    #[cfg(feature = "scripting")]
+    let scripts = queue_options.scripts.clone();
+    let mut queue = exit_on_error("queue database", Queue::<EventMessage>::new(queue_options));
+    // events are validated as they were published by clients
+    if !schemas.is_empty() {
+        queue = queue.with_publish_interceptor(schemas);
+    }
+    if !scrub_fields.is_empty() {
+        queue = queue.with_publish_interceptor(ScrubFieldsInterceptor::new(scrub_fields));
+    }
+    #[cfg(feature = "scripting")]
+    if let Some(scripts) = scripts {
+        queue = queue.with_publish_interceptor(exit_on_error(
+            "scripts",
+            crate::queue::script::ScriptInterceptor::new(scripts),
+        ));
+    }
+    // payloads are offloaded after all transformations and inlined before filtering
+    if let Some(blobs) = blobs {
+        let store = exit_on_error("blobs.path", FsBlobStore::new(blobs.path));
+        let interceptor = BlobInterceptor::new(store, blobs.threshold);
+        queue = queue
+            .with_publish_interceptor(interceptor.clone())
+            .with_delivery_interceptor(interceptor);
+    }
+    if let Some(tiering) = &tiering {
+        let store = exit_on_error("tiering.path", FsBlobStore::new(tiering.path.clone()));
+        queue = queue.with_cold_tier(store, tiering.hot_updates);
+    }
+    if let Some(field) = identity_field {
+        if config.secure.is_none() {
+            warn!(
+                field = %field,
+                "identity filter is advisory without secure mode, subscribers may claim any identity"
+            );
+        }
+        queue = queue.with_event_filter(PayloadFieldFilter::new(field));
+    }
+    if !routes.is_empty() {
+        queue = queue.with_router(RouteRules::new(routes));
+    }
+    let mut lifecycle_receiver = None;
+    let lifecycle = if lifecycle_queue {
+        let (lifecycle, receiver) = LifecycleEvents::channel();
+        lifecycle_receiver = Some(receiver);
+        lifecycle
+    } else {
+        LifecycleEvents::default()
+    };
+    queue = queue.with_lifecycle(lifecycle.clone());
+    // receipts are sent only for deliveries which passed the filters
+    let (receipts, receipts_receiver) = ReceiptInterceptor::new();
+    queue = queue.with_delivery_interceptor(receipts);
+    let mut traces_receiver = None;
+    if tracing {
+        let (traces, receiver) = TraceInterceptor::new();
+        queue = queue.with_delivery_interceptor(traces);
+        traces_receiver = Some(receiver);
+    }
+    let queue = web::Data::new(queue);
+    let socket_io = socket_io.map(|options| {
+        web::Data::new(SocketIoState {
+            queue: queue.clone(),
+            executor: executor.clone(),
+            admission: admission.clone(),
+            limiter: limiter.clone(),
+            failover: failover.clone(),
+            options,
+        })
+    });
+
+    actix::spawn(send_receipts(queue.clone(), receipts_receiver));
+
+    if let Some(lifecycle_receiver) = lifecycle_receiver {
+        actix::spawn(publish_lifecycle_events(queue.clone(), lifecycle_receiver));
+    }
+
+    if let Some(traces_receiver) = traces_receiver {
+        actix::spawn(record_traces(queue.clone(), traces_receiver));
+        actix::spawn(drop_expired_traces(queue.clone()));
+    }
+
+    if let Some(stats) = stats {
+        actix::spawn(report_stats(
+            queue.clone(),
+            subscriber_registry.clone(),
+            stats.interval,
+        ));
+    }
+
+    if let Some(tiering) = tiering {
+        actix::spawn(tier_history(queue.clone(), tiering.interval));
+    }
+
+    if let Some(segments) = segments.filter(|s| s.retention.is_some()) {
+        // segments expire only at their boundaries, but long segments are still checked every minute
+        actix::spawn(drop_expired_segments(
+            queue.clone(),
+            segments.duration.clamp(1, 60),
+        ));
+    }
+
+    actix::spawn(drop_expired_dedup_ids(queue.clone()));
+
+    if let Some(disk) = disk {
+        actix::spawn(watch_disk(queue.clone(), disk.interval));
+    }
+
+    if queue.is_journaled() {
+        actix::spawn(apply_journal(queue.clone()));
+    }
+
+    if let Some(ttl) = poll_sessions.ttl() {
+        actix::spawn(drop_expired_poll_sessions(poll_sessions.clone(), ttl));
+    }
+
+    if let Some(standby) = standby.filter(|_| role == NodeRole::Replica) {
+        for queue_name in standby.queues.clone() {
+            actix::spawn(sync_replica(
+                queue.clone(),
+                executor.clone(),
+                standby.clone(),
+                queue_name,
+            ));
+        }
+    } else if let Some(standby) = standby {
+        for queue_name in standby.queues.clone() {
+            actix::spawn(replicate(
+                queue.clone(),
+                executor.clone(),
+                failover.clone(),
+                standby.clone(),
+                queue_name,
+            ));
+        }
+    }
+
+    for sink in sinks {
+        let option = format!("sinks.{}", sink.name);
+        let writer = exit_on_error(&option, sink_writer(sink.target.clone()));
+        actix::spawn(run_sink(
+            queue.clone(),
+            executor.clone(),
+            failover.clone(),
+            sink,
+            writer,
+        ));
+    }
+
+    #[cfg(feature = "postgres")]
+    for source in sources {
+        actix::spawn(crate::queue::source::run_source(
+            queue.clone(),
+            executor.clone(),
+            failover.clone(),
+            source,
+        ));
+    }
+
+    lifecycle.emit(LifecycleEvent::NodeJoined {
+        address: address.to_string(),
+    });
+
+    actix::spawn({
+        let queue = queue.clone();
+        async move {
+            shutdown_signal().await;
+            lifecycle.emit(LifecycleEvent::NodeLeft {
+                address: address.to_string(),
+            });
+
+            info!("flushing pending writes");
+            let flushing = queue.clone();
+            match web::block(move || flushing.flush()).await {
+                Ok(Ok(applied)) => info!(applied, "pending writes flushed"),
+                Ok(Err(e)) => error!(error = %e, "flushing pending writes error"),
+                Err(e) => error!(error = %e, "flushing pending writes was canceled"),
+            }
+
+            info!("draining subscriptions");
+            queue.drain();
+            let (subscribers, undelivered) = wait_drained(&queue, max_drain_time).await;
+            if subscribers > 0 || undelivered > 0 {
+                warn!(
+                    subscribers,
+                    undelivered, "subscriptions were not drained in time"
+                );
+            } else {
+                info!("subscriptions drained");
+            }
+            lifecycle.emit(LifecycleEvent::Drained {
+                address: address.to_string(),
+                subscribers,
+                undelivered,
+            });
+        }
+    });
 
     let server = HttpServer::new(move || {
         App::new()
-            .wrap(Logger::default())
+            .wrap_fn({
+                let failover = failover.clone();
+                move |req, srv| match failover.intercept(req.request()) {
+                    Some(response) => Either::Left(futures::future::ok(
+                        req.into_response(response).map_into_right_body(),
+                    )),
+                    None => Either::Right(
+                        srv.call(req)
+                            .map(|r| r.map(ServiceResponse::map_into_left_body)),
+                    ),
+                }
+            })
+            .wrap(Condition::new(
+                cors.is_some(),
+                get_cors_from_config(cors.clone().unwrap_or_default()),
+            ))
+            .wrap(TracingLogger::default())
             .app_data(queue.clone())
+            .app_data(shared_config.clone())
+            .app_data(limiter.clone())
+            .app_data(admission.clone())
+            .app_data(subscriber_registry.clone())
+            .app_data(transcoder.clone())
+            .app_data(poll_sessions.clone())
+            .app_data(failover.clone())
+            .app_data(executor.clone())
             .service(queue_scope_factory!(
                 create_queue,
-                delete_from_queue,
+                set_queue_metadata,
+                delete_key_history,
+                revoke_event,
+                acquire_lease,
+                release_lease,
                 send_to_queue,
-                close_queue,
+                send_payload_to_queue,
+                send_batch_to_queue,
+                ack_event,
+                drop_queue,
+                clear_queue,
+                count_key,
+                peek_key,
+                replay_key,
+                head_key,
                 subscribe_queue_by_id_ws,
                 subscribe_queue_by_id_longpoll,
                 subscribe_queue_ws,
                 subscribe_queue_longpoll,
+                subscribe_queue_by_id_ndjson,
+                subscribe_queue_ndjson,
                 &secure,
             ))
+            .service(
+                admin_scope_factory!(
+                    sequence_gaps,
+                    message_trace,
+                    broadcasts,
+                    sizes,
+                    subscribers,
+                    disconnect_subscriber,
+                    &secure,
+                    server_info.clone(),
+                )
+                .service(failover_methods_factory(&secure)),
+            )
+            .service(spec_scope_factory(
+                env!("CARGO_PKG_VERSION"),
+                secure.is_some(),
+            ))
+            .route("/queues", web::get().to(list_queues))
+            .configure(configure_graphql)
+            .configure(|cfg| configure_socket_io(cfg, socket_io.clone()))
     });
 
+    let server =
+        configure_server!(server, &config.server).shutdown_timeout(max_drain_time.as_secs());
+
     let result = futures::future::select(rx, {
         match config.tls {
             None => server.bind(address)?,
@@ -287,3 +2492,42 @@ async fn main() -> tokio::io::Result<()> {
         Either::Right((r, _)) => r,
     }
 }
+
+/// Waits until all subscriptions are closed or the max drain time passes,
+/// returns count of open subscriptions and messages which they didn't receive
+async fn wait_drained(queue: &Queue<EventMessage>, max_drain_time: Duration) -> (usize, usize) {
+    let deadline = Instant::now() + max_drain_time;
+    loop {
+        let (subscribers, undelivered) = queue.undelivered();
+        if subscribers == 0 || Instant::now() >= deadline {
+            return (subscribers, undelivered);
+        }
+        actix_web::rt::time::sleep(DRAIN_CHECK_INTERVAL).await;
+    }
+}
+
+/// Resolves on SIGINT or SIGTERM, same signals stops the http server
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                futures::future::select(
+                    Box::pin(actix_web::rt::signal::ctrl_c()),
+                    Box::pin(terminate.recv()),
+                )
+                .await;
+            }
+            Err(e) => {
+                error!(error = %e, "listening terminate signal error");
+                let _ = actix_web::rt::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = actix_web::rt::signal::ctrl_c().await;
+    }
+}
@@ -0,0 +1,141 @@
+use crate::queue::connection::BroadcastMessage;
+use crate::queue::map::Subscription;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use derive_more::Display;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use serde::Serialize;
+use sonya_meta::config::Admission as AdmissionOptions;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+/// Bounds subscription preloads running at once, so clients reconnecting after a deploy
+/// don't scan the storage all together. Waiting subscriptions are admitted in arrival order,
+/// subscriptions over the waiting limit are rejected with a jittered retry hint.
+#[derive(Debug, Default)]
+pub struct Admission {
+    preloads: Option<Arc<Semaphore>>,
+    waiting: AtomicUsize,
+    max_waiting: usize,
+    retry_hint: Option<RetryHint>,
+}
+
+impl Admission {
+    pub fn new(options: Option<&AdmissionOptions>) -> Self {
+        match options {
+            None => Default::default(),
+            Some(o) => Self {
+                preloads: Some(Arc::new(Semaphore::new(o.max_preloads))),
+                waiting: AtomicUsize::new(0),
+                max_waiting: o.max_waiting,
+                retry_hint: Some(RetryHint(o.retry_after)),
+            },
+        }
+    }
+
+    /// Waits for the turn of the subscription preload
+    pub async fn admit(&self) -> Result<AdmissionPermit, AdmissionError> {
+        let preloads = match &self.preloads {
+            None => return Ok(AdmissionPermit(None)),
+            Some(p) => p.clone(),
+        };
+
+        if let Ok(permit) = preloads.clone().try_acquire_owned() {
+            return Ok(AdmissionPermit(Some(permit)));
+        }
+
+        let waiting = self.waiting.fetch_add(1, Ordering::AcqRel);
+        let permit = match waiting < self.max_waiting {
+            true => preloads.acquire_owned().await.ok(),
+            false => None,
+        };
+        self.waiting.fetch_sub(1, Ordering::AcqRel);
+
+        match permit {
+            Some(permit) => Ok(AdmissionPermit(Some(permit))),
+            None => {
+                warn!(waiting, "subscription was not admitted");
+                Err(AdmissionError(self.retry_hint.map_or(0, |h| h.jittered())))
+            }
+        }
+    }
+
+    pub fn retry_hint(&self) -> Option<RetryHint> {
+        self.retry_hint
+    }
+}
+
+/// Suggested delay before reconnecting
+#[derive(Debug, Clone, Copy)]
+pub struct RetryHint(u64);
+
+impl RetryHint {
+    /// Spreads reconnects of many clients over `[base, 2 * base]` seconds
+    pub fn jittered(&self) -> u64 {
+        let jitter = uuid::Uuid::new_v4().as_u128() as u64 % (self.0 + 1);
+        self.0 + jitter
+    }
+}
+
+/// Turn of the subscription preload, the next subscription is admitted when it's dropped
+#[derive(Debug)]
+pub struct AdmissionPermit(Option<OwnedSemaphorePermit>);
+
+impl AdmissionPermit {
+    /// Keeps the turn until the end of the subscription preload
+    pub fn hold<T: 'static + Send>(
+        self,
+        mut subscription: Subscription<'static, T>,
+    ) -> Subscription<'static, T> {
+        let permit = match self.0 {
+            Some(permit) if subscription.preload => permit,
+            _ => return subscription,
+        };
+
+        subscription.stream = subscription.stream.map(move |mut stream| {
+            Box::pin(async_stream::stream! {
+                let mut permit = Some(permit);
+                while let Some(message) = stream.next().await {
+                    if matches!(message, BroadcastMessage::EndOfPreload(_)) {
+                        permit.take();
+                    }
+                    yield message;
+                }
+            }) as BoxStream<'static, BroadcastMessage<T>>
+        });
+        subscription
+    }
+}
+
+#[derive(Debug, Display)]
+#[display(fmt = "too many subscriptions are waiting for preload")]
+pub struct AdmissionError(u64);
+
+impl std::error::Error for AdmissionError {}
+
+impl ResponseError for AdmissionError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header((RETRY_AFTER, self.0.to_string()))
+            .json(AdmissionErrorResponse {
+                success: false,
+                reason: self.to_string(),
+                retry_after: self.0,
+            })
+    }
+}
+
+#[derive(Serialize)]
+struct AdmissionErrorResponse {
+    success: bool,
+    reason: String,
+    retry_after: u64,
+}
@@ -0,0 +1,139 @@
+use crate::queue::filter::SubscriberInfo;
+use crate::queue::interceptor::{DeliveryInterceptor, InterceptorError, PublishInterceptor};
+use serde_json::{json, Value};
+use sonya_meta::message::EventMessage;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{error, warn};
+
+const BLOB_REFERENCE_FIELD: &str = "$blob";
+
+/// External storage for large payloads
+pub trait BlobStore: Debug + Send + Sync {
+    /// Stores the blob and returns its reference
+    fn put(&self, data: &[u8]) -> std::io::Result<String>;
+    fn get(&self, reference: &str) -> std::io::Result<Vec<u8>>;
+    fn remove(&self, reference: &str) -> std::io::Result<()>;
+}
+
+/// Stores blobs as files of the directory
+#[derive(Debug, Clone)]
+pub struct FsBlobStore {
+    path: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(path: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+
+    fn blob_path(&self, reference: &str) -> std::io::Result<PathBuf> {
+        // references are generated uuids, anything else must not escape the directory
+        if uuid::Uuid::parse_str(reference).is_err() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid blob reference",
+            ));
+        }
+        Ok(self.path.join(reference))
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    fn put(&self, data: &[u8]) -> std::io::Result<String> {
+        let reference = uuid::Uuid::new_v4().to_string();
+        // blob becomes visible only when it is fully written
+        let tmp = self.path.join(format!("{}.tmp", reference));
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(tmp, self.path.join(&reference))?;
+        Ok(reference)
+    }
+
+    fn get(&self, reference: &str) -> std::io::Result<Vec<u8>> {
+        std::fs::read(self.blob_path(reference)?)
+    }
+
+    fn remove(&self, reference: &str) -> std::io::Result<()> {
+        std::fs::remove_file(self.blob_path(reference)?)
+    }
+}
+
+/// Moves payloads above the threshold into the blob store, so only references are stored
+/// and broadcasted. Payloads are inlined back on delivery.
+#[derive(Debug, Clone)]
+pub struct BlobInterceptor {
+    store: Arc<dyn BlobStore>,
+    threshold: usize,
+}
+
+impl BlobInterceptor {
+    pub fn new(store: impl BlobStore + 'static, threshold: usize) -> Self {
+        Self {
+            store: Arc::new(store),
+            threshold,
+        }
+    }
+
+    /// Returns the blob payload, if the payload is a blob reference
+    fn inline(&self, payload: &Value) -> Option<std::io::Result<Value>> {
+        let reference = get_reference(payload)?;
+        Some(
+            self.store
+                .get(reference)
+                .and_then(|data| serde_json::from_slice(&data).map_err(std::io::Error::from)),
+        )
+    }
+}
+
+impl PublishInterceptor<EventMessage> for BlobInterceptor {
+    fn on_publish(
+        &self,
+        _queue_name: &str,
+        mut event: EventMessage,
+    ) -> Result<Vec<EventMessage>, InterceptorError> {
+        let data =
+            serde_json::to_vec(&event.payload).map_err(|e| InterceptorError(e.to_string()))?;
+        if data.len() <= self.threshold {
+            return Ok(vec![event]);
+        }
+
+        match self.store.put(&data) {
+            Ok(reference) => {
+                event.payload = json!({ BLOB_REFERENCE_FIELD: reference, "size": data.len() })
+            }
+            Err(e) => warn!(
+                error = %e,
+                key = %event.id,
+                "blob offloading error, payload is stored inline"
+            ),
+        }
+        Ok(vec![event])
+    }
+}
+
+impl DeliveryInterceptor<EventMessage> for BlobInterceptor {
+    fn on_deliver(
+        &self,
+        _subscriber: &SubscriberInfo,
+        mut event: EventMessage,
+    ) -> Option<EventMessage> {
+        match self.inline(&event.payload) {
+            Some(Ok(payload)) => event.payload = payload,
+            Some(Err(e)) => {
+                error!(error = %e, key = %event.id, "blob inlining error, reference is delivered")
+            }
+            None => {}
+        }
+        Some(event)
+    }
+}
+
+fn get_reference(payload: &Value) -> Option<&str> {
+    payload
+        .as_object()
+        .filter(|p| p.len() == 2 && p.contains_key("size"))?
+        .get(BLOB_REFERENCE_FIELD)?
+        .as_str()
+}
@@ -0,0 +1,143 @@
+use sonya_meta::config::PreloadCache as PreloadCacheOptions;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Recently preloaded key histories. Reconnecting subscribers of hot keys take them from memory
+/// instead of scanning and decoding the same storage ranges again.
+#[derive(Debug)]
+pub struct PreloadCache<T> {
+    capacity: usize,
+    max_events: usize,
+    fills: AtomicU64,
+    entries: Mutex<CacheEntries<T>>,
+}
+
+#[derive(Debug)]
+struct CacheEntries<T> {
+    keys: HashMap<(String, String), HashMap<Option<u64>, CachedRange<T>>>,
+    /// Ranges in the fill order, the oldest ones are evicted first
+    order: VecDeque<(String, String, Option<u64>)>,
+}
+
+#[derive(Debug)]
+enum CachedRange<T> {
+    /// Range is scanned by the subscription with the fill number
+    Filling(u64),
+    Filled(Arc<Vec<T>>),
+}
+
+/// Range of the key history, which is stored to the cache after the scan.
+/// Ranges invalidated during the scan are not stored.
+#[derive(Debug)]
+pub struct CacheFill {
+    queue_name: String,
+    id: String,
+    from: Option<u64>,
+    fill: u64,
+}
+
+impl<T: Clone> PreloadCache<T> {
+    pub fn new(options: &PreloadCacheOptions) -> Self {
+        Self {
+            capacity: options.capacity,
+            max_events: options.max_events,
+            fills: Default::default(),
+            entries: Mutex::new(CacheEntries {
+                keys: Default::default(),
+                order: Default::default(),
+            }),
+        }
+    }
+
+    /// Longer histories are streamed from the storage without caching
+    pub fn max_events(&self) -> usize {
+        self.max_events
+    }
+
+    /// Returns the cached history of the key since the `from` sequence, the first one if `None`.
+    /// Otherwise the range is marked as filling, so it's stored only if it wasn't changed.
+    pub fn get_or_fill(
+        &self,
+        queue_name: &str,
+        id: &str,
+        from: Option<u64>,
+    ) -> Result<Vec<T>, CacheFill> {
+        let key = (queue_name.to_string(), id.to_string());
+        let mut entries = self.entries.lock().unwrap();
+
+        let cached = entries.keys.get(&key).and_then(|ranges| ranges.get(&from));
+        if let Some(CachedRange::Filled(items)) = cached {
+            return Ok(items.as_ref().clone());
+        }
+
+        let fill = self.fills.fetch_add(1, Ordering::Relaxed);
+        entries
+            .keys
+            .entry(key)
+            .or_default()
+            .insert(from, CachedRange::Filling(fill));
+        entries
+            .order
+            .push_back((queue_name.to_string(), id.to_string(), from));
+        entries.evict(self.capacity);
+
+        Err(CacheFill {
+            queue_name: queue_name.to_string(),
+            id: id.to_string(),
+            from,
+            fill,
+        })
+    }
+
+    /// Stores the scanned range, if it's still marked as filling by the same subscription
+    pub fn store(&self, fill: CacheFill, items: Vec<T>) {
+        let mut entries = self.entries.lock().unwrap();
+        let range = entries
+            .keys
+            .get_mut(&(fill.queue_name, fill.id))
+            .and_then(|r| r.get_mut(&fill.from));
+
+        if let Some(range) = range {
+            if matches!(range, CachedRange::Filling(f) if *f == fill.fill) {
+                *range = CachedRange::Filled(Arc::new(items));
+            }
+        }
+    }
+
+    /// Forgets cached ranges of the key, or of the whole queue if `id` is `None`
+    pub fn invalidate(&self, queue_name: &str, id: Option<&str>) {
+        let mut entries = self.entries.lock().unwrap();
+        match id {
+            Some(id) => {
+                entries
+                    .keys
+                    .remove(&(queue_name.to_string(), id.to_string()));
+            }
+            None => entries.keys.retain(|(q, _), _| q != queue_name),
+        }
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().keys.clear();
+    }
+}
+
+impl<T> CacheEntries<T> {
+    fn evict(&mut self, capacity: usize) {
+        while self.order.len() > capacity {
+            let (queue_name, id, from) = match self.order.pop_front() {
+                None => return,
+                Some(range) => range,
+            };
+
+            let key = (queue_name, id);
+            if let Some(ranges) = self.keys.get_mut(&key) {
+                ranges.remove(&from);
+                if ranges.is_empty() {
+                    self.keys.remove(&key);
+                }
+            }
+        }
+    }
+}
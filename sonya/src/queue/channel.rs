@@ -0,0 +1,125 @@
+use crate::queue::connection::BroadcastMessage;
+use futures::Stream;
+use sonya_meta::response::ChannelMetrics;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::{RecvError, SendError};
+use tokio::sync::broadcast::{channel, Receiver, Sender};
+
+/// Count of messages kept for slow receivers of one channel
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Broadcast channel of the queue or the key, which counts messages
+/// nobody received and messages skipped by lagging receivers
+#[derive(Debug)]
+pub struct Channel<T> {
+    sender: Sender<BroadcastMessage<T>>,
+    counters: Arc<ChannelCounters>,
+}
+
+#[derive(Debug, Default)]
+struct ChannelCounters {
+    sent: AtomicU64,
+    unsent: AtomicU64,
+    dropped: AtomicU64,
+    lagged: AtomicU64,
+}
+
+impl<T: Clone> Channel<T> {
+    pub fn new() -> Self {
+        Self {
+            sender: channel(CHANNEL_CAPACITY).0,
+            counters: Default::default(),
+        }
+    }
+
+    pub fn send(
+        &self,
+        message: BroadcastMessage<T>,
+    ) -> Result<usize, SendError<BroadcastMessage<T>>> {
+        let result = self.sender.send(message);
+        match result {
+            Ok(_) => self.counters.sent.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.counters.unsent.fetch_add(1, Ordering::Relaxed),
+        };
+        result
+    }
+
+    pub fn subscribe(&self) -> ChannelReceiver<T> {
+        ChannelReceiver {
+            receiver: self.sender.subscribe(),
+            counters: self.counters.clone(),
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// Messages which are not received by every receiver yet
+    pub fn pending(&self) -> usize {
+        self.sender.len()
+    }
+
+    pub fn metrics(&self) -> ChannelMetrics {
+        ChannelMetrics {
+            receivers: self.sender.receiver_count(),
+            sent: self.counters.sent.load(Ordering::Relaxed),
+            unsent: self.counters.unsent.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            lagged: self.counters.lagged.load(Ordering::Relaxed),
+            capacity: CHANNEL_CAPACITY,
+        }
+    }
+}
+
+impl<T: Clone> Default for Channel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct ChannelReceiver<T> {
+    receiver: Receiver<BroadcastMessage<T>>,
+    counters: Arc<ChannelCounters>,
+}
+
+impl<T: Clone + Send> ChannelReceiver<T> {
+    /// Streams broadcast messages or counts of skipped messages until the sender is closed
+    pub fn into_stream(self) -> impl Stream<Item = Result<BroadcastMessage<T>, u64>> {
+        futures::stream::unfold(self, |mut this| async move {
+            match this.receiver.recv().await {
+                Ok(message) => Some((Ok(message), this)),
+                Err(RecvError::Lagged(skipped)) => {
+                    this.counters.dropped.fetch_add(skipped, Ordering::Relaxed);
+                    this.counters.lagged.fetch_add(1, Ordering::Relaxed);
+                    Some((Err(skipped), this))
+                }
+                Err(RecvError::Closed) => None,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[test]
+    fn lagging_receiver_is_counted() {
+        let channel = Channel::<u64>::new();
+        let mut stream = Box::pin(channel.subscribe().into_stream());
+
+        for i in 0..=CHANNEL_CAPACITY as u64 {
+            channel.send(BroadcastMessage::Message(i)).unwrap();
+        }
+
+        let skipped = futures::executor::block_on(stream.next());
+        assert!(matches!(skipped, Some(Err(1))));
+
+        let metrics = channel.metrics();
+        assert_eq!(metrics.lagged, 1);
+        assert_eq!(metrics.dropped, 1);
+    }
+}
@@ -0,0 +1,101 @@
+use sonya_meta::message::HlcTimestamp;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Source of the current time for retention, dedup windows, leases and head marks
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// Seconds since the unix epoch
+    fn unix_secs(&self) -> u64 {
+        self.since_epoch().as_secs()
+    }
+
+    /// Milliseconds since the unix epoch
+    fn unix_millis(&self) -> u64 {
+        self.since_epoch().as_millis() as u64
+    }
+
+    fn since_epoch(&self) -> Duration {
+        self.now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// Wall clock of the system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock which moves only when it is advanced, so expiration is tested without sleeping.
+/// Clones share the time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl ManualClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(SystemTime::UNIX_EPOCH)
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Hybrid logical clock of the server, its timestamps never go back even if the wall clock does.
+/// Observed timestamps of other servers and publishers move the clock forward,
+/// so events published after receiving an event are ordered after it.
+#[derive(Debug, Default)]
+pub struct HybridClock {
+    last: Mutex<HlcTimestamp>,
+}
+
+impl HybridClock {
+    /// Timestamp of a new event, after all issued timestamps and the observed one
+    pub fn tick_after(&self, clock: &dyn Clock, observed: Option<HlcTimestamp>) -> HlcTimestamp {
+        let mut last = self.last.lock().unwrap();
+        let latest = (*last).max(observed.unwrap_or(*last));
+        let wall = clock.unix_millis();
+        *last = match wall > latest.wall {
+            true => HlcTimestamp { wall, logical: 0 },
+            false => HlcTimestamp {
+                wall: latest.wall,
+                logical: latest.logical.saturating_add(1),
+            },
+        };
+        *last
+    }
+
+    /// Moves the clock past the timestamp of a replicated event, which keeps its own timestamp
+    pub fn observe(&self, observed: HlcTimestamp) {
+        let mut last = self.last.lock().unwrap();
+        *last = (*last).max(observed);
+    }
+}
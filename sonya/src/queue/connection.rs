@@ -1,22 +1,230 @@
+use crate::queue::admission::RetryHint;
+use crate::queue::reaper::Reaper;
+use crate::queue::session::{SessionCursor, CURSOR_SAVE_INTERVAL};
+use crate::queue::subscribers::SubscriberHandle;
 use actix::prelude::*;
 use actix_web_actors::ws;
 use actix_web_actors::ws::{CloseCode, CloseReason};
-use log::{error, info};
 use serde::Serialize;
-use sonya_meta::message::UniqId;
+use sonya_meta::api::JwtSession;
+use sonya_meta::close::QueueCloseReason;
+use sonya_meta::encoding::{DeliveryFormat, Transcoder};
+use sonya_meta::limit::ConnectionGuard;
+use sonya_meta::message::{ClientControlMessage, ControlMessage, SequenceId, UniqId};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info, warn, Span};
 
 pub struct QueueConnection<S> {
     id: Option<String>,
     queue_name: String,
     queue: Option<S>,
+    heartbeat_interval: Option<Duration>,
+    last_sent: Instant,
+    last_received: Instant,
+    reaper: Option<Reaper>,
+    max_skipped_messages: Option<u64>,
+    skipped_messages: u64,
+    _connection_guard: ConnectionGuard,
+    jwt_session: Option<JwtSession>,
+    expiration_handle: Option<SpawnHandle>,
+    retry_hint: Option<RetryHint>,
+    subscriber: Option<SubscriberHandle>,
+    session: Option<SessionCursor>,
+    format: DeliveryFormat,
+    transcoder: Arc<Transcoder>,
+    span: Span,
 }
 
 impl<S> QueueConnection<S> {
-    pub fn new(id: Option<String>, queue_name: String, queue: S) -> Self {
+    pub fn new(
+        id: Option<String>,
+        queue_name: String,
+        queue: S,
+        heartbeat_interval: Option<Duration>,
+        max_skipped_messages: Option<u64>,
+        connection_guard: ConnectionGuard,
+        jwt_session: Option<JwtSession>,
+        span: Span,
+    ) -> Self {
         Self {
             id,
             queue_name,
             queue: Some(queue),
+            heartbeat_interval,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            reaper: None,
+            max_skipped_messages,
+            skipped_messages: 0,
+            _connection_guard: connection_guard,
+            jwt_session,
+            expiration_handle: None,
+            retry_hint: None,
+            subscriber: None,
+            session: None,
+            format: DeliveryFormat::Json,
+            transcoder: Default::default(),
+            span,
+        }
+    }
+
+    /// Suggests clients a jittered delay before reconnecting when the server is draining
+    pub fn with_retry_hint(mut self, retry_hint: Option<RetryHint>) -> Self {
+        self.retry_hint = retry_hint;
+        self
+    }
+
+    /// Pings the client on every heartbeat interval and closes the connection
+    /// if nothing was received from the client for the reaper timeout
+    pub fn with_reaper(mut self, reaper: Option<Reaper>) -> Self {
+        self.reaper = reaper;
+        self
+    }
+
+    /// Lists the connection with client id and labels while it's open
+    pub fn with_subscriber(mut self, subscriber: Option<SubscriberHandle>) -> Self {
+        self.subscriber = subscriber;
+        self
+    }
+
+    /// Stores delivered sequences, so the session is resumed after reconnects
+    pub fn with_session(mut self, session: Option<SessionCursor>) -> Self {
+        self.session = session;
+        self
+    }
+
+    /// Sends events with the negotiated format, transcoders are shared by connections,
+    /// so every event is encoded once per format
+    pub fn with_format(mut self, format: DeliveryFormat, transcoder: Arc<Transcoder>) -> Self {
+        self.format = format;
+        self.transcoder = transcoder;
+        self
+    }
+
+    fn save_session(&mut self) {
+        if let Some(session) = &mut self.session {
+            if let Err(e) = session.save() {
+                error!(parent: &self.span, error = %e, "saving session cursor error");
+            }
+        }
+    }
+}
+
+impl<S, T> QueueConnection<S>
+where
+    S: 'static + Stream<Item = BroadcastMessage<T>> + Unpin,
+    T: 'static + Serialize + UniqId,
+{
+    /// Sends the serialized json event, json subscriptions get text frames and others binary ones
+    fn send(&self, event: String, ctx: &mut <Self as Actor>::Context) -> bool {
+        if self.format == DeliveryFormat::Json {
+            ctx.text(event);
+            return true;
+        }
+
+        match self.transcoder.transcode(&event, self.format) {
+            Ok(encoded) => {
+                ctx.binary(encoded);
+                true
+            }
+            Err(err) => {
+                let format = self.format;
+                error!(parent: &self.span, error = %err, format = %format, "transcoding error");
+                false
+            }
+        }
+    }
+
+    fn close(&self, reason: QueueCloseReason, ctx: &mut <Self as Actor>::Context) {
+        info!(parent: &self.span, reason = %reason, "closing connection");
+        ctx.close(Some(CloseReason {
+            code: CloseCode::from(reason.code()),
+            description: Some(reason.to_string()),
+        }));
+        ctx.stop()
+    }
+
+    /// Closes the connection on the admin request, the given reason is sent to the client
+    fn disconnect(&self, description: Option<String>, ctx: &mut <Self as Actor>::Context) {
+        let reason = QueueCloseReason::Disconnected;
+        let description = match description {
+            Some(description) => format!("{}: {}", reason, description),
+            None => reason.to_string(),
+        };
+        info!(parent: &self.span, reason = %description, "closing connection");
+        ctx.close(Some(CloseReason {
+            code: CloseCode::from(reason.code()),
+            description: Some(description),
+        }));
+        ctx.stop()
+    }
+
+    /// Closes the connection of the draining server. Every connection gets own delay,
+    /// so clients don't reconnect all at once.
+    fn drain(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let retry_after = self.retry_hint.map(|hint| hint.jittered());
+        match serde_json::to_string(&ControlMessage::Draining { retry_after }) {
+            Ok(s) => {
+                self.send(s, ctx);
+            }
+            Err(err) => error!(parent: &self.span, error = %err, "serialization error"),
+        }
+
+        let reason = QueueCloseReason::Draining;
+        let description = match retry_after {
+            Some(retry_after) => format!("{}, retry after {}s", reason, retry_after),
+            None => reason.to_string(),
+        };
+        info!(parent: &self.span, reason = %reason, retry_after, "closing connection");
+        ctx.close(Some(CloseReason {
+            code: CloseCode::from(reason.code()),
+            description: Some(description),
+        }));
+        ctx.stop()
+    }
+
+    fn schedule_expiration(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if let Some(handle) = self.expiration_handle.take() {
+            ctx.cancel_future(handle);
+        }
+
+        if let Some(session) = &self.jwt_session {
+            let ttl = session
+                .expiration
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            self.expiration_handle = Some(ctx.run_later(ttl, |act, ctx| {
+                act.close(QueueCloseReason::AuthExpired, ctx)
+            }));
+        }
+    }
+
+    fn refresh_token(&mut self, access_token: &str, ctx: &mut <Self as Actor>::Context) {
+        let id = match &self.id {
+            Some(id) => id.as_str(),
+            None => return,
+        };
+
+        let expiration = self
+            .jwt_session
+            .as_mut()
+            .and_then(|session| session.refresh(access_token, &self.queue_name, id));
+
+        match expiration {
+            Some(expiration) => {
+                self.schedule_expiration(ctx);
+                let expiration = expiration
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                <Self as StreamHandler<BroadcastMessage<T>>>::handle(
+                    self,
+                    BroadcastMessage::TokenRefreshed(expiration),
+                    ctx,
+                )
+            }
+            None => self.close(QueueCloseReason::AuthExpired, ctx),
         }
     }
 }
@@ -31,19 +239,49 @@ where
     fn started(&mut self, ctx: &mut Self::Context) {
         ctx.add_stream(self.queue.take().expect("queue is none"));
 
-        info!(
-            "created connection for queue: {}, id: {}",
-            self.queue_name,
-            self.id.clone().unwrap_or_else(|| "none".to_owned())
-        );
+        if let Some(interval) = self.heartbeat_interval {
+            // heartbeats are sent only when the subscription was idle for the whole interval
+            ctx.run_interval(interval, move |act, ctx| {
+                if let Some(reaper) = &act.reaper {
+                    // half-open connections don't fail writes until TCP retransmissions give up
+                    if reaper.reap(act.last_received) {
+                        warn!(parent: &act.span, "reaped dead connection");
+                        ctx.stop();
+                        return;
+                    }
+                    ctx.ping(b"");
+                }
+                if act.last_sent.elapsed() >= interval {
+                    <Self as StreamHandler<BroadcastMessage<T>>>::handle(
+                        act,
+                        BroadcastMessage::Heartbeat,
+                        ctx,
+                    )
+                }
+            });
+        }
+
+        self.schedule_expiration(ctx);
+
+        if self.session.is_some() {
+            ctx.run_interval(CURSOR_SAVE_INTERVAL, |act, _| act.save_session());
+        }
+
+        if let Some(subscriber) = &self.subscriber {
+            ctx.spawn(
+                subscriber
+                    .disconnected()
+                    .into_actor(self)
+                    .map(|reason, act, ctx| act.disconnect(reason, ctx)),
+            );
+        }
+
+        info!(parent: &self.span, "created connection");
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
-        info!(
-            "closed connection for queue: {}, id: {}",
-            self.queue_name,
-            self.id.clone().unwrap_or_else(|| "none".to_owned())
-        );
+        self.save_session();
+        info!(parent: &self.span, "closed connection");
     }
 }
 
@@ -53,12 +291,22 @@ where
     T: 'static + Serialize + UniqId,
 {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        self.last_received = Instant::now();
         match msg {
             Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
             Ok(ws::Message::Close(reason)) => {
                 ctx.close(reason);
                 ctx.stop();
             }
+            Ok(ws::Message::Text(text)) => {
+                match serde_json::from_str::<ClientControlMessage>(&text) {
+                    Ok(ClientControlMessage::RefreshToken { access_token }) => {
+                        self.refresh_token(&access_token, ctx)
+                    }
+                    Err(e) => warn!(parent: &self.span, error = %e, "invalid client message"),
+                }
+            }
+            Err(ws::ProtocolError::Overflow) => self.close(QueueCloseReason::MessageTooLarge, ctx),
             Err(_) => ctx.stop(),
             _ => (),
         }
@@ -71,42 +319,118 @@ where
     T: 'static + Serialize + UniqId,
 {
     fn handle(&mut self, message: BroadcastMessage<T>, ctx: &mut Self::Context) {
+        if let BroadcastMessage::Draining = message {
+            return self.drain(ctx);
+        }
+
+        let (serialized, sequence) = match &message {
+            BroadcastMessage::Message(m) => (serde_json::to_string(m), m.get_sequence()),
+            control => (serde_json::to_string(&control.control()), None),
+        };
+
+        match serialized {
+            Ok(s) => {
+                info!(parent: &self.span, sequence, event = %s, "accepted message");
+                if !self.send(s, ctx) {
+                    ctx.close(Some(CloseReason::from(CloseCode::Error)));
+                    ctx.stop();
+                    return;
+                }
+                self.last_sent = Instant::now();
+                if let (Some(session), Some(sequence)) = (&mut self.session, sequence) {
+                    session.deliver(sequence);
+                }
+            }
+            Err(err) => {
+                error!(parent: &self.span, error = %err, "serialization error");
+                ctx.close(Some(CloseReason::from((CloseCode::Error, err.to_string()))));
+                ctx.stop();
+                return;
+            }
+        }
+
         match message {
-            BroadcastMessage::Message(m) => {
-                let serialized = serde_json::to_string(&m);
-                match serialized {
-                    Ok(s) => {
-                        info!(
-                            "accepted message for queue: {}, id: {}, message: {}",
-                            self.queue_name,
-                            self.id.clone().unwrap_or_else(|| "none".to_owned()),
-                            s
-                        );
-                        ctx.text(s)
-                    }
-                    Err(err) => {
-                        error!(
-                            "serialization error for queue: {}, id: {}, error: {}",
-                            self.queue_name,
-                            self.id.clone().unwrap_or_else(|| "none".to_owned()),
-                            err
-                        );
-                        ctx.close(Some(CloseReason::from((CloseCode::Error, err.to_string()))));
-                        ctx.stop()
+            BroadcastMessage::Lagged(skipped) => {
+                self.skipped_messages += skipped;
+                let client = self.subscriber.as_ref().map(|s| {
+                    s.add_skipped(skipped);
+                    s.client()
+                });
+                warn!(
+                    parent: &self.span,
+                    skipped,
+                    total_skipped = self.skipped_messages,
+                    client_id = ?client.and_then(|c| c.client_id.as_ref()),
+                    labels = ?client.map(|c| &c.labels),
+                    "slow consumer"
+                );
+
+                if let Some(max) = self.max_skipped_messages {
+                    if self.skipped_messages > max {
+                        self.close(QueueCloseReason::SlowConsumer, ctx)
                     }
                 }
             }
-            BroadcastMessage::Close => {
-                ctx.close(Some(CloseReason::from(CloseCode::Normal)));
-                ctx.stop()
-            }
+            BroadcastMessage::QueueClosed => self.close(QueueCloseReason::QueueClosed, ctx),
+            BroadcastMessage::KeyDeleted => self.close(QueueCloseReason::KeyDeleted, ctx),
+            BroadcastMessage::Moved(_) => self.close(QueueCloseReason::Moved, ctx),
+            BroadcastMessage::PreloadFailed => self.close(QueueCloseReason::PreloadFailed, ctx),
+            _ => {}
         }
     }
 }
 
-#[derive(Message, Clone)]
+#[derive(Message, Clone, Debug)]
 #[rtype(result = "()")]
 pub enum BroadcastMessage<T> {
     Message(T),
-    Close,
+    /// Keep-alive event for idle subscriptions
+    Heartbeat,
+    /// Subscriber was too slow and skipped some messages
+    Lagged(u64),
+    /// All requested history was sent, next messages are live.
+    /// Contains the sequence to continue the limited preload from.
+    EndOfPreload(Option<SequenceId>),
+    /// Terminal event, queue was dropped
+    QueueClosed,
+    /// Terminal event, key history was deleted
+    KeyDeleted,
+    /// Event of the key was revoked
+    Revoked(String, SequenceId),
+    /// Terminal event, server is shutting down
+    Draining,
+    /// Jwt session was extended up to the expiration time in seconds
+    TokenRefreshed(u64),
+    /// Terminal event, server failed over to the address
+    Moved(String),
+    /// Terminal event, history preload failed or timed out
+    PreloadFailed,
+}
+
+impl<T> BroadcastMessage<T> {
+    /// Returns wire representation of control events, `None` for data messages
+    pub fn control(&self) -> Option<ControlMessage> {
+        match self {
+            BroadcastMessage::Message(_) => None,
+            BroadcastMessage::Heartbeat => Some(ControlMessage::Heartbeat),
+            BroadcastMessage::Lagged(skipped) => Some(ControlMessage::Lagged { skipped: *skipped }),
+            BroadcastMessage::EndOfPreload(continuation) => Some(ControlMessage::EndOfPreload {
+                continuation: *continuation,
+            }),
+            BroadcastMessage::QueueClosed => Some(ControlMessage::QueueClosed),
+            BroadcastMessage::KeyDeleted => Some(ControlMessage::KeyDeleted),
+            BroadcastMessage::Revoked(id, sequence) => Some(ControlMessage::Revoked {
+                id: id.clone(),
+                sequence: *sequence,
+            }),
+            BroadcastMessage::Draining => Some(ControlMessage::Draining { retry_after: None }),
+            BroadcastMessage::TokenRefreshed(expiration) => Some(ControlMessage::TokenRefreshed {
+                expiration: *expiration,
+            }),
+            BroadcastMessage::Moved(address) => Some(ControlMessage::Moved {
+                address: address.clone(),
+            }),
+            BroadcastMessage::PreloadFailed => Some(ControlMessage::PreloadFailed),
+        }
+    }
 }
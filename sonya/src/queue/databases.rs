@@ -0,0 +1,79 @@
+use crate::queue::map::QueueMap;
+use crate::queue::segment::tree_queue_name;
+use sled::IVec;
+
+/// Databases of queue events. Queues are stored in the database of the longest matching
+/// name prefix, e.g. on a faster disk, other queues and metadata of all queues
+/// are stored in the main database.
+#[derive(Debug, Clone)]
+pub struct Databases {
+    main: QueueMap,
+    /// Ordered from the longest prefix
+    prefixed: Vec<(String, QueueMap)>,
+}
+
+impl Databases {
+    pub fn new(main: QueueMap, mut prefixed: Vec<(String, QueueMap)>) -> Self {
+        prefixed.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Self { main, prefixed }
+    }
+
+    /// Returns the database of the queue events
+    pub fn route(&self, queue_name: &[u8]) -> &QueueMap {
+        self.prefixed
+            .iter()
+            .find(|(prefix, _)| queue_name.starts_with(prefix.as_bytes()))
+            .map_or(&self.main, |(_, db)| db)
+    }
+
+    /// Returns the database of the queue, segment or offsets tree
+    pub fn tree_db(&self, name: &[u8]) -> &QueueMap {
+        self.route(tree_queue_name(name))
+    }
+
+    /// Returns names of event trees of all databases.
+    /// Trees left in other databases after changes of prefixes are skipped
+    pub fn tree_names(&self) -> Vec<IVec> {
+        self.all_trees()
+            .filter(|(db, name)| self.is_routed(db, name))
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    /// Returns names of event trees which are stored in other databases than routed ones
+    pub fn stranded_tree_names(&self) -> Vec<IVec> {
+        self.all_trees()
+            .filter(|(db, name)| !self.is_routed(db, name))
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    /// Returns total size of all databases in bytes
+    pub fn size_on_disk(&self) -> sled::Result<u64> {
+        self.databases()
+            .try_fold(0, |size, db| Ok(size + db.size_on_disk()?))
+    }
+
+    /// Syncs all databases to the disk
+    pub fn flush(&self) -> sled::Result<()> {
+        self.databases().try_for_each(|db| db.flush().map(|_| ()))
+    }
+
+    /// Returns the main database and then prefixed ones
+    pub fn databases(&self) -> impl DoubleEndedIterator<Item = &QueueMap> {
+        std::iter::once(&self.main).chain(self.prefixed.iter().map(|(_, db)| db))
+    }
+
+    fn all_trees(&self) -> impl Iterator<Item = (&QueueMap, IVec)> {
+        self.databases().flat_map(|db| {
+            db.tree_names()
+                .into_iter()
+                .filter(move |name| name != &db.name())
+                .map(move |name| (db, name))
+        })
+    }
+
+    fn is_routed(&self, db: &QueueMap, name: &[u8]) -> bool {
+        std::ptr::eq(self.tree_db(name), db)
+    }
+}
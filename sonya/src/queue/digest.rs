@@ -0,0 +1,67 @@
+use sonya_meta::response::{KeyDigest, RangeDigest};
+use std::collections::BTreeMap;
+
+/// Sequences covered by one range checksum if the request sets no range
+pub const DEFAULT_DIGEST_RANGE: u64 = 1024;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Checksums of stored key entries over sequence ranges aligned by the range length,
+/// so servers compare ranges without agreeing on their bounds.
+/// Entries are summed in any order, which lets hot and cold entries be added as they are read.
+#[derive(Debug)]
+pub struct Digest {
+    range: u64,
+    head: u64,
+    ranges: BTreeMap<u64, RangeDigest>,
+}
+
+impl Digest {
+    pub fn new(range: u64) -> Self {
+        Self {
+            range: range.max(1),
+            head: 0,
+            ranges: Default::default(),
+        }
+    }
+
+    /// Adds the entry with its stored encoding
+    pub fn add(&mut self, sequence: u64, value: &[u8]) {
+        let index = sequence.saturating_sub(1) / self.range;
+        let range = self.range;
+        let digest = self.ranges.entry(index).or_insert_with(|| RangeDigest {
+            from: index * range + 1,
+            to: index.saturating_add(1).saturating_mul(range),
+            count: 0,
+            checksum: 0,
+        });
+        digest.count += 1;
+        digest.checksum = digest
+            .checksum
+            .wrapping_add(fnv(fnv(FNV_OFFSET, &sequence.to_be_bytes()), value));
+        self.head = self.head.max(sequence);
+    }
+
+    /// Root checksum of the key, equal on servers with equal ranges
+    pub fn key(&self) -> KeyDigest {
+        KeyDigest {
+            head: self.head,
+            count: self.ranges.values().map(|r| r.count).sum(),
+            checksum: self.ranges.values().fold(FNV_OFFSET, |hash, r| {
+                fnv(fnv(hash, &r.from.to_be_bytes()), &r.checksum.to_be_bytes())
+            }),
+        }
+    }
+
+    pub fn into_ranges(self) -> Vec<RangeDigest> {
+        self.ranges.into_values().collect()
+    }
+}
+
+/// FNV-1a, stable across builds unlike the std hasher
+fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |hash, b| (hash ^ *b as u64).wrapping_mul(FNV_PRIME))
+}
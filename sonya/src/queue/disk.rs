@@ -0,0 +1,68 @@
+use sonya_meta::config::{Disk as DiskOptions, DiskPolicy};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Disk usage of the databases, sizes are in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct DiskUsage {
+    /// The lowest free space of database disks, `None` for in-memory databases
+    pub free_space: Option<u64>,
+    pub db_size: u64,
+}
+
+/// Checks disk usage against thresholds and remembers if they are crossed,
+/// so only changes are reported
+#[derive(Debug)]
+pub struct DiskWatchdog {
+    options: DiskOptions,
+    paths: Vec<PathBuf>,
+    exceeded: AtomicBool,
+}
+
+impl DiskWatchdog {
+    pub fn new(options: DiskOptions, paths: Vec<PathBuf>) -> Self {
+        Self {
+            options,
+            paths,
+            exceeded: Default::default(),
+        }
+    }
+
+    pub fn policy(&self) -> DiskPolicy {
+        self.options.policy
+    }
+
+    pub fn trim_keep(&self) -> usize {
+        self.options.trim_keep
+    }
+
+    pub fn measure(&self, db_size: u64) -> std::io::Result<DiskUsage> {
+        let free_space = self
+            .paths
+            .iter()
+            .map(fs2::available_space)
+            .collect::<std::io::Result<Vec<_>>>()?
+            .into_iter()
+            .min();
+
+        Ok(DiskUsage {
+            free_space,
+            db_size,
+        })
+    }
+
+    /// Remembers if the usage crosses thresholds, returns the new and the previous state
+    pub fn update(&self, usage: DiskUsage) -> (bool, bool) {
+        let exceeded = matches!(
+            (usage.free_space, self.options.min_free_space),
+            (Some(free), Some(min)) if free < min
+        ) || matches!(self.options.max_db_size, Some(max) if usage.db_size > max);
+
+        (exceeded, self.exceeded.swap(exceeded, Ordering::Relaxed))
+    }
+
+    /// Publishes are rejected by the reject policy while thresholds are crossed
+    pub fn rejects_publishes(&self) -> bool {
+        self.options.policy == DiskPolicy::Reject && self.exceeded.load(Ordering::Relaxed)
+    }
+}
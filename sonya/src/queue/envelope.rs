@@ -0,0 +1,168 @@
+use derive_more::{Display, Error};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// Version of stored values written before envelopes, which are bare json
+const LEGACY_VERSION: u8 = 0;
+
+/// Version of the event format of this release, stored values are written with it
+/// unless upgrades to later versions are registered
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// Versions must stay below json object and array openings, so bare legacy values are detected
+pub const MAX_ENVELOPE_VERSION: u8 = b'[' - 1;
+
+/// Migrates the stored event of one version to the next one
+pub type Upgrade = Arc<dyn Fn(Value) -> Result<Value, String> + Send + Sync>;
+
+/// Stored value which can't be upgraded to the current version
+#[derive(Debug, Display, Error)]
+pub enum EnvelopeError {
+    #[display(fmt = "empty stored value")]
+    Empty,
+    #[display(fmt = "stored value version {} is newer than {}", version, current)]
+    Unknown {
+        version: u8,
+        current: u8,
+    },
+    #[display(fmt = "upgrade from version {} failed: {}", version, reason)]
+    Upgrade {
+        version: u8,
+        reason: String,
+    },
+    Decode(serde_json::Error),
+}
+
+impl From<serde_json::Error> for EnvelopeError {
+    fn from(e: serde_json::Error) -> Self {
+        EnvelopeError::Decode(e)
+    }
+}
+
+/// Prefixes stored events with the version byte and upgrades older events on read,
+/// so event formats change without rewriting the stored history.
+/// Missing upgrade steps keep events as is.
+#[derive(Clone)]
+pub struct Envelopes {
+    version: u8,
+    upgrades: BTreeMap<u8, Upgrade>,
+}
+
+impl Envelopes {
+    /// Registers the upgrade of events from the version to the next one,
+    /// the current version follows the latest upgrade
+    pub fn register(
+        &mut self,
+        from: u8,
+        upgrade: impl Fn(Value) -> Result<Value, String> + Send + Sync + 'static,
+    ) {
+        assert!(
+            from < MAX_ENVELOPE_VERSION,
+            "envelope versions must be less than {}",
+            MAX_ENVELOPE_VERSION
+        );
+        self.upgrades.insert(from, Arc::new(upgrade));
+        self.version = self.version.max(from + 1);
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn encode<V: Serialize + ?Sized>(&self, value: &V) -> serde_json::Result<Vec<u8>> {
+        let mut data = vec![self.version];
+        serde_json::to_writer(&mut data, value)?;
+        Ok(data)
+    }
+
+    /// Decodes the stored event, upgrading it if it was stored by the older version
+    pub fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, EnvelopeError> {
+        let (version, payload) = self.open(data)?;
+        if version == self.version {
+            return Ok(serde_json::from_slice(payload)?);
+        }
+
+        let value = self.upgrade(version, serde_json::from_slice(payload)?)?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Decodes stored events of the cold segment, upgrading every one of them
+    pub fn decode_all<T: DeserializeOwned>(&self, data: &[u8]) -> Result<Vec<T>, EnvelopeError> {
+        let (version, payload) = self.open(data)?;
+        if version == self.version {
+            return Ok(serde_json::from_slice(payload)?);
+        }
+
+        serde_json::from_slice::<Vec<Value>>(payload)?
+            .into_iter()
+            .map(|value| Ok(serde_json::from_value(self.upgrade(version, value)?)?))
+            .collect()
+    }
+
+    /// Joins stored events into the cold segment of the current version,
+    /// events of older versions are upgraded
+    pub fn encode_segment<'a>(
+        &self,
+        entries: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<Vec<u8>, EnvelopeError> {
+        let mut data = vec![self.version, b'['];
+        for (i, entry) in entries.into_iter().enumerate() {
+            if i > 0 {
+                data.push(b',');
+            }
+            match self.open(entry)? {
+                (version, payload) if version == self.version => data.extend_from_slice(payload),
+                (version, payload) => {
+                    let value = self.upgrade(version, serde_json::from_slice(payload)?)?;
+                    serde_json::to_writer(&mut data, &value)?;
+                }
+            }
+        }
+        data.push(b']');
+        Ok(data)
+    }
+
+    fn open<'a>(&self, data: &'a [u8]) -> Result<(u8, &'a [u8]), EnvelopeError> {
+        match data.split_first() {
+            None => Err(EnvelopeError::Empty),
+            Some((b'{' | b'[', _)) => Ok((LEGACY_VERSION, data)),
+            Some((&version, _)) if version > self.version => Err(EnvelopeError::Unknown {
+                version,
+                current: self.version,
+            }),
+            Some((&version, payload)) => Ok((version, payload)),
+        }
+    }
+
+    fn upgrade(&self, version: u8, mut value: Value) -> Result<Value, EnvelopeError> {
+        for (&from, upgrade) in self.upgrades.range(version..self.version) {
+            value = upgrade(value).map_err(|reason| EnvelopeError::Upgrade {
+                version: from,
+                reason,
+            })?;
+        }
+        Ok(value)
+    }
+}
+
+impl Default for Envelopes {
+    fn default() -> Self {
+        Self {
+            version: ENVELOPE_VERSION,
+            upgrades: Default::default(),
+        }
+    }
+}
+
+impl Debug for Envelopes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Envelopes")
+            .field("version", &self.version)
+            .field("upgrades", &self.upgrades.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
@@ -0,0 +1,147 @@
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use derive_more::{Display, Error};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+/// Runs storage operations on the blocking thread pool, so they don't stall async workers.
+/// Pending operations are bounded and every operation may be limited by timeout.
+#[derive(Debug)]
+pub struct StorageExecutor {
+    pending: Option<Arc<Semaphore>>,
+    timeout: Option<Duration>,
+    publish_timeout: Option<Duration>,
+    subscribe_timeout: Option<Duration>,
+}
+
+impl StorageExecutor {
+    pub fn new(max_pending: Option<usize>, timeout: Option<Duration>) -> Self {
+        Self {
+            pending: max_pending.map(|m| Arc::new(Semaphore::new(m))),
+            timeout,
+            publish_timeout: timeout,
+            subscribe_timeout: timeout,
+        }
+    }
+
+    pub fn with_publish_timeout(mut self, timeout: Option<Duration>) -> Self {
+        if timeout.is_some() {
+            self.publish_timeout = timeout;
+        }
+        self
+    }
+
+    pub fn with_subscribe_timeout(mut self, timeout: Option<Duration>) -> Self {
+        if timeout.is_some() {
+            self.subscribe_timeout = timeout;
+        }
+        self
+    }
+
+    pub async fn run<F, R>(&self, f: F) -> Result<R, StorageError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.run_with_timeout(self.timeout, f).await
+    }
+
+    /// Runs subscription setup, including the history preload
+    pub async fn run_subscribe<F, R>(&self, f: F) -> Result<R, StorageError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.run_with_timeout(self.subscribe_timeout, f).await
+    }
+
+    /// Runs publishing. A timed out publish is not canceled: the event is still stored
+    /// and broadcasted, so the client is told that the result is unknown.
+    pub async fn run_publish<F, R>(&self, f: F) -> Result<R, StorageError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        match self.run_with_timeout(self.publish_timeout, f).await {
+            Err(StorageError::Timeout) => {
+                warn!("publish timed out, event will be stored and delivered in background");
+                Err(StorageError::PublishTimeout)
+            }
+            result => result,
+        }
+    }
+
+    async fn run_with_timeout<F, R>(
+        &self,
+        timeout: Option<Duration>,
+        f: F,
+    ) -> Result<R, StorageError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        // permit is released when the operation is finished, even if it was timed out
+        let permit = match &self.pending {
+            None => None,
+            Some(s) => Some(
+                s.clone()
+                    .try_acquire_owned()
+                    .map_err(|_| StorageError::Overloaded)?,
+            ),
+        };
+
+        let operation = web::block(move || {
+            let result = f();
+            drop(permit);
+            result
+        });
+
+        let result = match timeout {
+            None => operation.await,
+            Some(t) => actix_web::rt::time::timeout(t, operation)
+                .await
+                .map_err(|_| StorageError::Timeout)?,
+        };
+
+        result.map_err(StorageError::Canceled)
+    }
+}
+
+#[derive(Debug, Display, Error)]
+pub enum StorageError {
+    #[display(fmt = "storage is overloaded")]
+    Overloaded,
+    #[display(fmt = "storage operation timed out")]
+    Timeout,
+    #[display(fmt = "publish timed out, event may still be delivered")]
+    PublishTimeout,
+    #[display(fmt = "storage operation was canceled")]
+    Canceled(BlockingError),
+}
+
+impl ResponseError for StorageError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            StorageError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            StorageError::Timeout | StorageError::PublishTimeout => StatusCode::GATEWAY_TIMEOUT,
+            StorageError::Canceled(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(StorageErrorResponse {
+            success: false,
+            reason: self.to_string(),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct StorageErrorResponse {
+    success: bool,
+    reason: String,
+}
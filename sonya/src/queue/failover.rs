@@ -0,0 +1,436 @@
+use crate::queue::digest::{Digest, DEFAULT_DIGEST_RANGE};
+use crate::queue::executor::{StorageError, StorageExecutor};
+use crate::queue::lifecycle::LifecycleEvent;
+use crate::queue::map::{Queue, QueueError};
+use actix_web::http::header::LOCATION;
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse};
+use awc::error::{JsonPayloadError, SendRequestError};
+use awc::ws::Frame;
+use derive_more::{Display, From};
+use futures::{SinkExt, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sonya_meta::config::{NodeRole, Standby};
+use sonya_meta::message::{ControlMessage, EventMessage};
+use sonya_meta::response::{DigestResponse, RangeDigest, RangeDigestResponse, ReplayResponse};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Role of the server in the active/standby pair.
+/// Standby servers replicate queues of the primary and reject writes until they are promoted,
+/// failed over primaries redirect clients to the new address.
+/// Delivery nodes are standbys which are never promoted.
+/// Replica nodes are never promoted too, they serve replays of periodically synced history.
+#[derive(Debug, Default)]
+pub struct Failover {
+    role: NodeRole,
+    standby: AtomicBool,
+    moved: RwLock<Option<String>>,
+}
+
+impl Failover {
+    pub fn new(role: NodeRole, standby: bool) -> Self {
+        Self {
+            role,
+            standby: AtomicBool::new(
+                standby || matches!(role, NodeRole::Delivery | NodeRole::Replica),
+            ),
+            moved: Default::default(),
+        }
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::Acquire)
+    }
+
+    /// Address of the new primary, if the server failed over
+    pub fn moved(&self) -> Option<String> {
+        self.moved.read().unwrap().clone()
+    }
+
+    pub fn move_to(&self, address: String) {
+        *self.moved.write().unwrap() = Some(address);
+    }
+
+    /// Makes the server primary, returns `false` if it was primary already
+    pub fn promote(&self) -> bool {
+        if matches!(self.role, NodeRole::Delivery | NodeRole::Replica) {
+            return false;
+        }
+        let standby = self.standby.swap(false, Ordering::AcqRel);
+        let moved = self.moved.write().unwrap().take();
+        standby || moved.is_some()
+    }
+
+    /// Response for queue requests, which the server can't serve in its role.
+    /// Requests are redirected after failover, writes are rejected by standbys,
+    /// key subscriptions and long polls are rejected by ingest nodes,
+    /// all live subscriptions are rejected by replica nodes.
+    pub fn intercept(&self, req: &HttpRequest) -> Option<HttpResponse> {
+        if self.role == NodeRole::Replica && req.path().starts_with("/socket.io") {
+            return Some(unavailable("replica node serves replays only"));
+        }
+        if !req.path().starts_with("/queue") {
+            return None;
+        }
+
+        if let Some(address) = self.moved() {
+            let location = match req.uri().path_and_query() {
+                Some(p) => format!("{}{}", address.trim_end_matches('/'), p),
+                None => address,
+            };
+            return Some(
+                HttpResponse::TemporaryRedirect()
+                    .insert_header((LOCATION, location))
+                    .finish(),
+            );
+        }
+
+        let write = *req.method() != Method::GET;
+        let reason = match self.role {
+            NodeRole::Delivery if write => "delivery node is read only",
+            NodeRole::Replica if write => "replica node is read only",
+            _ if write && self.is_standby() => "standby server is read only",
+            NodeRole::Ingest if is_fan_out(req.path()) => "ingest node serves no subscriptions",
+            NodeRole::Replica if req.path().starts_with("/queue/listen/") => {
+                "replica node serves replays only"
+            }
+            _ => return None,
+        };
+        Some(unavailable(reason))
+    }
+}
+
+fn unavailable(reason: &'static str) -> HttpResponse {
+    HttpResponse::ServiceUnavailable().json(StandbyResponse {
+        success: false,
+        reason,
+    })
+}
+
+/// Subscriptions of clients, whole queue websocket subscriptions are left for delivery nodes
+fn is_fan_out(path: &str) -> bool {
+    match path.strip_prefix("/queue/listen/") {
+        None => false,
+        Some(subscription) => match subscription.strip_prefix("ws/") {
+            Some(queue) => queue.contains('/'),
+            None => true,
+        },
+    }
+}
+
+#[derive(Serialize)]
+struct StandbyResponse {
+    success: bool,
+    reason: &'static str,
+}
+
+/// Limit of digest and replay responses of the primary, digests of large queues exceed defaults
+const PRIMARY_PAYLOAD_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Events pulled by one replay request of the backfill
+const BACKFILL_PAGE: usize = 500;
+
+/// Replicates the queue of the primary with a websocket subscription until the standby is promoted.
+/// Every connection subscribes to live events and backfills the history missed while
+/// the standby was away. Events keep sequences of the primary, so pulled and live entries
+/// overwrite the stored ones.
+pub async fn replicate(
+    queue: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    failover: web::Data<Failover>,
+    standby: Standby,
+    queue_name: String,
+) {
+    let client = awc::Client::default();
+    let url = format!(
+        "{}/queue/listen/ws/{}",
+        standby.primary.trim_end_matches('/'),
+        queue_name
+    );
+    let interval = Duration::from_secs(standby.reconnect_interval);
+
+    while failover.is_standby() {
+        let created = executor
+            .run({
+                let (queue, queue_name) = (queue.clone(), queue_name.clone());
+                move || queue.create_queue(queue_name)
+            })
+            .await;
+        if !matches!(created, Ok(Ok(_))) {
+            error!(queue = %queue_name, "creating replicated queue error");
+        }
+
+        let mut request = client.ws(url.as_str());
+        if let Some(token) = &standby.service_token {
+            request = request.bearer_auth(token);
+        }
+
+        match request.connect().await {
+            Ok((_, mut framed)) => {
+                info!(queue = %queue_name, primary = %standby.primary, "replicating queue");
+                let backfilling = actix_web::rt::spawn({
+                    let (client, queue, executor) =
+                        (client.clone(), queue.clone(), executor.clone());
+                    let (standby, queue_name) = (standby.clone(), queue_name.clone());
+                    async move {
+                        match backfill(&client, &queue, &executor, &standby, &queue_name).await {
+                            Ok(pulled) => {
+                                info!(queue = %queue_name, pulled, "backfill finished");
+                                if pulled > 0 {
+                                    queue.lifecycle().emit(LifecycleEvent::ReplicaLag {
+                                        queue: queue_name,
+                                        primary: standby.primary,
+                                        missed: pulled,
+                                    });
+                                }
+                            }
+                            Err(e) => warn!(queue = %queue_name, error = %e, "backfill error"),
+                        }
+                    }
+                });
+                while let Some(frame) = framed.next().await {
+                    if !failover.is_standby() {
+                        break;
+                    }
+                    match frame {
+                        Ok(Frame::Text(text)) => {
+                            store(&queue, &executor, &queue_name, &text).await;
+                        }
+                        Ok(Frame::Ping(p)) => {
+                            let _ = framed.send(awc::ws::Message::Pong(p)).await;
+                        }
+                        Ok(Frame::Close(reason)) => {
+                            warn!(queue = %queue_name, ?reason, "primary closed replication");
+                            break;
+                        }
+                        Err(e) => {
+                            warn!(queue = %queue_name, error = %e, "replication error");
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+                // the next connection starts its own backfill
+                backfilling.abort();
+            }
+            Err(e) => warn!(queue = %queue_name, error = %e, "connecting to primary error"),
+        }
+
+        if failover.is_standby() {
+            actix_web::rt::time::sleep(interval).await;
+        }
+    }
+
+    info!(queue = %queue_name, "replication stopped");
+}
+
+/// Syncs the history of the queue with the primary every `sync_interval` seconds.
+/// Replicas don't follow live events, so heavy replays are served from the last synced state
+/// and the primary only answers digests and replays of the changed ranges.
+pub async fn sync_replica(
+    queue: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    standby: Standby,
+    queue_name: String,
+) {
+    let client = awc::Client::default();
+    let mut ticker = actix_web::rt::time::interval(Duration::from_secs(standby.sync_interval));
+
+    loop {
+        ticker.tick().await;
+
+        let created = executor
+            .run({
+                let (queue, queue_name) = (queue.clone(), queue_name.clone());
+                move || queue.create_queue(queue_name)
+            })
+            .await;
+        if !matches!(created, Ok(Ok(_))) {
+            error!(queue = %queue_name, "creating replicated queue error");
+            continue;
+        }
+
+        match backfill(&client, &queue, &executor, &standby, &queue_name).await {
+            Ok(pulled) => {
+                info!(queue = %queue_name, pulled, "replica synced");
+                if pulled > 0 {
+                    queue.lifecycle().emit(LifecycleEvent::ReplicaLag {
+                        queue: queue_name.clone(),
+                        primary: standby.primary.clone(),
+                        missed: pulled,
+                    });
+                }
+            }
+            Err(e) => warn!(queue = %queue_name, error = %e, "replica sync error"),
+        }
+    }
+}
+
+/// Stores the replicated event or applies the replicated revocation
+async fn store(
+    queue: &web::Data<Queue<EventMessage>>,
+    executor: &web::Data<StorageExecutor>,
+    queue_name: &str,
+    text: &[u8],
+) {
+    let (queue, queue_name) = (queue.clone(), queue_name.to_string());
+    let result = match serde_json::from_slice::<ControlMessage>(text) {
+        Ok(ControlMessage::Revoked { id, sequence }) => {
+            executor
+                .run(move || queue.revoke_event(queue_name, id, sequence).map(|_| ()))
+                .await
+        }
+        Ok(_) => return,
+        Err(_) => match serde_json::from_slice::<EventMessage>(text) {
+            Ok(event) => {
+                executor
+                    .run(move || queue.send_to_queue(queue_name, event).map(|_| ()))
+                    .await
+            }
+            Err(e) => {
+                warn!(error = %e, "invalid replicated event");
+                return;
+            }
+        },
+    };
+
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => error!(error = %e, "storing replicated event error"),
+        Err(e) => error!(error = %e, "storing replicated event error"),
+    }
+}
+
+#[derive(Debug, Display, From)]
+enum BackfillError {
+    Request(SendRequestError),
+    Payload(JsonPayloadError),
+    Storage(StorageError),
+    Queue(QueueError),
+    #[display(fmt = "primary responded with {}", _0)]
+    Status(StatusCode),
+}
+
+/// Pulls entries which differ from the primary. Root checksums of keys are compared first,
+/// then checksums of sequence ranges of the diverged keys, and only diverged ranges are replayed.
+/// Returns count of pulled entries.
+async fn backfill(
+    client: &awc::Client,
+    queue: &web::Data<Queue<EventMessage>>,
+    executor: &web::Data<StorageExecutor>,
+    standby: &Standby,
+    queue_name: &str,
+) -> Result<usize, BackfillError> {
+    let primary: DigestResponse =
+        fetch(client, standby, format!("/admin/digest/{}", queue_name)).await?;
+    let local = executor
+        .run({
+            let (queue, queue_name) = (queue.clone(), queue_name.to_string());
+            move || queue.digest_queue(queue_name)
+        })
+        .await??
+        .unwrap_or_default();
+
+    let mut pulled = 0;
+    for (id, digest) in primary.keys {
+        if local.get(&id) == Some(&digest) {
+            continue;
+        }
+
+        let primary: RangeDigestResponse = fetch(
+            client,
+            standby,
+            format!(
+                "/admin/digest/{}/{}?range={}",
+                queue_name, id, DEFAULT_DIGEST_RANGE
+            ),
+        )
+        .await?;
+        let local: HashMap<u64, RangeDigest> = executor
+            .run({
+                let (queue, queue_name, id) = (queue.clone(), queue_name.to_string(), id.clone());
+                move || queue.digest_key(queue_name, id, DEFAULT_DIGEST_RANGE, None)
+            })
+            .await??
+            .map(Digest::into_ranges)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|r| (r.from, r))
+            .collect();
+
+        for range in primary.ranges {
+            if local.get(&range.from) != Some(&range) {
+                pulled += pull(client, queue, executor, standby, queue_name, &id, &range).await?;
+            }
+        }
+    }
+
+    Ok(pulled)
+}
+
+/// Replays the range of the key from the primary and stores its events
+async fn pull(
+    client: &awc::Client,
+    queue: &web::Data<Queue<EventMessage>>,
+    executor: &web::Data<StorageExecutor>,
+    standby: &Standby,
+    queue_name: &str,
+    id: &str,
+    range: &RangeDigest,
+) -> Result<usize, BackfillError> {
+    let mut pulled = 0;
+    let mut from = Some(range.from);
+    while let Some(sequence) = from.filter(|s| *s <= range.to) {
+        let page: ReplayResponse<EventMessage> = fetch(
+            client,
+            standby,
+            format!(
+                "/queue/replay/{}/{}?from={}&limit={}",
+                queue_name, id, sequence, BACKFILL_PAGE
+            ),
+        )
+        .await?;
+        from = page.next.map(|s| s.get());
+
+        let events: Vec<_> = page
+            .events
+            .into_iter()
+            .filter(|e| matches!(e.sequence, Some(s) if s.get() <= range.to))
+            .collect();
+        pulled += events.len();
+        executor
+            .run({
+                let (queue, queue_name) = (queue.clone(), queue_name.to_string());
+                move || {
+                    events.into_iter().try_for_each(|event| {
+                        queue.send_to_queue(queue_name.clone(), event).map(|_| ())
+                    })
+                }
+            })
+            .await??;
+    }
+
+    Ok(pulled)
+}
+
+async fn fetch<R: DeserializeOwned>(
+    client: &awc::Client,
+    standby: &Standby,
+    path: String,
+) -> Result<R, BackfillError> {
+    let mut request = client.get(format!("{}{}", standby.primary.trim_end_matches('/'), path));
+    if let Some(token) = &standby.service_token {
+        request = request.bearer_auth(token);
+    }
+
+    let mut response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(BackfillError::Status(response.status()));
+    }
+    Ok(response.json().limit(PRIMARY_PAYLOAD_LIMIT).await?)
+}
@@ -0,0 +1,73 @@
+use serde_json::Value;
+use sonya_meta::message::EventMessage;
+use std::fmt::Debug;
+
+/// Subscriber description which is passed to event filters
+#[derive(Debug, Clone)]
+pub struct SubscriberInfo {
+    pub queue_name: String,
+    pub id: Option<String>,
+    pub identity: Option<String>,
+    /// `false` for history reads without subscriptions, e.g. peek and replay
+    pub subscription: bool,
+}
+
+/// Decides per delivered event if the subscriber may see it.
+/// Invoked for preloaded and live events of every subscription.
+pub trait EventFilter<T>: Debug + Send + Sync {
+    fn is_visible(&self, subscriber: &SubscriberInfo, event: &T) -> bool;
+}
+
+/// Hides events which payload field is not equal to the subscriber identity.
+/// Events without the field are not filtered, subscribers without identity don't see owned events.
+#[derive(Debug, Clone)]
+pub struct PayloadFieldFilter {
+    field: String,
+}
+
+impl PayloadFieldFilter {
+    pub fn new(field: String) -> Self {
+        Self { field }
+    }
+}
+
+impl EventFilter<EventMessage> for PayloadFieldFilter {
+    fn is_visible(&self, subscriber: &SubscriberInfo, event: &EventMessage) -> bool {
+        match (&subscriber.identity, event.payload.get(&self.field)) {
+            (_, None) => true,
+            (Some(identity), Some(Value::String(owner))) => identity == owner,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn subscriber(identity: Option<&str>) -> SubscriberInfo {
+        SubscriberInfo {
+            queue_name: "test".to_string(),
+            id: Some("1".to_string()),
+            identity: identity.map(String::from),
+            subscription: true,
+        }
+    }
+
+    fn event(payload: Value) -> EventMessage {
+        serde_json::from_value(json!({ "id": "1", "payload": payload })).unwrap()
+    }
+
+    #[test]
+    fn owned_events_are_hidden_from_subscribers_without_identity() {
+        let filter = PayloadFieldFilter::new("user_id".to_string());
+        let owned = event(json!({ "user_id": "alice" }));
+        let shared = event(json!({ "text": "hello" }));
+
+        assert!(!filter.is_visible(&subscriber(None), &owned));
+        assert!(filter.is_visible(&subscriber(None), &shared));
+        assert!(filter.is_visible(&subscriber(Some("alice")), &owned));
+        assert!(!filter.is_visible(&subscriber(Some("bob")), &owned));
+    }
+}
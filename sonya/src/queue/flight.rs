@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Deduplicates concurrent identical computations.
+/// Callers which come while the computation is running wait for it and share its result.
+#[derive(Debug)]
+pub struct SingleFlight<V> {
+    flights: Mutex<HashMap<String, Arc<Flight<V>>>>,
+}
+
+#[derive(Debug)]
+struct Flight<V> {
+    /// `None` while the computation is running, `Some(None)` if it failed
+    result: Mutex<Option<Option<V>>>,
+    done: Condvar,
+}
+
+impl<V> Default for SingleFlight<V> {
+    fn default() -> Self {
+        Self {
+            flights: Default::default(),
+        }
+    }
+}
+
+impl<V: Clone> SingleFlight<V> {
+    /// Runs the computation of the key or waits for the running one.
+    /// If the running computation fails, waiting callers run their own.
+    pub fn run<E>(&self, key: &str, f: impl FnOnce() -> Result<V, E>) -> Result<V, E> {
+        let mut flights = self.flights.lock().unwrap();
+        if let Some(flight) = flights.get(key).cloned() {
+            drop(flights);
+
+            let result = flight.result.lock().unwrap();
+            let result = flight.done.wait_while(result, |r| r.is_none()).unwrap();
+            if let Some(Some(value)) = &*result {
+                return Ok(value.clone());
+            }
+            drop(result);
+
+            return f();
+        }
+
+        let flight = Arc::new(Flight {
+            result: Mutex::new(None),
+            done: Condvar::new(),
+        });
+        flights.insert(key.to_string(), flight.clone());
+        drop(flights);
+
+        let mut landing = Landing {
+            flights: &self.flights,
+            key,
+            flight,
+            value: None,
+        };
+        let result = f();
+        landing.value = result.as_ref().ok().cloned();
+
+        result
+    }
+}
+
+/// Publishes the result to waiting callers, even if the computation panicked
+struct Landing<'a, V> {
+    flights: &'a Mutex<HashMap<String, Arc<Flight<V>>>>,
+    key: &'a str,
+    flight: Arc<Flight<V>>,
+    value: Option<V>,
+}
+
+impl<'a, V> Drop for Landing<'a, V> {
+    fn drop(&mut self) {
+        if let Ok(mut flights) = self.flights.lock() {
+            flights.remove(self.key);
+        }
+        if let Ok(mut result) = self.flight.result.lock() {
+            *result = Some(self.value.take());
+        }
+        self.flight.done.notify_all();
+    }
+}
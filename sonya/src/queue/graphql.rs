@@ -0,0 +1,182 @@
+use crate::queue::admission::Admission;
+use crate::queue::connection::BroadcastMessage;
+use crate::queue::executor::StorageExecutor;
+use crate::queue::map::Queue;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use async_graphql::types::Json;
+use async_graphql::{Context, Data, EmptyMutation, Object, Schema, SimpleObject, Subscription};
+use async_graphql_actix_web::GraphQLSubscription;
+use futures::future::ready;
+use futures::{Stream, StreamExt};
+use serde_json::Value;
+use sonya_meta::api::{
+    authorize_key_subscription, extract_access_token, extract_any_data_from_query, IdentityQuery,
+};
+use sonya_meta::config::{Config, Secure};
+use sonya_meta::limit::ConnectionLimiter;
+use sonya_meta::message::{EventMessage, RequestSequenceId, SequenceId};
+use std::net::IpAddr;
+use tracing::instrument;
+
+pub type GraphqlSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+pub fn graphql_schema() -> GraphqlSchema {
+    Schema::new(QueryRoot, EmptyMutation, SubscriptionRoot)
+}
+
+/// Serves GraphQL subscriptions with the `graphql-transport-ws` and `graphql-ws` protocols
+#[instrument(skip_all)]
+pub async fn subscribe_graphql(
+    req: HttpRequest,
+    payload: web::Payload,
+    schema: web::Data<GraphqlSchema>,
+    srv: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    admission: web::Data<Admission>,
+    config: web::Data<Config>,
+    limiter: web::Data<ConnectionLimiter>,
+) -> Result<HttpResponse, Error> {
+    let connection = GraphqlConnection {
+        queue: srv,
+        executor,
+        admission,
+        limiter,
+        secure: config.secure.clone(),
+        peer: req.peer_addr().map(|a| a.ip()),
+        identity: extract_any_data_from_query::<IdentityQuery>(req.head())
+            .and_then(|query| query.identity),
+    };
+    let token = extract_access_token(req.head());
+
+    GraphQLSubscription::new(GraphqlSchema::clone(&schema))
+        .on_connection_init(move |init| async move {
+            // browsers can't set headers of websockets, so tokens are also sent on init
+            let token = init
+                .get("access_token")
+                .and_then(Value::as_str)
+                .map(String::from)
+                .or(token);
+            let mut data = Data::default();
+            data.insert(connection);
+            data.insert(AccessToken(token));
+            Ok(data)
+        })
+        .start(&req, payload)
+}
+
+/// Server state of the websocket connection, subscriptions of the connection share it
+struct GraphqlConnection {
+    queue: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    admission: web::Data<Admission>,
+    limiter: web::Data<ConnectionLimiter>,
+    secure: Option<Secure>,
+    peer: Option<IpAddr>,
+    /// Identity of subscriptions without secure mode
+    identity: Option<String>,
+}
+
+struct AccessToken(Option<String>);
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Version of the queue server
+    async fn version(&self) -> &'static str {
+        env!("CARGO_PKG_VERSION")
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Events of the queue key. Stored events with `>=fromSequence` are sent before the live ones,
+    /// `0` sends the whole key history.
+    /// The subscription ends when the queue is closed, the key is deleted or the server stops.
+    async fn queue(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        id: String,
+        from_sequence: Option<u64>,
+    ) -> async_graphql::Result<impl Stream<Item = GraphqlEvent>> {
+        let connection = ctx.data::<GraphqlConnection>()?;
+        let identity = match &connection.secure {
+            None => connection.identity.clone(),
+            Some(secure) => {
+                let token = ctx.data::<AccessToken>()?.0.as_deref();
+                token
+                    .and_then(|token| authorize_key_subscription(secure, token, &name, &id))
+                    .ok_or("Subscription is not authorized")?
+            }
+        };
+
+        let connection_guard = connection
+            .limiter
+            .clone()
+            .into_inner()
+            .acquire(&name, connection.peer)?;
+        let sequence = from_sequence
+            .map(|s| SequenceId::new(s).map_or(RequestSequenceId::First, RequestSequenceId::Id));
+        let permit = connection.admission.admit().await?;
+        let subscription = connection
+            .executor
+            .run_subscribe({
+                let queue = connection.queue.clone();
+                move || queue.subscribe_queue_by_id(name, id, sequence, None, identity)
+            })
+            .await??;
+        let stream = permit
+            .hold(subscription)
+            .stream
+            .ok_or("Queue does not exist")?;
+
+        Ok(stream
+            .take_while(|message| ready(!is_terminal(message)))
+            .filter_map(move |message| {
+                // the connection is counted until the subscription ends
+                let _guard = &connection_guard;
+                ready(match message {
+                    BroadcastMessage::Message(event) => Some(GraphqlEvent::from(event)),
+                    _ => None,
+                })
+            }))
+    }
+}
+
+fn is_terminal<T>(message: &BroadcastMessage<T>) -> bool {
+    matches!(
+        message,
+        BroadcastMessage::QueueClosed
+            | BroadcastMessage::KeyDeleted
+            | BroadcastMessage::Draining
+            | BroadcastMessage::Moved(_)
+            | BroadcastMessage::PreloadFailed
+    )
+}
+
+/// Event of the queue key
+#[derive(SimpleObject)]
+#[graphql(name = "Event")]
+pub struct GraphqlEvent {
+    id: String,
+    sequence: Option<u64>,
+    payload: Json<Value>,
+    tombstone: bool,
+    /// Hybrid logical clock timestamp as `{wall}.{logical}`
+    timestamp: Option<String>,
+}
+
+impl From<EventMessage> for GraphqlEvent {
+    fn from(event: EventMessage) -> Self {
+        Self {
+            id: event.id.clone(),
+            sequence: event.sequence.map(SequenceId::get),
+            payload: Json(event.payload),
+            tombstone: event.tombstone,
+            timestamp: event.timestamp.map(|t| t.to_string()),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use crate::queue::filter::{EventFilter, SubscriberInfo};
+use derive_more::{Display, Error};
+use serde_json::Value;
+use sonya_meta::message::EventMessage;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// Invoked on every published event before it is persisted and broadcast.
+/// Interceptors may mutate or enrich events, split them into several or drop them
+/// by returning an empty vector, or reject them with an error.
+pub trait PublishInterceptor<T>: Debug + Send + Sync {
+    fn on_publish(&self, queue_name: &str, event: T) -> Result<Vec<T>, InterceptorError>;
+}
+
+/// Invoked on every event delivered to a subscriber.
+/// Returning `None` suppresses the event for this subscriber.
+pub trait DeliveryInterceptor<T>: Debug + Send + Sync {
+    fn on_deliver(&self, subscriber: &SubscriberInfo, event: T) -> Option<T>;
+}
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "event rejected: {}", _0)]
+pub struct InterceptorError(#[error(not(source))] pub String);
+
+/// Runs delivery interceptors for one subscriber
+pub struct DeliveryPipeline<T> {
+    interceptors: Vec<Arc<dyn DeliveryInterceptor<T>>>,
+    subscriber: SubscriberInfo,
+}
+
+impl<T> DeliveryPipeline<T> {
+    pub fn new(
+        interceptors: Vec<Arc<dyn DeliveryInterceptor<T>>>,
+        subscriber: SubscriberInfo,
+    ) -> Self {
+        Self {
+            interceptors,
+            subscriber,
+        }
+    }
+
+    pub fn deliver(&self, event: T) -> Option<T> {
+        self.interceptors
+            .iter()
+            .try_fold(event, |event, i| i.on_deliver(&self.subscriber, event))
+    }
+}
+
+/// Adapts event filters to the delivery pipeline
+#[derive(Debug)]
+pub struct FilterInterceptor<F>(pub F);
+
+impl<F, T> DeliveryInterceptor<T> for FilterInterceptor<F>
+where
+    F: EventFilter<T>,
+{
+    fn on_deliver(&self, subscriber: &SubscriberInfo, event: T) -> Option<T> {
+        self.0.is_visible(subscriber, &event).then(|| event)
+    }
+}
+
+/// Removes configured payload fields before events are stored, e.g. personal data
+#[derive(Debug, Clone)]
+pub struct ScrubFieldsInterceptor {
+    fields: Vec<String>,
+}
+
+impl ScrubFieldsInterceptor {
+    pub fn new(fields: Vec<String>) -> Self {
+        Self { fields }
+    }
+}
+
+impl PublishInterceptor<EventMessage> for ScrubFieldsInterceptor {
+    fn on_publish(
+        &self,
+        _queue_name: &str,
+        mut event: EventMessage,
+    ) -> Result<Vec<EventMessage>, InterceptorError> {
+        if let Value::Object(payload) = &mut event.payload {
+            self.fields.iter().for_each(|f| {
+                payload.remove(f);
+            });
+        }
+        Ok(vec![event])
+    }
+}
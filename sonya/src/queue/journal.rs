@@ -0,0 +1,209 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use tokio::sync::Notify;
+use tracing::warn;
+
+const JOURNAL_FILE: &str = "publishes.log";
+
+/// Bytes of the length prefix of every record
+const LENGTH_SIZE: u64 = 4;
+
+/// Failed attempts to store the record after which it's dead lettered
+const MAX_APPLY_ATTEMPTS: u32 = 3;
+
+/// Publish which is acknowledged to the client and waits for applying to the database.
+/// Events are already passed through publish interceptors and dedup windows
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JournalRecord<T> {
+    pub queue: String,
+    pub events: Vec<T>,
+}
+
+/// Position of the journal applier: offset of the record which is applied
+/// and count of its events which are stored in the database
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct JournalProgress {
+    pub offset: u64,
+    pub stored: u64,
+}
+
+impl JournalProgress {
+    pub fn encode(&self) -> [u8; 16] {
+        let mut encoded = [0; 16];
+        encoded[..8].copy_from_slice(&self.offset.to_be_bytes());
+        encoded[8..].copy_from_slice(&self.stored.to_be_bytes());
+        encoded
+    }
+
+    pub fn decode(encoded: &[u8]) -> Option<Self> {
+        Some(Self {
+            offset: u64::from_be_bytes(encoded.get(..8)?.try_into().ok()?),
+            stored: u64::from_be_bytes(encoded.get(8..16)?.try_into().ok()?),
+        })
+    }
+}
+
+/// Append-only file of length prefixed json records. Appends are synced to the disk,
+/// so publishes are durable before they are written to sled trees.
+/// Records are applied in order and the file is truncated when all of them are applied.
+#[derive(Debug)]
+pub struct Journal {
+    file: Mutex<File>,
+    /// Held while records are applied, so the shutdown flush and the background
+    /// applier don't apply the same records
+    applier: Mutex<()>,
+    /// Offset of the record which failed to apply and count of its attempts.
+    /// Attempts are counted in memory, so records are retried after restarts
+    failed: Mutex<(u64, u32)>,
+    appended: Notify,
+}
+
+impl Journal {
+    /// Opens the journal in the directory, the tail torn by a crash is cut
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(path)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path.join(JOURNAL_FILE))?;
+
+        let len = file.metadata()?.len();
+        let valid = valid_len(&mut file, len)?;
+        if valid < len {
+            warn!(valid, len, "cutting torn tail of the publish journal");
+            file.set_len(valid)?;
+            file.sync_data()?;
+        }
+
+        Ok(Self {
+            file: Mutex::new(file),
+            applier: Mutex::new(()),
+            failed: Mutex::new((0, 0)),
+            appended: Notify::new(),
+        })
+    }
+
+    /// Appends records with one sync and wakes up the applier
+    pub fn append<T: Serialize>(&self, records: &[JournalRecord<T>]) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        for record in records {
+            let encoded = serde_json::to_vec(record)?;
+            buf.extend((encoded.len() as u32).to_le_bytes());
+            buf.extend(encoded);
+        }
+
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::End(0))?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        drop(file);
+
+        self.appended.notify_one();
+        Ok(())
+    }
+
+    /// Locks applying of records until the guard is dropped
+    pub fn lock_applier(&self) -> MutexGuard<'_, ()> {
+        self.applier.lock().unwrap()
+    }
+
+    /// Counts the failed attempt to apply the record at the offset,
+    /// `true` if the record failed too many times and should be dead lettered
+    pub fn fail(&self, offset: u64) -> bool {
+        let mut failed = self.failed.lock().unwrap();
+        *failed = match *failed {
+            (o, attempts) if o == offset => (offset, attempts + 1),
+            _ => (offset, 1),
+        };
+        if failed.1 < MAX_APPLY_ATTEMPTS {
+            return false;
+        }
+        *failed = (0, 0);
+        true
+    }
+
+    /// Size of the journal in bytes
+    pub fn size(&self) -> std::io::Result<u64> {
+        Ok(self.file.lock().unwrap().metadata()?.len())
+    }
+
+    /// Reads records after the offset, returns the effective offset and records
+    /// with offsets of their ends. Offsets after the end of the journal are left
+    /// by an interrupted truncation, so records are read from the start
+    pub fn read_from<T: DeserializeOwned>(
+        &self,
+        offset: u64,
+    ) -> std::io::Result<(u64, Vec<(u64, JournalRecord<T>)>)> {
+        let mut file = self.file.lock().unwrap();
+        let len = file.metadata()?.len();
+        let start = if offset > len { 0 } else { offset };
+        let mut offset = start;
+
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0; (len - offset) as usize];
+        file.read_exact(&mut buf)?;
+        drop(file);
+
+        let mut records = Vec::new();
+        let mut rest = buf.as_slice();
+        while let Some((record, tail)) = split_record(rest) {
+            offset += LENGTH_SIZE + record.len() as u64;
+            records.push((offset, serde_json::from_slice(record)?));
+            rest = tail;
+        }
+
+        Ok((start, records))
+    }
+
+    /// Empties the journal if all records are applied, returns `true` if it was truncated.
+    /// The applied offset is reset before new records are appended
+    pub fn truncate_applied<E: From<std::io::Error>>(
+        &self,
+        applied: u64,
+        reset: impl FnOnce() -> Result<(), E>,
+    ) -> Result<bool, E> {
+        let file = self.file.lock().unwrap();
+        if applied == 0 || file.metadata()?.len() != applied {
+            return Ok(false);
+        }
+
+        file.set_len(0)?;
+        file.sync_data()?;
+        // offsets of new records start again from zero
+        *self.failed.lock().unwrap() = (0, 0);
+        reset()?;
+        Ok(true)
+    }
+
+    /// Waits for the next append
+    pub async fn appended(&self) {
+        self.appended.notified().await
+    }
+}
+
+/// Returns length of the journal prefix with complete records
+fn valid_len(file: &mut File, len: u64) -> std::io::Result<u64> {
+    let mut valid = 0;
+    let mut length = [0; LENGTH_SIZE as usize];
+    while valid + LENGTH_SIZE <= len {
+        file.seek(SeekFrom::Start(valid))?;
+        file.read_exact(&mut length)?;
+        let end = valid + LENGTH_SIZE + u32::from_le_bytes(length) as u64;
+        if end > len {
+            break;
+        }
+        valid = end;
+    }
+    Ok(valid)
+}
+
+fn split_record(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    let length = u32::from_le_bytes(buf.get(..LENGTH_SIZE as usize)?.try_into().ok()?) as usize;
+    let rest = &buf[LENGTH_SIZE as usize..];
+    Some((rest.get(..length)?, &rest[length..]))
+}
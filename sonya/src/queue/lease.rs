@@ -0,0 +1,77 @@
+use derive_more::{Display, Error};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Single writer leases of keys. Leases are kept in memory, so they are released on restart.
+#[derive(Debug, Default)]
+pub struct Leases {
+    leases: Mutex<HashMap<(String, String), Lease>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Lease {
+    pub token: String,
+    pub expires: SystemTime,
+}
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "key is leased by another writer")]
+pub struct LeaseConflict;
+
+impl Leases {
+    /// Acquires the free lease or extends the held one, if the current token is passed
+    pub fn acquire(
+        &self,
+        queue_name: &str,
+        id: &str,
+        ttl: Duration,
+        token: Option<&str>,
+        now: SystemTime,
+    ) -> Result<Lease, LeaseConflict> {
+        let mut leases = self.leases.lock().unwrap();
+        leases.retain(|_, lease| lease.expires > now);
+
+        let key = (queue_name.to_string(), id.to_string());
+        let token = match leases.get(&key) {
+            Some(lease) if Some(lease.token.as_str()) != token => return Err(LeaseConflict),
+            Some(lease) => lease.token.clone(),
+            None => uuid::Uuid::new_v4().to_string(),
+        };
+
+        let lease = Lease {
+            token,
+            expires: now + ttl,
+        };
+        leases.insert(key, lease.clone());
+
+        Ok(lease)
+    }
+
+    /// Releases the lease held with the token, returns `false` if it is not held
+    pub fn release(&self, queue_name: &str, id: &str, token: &str) -> bool {
+        let key = (queue_name.to_string(), id.to_string());
+        let mut leases = self.leases.lock().unwrap();
+        match leases.get(&key) {
+            Some(lease) if lease.token == token => leases.remove(&key).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Keys without active leases at `now` are free to publish, leased keys only with the token
+    pub fn check(
+        &self,
+        queue_name: &str,
+        id: &str,
+        token: Option<&str>,
+        now: SystemTime,
+    ) -> Result<(), LeaseConflict> {
+        let leases = self.leases.lock().unwrap();
+        match leases.get(&(queue_name.to_string(), id.to_string())) {
+            Some(lease) if lease.expires > now && Some(lease.token.as_str()) != token => {
+                Err(LeaseConflict)
+            }
+            _ => Ok(()),
+        }
+    }
+}
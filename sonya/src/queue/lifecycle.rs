@@ -0,0 +1,92 @@
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use serde::Serialize;
+use tracing::info;
+
+/// Notable change of the server, published to the system lifecycle queue with its name as the key
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LifecycleEvent {
+    QueueCreated {
+        queue: String,
+    },
+    QueueDropped {
+        queue: String,
+    },
+    /// Expired data was removed, `task` is `segments`, `traces` or `disk`
+    RetentionRan {
+        task: &'static str,
+        removed: usize,
+    },
+    /// Standby pulled entries missed while it was disconnected from the primary
+    ReplicaLag {
+        queue: String,
+        primary: String,
+        missed: usize,
+    },
+    /// Disk usage thresholds were crossed or the usage returned under them
+    DiskUsage {
+        exceeded: bool,
+        free_space: Option<u64>,
+        db_size: u64,
+    },
+    NodeJoined {
+        address: String,
+    },
+    /// Server started draining its subscriptions before shutdown
+    NodeLeft {
+        address: String,
+    },
+    /// The server finished draining, `subscribers` were still open after the max drain time
+    /// and `undelivered` messages were not received by some of them
+    Drained {
+        address: String,
+        subscribers: usize,
+        undelivered: usize,
+    },
+}
+
+impl LifecycleEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LifecycleEvent::QueueCreated { .. } => "queue_created",
+            LifecycleEvent::QueueDropped { .. } => "queue_dropped",
+            LifecycleEvent::RetentionRan { .. } => "retention_ran",
+            LifecycleEvent::ReplicaLag { .. } => "replica_lag",
+            LifecycleEvent::DiskUsage { .. } => "disk_usage",
+            LifecycleEvent::NodeJoined { .. } => "node_joined",
+            LifecycleEvent::NodeLeft { .. } => "node_left",
+            LifecycleEvent::Drained { .. } => "drained",
+        }
+    }
+}
+
+/// Logs lifecycle events and passes them to the publisher of the system queue, if it's enabled
+#[derive(Debug, Clone, Default)]
+pub struct LifecycleEvents {
+    sender: Option<UnboundedSender<LifecycleEvent>>,
+}
+
+impl LifecycleEvents {
+    /// Events are published to the system queue by the receiver
+    pub fn channel() -> (Self, UnboundedReceiver<LifecycleEvent>) {
+        let (sender, receiver) = unbounded();
+        (
+            Self {
+                sender: Some(sender),
+            },
+            receiver,
+        )
+    }
+
+    pub fn emit(&self, event: LifecycleEvent) {
+        info!(
+            target: "sonya::lifecycle",
+            event = event.name(),
+            details = ?event,
+            "lifecycle event"
+        );
+        if let Some(sender) = &self.sender {
+            let _ = sender.unbounded_send(event);
+        }
+    }
+}
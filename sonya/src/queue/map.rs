@@ -1,76 +1,459 @@
+use crate::queue::blob::BlobStore;
+use crate::queue::cache::PreloadCache;
+use crate::queue::channel::{Channel, ChannelReceiver};
+use crate::queue::clock::{Clock, HybridClock, SystemClock};
 use crate::queue::connection::BroadcastMessage;
+use crate::queue::databases::Databases;
+use crate::queue::digest::{Digest, DEFAULT_DIGEST_RANGE};
+use crate::queue::disk::DiskWatchdog;
+use crate::queue::envelope::{EnvelopeError, Envelopes};
+use crate::queue::filter::{EventFilter, SubscriberInfo};
+use crate::queue::flight::SingleFlight;
+use crate::queue::interceptor::{
+    DeliveryInterceptor, DeliveryPipeline, FilterInterceptor, InterceptorError, PublishInterceptor,
+};
+use crate::queue::journal::{Journal, JournalProgress, JournalRecord};
+use crate::queue::lease::{Lease, LeaseConflict, Leases};
+use crate::queue::lifecycle::{LifecycleEvent, LifecycleEvents};
+use crate::queue::ordering::KeyLocks;
+use crate::queue::quarantine::{get_quarantine_prefix, PoisonGuard, Quarantine};
+use crate::queue::reaper::Reaper;
+use crate::queue::route::Router;
+use crate::queue::segment::{
+    get_segment_prefix, is_offsets_tree, offsets_tree_name, segment_tree_name, split_segment_name,
+    QueueTrees,
+};
+use crate::queue::session::SessionCursor;
+use crate::queue::stats::{QueueStats, StatsSnapshot};
+use actix_web::rt::task::JoinHandle;
 use derive_more::{Display, Error, From};
-use futures::stream::BoxStream;
-use log::error;
+use futures::stream::{select_all, BoxStream};
+use futures::StreamExt;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
-use sled::{Batch, IVec, Tree};
-use sonya_meta::config::Queue as QueueOptions;
-use sonya_meta::message::{RequestSequence, RequestSequenceId, SequenceId, UniqId};
-use std::collections::{BTreeMap, HashMap};
-use std::convert::TryInto;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{IVec, Transactional, Tree};
+use sonya_meta::config::{
+    DiskPolicy, Queue as QueueOptions, QueueMode, QueueOrdering, SlowPreload, Storage, StorageMode,
+};
+use sonya_meta::message::{
+    HlcTimestamp, RequestSequence, RequestSequenceId, SequenceId, Tombstone, UniqId,
+};
+use sonya_meta::response::{
+    ChannelMetrics, KeyDigest, MessageTrace, QueueMetadata, StoredSize, TraceAck, TraceRemoval,
+};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::{Infallible, TryInto};
 use std::fmt::Debug;
-use std::sync::Mutex;
-use tokio::sync::broadcast::{channel, Receiver, Sender};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{error, info, warn};
 
 pub type QueueMap = sled::Db;
 
+const TOPIC_SEPARATOR: char = '.';
+
+const COLD_INDEX_PREFIX: &str = "cold_";
+
+const HEAD_PREFIX: &str = "head_";
+
+const RECEIPT_PREFIX: &str = "receipt_";
+
+/// Value of the receipt mark of the acknowledged event, delivered events have empty marks
+const ACKED_RECEIPT: &[u8] = &[1];
+
+const SESSION_PREFIX: &str = "session_";
+
+const DEDUP_PREFIX: &str = "dedup_";
+
+const TRACE_PREFIX: &str = "trace_";
+
+const SINK_PREFIX: &str = "sink_";
+
+const SIZE_PREFIX: &str = "size_";
+
+const META_PREFIX: &str = "meta_";
+
+const COUNTER_PREFIX: &str = "id_";
+
+/// Progress of the publish journal applied to the database, every database stores
+/// the progress of events stored into it
+const JOURNAL_KEY: &str = "journal_applied";
+
+/// Journaled publishes which couldn't be applied
+const JOURNAL_DEAD_PREFIX: &str = "journal_dead_";
+
+/// Count of preloaded entries decoded by one blocking task
+const PRELOAD_CHUNK_SIZE: usize = 1024;
+
+/// Count of preload chunks decoded in parallel ahead of the stream
+const PRELOAD_DECODING_CHUNKS: usize = 4;
+
+/// Count of preload chunks decoded at once by all subscriptions,
+/// so long replays don't take the whole blocking thread pool
+const PRELOAD_DECODERS: usize = 16;
+
+/// Maximum count of entries in one cold segment
+const COLD_SEGMENT_SIZE: usize = 1000;
+
+/// Count of entries removed at once by the disk usage trimming
+const TRIM_BATCH_SIZE: usize = 1024;
+
 #[derive(Debug)]
 pub struct Queue<T> {
+    /// Main database, which stores metadata of all queues
     map: QueueMap,
+    databases: Databases,
+    disk: Option<DiskWatchdog>,
+    journal: Option<Journal>,
     max_key_updates: Option<usize>,
+    hierarchy: bool,
     queue_broadcasts: Mutex<HashMap<String, QueueBroadcast<T>>>,
+    publish_interceptors: Vec<Arc<dyn PublishInterceptor<T>>>,
+    delivery_interceptors: Vec<Arc<dyn DeliveryInterceptor<T>>>,
+    routers: Vec<Arc<dyn Router<T>>>,
+    stats: Arc<QueueStats>,
+    stats_queue: Option<String>,
+    lifecycle_queue: Option<String>,
+    lifecycle: LifecycleEvents,
+    slow_preload: SlowPreload,
+    preload_timeout: Option<Duration>,
+    max_preload: Option<usize>,
+    cold_tier: Option<ColdTier>,
+    segment_duration: Option<Duration>,
+    retention: Option<Duration>,
+    ordered_preload: bool,
+    collapse_superseded: HashSet<String>,
+    modes: HashMap<String, QueueMode>,
+    ordering: HashMap<String, QueueOrdering>,
+    key_locks: KeyLocks,
+    leases: Leases,
+    quarantine: Arc<Quarantine>,
+    envelopes: Arc<Envelopes>,
+    clock: Arc<dyn Clock>,
+    hlc: HybridClock,
+    max_clock_drift: Duration,
+    dedup_window: Duration,
+    /// Dedup windows of queues which override the default one
+    dedup_windows: HashMap<String, Duration>,
+    /// Message tracing is enabled if set
+    trace_retention: Option<Duration>,
+    preload_cache: Option<PreloadCache<T>>,
+    /// Permits of blocking tasks decoding streamed preloads
+    preload_decoders: Arc<Semaphore>,
+    /// Snapshots of the last events of queues, shared by concurrent subscriptions
+    last_preloads: SingleFlight<Arc<Vec<T>>>,
+}
+
+/// Applier of a journal record. Stored events commit the progress together with them,
+/// so events stored before a crash are skipped when the record is applied again
+#[derive(Debug)]
+struct JournalCursor {
+    progress: JournalProgress,
+    /// Count of events of the record which are already stored
+    skip: u64,
+}
+
+impl JournalCursor {
+    fn at(offset: u64) -> Self {
+        Self {
+            progress: JournalProgress { offset, stored: 0 },
+            skip: 0,
+        }
+    }
+}
+
+/// Object storage for old key history, indexed in the local database
+#[derive(Debug)]
+struct ColdTier {
+    store: Arc<dyn BlobStore>,
+    hot_updates: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ColdSegment {
+    reference: String,
+    first: u64,
+    last: u64,
 }
 
 impl<'a, T> Queue<T>
 where
-    T: 'a + Send + DeserializeOwned + Serialize + Debug + UniqId + Clone,
+    T: 'static + Send + DeserializeOwned + Serialize + Debug + UniqId + Tombstone + Clone,
 {
     pub fn new(config: QueueOptions) -> QueueResult<Self> {
-        let db_config = match config.db_path {
-            None => sled::Config::new().temporary(true),
-            Some(dp) => sled::Config::new().path(dp).use_compression(true),
-        };
+        let map = open_db(config.db_path.as_deref(), config.storage.as_ref())?;
+        let prefixed = config
+            .databases
+            .iter()
+            .map(|d| {
+                let db = open_db(Some(&d.path), config.storage.as_ref())?;
+                Ok((d.prefix.clone(), db))
+            })
+            .collect::<QueueResult<Vec<_>>>()?;
+        let databases = Databases::new(map.clone(), prefixed);
+
+        for name in databases.stranded_tree_names() {
+            warn!(
+                tree = %String::from_utf8_lossy(&name),
+                "events are stored in other database than configured for the queue and are not served"
+            );
+        }
+
+        let disk = config.disk.clone().map(|options| {
+            let paths = config
+                .db_path
+                .iter()
+                .chain(config.databases.iter().map(|d| &d.path))
+                .cloned()
+                .collect();
+            DiskWatchdog::new(options, paths)
+        });
 
-        let map = db_config.open()?;
+        let journal = config
+            .journal
+            .as_ref()
+            .map(|j| Journal::open(&j.path))
+            .transpose()?;
 
         let this = Self {
             map,
+            databases,
+            disk,
+            journal,
             max_key_updates: config.max_key_updates,
+            hierarchy: config.hierarchy,
             queue_broadcasts: Default::default(),
+            publish_interceptors: Default::default(),
+            delivery_interceptors: Default::default(),
+            routers: Default::default(),
+            stats: Default::default(),
+            stats_queue: config.stats.as_ref().map(|s| s.queue.clone()),
+            lifecycle_queue: config.lifecycle.as_ref().map(|l| l.queue.clone()),
+            lifecycle: Default::default(),
+            slow_preload: config.slow_preload.clone().unwrap_or_default(),
+            preload_timeout: config.preload_timeout.map(Duration::from_millis),
+            max_preload: config.max_preload,
+            cold_tier: None,
+            segment_duration: config
+                .segments
+                .as_ref()
+                .map(|s| Duration::from_secs(s.duration)),
+            retention: config
+                .segments
+                .as_ref()
+                .and_then(|s| s.retention)
+                .map(Duration::from_secs),
+            ordered_preload: config.ordered_preload,
+            collapse_superseded: config.collapse_superseded.iter().cloned().collect(),
+            modes: config.modes.clone(),
+            ordering: config.ordering.clone(),
+            key_locks: Default::default(),
+            leases: Default::default(),
+            quarantine: Default::default(),
+            envelopes: Default::default(),
+            clock: Arc::new(SystemClock),
+            hlc: Default::default(),
+            max_clock_drift: Duration::from_secs(config.max_clock_drift),
+            dedup_window: Duration::from_secs(config.dedup_window),
+            dedup_windows: config
+                .dedup_windows
+                .iter()
+                .map(|(queue_name, window)| (queue_name.clone(), Duration::from_secs(*window)))
+                .collect(),
+            trace_retention: config
+                .tracing
+                .as_ref()
+                .map(|t| Duration::from_secs(t.retention)),
+            preload_cache: config.preload_cache.as_ref().map(PreloadCache::new),
+            preload_decoders: Arc::new(Semaphore::new(PRELOAD_DECODERS)),
+            last_preloads: Default::default(),
         };
 
+        this.resync_counters()?;
+        if this.ordered_preload {
+            this.build_offset_indexes()?;
+        }
+
         config
-            .default
+            .default_queue_names()
             .into_iter()
+            .chain(this.stats_queue.clone())
+            .chain(this.lifecycle_queue.clone())
             .try_for_each(|q| this.create_queue(q))?;
 
+        if let Some(warmup) = &config.warmup {
+            this.warm_up(&warmup.hot_keys)?;
+        }
+
+        // publishes acknowledged before the crash are applied before new ones
+        let recovered = this.apply_journal()?;
+        if recovered > 0 {
+            info!(recovered, "recovered journaled publishes");
+        }
+
         Ok(this)
     }
 
+    /// Adds filter which decides if subscribers may see delivered events
+    pub fn with_event_filter(self, event_filter: impl EventFilter<T> + 'static) -> Self {
+        self.with_delivery_interceptor(FilterInterceptor(event_filter))
+    }
+
+    /// Adds interceptor which is invoked in registration order before events are stored
+    pub fn with_publish_interceptor(
+        mut self,
+        interceptor: impl PublishInterceptor<T> + 'static,
+    ) -> Self {
+        self.publish_interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Adds interceptor which is invoked in registration order before events are delivered
+    pub fn with_delivery_interceptor(
+        mut self,
+        interceptor: impl DeliveryInterceptor<T> + 'static,
+    ) -> Self {
+        self.delivery_interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Enables moving of key history older than `hot_updates` entries to the cold store
+    pub fn with_cold_tier(mut self, store: impl BlobStore + 'static, hot_updates: usize) -> Self {
+        self.cold_tier = Some(ColdTier {
+            store: Arc::new(store),
+            // the last entry of the key always stays local
+            hot_updates: hot_updates.max(1),
+        });
+        self
+    }
+
+    /// Replaces the system clock of retention, dedup windows and leases,
+    /// e.g. with `ManualClock` to fast-forward time in tests
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Registers the upgrade of stored events from the format version to the next one,
+    /// so history written by older releases is migrated on read.
+    /// New events are stored with the version after the latest upgrade
+    pub fn with_upgrade(
+        mut self,
+        from: u8,
+        upgrade: impl Fn(Value) -> Result<Value, String> + Send + Sync + 'static,
+    ) -> Self {
+        Arc::make_mut(&mut self.envelopes).register(from, upgrade);
+        self
+    }
+
+    /// Reports queue changes and retention runs as lifecycle events
+    pub fn with_lifecycle(mut self, lifecycle: LifecycleEvents) -> Self {
+        self.lifecycle = lifecycle;
+        self
+    }
+
+    /// Adds router which copies persisted events to other queues
+    pub fn with_router(mut self, router: impl Router<T> + 'static) -> Self {
+        self.routers.push(Arc::new(router));
+        self
+    }
+
     pub fn create_queue(&self, queue_name: String) -> QueueResult<()> {
-        self.map
-            .open_tree(queue_name.as_bytes())
-            .map(|_| ())
-            .map_err(QueueError::from)
+        let conflict = self.mode(&queue_name).conflict(
+            self.max_key_updates,
+            self.collapse_superseded.contains(&queue_name),
+            self.is_system_queue(&queue_name),
+        );
+        if let Some(reason) = conflict {
+            return Err(QueueError::InvalidMode {
+                queue: queue_name,
+                reason,
+            });
+        }
+
+        let created = !self.check_tree_exists(&queue_name);
+        self.databases
+            .route(queue_name.as_bytes())
+            .open_tree(queue_name.as_bytes())?;
+        if created {
+            self.lifecycle
+                .emit(LifecycleEvent::QueueCreated { queue: queue_name });
+        }
+        Ok(())
     }
 
-    pub fn delete_queue(&self, queue_name: String, id: String) -> QueueResult<()> {
-        let mut queue_b = self.queue_broadcasts.lock().unwrap();
-        let queue = get_queue_broadcast(queue_name.clone(), &mut queue_b);
-        queue.keys.remove(&id);
+    /// Statistics and lifecycle queues are written by the server only
+    fn is_system_queue(&self, queue_name: &str) -> bool {
+        [&self.stats_queue, &self.lifecycle_queue]
+            .into_iter()
+            .any(|q| q.as_deref() == Some(queue_name))
+    }
+
+    pub fn lifecycle(&self) -> &LifecycleEvents {
+        &self.lifecycle
+    }
+
+    /// Deletes all stored messages of the key.
+    /// With `tombstone` set, subscribers are kept and receive a tombstone event,
+    /// which also stays in the key history.
+    pub fn delete_key_history(
+        &self,
+        queue_name: String,
+        id: String,
+        tombstone: bool,
+    ) -> QueueResult<()> {
+        if !tombstone {
+            let mut queue_b = self.queue_broadcasts.lock().unwrap();
+            let queue = get_queue_broadcast(queue_name.clone(), &mut queue_b);
+            if let Some(key_sender) = queue.keys.remove(&id) {
+                let _ = key_sender.send(BroadcastMessage::KeyDeleted);
+            }
+        }
+
+        self.remove_key_history(&queue_name, &id)?;
 
-        let mut batch = Batch::default();
+        if tombstone {
+            self.send_to_queue(queue_name, T::tombstone(id))?;
+        }
 
-        let tree = self.map.open_tree(queue_name.as_bytes())?;
+        Ok(())
+    }
 
-        for response in tree.scan_prefix(id.as_bytes()) {
-            let (key, _) = response?;
+    /// Deletes the key copied to the shard which owns it after rebalancing.
+    /// Subscribers are pointed to the owner once the history is deleted
+    pub fn move_key(&self, queue_name: &str, id: &str, address: &str) -> QueueResult<()> {
+        self.remove_key_history(queue_name, id)?;
 
-            batch.remove(key);
+        let mut queue_b = self.queue_broadcasts.lock().unwrap();
+        let key_sender = queue_b
+            .get_mut(queue_name)
+            .and_then(|queue| queue.keys.remove(id));
+        if let Some(key_sender) = key_sender {
+            let _ = key_sender.send(BroadcastMessage::Moved(address.to_string()));
         }
+        Ok(())
+    }
 
-        tree.apply_batch(batch).map_err(QueueError::from)
+    fn remove_key_history(&self, queue_name: &str, id: &str) -> QueueResult<()> {
+        let trees = self.queue_trees(queue_name)?;
+
+        let keys = scan_key(&trees, id)
+            .map(|r| r.map(|(key, _)| key))
+            .collect::<sled::Result<Vec<_>>>()?;
+
+        trees.remove_all(&keys)?;
+        self.remove_cold_segments(queue_name, Some(id))?;
+        self.remove_marks(queue_name, Some(id))?;
+        self.trace_all_removed(queue_name, Some(id), TraceRemoval::Deleted)?;
+        self.remove_session_cursors(queue_name, Some(id))?;
+        self.invalidate_preloads(queue_name, Some(id));
+        Ok(())
     }
 
     pub fn subscribe_queue_by_id(
@@ -78,15 +461,30 @@ where
         queue_name: String,
         id: String,
         sequence: RequestSequence,
+        max_preload: Option<usize>,
+        identity: Option<String>,
     ) -> QueueResult<Subscription<'a, T>> {
         if !self.check_tree_exists(&queue_name) {
             return Ok(Default::default());
         }
-        let tree = self.map.open_tree(queue_name.as_bytes())?;
+        let trees = self.queue_trees(&queue_name)?;
 
-        let prev_items = get_prev_items::<T>(&tree, &id, sequence)?;
+        let subscriber = SubscriberInfo {
+            queue_name: queue_name.clone(),
+            id: Some(id.clone()),
+            identity,
+            subscription: true,
+        };
 
-        let prev_len = prev_items.as_ref().map(|i| i.len());
+        let pipeline = DeliveryPipeline::new(self.delivery_interceptors.clone(), subscriber);
+        let scan = PreloadScan::new(self.preload_timeout);
+        let poison = self.poison_guard(&queue_name, &trees);
+        let prev_items = self.key_preload(&trees, &queue_name, &id, sequence, &scan, &poison)?;
+        let prev_items = self
+            .with_cold_items(&queue_name, Some(&id), sequence, prev_items, &scan)?
+            .map(|p| p.limited(self.preload_limit(max_preload), true));
+        let progress = self.preload_progress(&queue_name, Some(&id), &scan);
+        let preload = prev_items.is_some();
 
         let mut map = self.queue_broadcasts.lock().unwrap();
         let queue = get_queue_broadcast(queue_name, &mut map);
@@ -96,272 +494,3989 @@ where
         drop(map);
 
         Ok(Subscription {
-            stream: Some(prepare_stream(recv, prev_items)),
-            preloaded_count: prev_len,
+            stream: Some(prepare_stream(
+                vec![recv],
+                prev_items,
+                progress,
+                pipeline,
+                poison,
+                self.preload_decoders.clone(),
+                self.stats.clone(),
+            )),
+            preload,
         })
     }
 
-    pub fn subscribe_queue(
+    /// Subscribes to several keys of the queue with one stream.
+    /// Histories are preloaded key by key, live events of all keys are merged.
+    pub fn subscribe_queue_by_ids(
         &self,
         queue_name: String,
+        mut ids: Vec<String>,
         sequence: RequestSequence,
+        max_preload: Option<usize>,
+        identity: Option<String>,
     ) -> QueueResult<Subscription<'a, T>> {
         if !self.check_tree_exists(&queue_name) {
             return Ok(Default::default());
         }
-        let tree = self.map.open_tree(queue_name.as_bytes())?;
+        ids.sort_unstable();
+        ids.dedup();
+        let trees = self.queue_trees(&queue_name)?;
 
-        let prev_items = get_prev_all_items::<T>(&tree, sequence)?;
+        let subscriber = SubscriberInfo {
+            queue_name: queue_name.clone(),
+            id: None,
+            identity,
+            subscription: true,
+        };
 
-        let prev_len = prev_items.as_ref().map(|i| i.len());
+        let pipeline = DeliveryPipeline::new(self.delivery_interceptors.clone(), subscriber);
+        let scan = PreloadScan::new(self.preload_timeout);
+        let poison = self.poison_guard(&queue_name, &trees);
+        let mut prev_items: Option<Preload<T>> = None;
+        for id in &ids {
+            let items = get_prev_items::<T>(&trees, id, sequence, &scan, &poison)?;
+            let items = self.with_cold_items(&queue_name, Some(id), sequence, items, &scan)?;
+            prev_items = match (prev_items, items) {
+                (Some(prev_items), Some(items)) => Some(prev_items.chain(items)),
+                (prev_items, items) => prev_items.or(items),
+            };
+        }
+        // merged histories have no single sequence to continue from
+        let prev_items = prev_items.map(|p| p.limited(self.preload_limit(max_preload), false));
+        let progress = self.preload_progress(&queue_name, None, &scan);
+        let preload = prev_items.is_some();
 
         let mut map = self.queue_broadcasts.lock().unwrap();
         let queue = get_queue_broadcast(queue_name, &mut map);
-
-        let recv = queue.sender.subscribe();
+        let receivers = ids
+            .into_iter()
+            .map(|id| get_key_broadcast(id, queue).subscribe())
+            .collect();
         drop(map);
 
         Ok(Subscription {
-            stream: Some(prepare_stream(recv, prev_items)),
-            preloaded_count: prev_len,
+            stream: Some(prepare_stream(
+                receivers,
+                prev_items,
+                progress,
+                pipeline,
+                poison,
+                self.preload_decoders.clone(),
+                self.stats.clone(),
+            )),
+            preload,
         })
     }
 
-    pub fn send_to_queue(&self, queue_name: String, mut value: T) -> QueueResult<bool> {
+    pub fn subscribe_queue(
+        &self,
+        queue_name: String,
+        sequence: RequestSequence,
+        max_preload: Option<usize>,
+        identity: Option<String>,
+    ) -> QueueResult<Subscription<'a, T>> {
         if !self.check_tree_exists(&queue_name) {
-            return Ok(false);
+            return Ok(Default::default());
         }
+        let trees = self.queue_trees(&queue_name)?;
 
-        let id = value.get_id();
+        let subscriber = SubscriberInfo {
+            queue_name: queue_name.clone(),
+            id: None,
+            identity,
+            subscription: true,
+        };
 
-        let sequence = match value.get_sequence() {
-            None => {
-                let id = self.generate_next_id(&queue_name, id)?;
+        let pipeline = DeliveryPipeline::new(self.delivery_interceptors.clone(), subscriber);
+        let scan = PreloadScan::new(self.preload_timeout);
+        let poison = self.poison_guard(&queue_name, &trees);
+        let offsets = match self.ordered_preload {
+            true => Some(self.offsets_tree(&queue_name)?),
+            false => None,
+        };
+        let prev_items = match sequence {
+            // every subscription from the last events would scan the whole queue,
+            // so concurrent ones share one snapshot
+            Some(RequestSequenceId::Last) => self
+                .last_preloads
+                .run(&queue_name, || {
+                    get_prev_all_items::<T>(&trees, offsets, sequence, &scan, &poison)
+                        .map(|p| Arc::new(p.map(|p| p.decoded).unwrap_or_default()))
+                })
+                .map(|items| Some(Preload::decoded(items.as_ref().clone())))?,
+            _ => get_prev_all_items::<T>(&trees, offsets, sequence, &scan, &poison)?,
+        };
+        let prev_items = self
+            .with_cold_items(&queue_name, None, sequence, prev_items, &scan)?
+            .map(|p| p.limited(self.preload_limit(max_preload), false));
+        let progress = self.preload_progress(&queue_name, None, &scan);
+        let preload = prev_items.is_some();
 
-                value.set_sequence(id);
+        let mut map = self.queue_broadcasts.lock().unwrap();
+        let queue = get_queue_broadcast(queue_name, &mut map);
 
-                id.get()
-            }
-            Some(s) => s.get(),
-        };
+        let recv = queue.sender.subscribe();
+        drop(map);
 
-        if !matches!(self.max_key_updates, Some(0)) {
-            let id = get_id(value.get_id(), sequence);
+        Ok(Subscription {
+            stream: Some(prepare_stream(
+                vec![recv],
+                prev_items,
+                progress,
+                pipeline,
+                poison,
+                self.preload_decoders.clone(),
+                self.stats.clone(),
+            )),
+            preload,
+        })
+    }
 
-            let tree = self.map.open_tree(queue_name.as_bytes())?;
+    pub fn send_to_queue(&self, queue_name: String, value: T) -> QueueResult<bool> {
+        self.publish(queue_name, value).map(|s| s.is_some())
+    }
 
-            tree.insert(id, serde_json::to_vec(&value)?)?;
+    /// Sends the event like `send_to_queue`, returns sequences of the events stored in the queue,
+    /// interceptors may split the event or drop it. Returns `None` if queue doesn't exist.
+    pub fn publish(&self, queue_name: String, value: T) -> QueueResult<Option<Vec<SequenceId>>> {
+        if !self.check_publish(&queue_name)? {
+            return Ok(None);
+        }
 
-            if let Some(m) = self.max_key_updates {
-                let mut batch = Batch::default();
+        let values = self.intercept(&queue_name, value)?;
+        self.store_intercepted(&queue_name, values, None).map(Some)
+    }
 
-                tree.scan_prefix(value.get_id().as_bytes())
-                    .rev()
-                    .skip(m - 1)
-                    .try_for_each::<_, QueueResult<()>>(|r| {
-                        let (k, _) = r?;
-                        batch.remove(k);
-                        Ok(())
-                    })?;
+    /// Rejects events with timestamps ahead of the server clock and passes events
+    /// through publish interceptors, which may split or drop them
+    fn intercept(&self, queue_name: &str, value: T) -> QueueResult<Vec<T>> {
+        let max_timestamp = self
+            .clock
+            .unix_millis()
+            .saturating_add(self.max_clock_drift.as_millis() as u64);
+        if let Some(timestamp) = value.get_timestamp().filter(|t| t.wall > max_timestamp) {
+            self.stats.add_rejected();
+            return Err(InterceptorError(format!(
+                "timestamp {} is ahead of the server clock",
+                timestamp
+            ))
+            .into());
+        }
 
-                tree.apply_batch(batch)?;
-            }
+        self.publish_interceptors
+            .iter()
+            .try_fold(vec![value], |values, i| {
+                values.into_iter().try_fold(Vec::new(), |mut acc, v| {
+                    acc.extend(i.on_publish(queue_name, v)?);
+                    Ok::<_, InterceptorError>(acc)
+                })
+            })
+            .map_err(|e| {
+                self.stats.add_rejected();
+                e.into()
+            })
+    }
+
+    /// Stores and broadcasts intercepted events and their routed copies
+    fn store_intercepted(
+        &self,
+        queue_name: &str,
+        values: Vec<T>,
+        mut journal: Option<&mut JournalCursor>,
+    ) -> QueueResult<Vec<SequenceId>> {
+        self.stats.add_published(values.len() as u64);
+
+        let mut sequences = Vec::with_capacity(values.len());
+        for value in values {
+            let routed: Vec<_> = self
+                .routers
+                .iter()
+                .flat_map(|r| r.route(queue_name, &value))
+                .collect();
+
+            sequences.extend(self.store_and_broadcast(
+                queue_name,
+                value,
+                journal.as_deref_mut(),
+            )?);
+
+            routed.into_iter().try_for_each(|(target, value)| {
+                if !self.check_tree_exists(&target) {
+                    warn!(queue = %queue_name, target = %target, "route target queue does not exist");
+                    return Ok(());
+                }
+                self.store_and_broadcast(&target, value, journal.as_deref_mut())
+                    .map(|_| ())
+            })?;
         }
 
-        let mut map = self.queue_broadcasts.lock().unwrap();
+        Ok(sequences)
+    }
 
-        let queue = get_queue_broadcast(queue_name, &mut map);
-        if let Err(e) = queue.sender.send(value.clone()) {
-            error!("broadcast message to queue subscribers error: {}", e)
+    /// Returns `false` if queue doesn't exist, rejects publishes to system queues
+    /// and publishes while the disk is full
+    fn check_publish(&self, queue_name: &str) -> QueueResult<bool> {
+        if !self.check_tree_exists(queue_name) {
+            return Ok(false);
         }
 
-        let key_sender = get_key_broadcast(value.get_id().to_string(), queue);
-        if let Err(e) = key_sender.send(value) {
-            error!("broadcast message to key subscribers error: {}", e)
+        if self.is_system_queue(queue_name) {
+            self.stats.add_rejected();
+            return Err(InterceptorError(format!("{} is a system queue", queue_name)).into());
+        }
+
+        if matches!(&self.disk, Some(d) if d.rejects_publishes()) {
+            self.stats.add_rejected();
+            return Err(QueueError::DiskFull);
         }
 
         Ok(true)
     }
 
-    pub fn close_queue(&self, queue_name: String) -> QueueResult<bool> {
-        let mut queue_b = self.queue_broadcasts.lock().unwrap();
-        queue_b.remove(&queue_name);
+    pub fn is_journaled(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    /// Checks events like `publish` and appends the accepted ones to the publish journal
+    /// with one sync, they are stored by `apply_journal` in background.
+    /// Duplicates of dedup ids are accepted without appending.
+    /// Returns results of events in order, `None` if queue doesn't exist
+    pub fn append_journal(
+        &self,
+        queue_name: String,
+        events: Vec<(T, Option<String>)>,
+    ) -> QueueResult<Option<Vec<QueueResult<()>>>> {
+        let journal = match &self.journal {
+            None => return Ok(None),
+            Some(journal) => journal,
+        };
+        if !self.check_publish(&queue_name)? {
+            return Ok(None);
+        }
+
+        let mut results = Vec::with_capacity(events.len());
+        let mut records = Vec::new();
+        let mut claimed = Vec::new();
+        for (event, dedup_id) in events {
+            let values = match self.intercept(&queue_name, event) {
+                Ok(values) => values,
+                Err(e) => {
+                    results.push(Err(e));
+                    continue;
+                }
+            };
+            results.push(Ok(()));
+
+            if let Some(dedup_id) = dedup_id {
+                if !self.claim_dedup_id(&queue_name, &dedup_id)? {
+                    continue;
+                }
+                claimed.push(dedup_id);
+            }
+            if !values.is_empty() {
+                records.push(JournalRecord {
+                    queue: queue_name.clone(),
+                    events: values,
+                });
+            }
+        }
 
-        self.map.drop_tree(queue_name).map_err(QueueError::from)
+        if records.is_empty() {
+            return Ok(Some(results));
+        }
+        if let Err(e) = journal.append(&records) {
+            // the publisher retries failed events with the same ids
+            for dedup_id in claimed {
+                self.release_dedup_id(&queue_name, &dedup_id)?;
+            }
+            return Err(e.into());
+        }
+        Ok(Some(results))
     }
 
-    fn check_tree_exists(&self, queue_name: &str) -> bool {
-        matches!(
+    /// Stores journaled events in order, returns count of applied records.
+    /// Events are checked before they are journaled, so storage errors stop applying
+    /// until the next run and acknowledged events are not lost. Records of missing queues,
+    /// rejected records and records which failed too many times are dead lettered.
+    /// The journal is truncated when all events are applied and synced to the databases.
+    /// Concurrent calls wait for each other, so records are applied once
+    pub fn apply_journal(&self) -> QueueResult<usize> {
+        let journal = match &self.journal {
+            None => return Ok(0),
+            Some(journal) => journal,
+        };
+        let _applier = journal.lock_applier();
+
+        let progress = self.journal_progress()?;
+        let (start, records) = journal.read_from::<T>(progress.offset)?;
+        let mut cursor = JournalCursor {
+            progress,
+            skip: progress.stored,
+        };
+        if start != progress.offset {
+            // progress of the interrupted truncation is after the end of the journal
+            self.reset_journal_progress()?;
+            cursor = JournalCursor::at(start);
+        }
+
+        let mut published = 0;
+        let mut result = Ok(());
+        for (end, record) in records {
+            if !self.check_tree_exists(&record.queue) {
+                self.dead_letter_journaled(&record, &cursor, end, "queue does not exist")?;
+            } else if let Err(e) =
+                self.store_intercepted(&record.queue, record.events.clone(), Some(&mut cursor))
+            {
+                if e.is_storage() && !journal.fail(cursor.progress.offset) {
+                    result = Err(e);
+                    break;
+                }
+                self.dead_letter_journaled(&record, &cursor, end, &e.to_string())?;
+            } else {
+                published += 1;
+            }
+            cursor = JournalCursor::at(end);
             self.map
-                .tree_names()
-                .into_iter()
-                .find(|v| v == queue_name.as_bytes()),
-            Some(_)
-        )
+                .insert(JOURNAL_KEY, &cursor.progress.encode()[..])?;
+        }
+
+        let applied = cursor.progress;
+        if applied.stored == 0 && applied.offset > 0 && journal.size()? == applied.offset {
+            self.databases.flush()?;
+            journal.truncate_applied(applied.offset, || {
+                self.reset_journal_progress()?;
+                self.databases.flush()?;
+                Ok::<_, QueueError>(())
+            })?;
+        }
+
+        result.map(|_| published)
     }
 
-    fn generate_next_id(&self, queue_name: &str, id: &str) -> QueueResult<SequenceId> {
-        let mut key = Vec::from("id_");
-        key.extend_from_slice(queue_name.as_bytes());
-        key.extend_from_slice(id.as_bytes());
+    /// Moves the record out of the journal together with the progress after it,
+    /// events of the record stored before the failure are kept
+    fn dead_letter_journaled(
+        &self,
+        record: &JournalRecord<T>,
+        cursor: &JournalCursor,
+        end: u64,
+        reason: &str,
+    ) -> QueueResult<()> {
+        let key = get_journal_dead_key(record.queue.as_bytes(), self.map.generate_id()?);
+        let mut batch = sled::Batch::default();
+        batch.insert(key, serde_json::to_vec(record)?);
+        batch.insert(JOURNAL_KEY, &JournalCursor::at(end).progress.encode()[..]);
+        self.map.apply_batch(batch)?;
+        self.stats.add_dead_lettered();
 
-        let res = self.map.update_and_fetch(key, |v| {
-            v.and_then(|v| Some(u64::from_be_bytes(v.try_into().ok()?)))
-                .and_then(|id| id.checked_add(1))
-                .map(|id| IVec::from(&id.to_be_bytes()))
-                .unwrap_or_else(|| IVec::from(&1u64.to_be_bytes()))
-                .into()
-        })?;
+        warn!(
+            queue = %record.queue,
+            events = record.events.len(),
+            stored = cursor.progress.stored,
+            reason,
+            "journaled events dead lettered"
+        );
+        Ok(())
+    }
 
-        res.and_then(|r| Some(u64::from_be_bytes(r.as_ref().try_into().ok()?)))
-            .and_then(SequenceId::new)
-            .map(Ok)
-            .unwrap_or_else(|| Err(QueueError::ZeroSequence))
+    /// Returns the furthest journal progress stored in the databases
+    fn journal_progress(&self) -> QueueResult<JournalProgress> {
+        self.databases
+            .databases()
+            .try_fold(JournalProgress::default(), |progress, db| {
+                let stored = db
+                    .get(JOURNAL_KEY)?
+                    .and_then(|v| JournalProgress::decode(&v))
+                    .unwrap_or_default();
+                Ok(progress.max(stored))
+            })
     }
-}
 
-fn prepare_stream<'a, T: 'a + DeserializeOwned + Send + Clone>(
-    mut receiver: Receiver<T>,
-    prev_items: Option<Vec<T>>,
-) -> BoxStream<'a, BroadcastMessage<T>> {
-    Box::pin(async_stream::stream! {
-        if let Some(pi) = prev_items {
-            let mut iter = pi.into_iter();
-            while let Some(e) = iter.next() {
-                yield BroadcastMessage::Message(e)
-            }
-        }
-        while let Ok(value) = receiver.recv().await {
-            yield BroadcastMessage::Message(value)
+    /// Removes the journal progress of all databases. The main database is the last one,
+    /// so the interrupted reset leaves the progress after the end of the truncated journal
+    fn reset_journal_progress(&self) -> QueueResult<()> {
+        for db in self.databases.databases().rev() {
+            db.remove(JOURNAL_KEY)?;
         }
-    })
-}
+        Ok(())
+    }
 
-fn get_id(id: &str, sequence: u64) -> Vec<u8> {
-    let mut id = Vec::from(id.as_bytes());
-    id.extend_from_slice(&sequence.to_be_bytes());
+    /// Inserts the journaled event together with the journal progress in one transaction
+    fn insert_journaled(
+        &self,
+        queue_name: &str,
+        tree: &Tree,
+        id: &[u8],
+        encoded: &[u8],
+        cursor: &mut JournalCursor,
+    ) -> QueueResult<Option<IVec>> {
+        let progress = JournalProgress {
+            stored: cursor.progress.stored + 1,
+            ..cursor.progress
+        };
+        let db = self.databases.route(queue_name.as_bytes());
 
-    id
-}
+        let previous = (tree, &**db)
+            .transaction(|(tree, db)| {
+                db.insert(JOURNAL_KEY, &progress.encode()[..])?;
+                Ok::<_, ConflictableTransactionError<Infallible>>(tree.insert(id, encoded)?)
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(never) => match never {},
+                TransactionError::Storage(e) => e,
+            })?;
 
-fn get_prev_items<T: DeserializeOwned>(
-    tree: &Tree,
-    id: &str,
-    sequence: RequestSequence,
-) -> QueueResult<Option<Vec<T>>> {
-    sequence
-        .map(|sequence_id| {
-            extract_sequences(tree, sequence_id, id)
-                .map(|r| {
-                    r.map(|(_, v)| v)
-                        .map_err(QueueError::from)
-                        .and_then(|v| serde_json::from_slice(&v).map_err(QueueError::from))
-                })
-                .collect()
-        })
-        .transpose()
-}
+        cursor.progress = progress;
+        Ok(previous)
+    }
 
-fn extract_sequences(
-    tree: &Tree,
-    sequence_id: RequestSequenceId,
-    id: &str,
-) -> Box<dyn Iterator<Item = sled::Result<(IVec, IVec)>>> {
-    match sequence_id {
-        RequestSequenceId::Id(s) => Box::new(tree.range(get_id(id, s.get())..get_id(id, u64::MAX))),
-        RequestSequenceId::Last => Box::new(tree.scan_prefix(id.as_bytes()).rev().take(1)),
-        RequestSequenceId::First => Box::new(tree.scan_prefix(id.as_bytes())),
+    /// Waits for the next append to the publish journal, never returns without the journal
+    pub async fn journal_appended(&self) {
+        match &self.journal {
+            None => futures::future::pending().await,
+            Some(journal) => journal.appended().await,
+        }
     }
-}
 
-fn get_prev_all_items<T: DeserializeOwned + UniqId>(
-    tree: &Tree,
-    sequence: RequestSequence,
-) -> QueueResult<Option<Vec<T>>> {
-    sequence
-        .map(|sequence_id| {
-            let i = tree.iter().values().map(|v| {
-                v.map_err(QueueError::from)
-                    .and_then(|v| serde_json::from_slice(&v).map_err(QueueError::from))
-            });
+    /// Sends event to the system stats queue, bypassing interceptors and routing
+    pub fn send_stats(&self, value: T) -> QueueResult<bool> {
+        match &self.stats_queue {
+            None => Ok(false),
+            Some(queue_name) => self
+                .store_and_broadcast(queue_name, value, None)
+                .map(|_| true),
+        }
+    }
 
-            let i: Box<dyn Iterator<Item = Result<T, QueueError>>> = match sequence_id {
-                RequestSequenceId::Id(s) => {
-                    Box::new(i.filter(move |v: &Result<T, QueueError>| match v {
-                        Ok(v) => v.get_sequence().filter(|cs| *cs >= s).is_some(),
-                        Err(_) => true,
-                    }))
-                }
-                RequestSequenceId::Last => {
-                    let mut map: BTreeMap<String, T> = BTreeMap::new();
+    /// Sends event to the system lifecycle queue, bypassing interceptors and routing
+    pub fn send_lifecycle(&self, value: T) -> QueueResult<bool> {
+        match &self.lifecycle_queue {
+            None => Ok(false),
+            Some(queue_name) => self
+                .store_and_broadcast(queue_name, value, None)
+                .map(|_| true),
+        }
+    }
 
-                    for item in i {
-                        match item {
-                            Ok(v) => {
-                                map.insert(v.get_id().to_string(), v);
-                            }
-                            e @ Err(_) => return e.map(|r| vec![r]),
-                        }
-                    }
+    /// Returns delivery metrics of the queue channel and its key channels,
+    /// `None` if queue doesn't exist
+    pub fn broadcasts(&self, queue_name: String) -> Option<Broadcasts> {
+        if !self.check_tree_exists(&queue_name) {
+            return None;
+        }
 
-                    Box::new(map.into_values().map(Ok))
-                }
-                RequestSequenceId::First => Box::new(i),
-            };
+        let map = self.queue_broadcasts.lock().unwrap();
+        let broadcasts = match map.get(&queue_name) {
+            None => Broadcasts {
+                queue: Channel::<T>::new().metrics(),
+                keys: Default::default(),
+            },
+            Some(queue) => Broadcasts {
+                queue: queue.sender.metrics(),
+                keys: queue
+                    .keys
+                    .iter()
+                    .map(|(id, key)| (id.clone(), key.metrics()))
+                    .collect(),
+            },
+        };
 
-            i.collect::<Result<Vec<_>, _>>()
-        })
-        .transpose()
-}
+        Some(broadcasts)
+    }
 
-#[derive(Debug, Display, From, Error)]
-pub enum QueueError {
-    Db(sled::Error),
-    Encode(serde_json::Error),
-    #[display(fmt = "sequence must be more then 0")]
-    ZeroSequence,
-}
+    /// Creates the reaper of subscriptions which received nothing from peers for the timeout
+    pub fn reaper(&self, timeout: Duration) -> Reaper {
+        Reaper::new(timeout, self.stats.clone())
+    }
 
-pub type QueueResult<T> = Result<T, QueueError>;
+    /// Counts the compressed response of subscriptions
+    pub fn record_compression(&self, saved: usize) {
+        self.stats.add_compressed(saved as u64);
+    }
 
-#[derive(Debug)]
-struct QueueBroadcast<T> {
-    sender: Sender<T>,
-    keys: HashMap<String, Sender<T>>,
-}
+    /// Returns counters snapshot and active subscribers per queue
+    pub fn stats(&self) -> (StatsSnapshot, HashMap<String, usize>) {
+        let subscribers = self
+            .queue_broadcasts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, queue)| {
+                let count = queue.sender.receiver_count()
+                    + queue
+                        .keys
+                        .values()
+                        .map(|k| k.receiver_count())
+                        .sum::<usize>();
+                (name.clone(), count)
+            })
+            .collect();
 
-fn get_queue_broadcast<T: Clone>(
+        (self.stats.snapshot(), subscribers)
+    }
+
+    /// Returns the sequence of the stored event, `None` if it was only broadcasted.
+    /// Journaled events stored before a crash are skipped, others are stored together
+    /// with the journal progress
+    fn store_and_broadcast(
+        &self,
+        queue_name: &str,
+        mut value: T,
+        journal: Option<&mut JournalCursor>,
+    ) -> QueueResult<Option<SequenceId>> {
+        // strict queues write events of one key one by one, so sequences, storage
+        // and broadcasts follow the same order
+        let _key_lock = match self.ordering(queue_name) {
+            QueueOrdering::Strict => Some(self.key_locks.lock(queue_name, &value.get_id())),
+            QueueOrdering::Relaxed => None,
+        };
+
+        // replicated events keep their timestamps, new ones are ordered after the published one
+        match (value.get_sequence(), value.get_timestamp()) {
+            (Some(_), Some(timestamp)) => self.hlc.observe(timestamp),
+            (_, observed) => {
+                value.set_timestamp(self.hlc.tick_after(self.clock.as_ref(), observed))
+            }
+        }
+
+        let mode = self.mode(queue_name);
+        if value.is_ephemeral() || mode == QueueMode::LiveOnly {
+            self.broadcast(queue_name, value);
+            return Ok(None);
+        }
+
+        // events which are not stored don't move the journal progress
+        let mut journal = journal.filter(|_| !matches!(self.max_key_updates, Some(0)));
+        if let Some(cursor) = journal.as_deref_mut().filter(|c| c.skip > 0) {
+            cursor.skip -= 1;
+            return Ok(None);
+        }
+
+        let sequence = match value.get_sequence() {
+            None => {
+                let id = self.generate_next_id(queue_name, &value.get_id())?;
+
+                value.set_sequence(id);
+
+                id.get()
+            }
+            Some(s) => {
+                self.advance_counter(queue_name, &value.get_id(), s.get())?;
+                s.get()
+            }
+        };
+
+        if !matches!(self.max_key_updates, Some(0)) {
+            let id = get_id(&value.get_id(), sequence);
+
+            let tree = self.current_tree(queue_name)?;
+
+            let encoded = self.envelopes.encode(&value)?;
+            let bytes = encoded.len() as i64;
+            let previous = match journal {
+                None => tree.insert(&id, encoded)?,
+                Some(cursor) => self.insert_journaled(queue_name, &tree, &id, &encoded, cursor)?,
+            };
+            self.trace_stored(queue_name, &id)?;
+            match previous {
+                None => self.update_size(queue_name, value.get_id().as_bytes(), 1, bytes)?,
+                Some(p) => self.update_size(
+                    queue_name,
+                    value.get_id().as_bytes(),
+                    0,
+                    bytes - p.len() as i64,
+                )?,
+            }
+
+            let offset = self.map.generate_id()?;
+            self.advance_heads(queue_name, &value.get_id(), sequence, offset)?;
+
+            if let Some(superseded) = value.get_supersedes().filter(|s| s.get() < sequence) {
+                if self.collapse_superseded.contains(queue_name) {
+                    self.collapse(queue_name, &value.get_id(), superseded.get(), sequence)?;
+                }
+            }
+
+            if self.ordered_preload {
+                self.offsets_tree(queue_name)?
+                    .insert(offset.to_be_bytes(), id)?;
+            }
+
+            if let Some(m) = self.max_key_updates {
+                let trees = self.queue_trees(queue_name)?;
+
+                let entries = scan_key(&trees, &value.get_id())
+                    .rev()
+                    .skip(m - 1)
+                    .map(|r| r.map(|(k, v)| (k, v.len())))
+                    .collect::<sled::Result<Vec<_>>>()?;
+
+                if !entries.is_empty() {
+                    let keys: Vec<_> = entries.iter().map(|(k, _)| k.clone()).collect();
+                    trees.remove_all(&keys)?;
+                    self.shrink_sizes(queue_name, &entries)?;
+                    self.trace_removed(
+                        queue_name,
+                        keys.iter().map(|k| k.as_ref()),
+                        TraceRemoval::Trimmed,
+                    )?;
+                }
+            }
+
+            self.invalidate_preloads(queue_name, Some(&value.get_id()));
+        }
+
+        if mode != QueueMode::PersistOnly {
+            self.broadcast(queue_name, value);
+        }
+
+        Ok(SequenceId::new(sequence))
+    }
+
+    /// Storage and broadcast mode of the queue, persisted by default
+    fn mode(&self, queue_name: &str) -> QueueMode {
+        self.modes.get(queue_name).copied().unwrap_or_default()
+    }
+
+    /// Per key ordering of the queue, relaxed by default
+    fn ordering(&self, queue_name: &str) -> QueueOrdering {
+        self.ordering.get(queue_name).copied().unwrap_or_default()
+    }
+
+    /// Sends the event to live subscribers of the queue, its key and parent queues
+    fn broadcast(&self, queue_name: &str, value: T) {
+        let sequence = value.get_sequence();
+        let mut map = self.queue_broadcasts.lock().unwrap();
+
+        if self.hierarchy {
+            parent_queues(queue_name)
+                .filter_map(|parent| map.get(parent))
+                .for_each(|parent| {
+                    let _ = parent.sender.send(BroadcastMessage::Message(value.clone()));
+                    if let Some(key_sender) = parent.keys.get(value.get_id()) {
+                        let _ = key_sender.send(BroadcastMessage::Message(value.clone()));
+                    }
+                });
+        }
+
+        let key = value.get_id().to_string();
+
+        let queue = get_queue_broadcast(queue_name.to_string(), &mut map);
+        if let Err(e) = queue.sender.send(BroadcastMessage::Message(value.clone())) {
+            error!(
+                queue = queue_name,
+                key = %key,
+                sequence,
+                error = %e,
+                "broadcast message to queue subscribers error"
+            )
+        }
+
+        let key_sender = get_key_broadcast(key.clone(), queue);
+        if let Err(e) = key_sender.send(BroadcastMessage::Message(value)) {
+            error!(
+                queue = queue_name,
+                key = %key,
+                sequence,
+                error = %e,
+                "broadcast message to key subscribers error"
+            )
+        }
+    }
+
+    pub fn drop_queue(&self, queue_name: String) -> QueueResult<bool> {
+        let mut queue_b = self.queue_broadcasts.lock().unwrap();
+        if let Some(queue) = queue_b.remove(&queue_name) {
+            let _ = queue.sender.send(BroadcastMessage::QueueClosed);
+            queue.keys.values().for_each(|key_sender| {
+                let _ = key_sender.send(BroadcastMessage::QueueClosed);
+            });
+        }
+
+        self.remove_cold_segments(&queue_name, None)?;
+        self.remove_marks(&queue_name, None)?;
+        self.remove_session_cursors(&queue_name, None)?;
+        self.remove_traces(&queue_name)?;
+        self.map.remove(get_meta_key(queue_name.as_bytes()))?;
+        self.drop_segments(&queue_name)?;
+        self.invalidate_preloads(&queue_name, None);
+        let db = self.databases.route(queue_name.as_bytes());
+        db.drop_tree(offsets_tree_name(queue_name.as_bytes()))?;
+        let dropped = db.drop_tree(queue_name.as_bytes())?;
+        if dropped {
+            self.lifecycle
+                .emit(LifecycleEvent::QueueDropped { queue: queue_name });
+        }
+        Ok(dropped)
+    }
+
+    /// Notifies all subscribers about server shutdown
+    pub fn drain(&self) {
+        let queue_b = self.queue_broadcasts.lock().unwrap();
+        queue_b.values().for_each(|queue| {
+            let _ = queue.sender.send(BroadcastMessage::Draining);
+            queue.keys.values().for_each(|key_sender| {
+                let _ = key_sender.send(BroadcastMessage::Draining);
+            });
+        });
+    }
+
+    /// Applies the publish journal and syncs databases, so writes acknowledged to publishers
+    /// are stored before subscriptions are drained. Returns count of applied journaled events
+    pub fn flush(&self) -> QueueResult<usize> {
+        let applied = self.apply_journal()?;
+        self.databases.flush()?;
+        Ok(applied)
+    }
+
+    /// Returns count of open subscriptions and messages which some of them didn't receive yet
+    pub fn undelivered(&self) -> (usize, usize) {
+        let queue_b = self.queue_broadcasts.lock().unwrap();
+        queue_b
+            .values()
+            .flat_map(|queue| std::iter::once(&queue.sender).chain(queue.keys.values()))
+            .fold((0, 0), |(subscribers, undelivered), channel| {
+                (
+                    subscribers + channel.receiver_count(),
+                    undelivered + channel.pending(),
+                )
+            })
+    }
+
+    /// Points all subscribers to the new primary after failover
+    pub fn move_subscribers(&self, address: &str) {
+        let queue_b = self.queue_broadcasts.lock().unwrap();
+        queue_b.values().for_each(|queue| {
+            let _ = queue
+                .sender
+                .send(BroadcastMessage::Moved(address.to_string()));
+            queue.keys.values().for_each(|key_sender| {
+                let _ = key_sender.send(BroadcastMessage::Moved(address.to_string()));
+            });
+        });
+    }
+
+    /// Removes every stored message of the queue, but keeps the queue itself,
+    /// its subscribers and sequence counters.
+    pub fn clear_queue(&self, queue_name: String) -> QueueResult<bool> {
+        if !self.check_tree_exists(&queue_name) {
+            return Ok(false);
+        }
+
+        let tree = self
+            .databases
+            .route(queue_name.as_bytes())
+            .open_tree(queue_name.as_bytes())?;
+        tree.clear()?;
+        self.drop_segments(&queue_name)?;
+        self.remove_cold_segments(&queue_name, None)?;
+        self.remove_marks(&queue_name, None)?;
+        self.trace_all_removed(&queue_name, None, TraceRemoval::Cleared)?;
+        self.invalidate_preloads(&queue_name, None);
+        if self.ordered_preload {
+            self.offsets_tree(&queue_name)?.clear()?;
+        }
+
+        Ok(true)
+    }
+
+    /// Removes the stored event and notifies subscribers, so clients can retract it.
+    /// Returns `false` if the event is not stored, `None` if queue doesn't exist
+    pub fn revoke_event(
+        &self,
+        queue_name: String,
+        id: String,
+        sequence: SequenceId,
+    ) -> QueueResult<Option<bool>> {
+        if !self.check_tree_exists(&queue_name) {
+            return Ok(None);
+        }
+
+        let trees = self.queue_trees(&queue_name)?;
+        let key = IVec::from(get_id(&id, sequence.get()));
+        let removed = match trees.get(&key)? {
+            Some(value) => {
+                trees.remove_all(&[key.clone()])?;
+                self.shrink_sizes(&queue_name, &[(key.clone(), value.len())])?;
+                true
+            }
+            None => self.revoke_cold_event(&queue_name, &id, sequence.get())?,
+        };
+        if removed {
+            self.trace_removed(&queue_name, [key.as_ref()], TraceRemoval::Revoked)?;
+        }
+        self.invalidate_preloads(&queue_name, Some(&id));
+
+        // not stored events may still be shown by clients, e.g. without history
+        if sequence.get() > self.published_sequence(&queue_name, &id)? {
+            return Ok(Some(removed));
+        }
+
+        let map = self.queue_broadcasts.lock().unwrap();
+        let revoked = BroadcastMessage::Revoked(id.clone(), sequence);
+        let queues: Vec<&str> = match self.hierarchy {
+            true => parent_queues(&queue_name)
+                .chain(Some(queue_name.as_str()))
+                .collect(),
+            false => vec![queue_name.as_str()],
+        };
+        queues
+            .into_iter()
+            .filter_map(|queue_name| map.get(queue_name))
+            .for_each(|queue| {
+                let _ = queue.sender.send(revoked.clone());
+                if let Some(key_sender) = queue.keys.get(&id) {
+                    let _ = key_sender.send(revoked.clone());
+                }
+            });
+
+        Ok(Some(removed))
+    }
+
+    /// Acquires the single writer lease of the key or extends it with the current token.
+    /// `None` if queue doesn't exist
+    pub fn acquire_lease(
+        &self,
+        queue_name: String,
+        id: String,
+        ttl: Duration,
+        token: Option<String>,
+    ) -> QueueResult<Option<Lease>> {
+        if !self.check_tree_exists(&queue_name) {
+            return Ok(None);
+        }
+
+        self.leases
+            .acquire(&queue_name, &id, ttl, token.as_deref(), self.clock.now())
+            .map(Some)
+            .map_err(QueueError::from)
+    }
+
+    /// Releases the lease held with the token, returns `false` if it is not held
+    pub fn release_lease(&self, queue_name: String, id: String, token: String) -> bool {
+        self.leases.release(&queue_name, &id, &token)
+    }
+
+    /// Leased keys accept events only from the lease holder
+    pub fn check_lease(&self, queue_name: &str, id: &str, token: Option<&str>) -> QueueResult<()> {
+        self.leases
+            .check(queue_name, id, token, self.clock.now())
+            .map_err(QueueError::from)
+    }
+
+    /// Returns count of the stored key entries, `None` if queue doesn't exist
+    pub fn count_key(&self, queue_name: String, id: String) -> QueueResult<Option<usize>> {
+        if !self.check_tree_exists(&queue_name) {
+            return Ok(None);
+        }
+
+        let trees = self.queue_trees(&queue_name)?;
+
+        scan_key(&trees, &id)
+            .try_fold(0, |count, r| r.map(|_| count + 1))
+            .map(Some)
+            .map_err(QueueError::from)
+    }
+
+    /// Returns the newest stored key entries from newest to oldest, `None` if queue doesn't exist
+    pub fn peek_key(
+        &self,
+        queue_name: String,
+        id: String,
+        n: usize,
+    ) -> QueueResult<Option<Vec<T>>> {
+        if !self.check_tree_exists(&queue_name) {
+            return Ok(None);
+        }
+
+        let trees = self.queue_trees(&queue_name)?;
+        let pipeline = DeliveryPipeline::new(
+            self.delivery_interceptors.clone(),
+            SubscriberInfo {
+                queue_name,
+                id: Some(id.clone()),
+                identity: None,
+                subscription: false,
+            },
+        );
+
+        scan_key(&trees, &id)
+            .rev()
+            .take(n)
+            .map(|r| {
+                r.map_err(QueueError::from)
+                    .and_then(|(_, v)| self.envelopes.decode::<T>(&v).map_err(QueueError::from))
+            })
+            .filter_map(|r| r.map(|v| pipeline.deliver(v)).transpose())
+            .collect::<QueueResult<_>>()
+            .map(Some)
+    }
+
+    /// Returns a page of key entries ordered by sequences, starting from the sequence,
+    /// without attaching to the broadcast. Entries are skipped until the `since` timestamp,
+    /// entries stored without timestamps are older than any of them. `None` if queue doesn't exist
+    pub fn replay_key(
+        &self,
+        queue_name: String,
+        id: String,
+        from: Option<SequenceId>,
+        since: Option<HlcTimestamp>,
+        limit: usize,
+    ) -> QueueResult<Option<Replay<T>>> {
+        if !self.check_tree_exists(&queue_name) {
+            return Ok(None);
+        }
+
+        let from = from.map(|s| s.get()).unwrap_or_default();
+        let is_since = |i: &T| since.map_or(true, |since| i.get_timestamp() >= Some(since));
+        // one more entry is read to find the next page cursor
+        let page = limit.saturating_add(1);
+
+        let mut items: Vec<T> = Vec::new();
+        if let Some(cold_tier) = &self.cold_tier {
+            for segment in self.cold_segments(&queue_name, Some(&id)) {
+                let (_, segment) = segment?;
+                if segment.last < from {
+                    continue;
+                }
+                if items.len() >= page {
+                    break;
+                }
+
+                let data = cold_tier.store.get(&segment.reference)?;
+                items.extend(
+                    self.envelopes
+                        .decode_all::<T>(&data)?
+                        .into_iter()
+                        .filter(|i| i.get_sequence().map(|s| s.get()).unwrap_or_default() >= from)
+                        .filter(is_since),
+                );
+            }
+        }
+
+        let trees = self.queue_trees(&queue_name)?;
+        let local = trees
+            .range(get_id(&id, from)..get_id(&id, u64::MAX))
+            .filter(|r| is_key_entry(r, &id));
+        for r in local {
+            if items.len() >= page {
+                break;
+            }
+            let (_, v) = r?;
+            let item: T = self.envelopes.decode(&v)?;
+            if is_since(&item) {
+                items.push(item);
+            }
+        }
+
+        let next = match items.len() > limit {
+            true => items.drain(limit..).next().and_then(|i| i.get_sequence()),
+            false => None,
+        };
+
+        let pipeline = DeliveryPipeline::new(
+            self.delivery_interceptors.clone(),
+            SubscriberInfo {
+                queue_name,
+                id: Some(id),
+                identity: None,
+                subscription: false,
+            },
+        );
+
+        Ok(Some(Replay {
+            events: items
+                .into_iter()
+                .filter_map(|v| pipeline.deliver(v))
+                .collect(),
+            next,
+        }))
+    }
+
+    /// Returns the latest stored sequence of the key and offset of the queue with their time,
+    /// `None` if queue doesn't exist
+    pub fn head(&self, queue_name: String, id: String) -> QueueResult<Option<Head>> {
+        if !self.check_tree_exists(&queue_name) {
+            return Ok(None);
+        }
+
+        let get_mark = |id: &str| -> QueueResult<Option<HeadMark>> {
+            let mark = self
+                .map
+                .get(get_head_key(queue_name.as_bytes(), id.as_bytes()))?;
+            Ok(mark.and_then(|m| HeadMark::from_bytes(&m)))
+        };
+
+        Ok(Some(Head {
+            key: get_mark(&id)?,
+            queue: get_mark("")?,
+        }))
+    }
+
+    /// Returns queues sorted by name with their head marks and metadata,
+    /// only the queue if it's set and exists
+    pub fn list_queues(&self, only: Option<&str>) -> QueueResult<Vec<QueueSummary>> {
+        let mut names: Vec<_> = self
+            .queue_names()
+            .into_iter()
+            .map(|name| String::from_utf8_lossy(&name).into_owned())
+            .filter(|name| only.map_or(true, |o| o == name))
+            .collect();
+        names.sort();
+
+        names
+            .into_iter()
+            .map(|name| {
+                let mark = self.map.get(get_head_key(name.as_bytes(), b""))?;
+                Ok(QueueSummary {
+                    head: mark.and_then(|m| HeadMark::from_bytes(&m)),
+                    metadata: self.metadata(&name)?,
+                    name,
+                })
+            })
+            .collect()
+    }
+
+    /// Replaces metadata of the queue, returns `false` if queue doesn't exist.
+    /// Metadata is kept when the queue is cleared and removed when it's closed
+    pub fn set_metadata(&self, queue_name: &str, metadata: &QueueMetadata) -> QueueResult<bool> {
+        if !self.check_tree_exists(queue_name) {
+            return Ok(false);
+        }
+
+        let key = get_meta_key(queue_name.as_bytes());
+        match metadata == &QueueMetadata::default() {
+            true => self.map.remove(key)?,
+            false => self.map.insert(key, serde_json::to_vec(metadata)?)?,
+        };
+        Ok(true)
+    }
+
+    /// Returns metadata of the queue, empty if it was not set
+    pub fn metadata(&self, queue_name: &str) -> QueueResult<QueueMetadata> {
+        match self.map.get(get_meta_key(queue_name.as_bytes()))? {
+            None => Ok(Default::default()),
+            Some(v) => Ok(serde_json::from_slice(&v)?),
+        }
+    }
+
+    /// Checks that events were published to the key after the sequence
+    pub fn has_updates_after(
+        &self,
+        queue_name: &str,
+        id: &str,
+        after: SequenceId,
+    ) -> QueueResult<bool> {
+        Ok(self.published_sequence(queue_name, id)? > after.get())
+    }
+
+    /// Sends the event once per publisher dedup id during the dedup window.
+    /// Retried publishes are acknowledged without sending while the first one is remembered.
+    /// Duplicates are acknowledged without sequences.
+    pub fn send_deduplicated(
+        &self,
+        queue_name: String,
+        value: T,
+        dedup_id: &str,
+    ) -> QueueResult<Option<Vec<SequenceId>>> {
+        if !self.check_tree_exists(&queue_name) {
+            return Ok(None);
+        }
+
+        if !self.claim_dedup_id(&queue_name, dedup_id)? {
+            return Ok(Some(vec![]));
+        }
+
+        let result = self.publish(queue_name.clone(), value);
+        // the publisher retries rejected and failed events with the same id
+        if !matches!(result, Ok(Some(_))) {
+            self.release_dedup_id(&queue_name, dedup_id)?;
+        }
+        result
+    }
+
+    /// Claims the dedup id for the dedup window of the queue, returns `false` for duplicates
+    fn claim_dedup_id(&self, queue_name: &str, dedup_id: &str) -> QueueResult<bool> {
+        let key = get_dedup_key(queue_name.as_bytes(), dedup_id.as_bytes());
+        let now = self.clock.unix_secs();
+        let window = self.dedup_window(queue_name);
+
+        let claimed = self.map.fetch_and_update(key, |v| match v {
+            Some(v) if !is_dedup_expired(v, now, window) => Some(v.to_vec()),
+            _ => Some(now.to_be_bytes().to_vec()),
+        })?;
+        if matches!(claimed, Some(v) if !is_dedup_expired(&v, now, window)) {
+            self.stats.add_duplicate();
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn release_dedup_id(&self, queue_name: &str, dedup_id: &str) -> QueueResult<()> {
+        self.map
+            .remove(get_dedup_key(queue_name.as_bytes(), dedup_id.as_bytes()))?;
+        Ok(())
+    }
+
+    /// Removes dedup ids older than the dedup windows of their queues.
+    /// Returns count of removed ids.
+    pub fn drop_expired_dedup_ids(&self) -> QueueResult<usize> {
+        let now = self.clock.unix_secs();
+
+        let mut dropped = 0;
+        for r in self.map.scan_prefix(DEDUP_PREFIX) {
+            let (key, value) = r?;
+            let queue_name = key[DEDUP_PREFIX.len()..]
+                .split(|b| *b == 0)
+                .next()
+                .map(String::from_utf8_lossy)
+                .unwrap_or_default();
+            if !is_dedup_expired(&value, now, self.dedup_window(&queue_name)) {
+                continue;
+            }
+            // the id could be claimed again since it was scanned
+            if self
+                .map
+                .compare_and_swap(key, Some(value), None::<&[u8]>)?
+                .is_ok()
+            {
+                dropped += 1;
+            }
+        }
+
+        Ok(dropped)
+    }
+
+    /// Dedup window of the queue, the default one unless it's overridden
+    fn dedup_window(&self, queue_name: &str) -> Duration {
+        self.dedup_windows
+            .get(queue_name)
+            .copied()
+            .unwrap_or(self.dedup_window)
+    }
+
+    /// Marks the first delivery of the event to subscribers, `false` if it was already delivered
+    pub fn mark_delivered(
+        &self,
+        queue_name: &str,
+        id: &str,
+        sequence: SequenceId,
+    ) -> QueueResult<bool> {
+        let mut key = get_receipt_prefix(queue_name.as_bytes());
+        key.extend_from_slice(&get_id(id, sequence.get()));
+
+        let swapped = self
+            .map
+            .compare_and_swap(key, None::<&[u8]>, Some(&[] as &[u8]))?;
+
+        Ok(swapped.is_ok())
+    }
+
+    /// Marks the delivered event as acknowledged by the subscriber,
+    /// returns `false` if it was already acknowledged
+    pub fn mark_acked(
+        &self,
+        queue_name: &str,
+        id: &str,
+        sequence: SequenceId,
+    ) -> QueueResult<bool> {
+        let mut key = get_receipt_prefix(queue_name.as_bytes());
+        key.extend_from_slice(&get_id(id, sequence.get()));
+
+        let previous = self.map.fetch_and_update(key, |_| Some(ACKED_RECEIPT))?;
+
+        Ok(previous.as_deref() != Some(ACKED_RECEIPT))
+    }
+
+    /// Returns the lifecycle of the event, `None` if queue doesn't exist
+    pub fn message_trace(
+        &self,
+        queue_name: String,
+        id: String,
+        sequence: SequenceId,
+    ) -> QueueResult<Option<Option<MessageTrace>>> {
+        if !self.check_tree_exists(&queue_name) {
+            return Ok(None);
+        }
+
+        let key = get_trace_key(queue_name.as_bytes(), &get_id(&id, sequence.get()));
+        let trace = self
+            .map
+            .get(key)?
+            .map(|v| serde_json::from_slice(&v))
+            .transpose()?;
+
+        Ok(Some(trace))
+    }
+
+    /// Counts the delivery of the event to a subscription
+    pub fn trace_delivery(
+        &self,
+        queue_name: &str,
+        id: &str,
+        sequence: SequenceId,
+    ) -> QueueResult<()> {
+        let now = self.clock.unix_millis();
+        let key = get_trace_key(queue_name.as_bytes(), &get_id(id, sequence.get()));
+        self.update_trace(&key, |trace| {
+            trace.deliveries += 1;
+            trace.first_delivered_at.get_or_insert(now);
+            trace.last_delivered_at = Some(now);
+        })
+    }
+
+    /// Records the acknowledgement of the event, every session is recorded once
+    pub fn trace_ack(
+        &self,
+        queue_name: &str,
+        id: &str,
+        sequence: SequenceId,
+        session: Option<&str>,
+    ) -> QueueResult<()> {
+        let now = self.clock.unix_millis();
+        let key = get_trace_key(queue_name.as_bytes(), &get_id(id, sequence.get()));
+        self.update_trace(&key, |trace| {
+            if !trace.acks.iter().any(|a| a.session.as_deref() == session) {
+                trace.acks.push(TraceAck {
+                    session: session.map(str::to_string),
+                    at: now,
+                });
+            }
+        })
+    }
+
+    /// Removes traces of events stored before the tracing retention period.
+    /// Returns count of removed traces.
+    pub fn drop_expired_traces(&self) -> QueueResult<usize> {
+        let retention = match self.trace_retention {
+            Some(retention) => retention,
+            None => return Ok(0),
+        };
+        let stored_before = self
+            .clock
+            .unix_millis()
+            .saturating_sub(retention.as_millis() as u64);
+
+        let mut dropped = 0;
+        for r in self.map.scan_prefix(TRACE_PREFIX) {
+            let (key, value) = r?;
+            let stored_at = serde_json::from_slice::<MessageTrace>(&value)
+                .ok()
+                .and_then(|t| t.stored_at);
+            if matches!(stored_at, Some(s) if s > stored_before) {
+                continue;
+            }
+            self.map.remove(key)?;
+            dropped += 1;
+        }
+
+        if dropped > 0 {
+            self.lifecycle.emit(LifecycleEvent::RetentionRan {
+                task: "traces",
+                removed: dropped,
+            });
+        }
+        Ok(dropped)
+    }
+
+    /// Starts the trace of the stored entry, if tracing is enabled
+    fn trace_stored(&self, queue_name: &str, entry: &[u8]) -> QueueResult<()> {
+        if self.trace_retention.is_none() {
+            return Ok(());
+        }
+
+        let trace = MessageTrace {
+            stored_at: Some(self.clock.unix_millis()),
+            ..Default::default()
+        };
+        self.map.insert(
+            get_trace_key(queue_name.as_bytes(), entry),
+            serde_json::to_vec(&trace)?,
+        )?;
+
+        Ok(())
+    }
+
+    /// Updates the existing trace, events stored without tracing are skipped
+    fn update_trace(&self, key: &[u8], update: impl Fn(&mut MessageTrace)) -> QueueResult<()> {
+        if self.trace_retention.is_none() {
+            return Ok(());
+        }
+
+        self.map.fetch_and_update(key, |v| {
+            let mut trace = serde_json::from_slice::<MessageTrace>(v?).ok()?;
+            update(&mut trace);
+            serde_json::to_vec(&trace).ok()
+        })?;
+
+        Ok(())
+    }
+
+    /// Marks traces of the removed entries, entries are keys of the queue trees
+    fn trace_removed<'e>(
+        &self,
+        queue_name: &str,
+        entries: impl IntoIterator<Item = &'e [u8]>,
+        removal: TraceRemoval,
+    ) -> QueueResult<()> {
+        if self.trace_retention.is_none() {
+            return Ok(());
+        }
+
+        let now = self.clock.unix_millis();
+        entries.into_iter().try_for_each(|entry| {
+            self.update_trace(&get_trace_key(queue_name.as_bytes(), entry), |trace| {
+                if trace.removal.is_none() {
+                    trace.removed_at = Some(now);
+                    trace.removal = Some(removal);
+                }
+            })
+        })
+    }
+
+    /// Marks traces of all stored entries of the queue or of the key only
+    fn trace_all_removed(
+        &self,
+        queue_name: &str,
+        id: Option<&str>,
+        removal: TraceRemoval,
+    ) -> QueueResult<()> {
+        if self.trace_retention.is_none() {
+            return Ok(());
+        }
+
+        let prefix = get_trace_key(queue_name.as_bytes(), &[]);
+        let mut scan = prefix.clone();
+        scan.extend_from_slice(id.unwrap_or_default().as_bytes());
+
+        let keys = self
+            .map
+            .scan_prefix(scan)
+            .keys()
+            .filter(|r| match (r, id) {
+                (Ok(key), Some(id)) => split_id(&key[prefix.len()..])
+                    .map_or(false, |(key_id, _)| key_id == id.as_bytes()),
+                _ => true,
+            })
+            .collect::<sled::Result<Vec<_>>>()?;
+
+        self.trace_removed(queue_name, keys.iter().map(|k| &k[prefix.len()..]), removal)
+    }
+
+    /// Marks traces of entries of the segment which is going to be dropped
+    fn trace_expired_segment(&self, name: &[u8]) -> QueueResult<()> {
+        let queue_name = match split_segment_name(name) {
+            Some((queue_name, _)) if self.trace_retention.is_some() => queue_name,
+            _ => return Ok(()),
+        };
+
+        let keys = self
+            .databases
+            .tree_db(name)
+            .open_tree(name)?
+            .iter()
+            .keys()
+            .collect::<sled::Result<Vec<_>>>()?;
+
+        self.trace_removed(
+            &String::from_utf8_lossy(queue_name),
+            keys.iter().map(|k| k.as_ref()),
+            TraceRemoval::Expired,
+        )
+    }
+
+    /// Removes traces of the dropped queue
+    fn remove_traces(&self, queue_name: &str) -> QueueResult<()> {
+        let keys = self
+            .map
+            .scan_prefix(get_trace_key(queue_name.as_bytes(), &[]))
+            .keys()
+            .collect::<sled::Result<Vec<_>>>()?;
+
+        keys.into_iter()
+            .try_for_each(|key| self.map.remove(key).map(|_| ()))
+            .map_err(QueueError::from)
+    }
+
+    /// Returns stored events of the queue after the offset in the publish order.
+    /// Requires ordered preloads, events removed before reading are skipped.
+    pub fn read_offsets(
+        &self,
+        queue_name: &str,
+        after: u64,
+        limit: usize,
+    ) -> QueueResult<OffsetBatch<T>> {
+        let mut batch = OffsetBatch {
+            events: Vec::new(),
+            last: None,
+        };
+        if !self.check_tree_exists(queue_name) {
+            return Ok(batch);
+        }
+
+        let trees = self.queue_trees(queue_name)?;
+        let pipeline = DeliveryPipeline::new(
+            self.delivery_interceptors.clone(),
+            SubscriberInfo {
+                queue_name: queue_name.to_string(),
+                id: None,
+                identity: None,
+                subscription: false,
+            },
+        );
+
+        let offsets = self.offsets_tree(queue_name)?;
+        for r in offsets
+            .range(after.saturating_add(1).to_be_bytes()..)
+            .take(limit)
+        {
+            let (offset, key) = r?;
+            batch.last = offset.as_ref().try_into().ok().map(u64::from_be_bytes);
+            if let Some(value) = trees.get(&key)? {
+                if let Some(event) = pipeline.deliver(self.envelopes.decode(&value)?) {
+                    batch.events.push(event);
+                }
+            }
+        }
+
+        Ok(batch)
+    }
+
+    /// Last offset written by the sink, 0 if nothing was written yet
+    pub fn sink_checkpoint(&self, name: &str) -> QueueResult<u64> {
+        let checkpoint = self.map.get(get_sink_key(name.as_bytes()))?;
+        Ok(checkpoint
+            .and_then(|c| c.as_ref().try_into().ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or_default())
+    }
+
+    pub fn save_sink_checkpoint(&self, name: &str, offset: u64) -> QueueResult<()> {
+        self.map
+            .insert(get_sink_key(name.as_bytes()), offset.to_be_bytes().to_vec())?;
+        Ok(())
+    }
+
+    /// Loads the delivery cursor of the resumable key session
+    pub fn session_cursor(
+        &self,
+        queue_name: &str,
+        id: &str,
+        session: &str,
+    ) -> QueueResult<SessionCursor> {
+        SessionCursor::load(
+            self.map.clone(),
+            get_session_key(queue_name.as_bytes(), id.as_bytes(), session.as_bytes()),
+        )
+    }
+
+    /// Compares stored key sequences, including the cold tier, with the last published one.
+    /// `None` if queue doesn't exist
+    pub fn sequence_gaps(
+        &self,
+        queue_name: String,
+        id: String,
+    ) -> QueueResult<Option<SequenceReport>> {
+        if !self.check_tree_exists(&queue_name) {
+            return Ok(None);
+        }
+
+        let mut sequences = Vec::new();
+        if let Some(cold_tier) = &self.cold_tier {
+            for segment in self.cold_segments(&queue_name, Some(&id)) {
+                let (_, segment) = segment?;
+                let data = cold_tier.store.get(&segment.reference)?;
+                sequences.extend(
+                    self.envelopes
+                        .decode_all::<T>(&data)?
+                        .iter()
+                        .filter_map(|i| i.get_sequence().map(|s| s.get())),
+                );
+            }
+        }
+
+        let trees = self.queue_trees(&queue_name)?;
+        for r in scan_key(&trees, &id) {
+            let (key, _) = r?;
+            sequences.extend(split_id(&key).map(|(_, sequence)| sequence));
+        }
+        sequences.sort_unstable();
+        sequences.dedup();
+
+        let published = self.published_sequence(&queue_name, &id)?;
+
+        // sequences start from 1, so zero is the bound before the first one
+        let bounds = std::iter::once(0)
+            .chain(sequences.iter().copied())
+            .chain(std::iter::once(published.saturating_add(1)));
+        let gaps = bounds
+            .clone()
+            .zip(bounds.skip(1))
+            .filter(|(prev, next)| next > &prev.saturating_add(1))
+            .map(|(prev, next)| prev + 1..=next - 1)
+            .collect();
+
+        Ok(Some(SequenceReport {
+            published,
+            stored: sequences.len(),
+            first: sequences.first().copied(),
+            last: sequences.last().copied(),
+            gaps,
+        }))
+    }
+
+    /// Returns root checksums of every stored key of the queue, `None` if queue doesn't exist
+    pub fn digest_queue(
+        &self,
+        queue_name: String,
+    ) -> QueueResult<Option<BTreeMap<String, KeyDigest>>> {
+        if !self.check_tree_exists(&queue_name) {
+            return Ok(None);
+        }
+
+        let mut digests: BTreeMap<String, Digest> = BTreeMap::new();
+        let mut add = |id: &[u8], sequence: u64, value: &[u8]| {
+            digests
+                .entry(String::from_utf8_lossy(id).into_owned())
+                .or_insert_with(|| Digest::new(DEFAULT_DIGEST_RANGE))
+                .add(sequence, value)
+        };
+
+        if let Some(cold_tier) = &self.cold_tier {
+            for segment in self.cold_segments(&queue_name, None) {
+                let (_, segment) = segment?;
+                let data = cold_tier.store.get(&segment.reference)?;
+                for item in self.envelopes.decode_all::<T>(&data)? {
+                    if let Some(sequence) = item.get_sequence() {
+                        let id = item.get_id();
+                        add(
+                            id.as_bytes(),
+                            sequence.get(),
+                            &self.envelopes.encode(&item)?,
+                        );
+                    }
+                }
+            }
+        }
+
+        for r in self.queue_trees(&queue_name)?.iter() {
+            let (key, value) = r?;
+            if let Some((id, sequence)) = split_id(&key) {
+                add(id, sequence, &value);
+            }
+        }
+
+        Ok(Some(
+            digests
+                .into_iter()
+                .map(|(id, digest)| (id, digest.key()))
+                .collect(),
+        ))
+    }
+
+    /// Returns checksums of the key entries over sequence ranges of the length,
+    /// skipping entries after the `to` sequence. `None` if queue doesn't exist
+    pub fn digest_key(
+        &self,
+        queue_name: String,
+        id: String,
+        range: u64,
+        to: Option<u64>,
+    ) -> QueueResult<Option<Digest>> {
+        if !self.check_tree_exists(&queue_name) {
+            return Ok(None);
+        }
+
+        let to = to.unwrap_or(u64::MAX);
+        let mut digest = Digest::new(range);
+        if let Some(cold_tier) = &self.cold_tier {
+            for segment in self.cold_segments(&queue_name, Some(&id)) {
+                let (_, segment) = segment?;
+                let data = cold_tier.store.get(&segment.reference)?;
+                for item in self.envelopes.decode_all::<T>(&data)? {
+                    match item.get_sequence() {
+                        Some(sequence) if sequence.get() <= to => {
+                            digest.add(sequence.get(), &self.envelopes.encode(&item)?)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let trees = self.queue_trees(&queue_name)?;
+        let entries = trees
+            .range(get_id(&id, 0)..=get_id(&id, to))
+            .filter(|r| is_key_entry(r, &id));
+        for r in entries {
+            let (key, value) = r?;
+            if let Some((_, sequence)) = split_id(&key) {
+                digest.add(sequence, &value);
+            }
+        }
+
+        Ok(Some(digest))
+    }
+
+    /// Moves key history older than the hot updates limit to the cold store.
+    /// Returns count of moved entries.
+    pub fn tier_history(&self) -> QueueResult<usize> {
+        let cold_tier = match &self.cold_tier {
+            None => return Ok(0),
+            Some(cold_tier) => cold_tier,
+        };
+
+        let mut moved = 0;
+        for queue_name in self.queue_names() {
+            let trees = self.queue_trees(&String::from_utf8_lossy(&queue_name))?;
+
+            let mut counts: BTreeMap<Vec<u8>, usize> = BTreeMap::new();
+            for r in trees.iter() {
+                let (key, _) = r?;
+                if let Some((id, _)) = split_id(&key) {
+                    *counts.entry(id.to_vec()).or_default() += 1;
+                }
+            }
+
+            for (id, count) in counts {
+                let cold_count = count.saturating_sub(cold_tier.hot_updates);
+                if cold_count == 0 {
+                    continue;
+                }
+
+                let entries = trees
+                    .scan_prefix(&id)
+                    .filter(|r| match r {
+                        Ok((k, _)) => {
+                            matches!(split_id(k), Some((key_id, _)) if key_id == id.as_slice())
+                        }
+                        Err(_) => true,
+                    })
+                    .take(cold_count)
+                    .collect::<sled::Result<Vec<_>>>()?;
+
+                for segment in entries.chunks(COLD_SEGMENT_SIZE) {
+                    self.move_cold_segment(cold_tier, &queue_name, &id, &trees, segment)?;
+                    moved += segment.len();
+                }
+            }
+        }
+
+        Ok(moved)
+    }
+
+    fn move_cold_segment(
+        &self,
+        cold_tier: &ColdTier,
+        queue_name: &[u8],
+        id: &[u8],
+        trees: &QueueTrees,
+        entries: &[(IVec, IVec)],
+    ) -> QueueResult<()> {
+        let (first, last) = match (entries.first(), entries.last()) {
+            (Some((first, _)), Some((last, _))) => (first, last),
+            _ => return Ok(()),
+        };
+        let first = split_id(first).map(|(_, s)| s).unwrap_or_default();
+        let last = split_id(last).map(|(_, s)| s).unwrap_or_default();
+
+        let data = self
+            .envelopes
+            .encode_segment(entries.iter().map(|(_, v)| v.as_ref()))?;
+        let reference = cold_tier.store.put(&data)?;
+        let segment = ColdSegment {
+            reference,
+            first,
+            last,
+        };
+        self.map.insert(
+            get_cold_index_key(queue_name, id, last),
+            serde_json::to_vec(&segment)?,
+        )?;
+
+        let keys: Vec<_> = entries.iter().map(|(k, _)| k.clone()).collect();
+        trees.remove_all(&keys)?;
+        let sizes: Vec<_> = entries.iter().map(|(k, v)| (k.clone(), v.len())).collect();
+        self.shrink_sizes(&String::from_utf8_lossy(queue_name), &sizes)?;
+        // moved entries are preloaded from the cold store now
+        self.invalidate_preloads(
+            &String::from_utf8_lossy(queue_name),
+            Some(&String::from_utf8_lossy(id)),
+        );
+
+        Ok(())
+    }
+
+    /// Prepends history from the cold store to the locally preloaded items
+    fn with_cold_items(
+        &self,
+        queue_name: &str,
+        id: Option<&str>,
+        sequence: RequestSequence,
+        prev_items: Option<Preload<T>>,
+        scan: &PreloadScan,
+    ) -> QueueResult<Option<Preload<T>>> {
+        let cold_tier = match &self.cold_tier {
+            None => return Ok(prev_items),
+            Some(cold_tier) => cold_tier,
+        };
+
+        let from = match sequence {
+            Some(RequestSequenceId::Id(s)) => s.get(),
+            Some(RequestSequenceId::First) => 0,
+            // the last entries are always local
+            _ => return Ok(prev_items),
+        };
+
+        let mut items = Vec::new();
+        for segment in self.cold_segments(queue_name, id) {
+            let (_, segment) = segment?;
+            if segment.last < from {
+                continue;
+            }
+
+            let data = cold_tier.store.get(&segment.reference)?;
+            for item in self.envelopes.decode_all::<T>(&data)? {
+                scan.next()?;
+                if item.get_sequence().map(|s| s.get()).unwrap_or_default() >= from {
+                    items.push(item);
+                }
+            }
+        }
+
+        let mut preload = prev_items.unwrap_or_else(|| Preload::decoded(Vec::new()));
+        items.append(&mut preload.decoded);
+        preload.decoded = items;
+        Ok(Some(preload))
+    }
+
+    /// Last sequence generated for the key, zero if nothing was published
+    fn published_sequence(&self, queue_name: &str, id: &str) -> QueueResult<u64> {
+        let counter = self
+            .map
+            .get(get_counter_key(queue_name.as_bytes(), id.as_bytes()))?;
+
+        Ok(counter
+            .and_then(|v| Some(u64::from_be_bytes(v.as_ref().try_into().ok()?)))
+            .unwrap_or_default())
+    }
+
+    /// Moves head marks of the key and the queue forward, concurrent publishes never move them back
+    fn advance_heads(
+        &self,
+        queue_name: &str,
+        id: &str,
+        sequence: u64,
+        offset: u64,
+    ) -> QueueResult<()> {
+        let timestamp = self.clock.unix_millis();
+
+        // the queue mark is stored with the empty key, which can't be published
+        for (id, position) in [(id, sequence), ("", offset)] {
+            let mark = HeadMark {
+                position,
+                timestamp,
+            };
+            self.map
+                .fetch_and_update(
+                    get_head_key(queue_name.as_bytes(), id.as_bytes()),
+                    |v| match v.and_then(HeadMark::from_bytes) {
+                        Some(prev) if prev.position > mark.position => Some(prev.to_bytes()),
+                        _ => Some(mark.to_bytes()),
+                    },
+                )?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the size estimate of the key, removed entries and bytes are negative
+    fn update_size(
+        &self,
+        queue_name: &str,
+        id: &[u8],
+        entries: i64,
+        bytes: i64,
+    ) -> QueueResult<()> {
+        self.map
+            .fetch_and_update(get_size_key(queue_name.as_bytes(), id), |v| {
+                let size = v.and_then(decode_size).unwrap_or_default();
+                let size = StoredSize {
+                    entries: size.entries.saturating_add_signed(entries),
+                    bytes: size.bytes.saturating_add_signed(bytes),
+                };
+                (size.entries > 0).then(|| encode_size(&size))
+            })?;
+
+        Ok(())
+    }
+
+    /// Removes entries from size estimates of their keys, with sizes of their values
+    fn shrink_sizes(&self, queue_name: &str, entries: &[(IVec, usize)]) -> QueueResult<()> {
+        let mut removed: HashMap<&[u8], (i64, i64)> = HashMap::new();
+        for (key, bytes) in entries {
+            if let Some((id, _)) = split_id(key) {
+                let size = removed.entry(id).or_default();
+                size.0 -= 1;
+                size.1 -= *bytes as i64;
+            }
+        }
+
+        removed
+            .into_iter()
+            .try_for_each(|(id, (entries, bytes))| self.update_size(queue_name, id, entries, bytes))
+    }
+
+    fn shrink_segment_sizes(&self, name: &[u8]) -> QueueResult<()> {
+        let queue_name = match split_segment_name(name) {
+            None => return Ok(()),
+            Some((queue_name, _)) => String::from_utf8_lossy(queue_name).into_owned(),
+        };
+
+        let entries = self
+            .databases
+            .tree_db(name)
+            .open_tree(name)?
+            .iter()
+            .map(|r| r.map(|(k, v)| (k, v.len())))
+            .collect::<sled::Result<Vec<_>>>()?;
+
+        self.shrink_sizes(&queue_name, &entries)
+    }
+
+    /// Returns size estimates of stored entries of the queue and its keys,
+    /// `None` if queue doesn't exist. Estimates are maintained on writes, so keys are not scanned
+    pub fn sizes(&self, queue_name: &str) -> QueueResult<Option<Sizes>> {
+        if !self.check_tree_exists(queue_name) {
+            return Ok(None);
+        }
+
+        let prefix = get_size_key(queue_name.as_bytes(), &[]);
+        let mut sizes = Sizes {
+            queue: Default::default(),
+            keys: Default::default(),
+        };
+        for r in self.map.scan_prefix(&prefix) {
+            let (key, value) = r?;
+            let size = match decode_size(&value) {
+                None => continue,
+                Some(size) => size,
+            };
+            sizes.queue.entries += size.entries;
+            sizes.queue.bytes += size.bytes;
+            sizes.keys.insert(
+                String::from_utf8_lossy(&key[prefix.len()..]).into_owned(),
+                size,
+            );
+        }
+
+        Ok(Some(sizes))
+    }
+
+    /// Removes head marks, size estimates, delivery receipts and quarantined entries of the queue
+    /// or of the key only, dedup ids are removed with the whole queue
+    fn remove_marks(&self, queue_name: &str, id: Option<&str>) -> QueueResult<()> {
+        let mut keys = Vec::new();
+        for mut prefix in [
+            get_receipt_prefix(queue_name.as_bytes()),
+            get_quarantine_prefix(queue_name.as_bytes()),
+        ] {
+            let prefix_len = prefix.len();
+            prefix.extend_from_slice(id.unwrap_or_default().as_bytes());
+
+            let entries = self
+                .map
+                .scan_prefix(prefix)
+                .map(|r| r.map(|(key, _)| key))
+                .filter(|r| match (r, id) {
+                    (Ok(key), Some(id)) => split_id(&key[prefix_len..])
+                        .map_or(false, |(key_id, _)| key_id == id.as_bytes()),
+                    _ => true,
+                });
+            keys.extend(entries.collect::<sled::Result<Vec<_>>>()?);
+        }
+
+        match id {
+            Some(id) => {
+                keys.push(get_head_key(queue_name.as_bytes(), id.as_bytes()).into());
+                keys.push(get_size_key(queue_name.as_bytes(), id.as_bytes()).into());
+            }
+            None => {
+                for prefix in [
+                    get_head_key(queue_name.as_bytes(), &[]),
+                    get_size_key(queue_name.as_bytes(), &[]),
+                ] {
+                    let marks = self.map.scan_prefix(prefix).map(|r| r.map(|(key, _)| key));
+                    keys.extend(marks.collect::<sled::Result<Vec<_>>>()?);
+                }
+
+                let dedup_ids = self
+                    .map
+                    .scan_prefix(get_dedup_key(queue_name.as_bytes(), &[]))
+                    .map(|r| r.map(|(key, _)| key));
+                keys.extend(dedup_ids.collect::<sled::Result<Vec<_>>>()?);
+            }
+        }
+
+        keys.into_iter()
+            .try_for_each(|key| self.map.remove(key).map(|_| ()))
+            .map_err(QueueError::from)
+    }
+
+    /// Removes session cursors of the queue or of the key only
+    fn remove_session_cursors(&self, queue_name: &str, id: Option<&str>) -> QueueResult<()> {
+        let mut prefix = get_session_prefix(queue_name.as_bytes());
+        if let Some(id) = id {
+            prefix.extend_from_slice(id.as_bytes());
+            prefix.push(0);
+        }
+
+        let keys = self
+            .map
+            .scan_prefix(prefix)
+            .keys()
+            .collect::<sled::Result<Vec<_>>>()?;
+
+        keys.into_iter()
+            .try_for_each(|key| self.map.remove(key).map(|_| ()))
+            .map_err(QueueError::from)
+    }
+
+    /// Removes the superseded event and its earlier corrections, so only the latest version
+    /// of the event is replayed
+    fn collapse(
+        &self,
+        queue_name: &str,
+        id: &str,
+        superseded: u64,
+        sequence: u64,
+    ) -> QueueResult<()> {
+        let trees = self.queue_trees(queue_name)?;
+        // corrections are always published after the superseded event
+        let entries = trees
+            .range(get_id(id, superseded)..get_id(id, sequence))
+            .filter(|r| is_key_entry(r, id))
+            .map(|r| -> QueueResult<Option<(IVec, usize)>> {
+                let (key, value) = r?;
+                if split_id(&key).map(|(_, s)| s) == Some(superseded) {
+                    return Ok(Some((key, value.len())));
+                }
+                let bytes = value.len();
+                let value = self.envelopes.decode::<T>(&value)?;
+                let corrects = value.get_supersedes().map(|s| s.get()) == Some(superseded);
+                Ok(corrects.then_some((key, bytes)))
+            })
+            .filter_map(QueueResult::transpose)
+            .collect::<QueueResult<Vec<_>>>()?;
+
+        let keys: Vec<_> = entries.iter().map(|(k, _)| k.clone()).collect();
+        trees.remove_all(&keys)?;
+        self.shrink_sizes(queue_name, &entries)?;
+        self.trace_removed(
+            queue_name,
+            keys.iter().map(|k| k.as_ref()),
+            TraceRemoval::Superseded,
+        )?;
+        self.revoke_cold_event(queue_name, id, superseded)?;
+
+        Ok(())
+    }
+
+    /// Rewrites the cold segment which stores the sequence without it
+    fn revoke_cold_event(&self, queue_name: &str, id: &str, sequence: u64) -> QueueResult<bool> {
+        let cold_tier = match &self.cold_tier {
+            None => return Ok(false),
+            Some(cold_tier) => cold_tier,
+        };
+
+        for segment in self.cold_segments(queue_name, Some(id)) {
+            let (index_key, segment) = segment?;
+            if !(segment.first..=segment.last).contains(&sequence) {
+                continue;
+            }
+
+            let data = cold_tier.store.get(&segment.reference)?;
+            let mut items = self.envelopes.decode_all::<T>(&data)?;
+            let count = items.len();
+            items.retain(|i| i.get_sequence().map(|s| s.get()) != Some(sequence));
+            if items.len() == count {
+                return Ok(false);
+            }
+
+            let bounds = items
+                .first()
+                .zip(items.last())
+                .and_then(|(first, last)| first.get_sequence().zip(last.get_sequence()));
+            // the new segment is indexed before the old one is removed, so history is never lost
+            let new_index_key = match bounds {
+                Some((first, last)) => {
+                    let segment = ColdSegment {
+                        reference: cold_tier.store.put(&self.envelopes.encode(&items)?)?,
+                        first: first.get(),
+                        last: last.get(),
+                    };
+                    let key = get_cold_index_key(queue_name.as_bytes(), id.as_bytes(), last.get());
+                    self.map.insert(&key, serde_json::to_vec(&segment)?)?;
+                    Some(key)
+                }
+                None => None,
+            };
+            if new_index_key.as_deref() != Some(index_key.as_ref()) {
+                self.map.remove(index_key)?;
+            }
+            if let Err(e) = cold_tier.store.remove(&segment.reference) {
+                warn!(queue = queue_name, key = id, error = %e, "removing cold segment error");
+            }
+
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    fn remove_cold_segments(&self, queue_name: &str, id: Option<&str>) -> QueueResult<()> {
+        let cold_tier = match &self.cold_tier {
+            None => return Ok(()),
+            Some(cold_tier) => cold_tier,
+        };
+
+        for segment in self.cold_segments(queue_name, id) {
+            let (key, segment) = segment?;
+            if let Err(e) = cold_tier.store.remove(&segment.reference) {
+                warn!(queue = queue_name, key = ?id, error = %e, "removing cold segment error");
+            }
+            self.map.remove(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns cold segments of the queue or the exact key ordered by sequences
+    fn cold_segments<'b>(
+        &self,
+        queue_name: &str,
+        id: Option<&'b str>,
+    ) -> Box<dyn Iterator<Item = QueueResult<(IVec, ColdSegment)>> + 'b> {
+        let mut scan_prefix = get_cold_index_prefix(queue_name.as_bytes());
+        let prefix_len = scan_prefix.len();
+        if let Some(id) = id {
+            scan_prefix.extend_from_slice(id.as_bytes());
+        }
+
+        Box::new(
+            self.map
+                .scan_prefix(scan_prefix)
+                .filter(move |r| match (r, id) {
+                    (Ok((k, _)), Some(id)) => {
+                        let key = split_id(&k[prefix_len..]);
+                        matches!(key, Some((key_id, _)) if key_id == id.as_bytes())
+                    }
+                    _ => true,
+                })
+                .map(|r| {
+                    let (k, v) = r?;
+                    Ok((k, serde_json::from_slice(&v)?))
+                }),
+        )
+    }
+
+    /// Preloads the key history from the cache. Short scanned histories are decoded at once
+    /// and cached, longer ones are streamed.
+    fn key_preload(
+        &self,
+        trees: &QueueTrees,
+        queue_name: &str,
+        id: &str,
+        sequence: RequestSequence,
+        scan: &PreloadScan,
+        poison: &PoisonGuard,
+    ) -> QueueResult<Option<Preload<T>>> {
+        let (cache, sequence_id, from) = match (&self.preload_cache, sequence) {
+            (Some(cache), Some(s @ RequestSequenceId::First)) => (cache, s, None),
+            (Some(cache), Some(s @ RequestSequenceId::Id(from))) => (cache, s, Some(from.get())),
+            _ => return get_prev_items(trees, id, sequence, scan, poison),
+        };
+
+        let fill = match cache.get_or_fill(queue_name, id, from) {
+            Ok(items) => {
+                self.stats.add_cached_preload();
+                return Ok(Some(Preload::decoded(items)));
+            }
+            Err(fill) => fill,
+        };
+
+        let mut values = extract_sequences(trees, sequence_id, id);
+        let mut items = Vec::new();
+        for r in values.by_ref().take(cache.max_events() + 1) {
+            scan.next()?;
+            let (key, value) = r?;
+            items.extend(poison.decode::<T>(&key, &value));
+        }
+
+        if items.len() > cache.max_events() {
+            let mut preload = Preload::raw(values, None);
+            preload.decoded = items;
+            return Ok(Some(preload));
+        }
+
+        cache.store(fill, items.clone());
+        Ok(Some(Preload::decoded(items)))
+    }
+
+    /// Decoder of preloaded entries of the queue, which quarantines poison ones
+    fn poison_guard(&self, queue_name: &str, trees: &QueueTrees) -> PoisonGuard {
+        PoisonGuard::new(
+            self.quarantine.clone(),
+            self.envelopes.clone(),
+            self.map.clone(),
+            trees.clone(),
+            queue_name.to_string(),
+            self.stats.clone(),
+        )
+    }
+
+    /// Forgets cached preloads of the changed key, or of the whole queue if `id` is `None`
+    fn invalidate_preloads(&self, queue_name: &str, id: Option<&str>) {
+        if let Some(cache) = &self.preload_cache {
+            cache.invalidate(queue_name, id);
+        }
+    }
+
+    /// Requested preload limit, capped by the server limit
+    fn preload_limit(&self, requested: Option<usize>) -> Option<usize> {
+        match (requested, self.max_preload) {
+            (Some(r), Some(m)) => Some(r.min(m)),
+            (r, m) => r.or(m),
+        }
+    }
+
+    /// Logs and counts preloads which scan too many entries or take too long
+    fn preload_progress(
+        &self,
+        queue_name: &str,
+        id: Option<&str>,
+        scan: &PreloadScan,
+    ) -> PreloadProgress {
+        PreloadProgress {
+            queue_name: queue_name.to_string(),
+            id: id.map(ToString::to_string),
+            scanned: scan.scanned.get(),
+            started: scan.started,
+            deadline: scan.deadline,
+            limits: self.slow_preload.clone(),
+        }
+    }
+
+    /// Checks disk usage of the databases, reports crossed thresholds
+    /// and trims key histories by the trim policy
+    pub fn watch_disk(&self) -> QueueResult<()> {
+        let watchdog = match &self.disk {
+            None => return Ok(()),
+            Some(watchdog) => watchdog,
+        };
+
+        let usage = watchdog.measure(self.databases.size_on_disk()?)?;
+        let (exceeded, was_exceeded) = watchdog.update(usage);
+        if exceeded != was_exceeded {
+            if exceeded {
+                warn!(?usage, policy = ?watchdog.policy(), "disk usage thresholds are crossed");
+            }
+            self.lifecycle.emit(LifecycleEvent::DiskUsage {
+                exceeded,
+                free_space: usage.free_space,
+                db_size: usage.db_size,
+            });
+        }
+
+        if exceeded && watchdog.policy() == DiskPolicy::Trim {
+            let removed = self.trim_history(watchdog.trim_keep())?;
+            if removed > 0 {
+                self.lifecycle.emit(LifecycleEvent::RetentionRan {
+                    task: "disk",
+                    removed,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keeps only the newest `keep` entries of every key, returns count of removed entries.
+    /// Entries are sorted by key, so keys are trimmed one at a time and removed in batches
+    fn trim_history(&self, keep: usize) -> QueueResult<usize> {
+        let mut removed = 0;
+        for queue_name in self.queue_names() {
+            let queue_name = String::from_utf8_lossy(&queue_name).into_owned();
+            let trees = self.queue_trees(&queue_name)?;
+
+            // Entries of a key are met again after other keys if the key starts with them
+            // or the queue is segmented, the batch keeps every trimmed entry once
+            let mut batch: HashMap<IVec, usize> = HashMap::new();
+            let mut last_id: Option<Vec<u8>> = None;
+            let mut queue_removed = 0;
+            for r in trees.iter() {
+                let (key, _) = r?;
+                let id = match split_id(&key) {
+                    Some((id, _)) if last_id.as_deref() != Some(id) => id,
+                    _ => continue,
+                };
+
+                for r in trees
+                    .scan_prefix(id)
+                    .rev()
+                    .filter(|r| is_key_entry(r, id))
+                    .skip(keep)
+                {
+                    let (key, value) = r?;
+                    batch.insert(key, value.len());
+                }
+                last_id = Some(id.to_vec());
+
+                if batch.len() >= TRIM_BATCH_SIZE {
+                    queue_removed += self.remove_trimmed(&queue_name, &trees, &mut batch)?;
+                }
+            }
+            queue_removed += self.remove_trimmed(&queue_name, &trees, &mut batch)?;
+
+            if queue_removed == 0 {
+                continue;
+            }
+            self.invalidate_preloads(&queue_name, None);
+            if self.ordered_preload {
+                self.prune_offsets(&queue_name)?;
+            }
+            removed += queue_removed;
+        }
+
+        Ok(removed)
+    }
+
+    /// Removes the trimmed entries of the batch, returns count of removed entries
+    fn remove_trimmed(
+        &self,
+        queue_name: &str,
+        trees: &QueueTrees,
+        batch: &mut HashMap<IVec, usize>,
+    ) -> QueueResult<usize> {
+        if batch.is_empty() {
+            return Ok(0);
+        }
+
+        let trimmed: Vec<_> = batch.drain().collect();
+        let keys: Vec<_> = trimmed.iter().map(|(k, _)| k.clone()).collect();
+        trees.remove_all(&keys)?;
+        self.shrink_sizes(queue_name, &trimmed)?;
+        self.trace_removed(
+            queue_name,
+            keys.iter().map(|k| k.as_ref()),
+            TraceRemoval::Trimmed,
+        )?;
+
+        Ok(keys.len())
+    }
+
+    /// Drops segments which are older than the retention period.
+    /// Returns count of dropped segments.
+    pub fn drop_expired_segments(&self) -> QueueResult<usize> {
+        let (duration, retention) = match (self.segment_duration, self.retention) {
+            (Some(duration), Some(retention)) => (duration, retention),
+            _ => return Ok(0),
+        };
+
+        let now = self.clock.unix_secs();
+
+        let mut dropped = 0;
+        for name in self.databases.tree_names() {
+            let expired = matches!(
+                split_segment_name(&name),
+                Some((_, start)) if start + duration.as_secs() + retention.as_secs() <= now
+            );
+            if expired {
+                self.trace_expired_segment(&name)?;
+                self.shrink_segment_sizes(&name)?;
+                self.databases.tree_db(&name).drop_tree(&name)?;
+                dropped += 1;
+            }
+        }
+
+        if dropped > 0 {
+            if let Some(cache) = &self.preload_cache {
+                cache.clear();
+            }
+        }
+
+        if self.ordered_preload && dropped > 0 {
+            for queue_name in self.queue_names() {
+                self.prune_offsets(&String::from_utf8_lossy(&queue_name))?;
+            }
+        }
+
+        if dropped > 0 {
+            self.lifecycle.emit(LifecycleEvent::RetentionRan {
+                task: "segments",
+                removed: dropped,
+            });
+        }
+        Ok(dropped)
+    }
+
+    /// Returns names of the queue trees, without segments and internal trees
+    fn queue_names(&self) -> Vec<IVec> {
+        self.databases
+            .tree_names()
+            .into_iter()
+            .filter(|name| split_segment_name(name).is_none() && !is_offsets_tree(name))
+            .collect()
+    }
+
+    fn offsets_tree(&self, queue_name: &str) -> QueueResult<Tree> {
+        self.databases
+            .route(queue_name.as_bytes())
+            .open_tree(offsets_tree_name(queue_name.as_bytes()))
+            .map_err(QueueError::from)
+    }
+
+    /// Indexes events stored before ordered preloads were enabled in storage order
+    fn build_offset_indexes(&self) -> QueueResult<()> {
+        for queue_name in self.queue_names() {
+            let queue_name = String::from_utf8_lossy(&queue_name);
+            let offsets = self.offsets_tree(&queue_name)?;
+            if !offsets.is_empty() {
+                continue;
+            }
+
+            for r in self.queue_trees(&queue_name)?.iter().keys() {
+                offsets.insert(self.map.generate_id()?.to_be_bytes(), r?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the oldest offsets of the queue whose events are not stored anymore
+    fn prune_offsets(&self, queue_name: &str) -> QueueResult<()> {
+        let trees = self.queue_trees(queue_name)?;
+        let offsets = self.offsets_tree(queue_name)?;
+
+        for r in offsets.iter() {
+            let (offset, key) = r?;
+            if trees.get(&key)?.is_some() {
+                break;
+            }
+            offsets.remove(offset)?;
+        }
+
+        Ok(())
+    }
+
+    fn drop_segments(&self, queue_name: &str) -> QueueResult<()> {
+        let db = self.databases.route(queue_name.as_bytes());
+        self.segment_names(queue_name)
+            .into_iter()
+            .try_for_each(|name| db.drop_tree(name).map(|_| ()))
+            .map_err(QueueError::from)
+    }
+
+    /// Returns segment tree names of the queue ordered from the oldest
+    fn segment_names(&self, queue_name: &str) -> Vec<IVec> {
+        let prefix = get_segment_prefix(queue_name.as_bytes());
+        let mut names: Vec<_> = self
+            .databases
+            .route(queue_name.as_bytes())
+            .tree_names()
+            .into_iter()
+            .filter(|name| name.starts_with(&prefix) && name.len() == prefix.len() + 8)
+            .collect();
+        names.sort();
+
+        names
+    }
+
+    fn queue_trees(&self, queue_name: &str) -> QueueResult<QueueTrees> {
+        let db = self.databases.route(queue_name.as_bytes());
+        std::iter::once(IVec::from(queue_name.as_bytes()))
+            .chain(self.segment_names(queue_name))
+            .map(|name| db.open_tree(name))
+            .collect::<sled::Result<Vec<_>>>()
+            .map(QueueTrees::new)
+            .map_err(QueueError::from)
+    }
+
+    /// Returns tree for new events, the current segment if segmentation is enabled
+    fn current_tree(&self, queue_name: &str) -> QueueResult<Tree> {
+        let name = match self.segment_duration {
+            None => queue_name.as_bytes().to_vec(),
+            Some(duration) => segment_tree_name(queue_name.as_bytes(), duration, self.clock.now()),
+        };
+
+        self.databases
+            .route(queue_name.as_bytes())
+            .open_tree(name)
+            .map_err(QueueError::from)
+    }
+
+    pub fn check_tree_exists(&self, queue_name: &str) -> bool {
+        matches!(
+            self.databases
+                .route(queue_name.as_bytes())
+                .tree_names()
+                .into_iter()
+                .find(|v| v == queue_name.as_bytes()),
+            Some(_)
+        )
+    }
+
+    /// Fast-forwards sequence counters to the max stored sequence of every key.
+    /// Lost or stale counters (e.g. after a partial restore) would otherwise reissue
+    /// already existing sequences and silently overwrite history.
+    /// Size estimates of keys are recounted by the same scan, so they never drift across restarts.
+    /// Runs on every start, counters restored from other backups than queues are fixed too
+    fn resync_counters(&self) -> QueueResult<()> {
+        self.migrate_counter_keys()?;
+
+        let mut sizes: HashMap<Vec<u8>, StoredSize> = HashMap::new();
+        for queue_name in self.databases.tree_names() {
+            if is_offsets_tree(&queue_name) {
+                continue;
+            }
+
+            let tree = self.databases.tree_db(&queue_name).open_tree(&queue_name)?;
+            // segments share counters of their queue
+            let queue_name = split_segment_name(&queue_name)
+                .map(|(queue_name, _)| IVec::from(queue_name))
+                .unwrap_or(queue_name);
+
+            let mut high_water: HashMap<Vec<u8>, u64> = HashMap::new();
+            for r in tree.iter() {
+                let (key, value) = r?;
+                if let Some((id, sequence)) = split_id(&key) {
+                    let max = high_water.entry(id.to_vec()).or_default();
+                    *max = sequence.max(*max);
+
+                    let size = sizes.entry(get_size_key(&queue_name, id)).or_default();
+                    size.entries += 1;
+                    size.bytes += value.len() as u64;
+                }
+            }
+
+            for (id, sequence) in high_water {
+                self.map
+                    .update_and_fetch(get_counter_key(&queue_name, &id), |v| {
+                        let current = v
+                            .and_then(|v| Some(u64::from_be_bytes(v.try_into().ok()?)))
+                            .unwrap_or_default();
+
+                        Some(IVec::from(&current.max(sequence).to_be_bytes()))
+                    })?;
+            }
+        }
+
+        let stale = self
+            .map
+            .scan_prefix(SIZE_PREFIX)
+            .keys()
+            .collect::<sled::Result<Vec<_>>>()?;
+        stale
+            .into_iter()
+            .try_for_each(|key| self.map.remove(key).map(|_| ()))?;
+        sizes
+            .into_iter()
+            .try_for_each(|(key, size)| self.map.insert(key, encode_size(&size)).map(|_| ()))?;
+
+        Ok(())
+    }
+
+    /// Moves counters stored by older releases without the separator after the queue name.
+    /// Counters which match no queue or several queues are left, resyncing restores their
+    /// stored sequences
+    fn migrate_counter_keys(&self) -> QueueResult<()> {
+        let legacy = self
+            .map
+            .scan_prefix(COUNTER_PREFIX)
+            .filter(|r| !matches!(r, Ok((key, _)) if key[COUNTER_PREFIX.len()..].contains(&0)))
+            .collect::<sled::Result<Vec<_>>>()?;
+        if legacy.is_empty() {
+            return Ok(());
+        }
+
+        let queue_names = self.queue_names();
+        for (key, value) in legacy {
+            let name = &key[COUNTER_PREFIX.len()..];
+            let mut owners = queue_names.iter().filter(|q| name.starts_with(&q[..]));
+            let queue_name = match (owners.next(), owners.next()) {
+                (Some(queue_name), None) => queue_name,
+                _ => continue,
+            };
+
+            let sequence = value
+                .as_ref()
+                .try_into()
+                .map(u64::from_be_bytes)
+                .unwrap_or_default();
+            self.map.fetch_and_update(
+                get_counter_key(queue_name, &name[queue_name.len()..]),
+                |v| {
+                    let counter = v
+                        .and_then(|v| v.try_into().ok())
+                        .map(u64::from_be_bytes)
+                        .unwrap_or_default();
+                    Some(counter.max(sequence).to_be_bytes().to_vec())
+                },
+            )?;
+            self.map.remove(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Opens trees of stored queues, restores missing head marks of their keys
+    /// and creates broadcasts of hot keys, so the first subscriptions after restarts are not delayed
+    fn warm_up(&self, hot_keys: &HashMap<String, Vec<String>>) -> QueueResult<()> {
+        let started = Instant::now();
+
+        let queue_names = self.queue_names();
+        for queue_name in &queue_names {
+            let queue_name = String::from_utf8_lossy(queue_name);
+            self.rebuild_heads(&queue_name)?;
+        }
+
+        for queue_name in hot_keys.keys() {
+            self.create_queue(queue_name.clone())?;
+        }
+
+        let mut map = self.queue_broadcasts.lock().unwrap();
+        for (queue_name, ids) in hot_keys {
+            let queue = get_queue_broadcast(queue_name.clone(), &mut map);
+            for id in ids {
+                get_key_broadcast(id.clone(), queue);
+            }
+        }
+
+        info!(
+            queues = queue_names.len(),
+            hot_keys = hot_keys.values().map(Vec::len).sum::<usize>(),
+            elapsed = ?started.elapsed(),
+            "queues are warmed up"
+        );
+
+        Ok(())
+    }
+
+    /// Restores head marks of keys which were stored without them, e.g. by older releases
+    /// or restored from backups. Their times are unknown, so the current one is used
+    fn rebuild_heads(&self, queue_name: &str) -> QueueResult<()> {
+        let mut last: HashMap<Vec<u8>, u64> = HashMap::new();
+        for r in self.queue_trees(queue_name)?.iter().keys() {
+            let key = r?;
+            if let Some((id, sequence)) = split_id(&key) {
+                let max = last.entry(id.to_vec()).or_default();
+                *max = sequence.max(*max);
+            }
+        }
+
+        let timestamp = self.clock.unix_millis();
+        for (id, position) in last {
+            let mark = HeadMark {
+                position,
+                timestamp,
+            };
+            // existing marks are kept, publishes could move them after the scan
+            let _ = self.map.compare_and_swap(
+                get_head_key(queue_name.as_bytes(), &id),
+                None::<&[u8]>,
+                Some(mark.to_bytes()),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the key counter to the sequence set by the publisher, e.g. the replicated primary,
+    /// so generated sequences continue after it
+    fn advance_counter(&self, queue_name: &str, id: &str, sequence: u64) -> QueueResult<()> {
+        let key = get_counter_key(queue_name.as_bytes(), id.as_bytes());
+
+        self.map.fetch_and_update(key, |v| {
+            let counter = v
+                .and_then(|v| v.try_into().ok())
+                .map(u64::from_be_bytes)
+                .unwrap_or_default();
+            Some(counter.max(sequence).to_be_bytes().to_vec())
+        })?;
+
+        Ok(())
+    }
+
+    fn generate_next_id(&self, queue_name: &str, id: &str) -> QueueResult<SequenceId> {
+        let key = get_counter_key(queue_name.as_bytes(), id.as_bytes());
+
+        let res = self.map.update_and_fetch(key, |v| {
+            v.and_then(|v| Some(u64::from_be_bytes(v.try_into().ok()?)))
+                .and_then(|id| id.checked_add(1))
+                .map(|id| IVec::from(&id.to_be_bytes()))
+                .unwrap_or_else(|| IVec::from(&1u64.to_be_bytes()))
+                .into()
+        })?;
+
+        res.and_then(|r| Some(u64::from_be_bytes(r.as_ref().try_into().ok()?)))
+            .and_then(SequenceId::new)
+            .map(Ok)
+            .unwrap_or_else(|| Err(QueueError::ZeroSequence))
+    }
+}
+
+/// Preloaded history. Raw values are scanned and decoded in chunks while the subscription
+/// is streamed, so long replays are never materialized at once.
+struct Preload<T> {
+    decoded: Vec<T>,
+    /// Stored keys and values, decoded while they are streamed
+    raw: Box<dyn Iterator<Item = sled::Result<(IVec, IVec)>> + Send>,
+    /// Raw values with lower sequences are skipped
+    min_sequence: Option<SequenceId>,
+    limit: PreloadLimit,
+}
+
+impl<T> Preload<T> {
+    fn decoded(decoded: Vec<T>) -> Self {
+        Self {
+            decoded,
+            raw: Box::new(std::iter::empty()),
+            min_sequence: None,
+            limit: Default::default(),
+        }
+    }
+
+    fn raw(
+        raw: impl Iterator<Item = sled::Result<(IVec, IVec)>> + Send + 'static,
+        min_sequence: Option<SequenceId>,
+    ) -> Self {
+        Self {
+            decoded: Vec::new(),
+            raw: Box::new(raw),
+            min_sequence,
+            limit: Default::default(),
+        }
+    }
+
+    /// Appends the preload of another key, histories of both keys stay ordered
+    fn chain(mut self, mut other: Preload<T>) -> Self {
+        self.decoded.append(&mut other.decoded);
+        self.raw = Box::new(self.raw.chain(other.raw));
+        self
+    }
+
+    fn limited(mut self, max: Option<usize>, continuable: bool) -> Self {
+        self.limit = PreloadLimit {
+            max,
+            continuable,
+            ..Default::default()
+        };
+        self
+    }
+}
+
+/// Counts delivered history against the preload limit
+#[derive(Default)]
+struct PreloadLimit {
+    max: Option<usize>,
+    /// Only key histories are ordered by sequences and may be continued
+    continuable: bool,
+    delivered: usize,
+    last_sequence: Option<SequenceId>,
+}
+
+impl PreloadLimit {
+    fn add(&mut self, sequence: Option<SequenceId>) {
+        self.delivered += 1;
+        self.last_sequence = sequence;
+    }
+
+    fn is_reached(&self) -> bool {
+        matches!(self.max, Some(m) if self.delivered >= m)
+    }
+
+    /// Returns the sequence to continue the limited preload from
+    fn continuation(&self) -> Option<SequenceId> {
+        if !self.continuable || !self.is_reached() {
+            return None;
+        }
+
+        self.last_sequence
+            .and_then(|s| s.get().checked_add(1))
+            .and_then(SequenceId::new)
+    }
+}
+
+/// Progress of the preload, which is finished in the subscription stream
+struct PreloadProgress {
+    queue_name: String,
+    id: Option<String>,
+    scanned: usize,
+    started: Instant,
+    deadline: Option<Instant>,
+    limits: SlowPreload,
+}
+
+impl PreloadProgress {
+    /// Streamed history is scanned chunk by chunk, the deadline is checked between them
+    fn is_expired(&self) -> bool {
+        matches!(self.deadline, Some(d) if Instant::now() > d)
+    }
+
+    /// Logs and counts preloads which scan too many entries or take too long
+    fn finish(&self, stats: &QueueStats) {
+        let elapsed = self.started.elapsed();
+        let is_slow = matches!(self.limits.max_entries, Some(m) if self.scanned > m)
+            || matches!(self.limits.max_duration, Some(d) if elapsed.as_millis() > d as u128);
+
+        if is_slow {
+            stats.add_slow_preload();
+            warn!(
+                queue = %self.queue_name,
+                key = ?self.id,
+                scanned = self.scanned,
+                elapsed_ms = elapsed.as_millis() as u64,
+                "slow preload"
+            );
+        }
+    }
+}
+
+/// Last sequences of keys scanned by the streamed preload. The subscription receives live events
+/// while the history is scanned, so events stored meanwhile are both scanned and received.
+#[derive(Default)]
+struct PreloadedSequences(HashMap<String, SequenceId>);
+
+impl PreloadedSequences {
+    fn add<T: UniqId>(&mut self, value: &T) {
+        let sequence = match value.get_sequence() {
+            Some(sequence) => sequence,
+            None => return,
+        };
+        match self.0.get_mut(value.get_id()) {
+            Some(last) => *last = sequence.max(*last),
+            None => {
+                self.0.insert(value.get_id().to_string(), sequence);
+            }
+        }
+    }
+
+    /// Checks if the live event was already preloaded,
+    /// the key is forgotten after its first newer event
+    fn is_preloaded<T: UniqId>(&mut self, value: &T) -> bool {
+        let sequence = match value.get_sequence() {
+            Some(sequence) => sequence,
+            None => return false,
+        };
+        match self.0.get(value.get_id()) {
+            Some(last) if sequence <= *last => true,
+            Some(_) => {
+                self.0.remove(value.get_id());
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// Decodes the chunk on the blocking thread pool, the permit is released when it's decoded
+fn decode_chunk<T: 'static + DeserializeOwned + Send>(
+    chunk: Vec<(IVec, IVec)>,
+    poison: PoisonGuard,
+    permit: OwnedSemaphorePermit,
+) -> JoinHandle<Vec<T>> {
+    actix_web::rt::task::spawn_blocking(move || {
+        let values = chunk
+            .iter()
+            .filter_map(|(k, v)| poison.decode(k, v))
+            .collect();
+        drop(permit);
+        values
+    })
+}
+
+fn prepare_stream<'a, T: 'static + DeserializeOwned + UniqId + Send + Clone>(
+    receivers: Vec<ChannelReceiver<T>>,
+    prev_items: Option<Preload<T>>,
+    mut progress: PreloadProgress,
+    pipeline: DeliveryPipeline<T>,
+    poison: PoisonGuard,
+    decoders: Arc<Semaphore>,
+    stats: Arc<QueueStats>,
+) -> BoxStream<'a, BroadcastMessage<T>> {
+    Box::pin(async_stream::stream! {
+        let mut preloaded = PreloadedSequences::default();
+        if let Some(mut preload) = prev_items {
+            for value in std::mem::take(&mut preload.decoded) {
+                if preload.limit.is_reached() {
+                    break;
+                }
+                if let Some(value) = pipeline.deliver(value) {
+                    preload.limit.add(value.get_sequence());
+                    yield BroadcastMessage::Message(value)
+                }
+            }
+
+            let mut scanning = !preload.limit.is_reached();
+            let mut failed = false;
+            let mut decoding = VecDeque::new();
+            loop {
+                while scanning && decoding.len() < PRELOAD_DECODING_CHUNKS {
+                    if progress.is_expired() {
+                        warn!(
+                            queue = %progress.queue_name,
+                            key = ?progress.id,
+                            scanned = progress.scanned,
+                            "preload timed out"
+                        );
+                        failed = true;
+                        break;
+                    }
+
+                    let chunk = preload
+                        .raw
+                        .by_ref()
+                        .take(PRELOAD_CHUNK_SIZE)
+                        .collect::<sled::Result<Vec<_>>>();
+
+                    match chunk {
+                        Ok(chunk) if chunk.is_empty() => scanning = false,
+                        Ok(chunk) => {
+                            progress.scanned += chunk.len();
+                            // decoding waits for a free permit of the shared pool
+                            let permit = match decoders.clone().acquire_owned().await {
+                                Ok(permit) => permit,
+                                Err(e) => {
+                                    error!(error = %e, "preload decoding was canceled");
+                                    failed = true;
+                                    break;
+                                }
+                            };
+                            decoding.push_back(decode_chunk::<T>(chunk, poison.clone(), permit));
+                        }
+                        Err(e) => {
+                            error!(error = %e, "preload scanning error");
+                            failed = true;
+                            break;
+                        }
+                    }
+                }
+                if failed {
+                    break;
+                }
+
+                let chunk = match decoding.pop_front() {
+                    None => break,
+                    Some(chunk) => chunk.await,
+                };
+
+                match chunk {
+                    Ok(values) => {
+                        for value in values {
+                            if preload.limit.is_reached() {
+                                break;
+                            }
+                            let skipped = matches!(
+                                preload.min_sequence,
+                                Some(s) if value.get_sequence().filter(|cs| *cs >= s).is_none()
+                            );
+                            if skipped {
+                                continue;
+                            }
+                            preloaded.add(&value);
+                            if let Some(value) = pipeline.deliver(value) {
+                                preload.limit.add(value.get_sequence());
+                                yield BroadcastMessage::Message(value)
+                            }
+                        }
+                        if preload.limit.is_reached() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        error!(error = %e, "preload decoding was canceled");
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+
+            progress.finish(&stats);
+            // the client resubscribes from the last received sequence instead of missing history
+            if failed {
+                yield BroadcastMessage::PreloadFailed;
+                return;
+            }
+            yield BroadcastMessage::EndOfPreload(preload.limit.continuation())
+        }
+        let mut live = select_all(receivers.into_iter().map(|r| Box::pin(r.into_stream())));
+        while let Some(message) = live.next().await {
+            match message {
+                Ok(BroadcastMessage::Message(value)) => {
+                    if preloaded.is_preloaded(&value) {
+                        continue;
+                    }
+                    if let Some(value) = pipeline.deliver(value) {
+                        yield BroadcastMessage::Message(value)
+                    }
+                }
+                Ok(value) => yield value,
+                Err(skipped) => {
+                    stats.add_dropped(skipped);
+                    yield BroadcastMessage::Lagged(skipped)
+                }
+            }
+        }
+    })
+}
+
+fn open_db(path: Option<&Path>, storage: Option<&Storage>) -> sled::Result<QueueMap> {
+    let db_config = match path {
+        None => sled::Config::new().temporary(true),
+        Some(dp) => sled::Config::new().path(dp).use_compression(true),
+    };
+
+    match storage {
+        None => db_config.open(),
+        Some(storage) => tune_db(db_config, storage).open(),
+    }
+}
+
+fn tune_db(mut db_config: sled::Config, storage: &Storage) -> sled::Config {
+    if let Some(cache_capacity) = storage.cache_capacity {
+        db_config = db_config.cache_capacity(cache_capacity);
+    }
+    if let Some(compression_factor) = storage.compression_factor {
+        db_config = db_config
+            .use_compression(true)
+            .compression_factor(compression_factor);
+    }
+    if let Some(segment_size) = storage.segment_size {
+        db_config = db_config.segment_size(segment_size);
+    }
+    match storage.mode {
+        None => db_config,
+        Some(StorageMode::LowSpace) => db_config.mode(sled::Mode::LowSpace),
+        Some(StorageMode::HighThroughput) => db_config.mode(sled::Mode::HighThroughput),
+    }
+}
+
+fn get_id(id: &str, sequence: u64) -> Vec<u8> {
+    let mut id = Vec::from(id.as_bytes());
+    id.extend_from_slice(&sequence.to_be_bytes());
+
+    id
+}
+
+pub(crate) fn split_id(key: &[u8]) -> Option<(&[u8], u64)> {
+    let split = key.len().checked_sub(8)?;
+    let (id, sequence) = key.split_at(split);
+
+    Some((id, u64::from_be_bytes(sequence.try_into().ok()?)))
+}
+
+/// Scans entries of the exact key, skipping keys which only start with it
+fn scan_key<'a>(
+    trees: &QueueTrees,
+    id: &'a str,
+) -> impl DoubleEndedIterator<Item = sled::Result<(IVec, IVec)>> + 'a {
+    trees
+        .scan_prefix(id.as_bytes())
+        .filter(move |r| is_key_entry(r, id))
+}
+
+/// Checks that the entry belongs to the exact key, not to a key which starts with it
+fn is_key_entry(entry: &sled::Result<(IVec, IVec)>, id: impl AsRef<[u8]>) -> bool {
+    match entry {
+        Ok((k, _)) => matches!(split_id(k), Some((key_id, _)) if key_id == id.as_ref()),
+        Err(_) => true,
+    }
+}
+
+/// Cold index is stored in the default tree, so it never clashes with queues
+fn get_cold_index_prefix(queue_name: &[u8]) -> Vec<u8> {
+    let mut key = Vec::from(COLD_INDEX_PREFIX);
+    key.extend_from_slice(queue_name);
+    key.push(0);
+
+    key
+}
+
+fn get_cold_index_key(queue_name: &[u8], id: &[u8], last: u64) -> Vec<u8> {
+    let mut key = get_cold_index_prefix(queue_name);
+    key.extend_from_slice(id);
+    key.extend_from_slice(&last.to_be_bytes());
+
+    key
+}
+
+/// Head marks are stored in the default tree, the queue mark has the empty key
+fn get_head_key(queue_name: &[u8], id: &[u8]) -> Vec<u8> {
+    let mut key = Vec::from(HEAD_PREFIX);
+    key.extend_from_slice(queue_name);
+    key.push(0);
+    key.extend_from_slice(id);
+
+    key
+}
+
+/// Metadata of queues is stored in the default tree
+fn get_meta_key(queue_name: &[u8]) -> Vec<u8> {
+    let mut key = Vec::from(META_PREFIX);
+    key.extend_from_slice(queue_name);
+    key.push(0);
+
+    key
+}
+
+/// Size estimates of keys are stored in the default tree, queue sizes are their sums
+fn get_size_key(queue_name: &[u8], id: &[u8]) -> Vec<u8> {
+    let mut key = Vec::from(SIZE_PREFIX);
+    key.extend_from_slice(queue_name);
+    key.push(0);
+    key.extend_from_slice(id);
+
+    key
+}
+
+fn encode_size(size: &StoredSize) -> Vec<u8> {
+    let mut bytes = Vec::from(size.entries.to_be_bytes());
+    bytes.extend_from_slice(&size.bytes.to_be_bytes());
+
+    bytes
+}
+
+fn decode_size(bytes: &[u8]) -> Option<StoredSize> {
+    let (entries, size) = bytes.split_at(8.min(bytes.len()));
+
+    Some(StoredSize {
+        entries: u64::from_be_bytes(entries.try_into().ok()?),
+        bytes: u64::from_be_bytes(size.try_into().ok()?),
+    })
+}
+
+/// Delivery receipt marks are stored in the default tree, so they never clash with queues
+fn get_receipt_prefix(queue_name: &[u8]) -> Vec<u8> {
+    let mut key = Vec::from(RECEIPT_PREFIX);
+    key.extend_from_slice(queue_name);
+    key.push(0);
+
+    key
+}
+
+/// Message traces are stored in the default tree, keyed by the queue and the entry key
+fn get_trace_key(queue_name: &[u8], entry: &[u8]) -> Vec<u8> {
+    let mut key = Vec::from(TRACE_PREFIX);
+    key.extend_from_slice(queue_name);
+    key.push(0);
+    key.extend_from_slice(entry);
+
+    key
+}
+
+/// Session cursors are stored in the default tree too, keyed by the queue, the key and the session
+fn get_session_prefix(queue_name: &[u8]) -> Vec<u8> {
+    let mut key = Vec::from(SESSION_PREFIX);
+    key.extend_from_slice(queue_name);
+    key.push(0);
+
+    key
+}
+
+fn get_session_key(queue_name: &[u8], id: &[u8], session: &[u8]) -> Vec<u8> {
+    let mut key = get_session_prefix(queue_name);
+    key.extend_from_slice(id);
+    key.push(0);
+    key.extend_from_slice(session);
+
+    key
+}
+
+fn is_dedup_expired(claimed: &[u8], now: u64, window: Duration) -> bool {
+    let claimed = claimed
+        .try_into()
+        .map(u64::from_be_bytes)
+        .unwrap_or_default();
+    claimed.saturating_add(window.as_secs()) <= now
+}
+
+/// Dedup ids of publishers are stored in the default tree with the claim time in seconds
+fn get_dedup_key(queue_name: &[u8], dedup_id: &[u8]) -> Vec<u8> {
+    let mut key = Vec::from(DEDUP_PREFIX);
+    key.extend_from_slice(queue_name);
+    key.push(0);
+    key.extend_from_slice(dedup_id);
+
+    key
+}
+
+/// Dead letters of the journal are stored in the default tree by the queue and generated ids
+fn get_journal_dead_key(queue_name: &[u8], id: u64) -> Vec<u8> {
+    let mut key = Vec::from(JOURNAL_DEAD_PREFIX);
+    key.extend_from_slice(queue_name);
+    key.push(0);
+    key.extend_from_slice(&id.to_be_bytes());
+
+    key
+}
+
+/// Sink checkpoints are stored in the default tree by the sink name
+fn get_sink_key(name: &[u8]) -> Vec<u8> {
+    let mut key = Vec::from(SINK_PREFIX);
+    key.extend_from_slice(name);
+
+    key
+}
+
+/// Sequence counters are stored in the default tree, the separator keeps keys of queues
+/// apart from keys of queues whose names start with them
+fn get_counter_key(queue_name: &[u8], id: &[u8]) -> Vec<u8> {
+    let mut key = Vec::from(COUNTER_PREFIX);
+    key.extend_from_slice(queue_name);
+    key.push(0);
+    key.extend_from_slice(id);
+
+    key
+}
+
+/// Tracks scanned entries and the deadline of subscription preload
+struct PreloadScan {
+    scanned: Cell<usize>,
+    started: Instant,
+    deadline: Option<Instant>,
+}
+
+impl PreloadScan {
+    fn new(timeout: Option<Duration>) -> Self {
+        let started = Instant::now();
+        Self {
+            scanned: Cell::new(0),
+            started,
+            deadline: timeout.map(|t| started + t),
+        }
+    }
+
+    fn next(&self) -> QueueResult<()> {
+        self.scanned.set(self.scanned.get() + 1);
+        match self.deadline {
+            Some(d) if Instant::now() > d => Err(QueueError::PreloadTimeout),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn get_prev_items<T: DeserializeOwned + Tombstone>(
+    trees: &QueueTrees,
+    id: &str,
+    sequence: RequestSequence,
+    scan: &PreloadScan,
+    poison: &PoisonGuard,
+) -> QueueResult<Option<Preload<T>>> {
+    sequence
+        .map(|sequence_id| {
+            let values = extract_sequences(trees, sequence_id, id);
+
+            match sequence_id {
+                RequestSequenceId::Last => {
+                    let mut items = Vec::new();
+                    for r in values {
+                        scan.next()?;
+                        let (key, value) = r?;
+                        let value = poison.decode::<T>(&key, &value);
+                        items.extend(value.filter(|v| !v.is_tombstone()));
+                    }
+                    Ok(Preload::decoded(items))
+                }
+                // long histories are scanned while they are streamed
+                _ => Ok(Preload::raw(values, None)),
+            }
+        })
+        .transpose()
+}
+
+fn extract_sequences(
+    trees: &QueueTrees,
+    sequence_id: RequestSequenceId,
+    id: &str,
+) -> Box<dyn Iterator<Item = sled::Result<(IVec, IVec)>> + Send> {
+    let values: Box<dyn DoubleEndedIterator<Item = sled::Result<(IVec, IVec)>> + Send> =
+        match sequence_id {
+            RequestSequenceId::Id(s) => {
+                Box::new(trees.range(get_id(id, s.get())..get_id(id, u64::MAX)))
+            }
+            RequestSequenceId::Last | RequestSequenceId::First => {
+                Box::new(trees.scan_prefix(id.as_bytes()))
+            }
+        };
+
+    // keys which start with the id are stored between its entries
+    let id = id.to_string();
+    let values = values.filter(move |r| is_key_entry(r, &id));
+    match sequence_id {
+        RequestSequenceId::Last => Box::new(values.rev().take(1)),
+        _ => Box::new(values),
+    }
+}
+
+fn get_prev_all_items<T: DeserializeOwned + UniqId + Tombstone>(
+    trees: &QueueTrees,
+    offsets: Option<Tree>,
+    sequence: RequestSequence,
+    scan: &PreloadScan,
+    poison: &PoisonGuard,
+) -> QueueResult<Option<Preload<T>>> {
+    sequence
+        .map(|sequence_id| {
+            let values = trees.iter();
+
+            match (sequence_id, offsets) {
+                (RequestSequenceId::Id(s), Some(offsets)) => Ok(Preload::raw(
+                    ordered_values(trees.clone(), offsets),
+                    Some(s),
+                )),
+                (RequestSequenceId::First, Some(offsets)) => {
+                    Ok(Preload::raw(ordered_values(trees.clone(), offsets), None))
+                }
+                (RequestSequenceId::Id(s), None) => Ok(Preload::raw(values, Some(s))),
+                (RequestSequenceId::Last, _) => {
+                    let mut map: BTreeMap<String, T> = BTreeMap::new();
+
+                    for r in values {
+                        scan.next()?;
+                        let (key, value) = r?;
+                        if let Some(v) = poison.decode::<T>(&key, &value) {
+                            map.insert(v.get_id().to_string(), v);
+                        }
+                    }
+
+                    Ok(Preload::decoded(
+                        map.into_values().filter(|v| !v.is_tombstone()).collect(),
+                    ))
+                }
+                (RequestSequenceId::First, None) => Ok(Preload::raw(values, None)),
+            }
+        })
+        .transpose()
+}
+
+/// Stored keys and values of the queue in publish order, removed events are skipped
+fn ordered_values(
+    trees: QueueTrees,
+    offsets: Tree,
+) -> impl Iterator<Item = sled::Result<(IVec, IVec)>> + Send {
+    offsets.iter().values().filter_map(move |key| {
+        key.and_then(|key| Ok(trees.get(&key)?.map(|value| (key, value))))
+            .transpose()
+    })
+}
+
+#[derive(Debug, Display, From, Error)]
+pub enum QueueError {
+    Db(sled::Error),
+    Encode(serde_json::Error),
+    Envelope(EnvelopeError),
+    #[display(fmt = "sequence must be more then 0")]
+    ZeroSequence,
+    Rejected(InterceptorError),
+    #[display(fmt = "preload timed out")]
+    PreloadTimeout,
+    Io(std::io::Error),
+    LeaseConflict(LeaseConflict),
+    #[display(fmt = "disk usage thresholds are crossed")]
+    DiskFull,
+    #[display(fmt = "{} queue mode is not valid: {}", queue, reason)]
+    InvalidMode {
+        queue: String,
+        reason: &'static str,
+    },
+}
+
+impl QueueError {
+    /// Errors of the databases and files, which aren't caused by the stored event
+    pub fn is_storage(&self) -> bool {
+        matches!(self, QueueError::Db(_) | QueueError::Io(_))
+    }
+}
+
+pub type QueueResult<T> = Result<T, QueueError>;
+
+#[derive(Debug)]
+struct QueueBroadcast<T> {
+    sender: Channel<T>,
+    keys: HashMap<String, Channel<T>>,
+}
+
+fn get_queue_broadcast<T: Clone>(
     queue_name: String,
     queue_broadcasts: &mut HashMap<String, QueueBroadcast<T>>,
 ) -> &mut QueueBroadcast<T> {
     queue_broadcasts
         .entry(queue_name)
         .or_insert_with(|| QueueBroadcast {
-            sender: channel(1024).0,
+            sender: Channel::new(),
             keys: Default::default(),
         })
 }
 
+/// Returns parent topics of the hierarchical queue name, `a.b.c` has `a` and `a.b` parents
+fn parent_queues(queue_name: &str) -> impl Iterator<Item = &str> {
+    queue_name
+        .match_indices(TOPIC_SEPARATOR)
+        .map(move |(i, _)| &queue_name[..i])
+}
+
 fn get_key_broadcast<T: Clone>(
     id: String,
     queue_broadcast: &mut QueueBroadcast<T>,
-) -> &mut Sender<T> {
-    queue_broadcast
-        .keys
-        .entry(id)
-        .or_insert_with(|| channel(1024).0)
+) -> &mut Channel<T> {
+    queue_broadcast.keys.entry(id).or_insert_with(Channel::new)
+}
+
+/// Delivery metrics of the queue broadcast channels
+pub struct Broadcasts {
+    pub queue: ChannelMetrics,
+    pub keys: BTreeMap<String, ChannelMetrics>,
+}
+
+/// Page of the replayed key history
+pub struct Replay<T> {
+    pub events: Vec<T>,
+    /// Sequence of the next page, `None` if the stored history is over
+    pub next: Option<SequenceId>,
+}
+
+/// Events of the queue read by the sink
+pub struct OffsetBatch<T> {
+    pub events: Vec<T>,
+    /// Last read offset, `None` if there are no events after the checkpoint
+    pub last: Option<u64>,
+}
+
+/// Size estimates of the queue and its keys
+pub struct Sizes {
+    pub queue: StoredSize,
+    pub keys: BTreeMap<String, StoredSize>,
+}
+
+/// Listed queue with its latest stored offset and metadata, returned by [`Queue::list_queues`]
+pub struct QueueSummary {
+    pub name: String,
+    pub head: Option<HeadMark>,
+    pub metadata: QueueMetadata,
+}
+
+/// Latest stored positions of the key and the queue
+pub struct Head {
+    pub key: Option<HeadMark>,
+    pub queue: Option<HeadMark>,
+}
+
+/// Position is a sequence for keys and an offset for queues, timestamp is in milliseconds
+#[derive(Clone, Copy)]
+pub struct HeadMark {
+    pub position: u64,
+    pub timestamp: u64,
+}
+
+impl HeadMark {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::from(self.position.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (position, timestamp) = bytes.split_at(8.min(bytes.len()));
+
+        Some(Self {
+            position: u64::from_be_bytes(position.try_into().ok()?),
+            timestamp: u64::from_be_bytes(timestamp.try_into().ok()?),
+        })
+    }
+}
+
+/// Stored sequences of the key compared with the published ones
+pub struct SequenceReport {
+    /// Last sequence generated for the key, zero if nothing was published
+    pub published: u64,
+    pub stored: usize,
+    pub first: Option<u64>,
+    pub last: Option<u64>,
+    /// Missing sequences up to the published one, including the ones before the first stored
+    pub gaps: Vec<RangeInclusive<u64>>,
 }
 
 pub struct Subscription<'a, T> {
     pub stream: Option<BoxStream<'a, BroadcastMessage<T>>>,
-    pub preloaded_count: Option<usize>,
+    /// Stored history is sent before live events and followed by the end of preload event
+    pub preload: bool,
 }
 
 impl<'a, T> Default for Subscription<'a, T> {
     fn default() -> Self {
         Self {
             stream: None,
-            preloaded_count: None,
+            preload: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::clock::ManualClock;
+    use futures::FutureExt;
+    use serde_json::json;
+    use sonya_meta::message::EventMessage;
+    use sonya_meta::response::RangeDigest;
+
+    fn queue(options: serde_json::Value) -> Queue<EventMessage> {
+        Queue::new(serde_json::from_value(options).unwrap()).unwrap()
+    }
+
+    fn event(id: &str) -> EventMessage {
+        serde_json::from_value(json!({ "id": id, "payload": {} })).unwrap()
+    }
+
+    fn send(queue: &Queue<EventMessage>, ids: &[&str]) {
+        for id in ids {
+            queue.send_to_queue("test".into(), event(id)).unwrap();
+        }
+    }
+
+    fn count(queue: &Queue<EventMessage>, id: &str) -> Option<usize> {
+        queue.count_key("test".into(), id.into()).unwrap()
+    }
+
+    fn temp_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("sonya-test-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn counter(queue: &Queue<EventMessage>, queue_name: &str, id: &str) -> Option<u64> {
+        queue
+            .map
+            .get(get_counter_key(queue_name.as_bytes(), id.as_bytes()))
+            .unwrap()
+            .map(|v| u64::from_be_bytes(v.as_ref().try_into().unwrap()))
+    }
+
+    #[test]
+    fn deleting_key_keeps_keys_starting_with_it() {
+        let queue = queue(json!({}));
+        queue.create_queue("test".into()).unwrap();
+        send(&queue, &["1", "10", "1", "10"]);
+
+        queue
+            .delete_key_history("test".into(), "1".into(), false)
+            .unwrap();
+
+        assert_eq!(count(&queue, "1"), Some(0));
+        assert_eq!(count(&queue, "10"), Some(2));
+    }
+
+    #[test]
+    fn trimming_key_keeps_keys_starting_with_it() {
+        let queue = queue(json!({ "max_key_updates": 2 }));
+        queue.create_queue("test".into()).unwrap();
+        send(&queue, &["10", "10", "1", "1", "1"]);
+
+        assert_eq!(count(&queue, "1"), Some(2));
+        assert_eq!(count(&queue, "10"), Some(2));
+    }
+
+    #[test]
+    fn disk_trimming_keeps_newest_entries_of_every_key() {
+        let queue = queue(json!({}));
+        queue.create_queue("test".into()).unwrap();
+        let ids: Vec<_> = (0..TRIM_BATCH_SIZE).map(|i| i.to_string()).collect();
+        for _ in 0..3 {
+            send(&queue, &ids.iter().map(String::as_str).collect::<Vec<_>>());
+        }
+
+        assert_eq!(queue.trim_history(1).unwrap(), TRIM_BATCH_SIZE * 2);
+
+        for id in ["1", "10", "100"] {
+            let report = queue
+                .sequence_gaps("test".into(), id.into())
+                .unwrap()
+                .unwrap();
+            assert_eq!(report.stored, 1);
+            assert_eq!(report.first, Some(3));
+        }
+    }
+
+    #[test]
+    fn stale_counters_are_resynced_on_open() {
+        let path = temp_path();
+        let options = json!({ "db_path": path });
+        {
+            let queue = queue(options.clone());
+            queue.create_queue("test".into()).unwrap();
+            send(&queue, &["1", "1"]);
+            // the default tree is restored from a backup taken before the publishes
+            queue.map.remove(get_counter_key(b"test", b"1")).unwrap();
+            queue.map.flush().unwrap();
+        }
+
+        let queue = queue(options);
+        assert_eq!(counter(&queue, "test", "1"), Some(2));
+
+        send(&queue, &["1"]);
+        assert_eq!(counter(&queue, "test", "1"), Some(3));
+        drop(queue);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn counters_of_queues_sharing_name_prefix_are_separate() {
+        let queue = queue(json!({}));
+        queue.create_queue("a".into()).unwrap();
+        queue.create_queue("ab".into()).unwrap();
+        queue.send_to_queue("a".into(), event("bc")).unwrap();
+        queue.send_to_queue("a".into(), event("bc")).unwrap();
+        queue.send_to_queue("ab".into(), event("c")).unwrap();
+
+        assert_eq!(counter(&queue, "a", "bc"), Some(2));
+        assert_eq!(counter(&queue, "ab", "c"), Some(1));
+    }
+
+    fn journaled(path: &Path) -> Queue<EventMessage> {
+        let queue = queue(json!({ "journal": { "path": path } }));
+        queue.create_queue("test".into()).unwrap();
+        queue
+    }
+
+    fn append(queue: &Queue<EventMessage>, queue_name: &str, ids: &[&str]) {
+        let events = ids.iter().map(|id| (event(id), None)).collect();
+        queue
+            .append_journal(queue_name.into(), events)
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn journal_is_truncated_after_applying() {
+        let path = temp_path();
+        let queue = journaled(&path);
+        append(&queue, "test", &["1", "2", "1"]);
+
+        assert_eq!(queue.apply_journal().unwrap(), 3);
+        assert_eq!(count(&queue, "1"), Some(2));
+        assert_eq!(queue.journal.as_ref().unwrap().size().unwrap(), 0);
+        assert_eq!(
+            queue.journal_progress().unwrap(),
+            JournalProgress::default()
+        );
+        drop(queue);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn record_interrupted_by_crash_is_applied_once() {
+        let path = temp_path();
+        let queue = journaled(&path);
+        let events = vec![event("1"), event("1"), event("1")];
+        let record = JournalRecord {
+            queue: "test".to_string(),
+            events: events.clone(),
+        };
+        queue.journal.as_ref().unwrap().append(&[record]).unwrap();
+        // the crash happened after the first event of the record was stored
+        let mut cursor = JournalCursor::at(0);
+        queue
+            .store_intercepted("test", events[..1].to_vec(), Some(&mut cursor))
+            .unwrap();
+
+        assert_eq!(queue.apply_journal().unwrap(), 1);
+        assert_eq!(count(&queue, "1"), Some(3));
+        let sequences = queue.publish("test".into(), event("1")).unwrap().unwrap();
+        assert_eq!(sequences[0].get(), 4);
+        drop(queue);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn records_of_dropped_queues_are_dead_lettered() {
+        let path = temp_path();
+        let queue = journaled(&path);
+        queue.create_queue("other".into()).unwrap();
+        append(&queue, "test", &["1"]);
+        append(&queue, "other", &["1"]);
+        queue.drop_queue("test".into()).unwrap();
+
+        assert_eq!(queue.apply_journal().unwrap(), 1);
+        assert_eq!(queue.stats.snapshot().dead_lettered, 1);
+        assert_eq!(
+            queue.map.scan_prefix(JOURNAL_DEAD_PREFIX).count(),
+            1,
+            "dead letter is kept"
+        );
+        assert_eq!(
+            queue.count_key("other".into(), "1".into()).unwrap(),
+            Some(1)
+        );
+        assert_eq!(queue.journal.as_ref().unwrap().size().unwrap(), 0);
+        drop(queue);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn events_published_during_preload_are_delivered_once() {
+        let queue = queue(json!({}));
+        queue.create_queue("test".into()).unwrap();
+        let stored = PRELOAD_CHUNK_SIZE * (PRELOAD_DECODING_CHUNKS + 2);
+        send(&queue, &vec!["1"; stored]);
+
+        let mut stream = queue
+            .subscribe_queue_by_id(
+                "test".into(),
+                "1".into(),
+                Some(RequestSequenceId::First),
+                None,
+                None,
+            )
+            .unwrap()
+            .stream
+            .unwrap();
+        let mut sequences = Vec::new();
+        // the first chunks are scanned, the rest of the history is scanned after the publishes
+        if let Some(BroadcastMessage::Message(m)) = stream.next().await {
+            sequences.extend(m.get_sequence().map(SequenceId::get));
+        }
+        send(&queue, &["1"; 10]);
+
+        while let Some(message) = stream.next().await {
+            match message {
+                BroadcastMessage::Message(m) => {
+                    sequences.extend(m.get_sequence().map(SequenceId::get))
+                }
+                BroadcastMessage::EndOfPreload(_) => break,
+                _ => {}
+            }
+        }
+        // live events which were scanned by the preload are already received
+        while let Some(Some(BroadcastMessage::Message(m))) = stream.next().now_or_never() {
+            sequences.extend(m.get_sequence().map(SequenceId::get));
+        }
+
+        assert_eq!(sequences, (1..=stored as u64 + 10).collect::<Vec<_>>());
+    }
+
+    async fn preloaded(
+        queue: &Queue<EventMessage>,
+        id: &str,
+        sequence: RequestSequenceId,
+    ) -> Vec<EventMessage> {
+        let mut stream = queue
+            .subscribe_queue_by_id("test".into(), id.into(), Some(sequence), None, None)
+            .unwrap()
+            .stream
+            .unwrap();
+        let mut events = Vec::new();
+        while let Some(BroadcastMessage::Message(m)) = stream.next().await {
+            events.push(m);
+        }
+        events
+    }
+
+    fn sequences(events: &[EventMessage]) -> Vec<u64> {
+        events
+            .iter()
+            .filter_map(|e| e.get_sequence().map(SequenceId::get))
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn tiered_history_is_preloaded_from_cold_store() {
+        let path = temp_path();
+        let store = crate::queue::blob::FsBlobStore::new(path.clone()).unwrap();
+        let queue = queue(json!({})).with_cold_tier(store, 2);
+        queue.create_queue("test".into()).unwrap();
+        send(&queue, &["1"; 5]);
+
+        assert_eq!(queue.tier_history().unwrap(), 3);
+        assert_eq!(queue.tier_history().unwrap(), 0);
+        assert_eq!(count(&queue, "1"), Some(2));
+
+        let events = preloaded(&queue, "1", RequestSequenceId::First).await;
+        assert_eq!(sequences(&events), vec![1, 2, 3, 4, 5]);
+        let from = RequestSequenceId::Id(SequenceId::new(2).unwrap());
+        let events = preloaded(&queue, "1", from).await;
+        assert_eq!(sequences(&events), vec![2, 3, 4, 5]);
+
+        queue
+            .delete_key_history("test".into(), "1".into(), false)
+            .unwrap();
+        assert_eq!(std::fs::read_dir(&path).unwrap().count(), 0);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn expired_segments_are_dropped_with_their_events() {
+        let clock = ManualClock::default();
+        let queue = queue(json!({ "segments": { "duration": 60, "retention": 120 } }))
+            .with_clock(clock.clone());
+        queue.create_queue("test".into()).unwrap();
+        send(&queue, &["1", "2"]);
+        clock.advance(Duration::from_secs(60));
+        send(&queue, &["1"]);
+
+        clock.advance(Duration::from_secs(119));
+        assert_eq!(queue.drop_expired_segments().unwrap(), 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(queue.drop_expired_segments().unwrap(), 1);
+
+        assert_eq!(count(&queue, "1"), Some(1));
+        assert_eq!(count(&queue, "2"), Some(0));
+        let events = preloaded(&queue, "1", RequestSequenceId::First).await;
+        assert_eq!(sequences(&events), vec![2]);
+    }
+
+    fn correction(id: &str, supersedes: u64) -> EventMessage {
+        serde_json::from_value(json!({ "id": id, "supersedes": supersedes, "payload": {} }))
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn corrections_collapse_superseded_events() {
+        for (options, expected) in [
+            (json!({}), vec![1, 2, 3, 4]),
+            (json!({ "collapse_superseded": ["test"] }), vec![2, 4]),
+        ] {
+            let queue = queue(options);
+            queue.create_queue("test".into()).unwrap();
+            send(&queue, &["1", "1"]);
+            for _ in 0..2 {
+                queue
+                    .send_to_queue("test".into(), correction("1", 1))
+                    .unwrap();
+            }
+
+            let events = preloaded(&queue, "1", RequestSequenceId::First).await;
+            assert_eq!(sequences(&events), expected);
+        }
+    }
+
+    #[test]
+    fn leased_keys_accept_only_holder_until_expiration() {
+        let clock = ManualClock::default();
+        let queue = queue(json!({})).with_clock(clock.clone());
+        queue.create_queue("test".into()).unwrap();
+        let ttl = Duration::from_secs(10);
+        let acquire = |token: Option<&str>| {
+            queue.acquire_lease("test".into(), "1".into(), ttl, token.map(Into::into))
+        };
+
+        let lease = acquire(None).unwrap().unwrap();
+        assert!(acquire(None).is_err());
+        assert!(queue.check_lease("test", "1", None).is_err());
+        assert!(queue.check_lease("test", "1", Some("other")).is_err());
+        assert!(queue.check_lease("test", "1", Some(&lease.token)).is_ok());
+        assert!(queue.check_lease("test", "2", None).is_ok());
+
+        clock.advance(Duration::from_secs(5));
+        let extended = acquire(Some(&lease.token)).unwrap().unwrap();
+        assert_eq!(extended.token, lease.token);
+        clock.advance(Duration::from_secs(9));
+        assert!(queue.check_lease("test", "1", None).is_err());
+        clock.advance(Duration::from_secs(1));
+        assert!(queue.check_lease("test", "1", None).is_ok());
+
+        let next = acquire(None).unwrap().unwrap();
+        assert_ne!(next.token, lease.token);
+        assert!(!queue.release_lease("test".into(), "1".into(), lease.token));
+        assert!(queue.release_lease("test".into(), "1".into(), next.token));
+        assert!(queue.check_lease("test", "1", None).is_ok());
+    }
+
+    fn sequenced(id: &str, sequence: u64) -> EventMessage {
+        serde_json::from_value(json!({ "id": id, "sequence": sequence, "payload": {} })).unwrap()
+    }
+
+    #[test]
+    fn explicit_sequences_advance_key_counter() {
+        let queue = queue(json!({}));
+        queue.create_queue("test".into()).unwrap();
+        let publish =
+            |event: EventMessage| queue.publish("test".into(), event).unwrap().unwrap()[0].get();
+
+        assert_eq!(publish(sequenced("1", 5)), 5);
+        assert_eq!(publish(event("1")), 6);
+        // older sequences overwrite history without moving the counter back
+        assert_eq!(publish(sequenced("1", 3)), 3);
+        assert_eq!(publish(event("1")), 7);
+        assert_eq!(publish(event("2")), 1);
+    }
+
+    /// Copies the key range like the standby backfill does with the replay method
+    fn copy_range(
+        from: &Queue<EventMessage>,
+        to: &Queue<EventMessage>,
+        id: &str,
+        range: &RangeDigest,
+    ) {
+        let replay = from
+            .replay_key(
+                "test".into(),
+                id.into(),
+                SequenceId::new(range.from),
+                None,
+                100,
+            )
+            .unwrap()
+            .unwrap();
+        for event in replay.events {
+            if matches!(event.get_sequence(), Some(s) if s.get() <= range.to) {
+                to.send_to_queue("test".into(), event).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn backfill_copies_only_diverged_ranges() {
+        let (primary, standby) = (queue(json!({})), queue(json!({})));
+        primary.create_queue("test".into()).unwrap();
+        standby.create_queue("test".into()).unwrap();
+        send(&primary, &["1", "1", "1", "1", "1", "2", "2"]);
+        let digest = |queue: &Queue<EventMessage>, id: &str| {
+            queue
+                .digest_key("test".into(), id.into(), 2, None)
+                .unwrap()
+                .unwrap()
+        };
+        for range in digest(&primary, "2").into_ranges() {
+            copy_range(&primary, &standby, "2", &range);
+        }
+        copy_range(
+            &primary,
+            &standby,
+            "1",
+            &digest(&primary, "1").into_ranges()[0],
+        );
+
+        let (keys, standby_keys) = (
+            primary.digest_queue("test".into()).unwrap().unwrap(),
+            standby.digest_queue("test".into()).unwrap().unwrap(),
+        );
+        assert_eq!(keys.get("2"), standby_keys.get("2"));
+        assert_ne!(keys.get("1"), standby_keys.get("1"));
+        assert_eq!(standby_keys["1"].head, 2);
+
+        let standby_ranges = digest(&standby, "1").into_ranges();
+        let diverged: Vec<_> = digest(&primary, "1")
+            .into_ranges()
+            .into_iter()
+            .filter(|r| !standby_ranges.contains(r))
+            .collect();
+        assert_eq!(
+            diverged.iter().map(|r| r.from).collect::<Vec<_>>(),
+            vec![3, 5]
+        );
+        for range in &diverged {
+            copy_range(&primary, &standby, "1", range);
         }
+
+        assert_eq!(
+            primary.digest_queue("test".into()).unwrap(),
+            standby.digest_queue("test".into()).unwrap()
+        );
+    }
+
+    fn deduplicated(queue: &Queue<EventMessage>, queue_name: &str, dedup_id: &str) -> usize {
+        queue
+            .send_deduplicated(queue_name.into(), event("1"), dedup_id)
+            .unwrap()
+            .unwrap()
+            .len()
+    }
+
+    #[test]
+    fn retried_publishes_are_sent_once() {
+        let queue = queue(json!({}));
+        queue.create_queue("test".into()).unwrap();
+        queue.create_queue("other".into()).unwrap();
+
+        assert_eq!(deduplicated(&queue, "test", "a"), 1);
+        assert_eq!(deduplicated(&queue, "test", "a"), 0);
+        assert_eq!(deduplicated(&queue, "test", "b"), 1);
+        assert_eq!(deduplicated(&queue, "other", "a"), 1);
+        assert_eq!(count(&queue, "1"), Some(2));
+    }
+
+    #[test]
+    fn dedup_windows_expire_per_queue() {
+        let clock = ManualClock::default();
+        let options = json!({ "dedup_window": 60, "dedup_windows": { "test": 10 } });
+        let queue = queue(options).with_clock(clock.clone());
+        queue.create_queue("test".into()).unwrap();
+        queue.create_queue("other".into()).unwrap();
+        assert_eq!(deduplicated(&queue, "test", "a"), 1);
+        assert_eq!(deduplicated(&queue, "other", "a"), 1);
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(deduplicated(&queue, "test", "a"), 0);
+        assert_eq!(deduplicated(&queue, "other", "a"), 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(deduplicated(&queue, "test", "a"), 1);
+        assert_eq!(deduplicated(&queue, "other", "a"), 0);
+        assert_eq!(queue.stats.snapshot().duplicates, 3);
+
+        clock.advance(Duration::from_secs(49));
+        assert_eq!(queue.drop_expired_dedup_ids().unwrap(), 1);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(queue.drop_expired_dedup_ids().unwrap(), 1);
+    }
+
+    #[test]
+    fn timestamps_are_ordered_when_wall_clock_goes_back() {
+        let start = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let clock = ManualClock::new(start);
+        let queue = queue(json!({})).with_clock(clock.clone());
+        queue.create_queue("test".into()).unwrap();
+        send(&queue, &["1"]);
+        clock.set(start - Duration::from_secs(1));
+        send(&queue, &["1"]);
+        clock.set(start + Duration::from_secs(1));
+        send(&queue, &["1"]);
+        // replicated events keep their timestamps and move the clock forward
+        let replicated = json!({
+            "id": "1",
+            "sequence": 4,
+            "timestamp": { "wall": 1_030_000, "logical": 2 },
+            "payload": {}
+        });
+        let replicated = serde_json::from_value(replicated).unwrap();
+        queue.publish("test".into(), replicated).unwrap();
+        send(&queue, &["1"]);
+        let ahead = json!({
+            "id": "1",
+            "timestamp": { "wall": 1_062_000, "logical": 0 },
+            "payload": {}
+        });
+        let ahead = serde_json::from_value(ahead).unwrap();
+        assert!(queue.publish("test".into(), ahead).is_err());
+
+        let timestamps: Vec<_> = queue
+            .replay_key("test".into(), "1".into(), None, None, 10)
+            .unwrap()
+            .unwrap()
+            .events
+            .iter()
+            .filter_map(|e| e.get_timestamp())
+            .map(|t| (t.wall, t.logical))
+            .collect();
+        assert_eq!(
+            timestamps,
+            vec![
+                (1_000_000, 0),
+                (1_000_000, 1),
+                (1_001_000, 0),
+                (1_030_000, 2),
+                (1_030_000, 3)
+            ]
+        );
     }
 }
@@ -1,2 +1,40 @@
+pub mod admission;
+pub mod blob;
+pub mod cache;
+pub mod channel;
+pub mod clock;
 pub mod connection;
+pub mod databases;
+pub mod digest;
+pub mod disk;
+pub mod envelope;
+pub mod executor;
+pub mod failover;
+pub mod filter;
+pub mod flight;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+pub mod interceptor;
+pub mod journal;
+pub mod lease;
+pub mod lifecycle;
 pub mod map;
+pub mod ndjson;
+pub mod ordering;
+pub mod poll;
+pub mod quarantine;
+pub mod reaper;
+pub mod receipt;
+pub mod route;
+pub mod schema;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod segment;
+pub mod session;
+pub mod sink;
+pub mod socketio;
+#[cfg(feature = "postgres")]
+pub mod source;
+pub mod stats;
+pub mod subscribers;
+pub mod trace;
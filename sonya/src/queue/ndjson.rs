@@ -0,0 +1,195 @@
+use crate::queue::admission::RetryHint;
+use crate::queue::connection::BroadcastMessage;
+use crate::queue::subscribers::SubscriberHandle;
+use actix_web::web::Bytes;
+use actix_web::Error;
+use futures::stream::{self, BoxStream};
+use futures::{FutureExt, Stream, StreamExt};
+use serde::Serialize;
+use sonya_meta::api::JwtSession;
+use sonya_meta::encoding::NDJSON_CONTENT_TYPE;
+use sonya_meta::limit::ConnectionGuard;
+use sonya_meta::message::ControlMessage;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info, warn, Span};
+
+/// Streaming http subscription, every event and control event is one json line.
+/// Terminal control events are the last lines of the response.
+pub struct NdjsonConnection<T> {
+    queue: BoxStream<'static, BroadcastMessage<T>>,
+    heartbeat_interval: Option<Duration>,
+    max_skipped_messages: Option<u64>,
+    connection_guard: ConnectionGuard,
+    jwt_session: Option<JwtSession>,
+    retry_hint: Option<RetryHint>,
+    subscriber: Option<SubscriberHandle>,
+    span: Span,
+}
+
+enum Tick<T> {
+    Message(BroadcastMessage<T>),
+    Heartbeat,
+    /// Subscription stream has ended
+    End,
+    Disconnected(Option<String>),
+    Expired,
+}
+
+impl<T: 'static + Send + Serialize> NdjsonConnection<T> {
+    pub fn new(
+        queue: BoxStream<'static, BroadcastMessage<T>>,
+        heartbeat_interval: Option<Duration>,
+        max_skipped_messages: Option<u64>,
+        connection_guard: ConnectionGuard,
+        jwt_session: Option<JwtSession>,
+        span: Span,
+    ) -> Self {
+        Self {
+            queue,
+            heartbeat_interval,
+            max_skipped_messages,
+            connection_guard,
+            jwt_session,
+            retry_hint: None,
+            subscriber: None,
+            span,
+        }
+    }
+
+    /// Suggests clients a jittered delay before reconnecting when the server is draining
+    pub fn with_retry_hint(mut self, retry_hint: Option<RetryHint>) -> Self {
+        self.retry_hint = retry_hint;
+        self
+    }
+
+    /// Lists the subscription with client id and labels while it's streamed
+    pub fn with_subscriber(mut self, subscriber: Option<SubscriberHandle>) -> Self {
+        self.subscriber = subscriber;
+        self
+    }
+
+    /// Body of the streaming response
+    pub fn into_stream(self) -> impl Stream<Item = Result<Bytes, Error>> {
+        let Self {
+            queue,
+            heartbeat_interval,
+            max_skipped_messages,
+            connection_guard,
+            jwt_session,
+            retry_hint,
+            subscriber,
+            span,
+        } = self;
+
+        let heartbeats = match heartbeat_interval {
+            Some(interval) => stream::unfold((), move |_| async move {
+                actix_web::rt::time::sleep(interval).await;
+                Some((Tick::Heartbeat, ()))
+            })
+            .boxed(),
+            None => stream::pending().boxed(),
+        };
+        let disconnects = match &subscriber {
+            Some(subscriber) => subscriber
+                .disconnected()
+                .map(Tick::Disconnected)
+                .into_stream()
+                .boxed(),
+            None => stream::pending().boxed(),
+        };
+        // jwt sessions of key subscriptions can't be refreshed without a client channel
+        let expirations = match jwt_session.map(|s| s.expiration) {
+            Some(expiration) => {
+                let left = expiration
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default();
+                actix_web::rt::time::sleep(left)
+                    .map(|_| Tick::Expired)
+                    .into_stream()
+                    .boxed()
+            }
+            None => stream::pending().boxed(),
+        };
+        let mut ticks = stream::select(
+            queue
+                .map(Tick::Message)
+                .chain(stream::once(futures::future::ready(Tick::End))),
+            stream::select(heartbeats, stream::select(disconnects, expirations)),
+        );
+
+        async_stream::stream! {
+            // the connection is counted by limits and listed until the response is dropped
+            let _connection_guard = connection_guard;
+            let mut last_sent = Instant::now();
+            let mut skipped_messages = 0;
+            info!(parent: &span, "created ndjson stream");
+
+            while let Some(tick) = ticks.next().await {
+                let message = match tick {
+                    Tick::Message(message) => message,
+                    // heartbeats are sent only after the whole idle interval
+                    Tick::Heartbeat => match heartbeat_interval {
+                        Some(interval) if last_sent.elapsed() >= interval => {
+                            BroadcastMessage::Heartbeat
+                        }
+                        _ => continue,
+                    },
+                    Tick::End => break,
+                    Tick::Disconnected(reason) => {
+                        info!(parent: &span, reason = ?reason, "disconnected by admin");
+                        break;
+                    }
+                    Tick::Expired => {
+                        info!(parent: &span, "jwt session expired");
+                        break;
+                    }
+                };
+
+                let line = match &message {
+                    BroadcastMessage::Message(m) => serde_json::to_vec(m),
+                    BroadcastMessage::Draining => serde_json::to_vec(&ControlMessage::Draining {
+                        retry_after: retry_hint.map(|hint| hint.jittered()),
+                    }),
+                    control => serde_json::to_vec(&control.control()),
+                };
+                match line {
+                    Ok(mut line) => {
+                        line.push(b'\n');
+                        yield Ok(Bytes::from(line));
+                        last_sent = Instant::now();
+                    }
+                    Err(err) => {
+                        error!(parent: &span, error = %err, "serialization error");
+                        break;
+                    }
+                }
+
+                match message {
+                    BroadcastMessage::Lagged(skipped) => {
+                        skipped_messages += skipped;
+                        if let Some(subscriber) = &subscriber {
+                            subscriber.add_skipped(skipped);
+                        }
+                        warn!(
+                            parent: &span,
+                            skipped,
+                            total_skipped = skipped_messages,
+                            "slow consumer"
+                        );
+                        if max_skipped_messages.map_or(false, |max| skipped_messages > max) {
+                            break;
+                        }
+                    }
+                    BroadcastMessage::QueueClosed
+                    | BroadcastMessage::KeyDeleted
+                    | BroadcastMessage::Draining
+                    | BroadcastMessage::Moved(_)
+                    | BroadcastMessage::PreloadFailed => break,
+                    _ => {}
+                }
+            }
+
+            info!(parent: &span, "closed ndjson stream");
+        }
+    }
+}
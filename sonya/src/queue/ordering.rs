@@ -0,0 +1,32 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+
+/// Count of lock stripes, keys of different stripes are written in parallel
+const KEY_LOCK_STRIPES: usize = 256;
+
+/// Serializes writes of keys of strict queues.
+/// Keys are spread over a fixed set of locks, so keys sharing a lock wait for each other,
+/// but the memory doesn't grow with the count of keys.
+#[derive(Debug)]
+pub struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+}
+
+impl Default for KeyLocks {
+    fn default() -> Self {
+        Self {
+            stripes: (0..KEY_LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+        }
+    }
+}
+
+impl KeyLocks {
+    /// Locks the key until the guard is dropped
+    pub fn lock(&self, queue_name: &str, key: &str) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        (queue_name, key).hash(&mut hasher);
+        let stripe = hasher.finish() as usize % self.stripes.len();
+        self.stripes[stripe].lock().unwrap()
+    }
+}
@@ -0,0 +1,198 @@
+use crate::queue::connection::BroadcastMessage;
+use futures::future::{abortable, AbortHandle};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use sonya_meta::config::PollSessions;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Live longpoll subscriptions kept between polls, so events published while the client
+/// is reconnecting are returned by the next poll instead of being lost
+pub struct PollSessionRegistry<T> {
+    sessions: Mutex<HashMap<String, Arc<PollSession<T>>>>,
+    /// Sessions are disabled without ttl
+    ttl: Option<Duration>,
+    max_buffered: usize,
+}
+
+pub struct PollSession<T> {
+    queue_name: String,
+    key: Option<String>,
+    buffer: Mutex<PollBuffer<T>>,
+    notify: Notify,
+    last_poll: Mutex<Instant>,
+    pump: AbortHandle,
+}
+
+struct PollBuffer<T> {
+    events: VecDeque<T>,
+    skipped: u64,
+    terminal: Option<BroadcastMessage<T>>,
+}
+
+/// Events buffered since the previous poll
+pub struct Polled<T> {
+    pub events: Vec<T>,
+    /// Events dropped because the buffer was full or the subscription lagged
+    pub skipped: u64,
+    /// Terminal event of the subscription, returned once buffered events are taken
+    pub terminal: Option<BroadcastMessage<T>>,
+}
+
+impl<T: 'static + Send> PollSessionRegistry<T> {
+    pub fn new(options: Option<&PollSessions>) -> Self {
+        Self {
+            sessions: Default::default(),
+            ttl: options.map(|o| Duration::from_secs(o.ttl)),
+            max_buffered: options.map(|o| o.max_buffered).unwrap_or_default(),
+        }
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    /// Keeps the rest of the subscription in the session, returns its token
+    /// or gives the subscription back if sessions are disabled
+    pub fn open(
+        &self,
+        queue_name: String,
+        key: Option<String>,
+        mut stream: BoxStream<'static, BroadcastMessage<T>>,
+    ) -> Result<String, BoxStream<'static, BroadcastMessage<T>>> {
+        if self.ttl.is_none() {
+            return Err(stream);
+        }
+        let token = uuid::Uuid::new_v4().to_string();
+        let max_buffered = self.max_buffered;
+        let session = Arc::new_cyclic(|session: &Weak<PollSession<T>>| {
+            // the pump holds the session weakly, so dropped sessions stop buffering
+            let session = session.clone();
+            let (pump, handle) = abortable(async move {
+                loop {
+                    let message = stream.next().await.unwrap_or(BroadcastMessage::QueueClosed);
+                    match session.upgrade() {
+                        Some(session) if session.push(message, max_buffered) => {}
+                        _ => break,
+                    }
+                }
+            });
+            actix_web::rt::spawn(pump);
+
+            PollSession {
+                queue_name,
+                key,
+                buffer: Mutex::new(PollBuffer {
+                    events: VecDeque::new(),
+                    skipped: 0,
+                    terminal: None,
+                }),
+                notify: Notify::new(),
+                last_poll: Mutex::new(Instant::now()),
+                pump: handle,
+            }
+        });
+        self.sessions.lock().unwrap().insert(token.clone(), session);
+        Ok(token)
+    }
+
+    /// Session of the token, if it's opened for the same subscription
+    pub fn get(
+        &self,
+        token: &str,
+        queue_name: &str,
+        key: Option<&str>,
+    ) -> Option<Arc<PollSession<T>>> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(token)
+            .filter(|s| s.queue_name == queue_name && s.key.as_deref() == key)
+            .cloned()
+    }
+
+    pub fn close(&self, token: &str) {
+        self.sessions.lock().unwrap().remove(token);
+    }
+
+    /// Drops sessions which weren't polled during ttl, returns count of dropped sessions
+    pub fn drop_expired(&self) -> usize {
+        let ttl = match self.ttl {
+            None => return 0,
+            Some(ttl) => ttl,
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        // sessions referenced outside of the registry are being polled right now
+        sessions
+            .retain(|_, s| Arc::strong_count(s) > 1 || s.last_poll.lock().unwrap().elapsed() < ttl);
+        before - sessions.len()
+    }
+}
+
+impl<T> PollSession<T> {
+    /// Takes buffered events, waits for the next one if there are none
+    pub async fn poll(&self) -> Polled<T> {
+        loop {
+            *self.last_poll.lock().unwrap() = Instant::now();
+            {
+                let mut buffer = self.buffer.lock().unwrap();
+                if !buffer.events.is_empty() {
+                    return Polled {
+                        events: buffer.events.drain(..).collect(),
+                        skipped: std::mem::take(&mut buffer.skipped),
+                        terminal: None,
+                    };
+                }
+                if buffer.terminal.is_some() {
+                    return Polled {
+                        events: Vec::new(),
+                        skipped: std::mem::take(&mut buffer.skipped),
+                        terminal: buffer.terminal.take(),
+                    };
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Buffers the subscription message, returns false after terminal events
+    fn push(&self, message: BroadcastMessage<T>, max_buffered: usize) -> bool {
+        let mut buffer = self.buffer.lock().unwrap();
+        let live = match message {
+            BroadcastMessage::Message(event) => {
+                if buffer.events.len() >= max_buffered {
+                    buffer.events.pop_front();
+                    buffer.skipped += 1;
+                }
+                buffer.events.push_back(event);
+                true
+            }
+            BroadcastMessage::Lagged(skipped) => {
+                buffer.skipped += skipped;
+                true
+            }
+            terminal @ (BroadcastMessage::QueueClosed
+            | BroadcastMessage::KeyDeleted
+            | BroadcastMessage::Draining
+            | BroadcastMessage::Moved(_)
+            | BroadcastMessage::PreloadFailed) => {
+                buffer.terminal = Some(terminal);
+                false
+            }
+            _ => return true,
+        };
+        drop(buffer);
+        // the permit is stored if no poll is waiting, so the next poll returns at once
+        self.notify.notify_one();
+        live
+    }
+}
+
+impl<T> Drop for PollSession<T> {
+    fn drop(&mut self) {
+        self.pump.abort();
+    }
+}
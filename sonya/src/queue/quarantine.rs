@@ -0,0 +1,116 @@
+use crate::queue::envelope::Envelopes;
+use crate::queue::map::{split_id, QueueMap, QueueResult};
+use crate::queue::segment::QueueTrees;
+use crate::queue::stats::QueueStats;
+use serde::de::DeserializeOwned;
+use sled::IVec;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::{error, warn};
+
+const QUARANTINE_PREFIX: &str = "quarantine_";
+
+/// Decoding failures after which the stored entry is moved out of its queue
+const POISON_THRESHOLD: u32 = 3;
+
+/// Decoding failures of stored entries. Failures are counted in memory,
+/// so entries which failed fewer times are retried after restarts.
+#[derive(Debug, Default)]
+pub struct Quarantine {
+    failures: Mutex<HashMap<Vec<u8>, u32>>,
+}
+
+impl Quarantine {
+    /// Counts the failure, `true` if the entry failed often enough to be quarantined
+    fn fail(&self, key: &[u8]) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        let count = failures.entry(key.to_vec()).or_default();
+        *count += 1;
+        if *count < POISON_THRESHOLD {
+            return false;
+        }
+        failures.remove(key);
+        true
+    }
+}
+
+/// Decodes stored entries of the queue for preloads. Poison entries, which repeatedly fail
+/// to decode (e.g. written by a newer version or failing upgrades), are skipped and then moved
+/// to the default tree with their original keys, so preloads continue without them.
+#[derive(Clone)]
+pub struct PoisonGuard {
+    quarantine: Arc<Quarantine>,
+    envelopes: Arc<Envelopes>,
+    map: QueueMap,
+    trees: QueueTrees,
+    queue_name: String,
+    stats: Arc<QueueStats>,
+}
+
+impl PoisonGuard {
+    pub fn new(
+        quarantine: Arc<Quarantine>,
+        envelopes: Arc<Envelopes>,
+        map: QueueMap,
+        trees: QueueTrees,
+        queue_name: String,
+        stats: Arc<QueueStats>,
+    ) -> Self {
+        Self {
+            quarantine,
+            envelopes,
+            map,
+            trees,
+            queue_name,
+            stats,
+        }
+    }
+
+    /// Decodes the entry, `None` if it can't be decoded
+    pub fn decode<T: DeserializeOwned>(&self, key: &[u8], value: &[u8]) -> Option<T> {
+        let e = match self.envelopes.decode(value) {
+            Ok(value) => return Some(value),
+            Err(e) => e,
+        };
+
+        error!(queue = %self.queue_name, error = %e, "preload decoding error");
+        let quarantine_key = get_quarantine_key(self.queue_name.as_bytes(), key);
+        if self.quarantine.fail(&quarantine_key) {
+            if let Err(e) = self.isolate(quarantine_key, key, value) {
+                error!(queue = %self.queue_name, error = %e, "quarantine error");
+            }
+        }
+        None
+    }
+
+    fn isolate(&self, quarantine_key: Vec<u8>, key: &[u8], value: &[u8]) -> QueueResult<()> {
+        self.map.insert(quarantine_key, value)?;
+        self.trees.remove_all(&[IVec::from(key)])?;
+        self.stats.add_quarantined();
+
+        let (id, sequence) = split_id(key).unwrap_or((key, 0));
+        warn!(
+            queue = %self.queue_name,
+            key = %String::from_utf8_lossy(id),
+            sequence,
+            "quarantined poison entry"
+        );
+        Ok(())
+    }
+}
+
+/// Quarantined entries are stored in the default tree, keyed by the queue and the entry key
+pub fn get_quarantine_prefix(queue_name: &[u8]) -> Vec<u8> {
+    let mut key = Vec::from(QUARANTINE_PREFIX);
+    key.extend_from_slice(queue_name);
+    key.push(0);
+
+    key
+}
+
+fn get_quarantine_key(queue_name: &[u8], entry: &[u8]) -> Vec<u8> {
+    let mut key = get_quarantine_prefix(queue_name);
+    key.extend_from_slice(entry);
+
+    key
+}
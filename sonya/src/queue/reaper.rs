@@ -0,0 +1,27 @@
+use crate::queue::stats::QueueStats;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Detects subscriptions whose peers vanished without a close frame, e.g. half-open TCP
+/// connections of crashed clients or dropped networks. Reaped connections free their
+/// broadcast receivers, connection limits and subscriber list entries.
+#[derive(Debug, Clone)]
+pub struct Reaper {
+    timeout: Duration,
+    stats: Arc<QueueStats>,
+}
+
+impl Reaper {
+    pub fn new(timeout: Duration, stats: Arc<QueueStats>) -> Self {
+        Self { timeout, stats }
+    }
+
+    /// Returns `true` and counts the reap if the peer sent nothing for longer than the timeout
+    pub fn reap(&self, last_received: Instant) -> bool {
+        if last_received.elapsed() <= self.timeout {
+            return false;
+        }
+        self.stats.add_reaped();
+        true
+    }
+}
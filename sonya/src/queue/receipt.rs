@@ -0,0 +1,58 @@
+use crate::queue::filter::SubscriberInfo;
+use crate::queue::interceptor::DeliveryInterceptor;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use serde::Serialize;
+use sonya_meta::message::{EventMessage, SequenceId};
+
+/// Delivery of the event which publisher asked to confirm
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryReceipt {
+    pub queue: String,
+    pub id: String,
+    pub sequence: SequenceId,
+    /// The subscriber acknowledged processing of the event
+    pub acked: bool,
+    #[serde(skip)]
+    pub reply_to: String,
+}
+
+impl DeliveryReceipt {
+    /// Receipt event published to the reply queue with the key of the confirmed event
+    pub fn to_event(&self) -> EventMessage {
+        EventMessage::new(
+            self.id.clone(),
+            serde_json::to_value(self).expect("receipts are serializable"),
+        )
+    }
+}
+
+/// Reports deliveries of events with `reply_to` to subscriptions.
+/// Must be registered after filters, so hidden events are not confirmed.
+#[derive(Debug, Clone)]
+pub struct ReceiptInterceptor {
+    sender: UnboundedSender<DeliveryReceipt>,
+}
+
+impl ReceiptInterceptor {
+    pub fn new() -> (Self, UnboundedReceiver<DeliveryReceipt>) {
+        let (sender, receiver) = unbounded();
+        (Self { sender }, receiver)
+    }
+}
+
+impl DeliveryInterceptor<EventMessage> for ReceiptInterceptor {
+    fn on_deliver(&self, subscriber: &SubscriberInfo, event: EventMessage) -> Option<EventMessage> {
+        if let (true, Some(reply_to), Some(sequence)) =
+            (subscriber.subscription, &event.reply_to, event.sequence)
+        {
+            let _ = self.sender.unbounded_send(DeliveryReceipt {
+                queue: subscriber.queue_name.clone(),
+                id: event.id.clone(),
+                sequence,
+                acked: false,
+                reply_to: reply_to.clone(),
+            });
+        }
+        Some(event)
+    }
+}
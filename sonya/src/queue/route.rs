@@ -0,0 +1,72 @@
+use serde_json::Value;
+use sonya_meta::config::Route;
+use sonya_meta::message::EventMessage;
+use std::fmt::Debug;
+
+/// Copies persisted events to other queues.
+/// Routed events are stored with new sequences and are not routed again.
+pub trait Router<T>: Debug + Send + Sync {
+    fn route(&self, queue_name: &str, event: &T) -> Vec<(String, T)>;
+}
+
+/// Declarative routing rules from the queue config
+#[derive(Debug, Clone)]
+pub struct RouteRules {
+    routes: Vec<Route>,
+}
+
+impl RouteRules {
+    pub fn new(routes: Vec<Route>) -> Self {
+        Self { routes }
+    }
+}
+
+impl Router<EventMessage> for RouteRules {
+    fn route(&self, queue_name: &str, event: &EventMessage) -> Vec<(String, EventMessage)> {
+        if event.tombstone {
+            return vec![];
+        }
+
+        self.routes
+            .iter()
+            .filter(|r| r.from == queue_name && is_matched(r, &event.payload))
+            .flat_map(|r| {
+                let id = r
+                    .key_field
+                    .as_ref()
+                    .and_then(|f| event.payload.get(f))
+                    .map(key_to_string)
+                    .unwrap_or_else(|| event.id.clone());
+
+                r.to.iter().map(move |to| {
+                    let routed = EventMessage {
+                        id: id.clone(),
+                        sequence: None,
+                        payload: event.payload.clone(),
+                        tombstone: false,
+                        reply_to: None,
+                        supersedes: None,
+                        ephemeral: event.ephemeral,
+                        // routed events observe the timestamp published with the source event
+                        timestamp: event.timestamp,
+                    };
+                    (to.clone(), routed)
+                })
+            })
+            .collect()
+    }
+}
+
+fn is_matched(route: &Route, payload: &Value) -> bool {
+    route
+        .when
+        .iter()
+        .all(|(field, value)| payload.get(field) == Some(value))
+}
+
+pub(crate) fn key_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
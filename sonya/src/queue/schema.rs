@@ -0,0 +1,95 @@
+use crate::queue::interceptor::{InterceptorError, PublishInterceptor};
+use serde_json::Value;
+use sonya_meta::config::{FieldType, Schema};
+use sonya_meta::message::EventMessage;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// Validates event payloads of one queue
+pub trait EventSchema: Debug + Send + Sync {
+    fn validate(&self, payload: &Value) -> Result<(), String>;
+}
+
+/// Registry of per queue event schemas, so queues of one server may carry different events.
+/// Events of queues without a schema are accepted as is.
+#[derive(Debug, Clone, Default)]
+pub struct SchemaRegistry {
+    schemas: HashMap<String, Arc<dyn EventSchema>>,
+}
+
+impl SchemaRegistry {
+    pub fn new(schemas: HashMap<String, Schema>) -> Self {
+        schemas
+            .into_iter()
+            .fold(Self::default(), |registry, (queue_name, schema)| {
+                registry.with_schema(queue_name, FieldsSchema(schema))
+            })
+    }
+
+    pub fn with_schema(
+        mut self,
+        queue_name: impl Into<String>,
+        schema: impl EventSchema + 'static,
+    ) -> Self {
+        self.schemas.insert(queue_name.into(), Arc::new(schema));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schemas.is_empty()
+    }
+}
+
+impl PublishInterceptor<EventMessage> for SchemaRegistry {
+    fn on_publish(
+        &self,
+        queue_name: &str,
+        event: EventMessage,
+    ) -> Result<Vec<EventMessage>, InterceptorError> {
+        if let Some(schema) = self.schemas.get(queue_name) {
+            schema
+                .validate(&event.payload)
+                .map_err(|e| InterceptorError(format!("invalid {} event, {}", queue_name, e)))?;
+        }
+        Ok(vec![event])
+    }
+}
+
+/// Checks payload object fields with the configured schema.
+/// Optional fields may be null.
+#[derive(Debug)]
+pub struct FieldsSchema(pub Schema);
+
+impl EventSchema for FieldsSchema {
+    fn validate(&self, payload: &Value) -> Result<(), String> {
+        let payload = payload.as_object().ok_or("payload is not an object")?;
+
+        if let Some(field) = self.0.required.iter().find(|f| !payload.contains_key(*f)) {
+            return Err(format!("missing {} field", field));
+        }
+
+        for (field, field_type) in &self.0.fields {
+            match payload.get(field) {
+                Some(Value::Null) if !self.0.required.contains(field) => {}
+                Some(value) if !is_type(value, *field_type) => {
+                    return Err(format!("{} field is not {:?}", field, field_type))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn is_type(value: &Value, field_type: FieldType) -> bool {
+    match field_type {
+        FieldType::String => value.is_string(),
+        FieldType::Number => value.is_number(),
+        FieldType::Integer => value.is_i64() || value.is_u64(),
+        FieldType::Boolean => value.is_boolean(),
+        FieldType::Object => value.is_object(),
+        FieldType::Array => value.is_array(),
+    }
+}
@@ -0,0 +1,81 @@
+use crate::queue::interceptor::{InterceptorError, PublishInterceptor};
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{Dynamic, Engine, Scope, AST};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sonya_meta::config::Scripts;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+
+const TRANSFORM_FN: &str = "transform";
+
+/// Runs per queue Rhai scripts on published events.
+///
+/// Scripts must define `fn transform(event)` which returns the changed event,
+/// an array of events to split the event or `()` to drop it.
+pub struct ScriptInterceptor {
+    engine: Engine,
+    scripts: HashMap<String, AST>,
+}
+
+impl ScriptInterceptor {
+    pub fn new(config: Scripts) -> Result<Self, Box<rhai::EvalAltResult>> {
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(config.max_operations)
+            .set_max_call_levels(config.max_call_levels)
+            .set_max_string_size(config.max_string_size)
+            .set_max_array_size(config.max_array_size)
+            .set_max_map_size(config.max_map_size);
+
+        let scripts = config
+            .queues
+            .into_iter()
+            .map(|(queue, path)| engine.compile_file(path).map(|ast| (queue, ast)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { engine, scripts })
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> PublishInterceptor<T> for ScriptInterceptor {
+    fn on_publish(&self, queue_name: &str, event: T) -> Result<Vec<T>, InterceptorError> {
+        let ast = match self.scripts.get(queue_name) {
+            None => return Ok(vec![event]),
+            Some(ast) => ast,
+        };
+
+        let event = to_dynamic(event).map_err(script_error)?;
+        let result: Dynamic = self
+            .engine
+            .call_fn(&mut Scope::new(), ast, TRANSFORM_FN, (event,))
+            .map_err(script_error)?;
+
+        if result.is_unit() {
+            return Ok(vec![]);
+        }
+
+        if result.is_array() {
+            return result
+                .into_array()
+                .map_err(|t| InterceptorError(format!("invalid script result: {}", t)))?
+                .into_iter()
+                .map(|v| from_dynamic(&v).map_err(script_error))
+                .collect();
+        }
+
+        from_dynamic(&result).map(|v| vec![v]).map_err(script_error)
+    }
+}
+
+impl Debug for ScriptInterceptor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScriptInterceptor")
+            .field("queues", &self.scripts.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+fn script_error(e: Box<rhai::EvalAltResult>) -> InterceptorError {
+    InterceptorError(format!("script error: {}", e))
+}
@@ -0,0 +1,116 @@
+use sled::{Batch, IVec, Tree};
+use std::convert::TryInto;
+use std::ops::RangeBounds;
+use std::time::{Duration, SystemTime};
+
+/// Segment trees start with the zero byte, so they never clash with queue names
+const SEGMENT_PREFIX: &[u8] = b"\0seg\0";
+/// Offset index trees map publish offsets of queue events to their storage keys
+const OFFSETS_PREFIX: &[u8] = b"\0off\0";
+
+/// Trees of one queue ordered from the oldest to the newest.
+/// The queue tree itself goes first, it keeps events stored before segmentation was enabled.
+#[derive(Clone)]
+pub struct QueueTrees {
+    trees: Vec<Tree>,
+}
+
+impl QueueTrees {
+    pub fn new(trees: Vec<Tree>) -> Self {
+        Self { trees }
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = sled::Result<(IVec, IVec)>> {
+        self.trees.clone().into_iter().flat_map(|t| t.iter())
+    }
+
+    pub fn scan_prefix(
+        &self,
+        prefix: &[u8],
+    ) -> impl DoubleEndedIterator<Item = sled::Result<(IVec, IVec)>> {
+        let prefix = prefix.to_vec();
+        self.trees
+            .clone()
+            .into_iter()
+            .flat_map(move |t| t.scan_prefix(&prefix))
+    }
+
+    pub fn range<R>(&self, range: R) -> impl DoubleEndedIterator<Item = sled::Result<(IVec, IVec)>>
+    where
+        R: RangeBounds<Vec<u8>> + Clone,
+    {
+        self.trees
+            .clone()
+            .into_iter()
+            .flat_map(move |t| t.range(range.clone()))
+    }
+
+    /// Returns the value of the key from the tree which stores it
+    pub fn get(&self, key: &[u8]) -> sled::Result<Option<IVec>> {
+        for tree in self.trees.iter().rev() {
+            if let Some(value) = tree.get(key)? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Removes keys from every tree, keys are unique across segments
+    pub fn remove_all(&self, keys: &[IVec]) -> sled::Result<()> {
+        self.trees.iter().try_for_each(|t| {
+            let mut batch = Batch::default();
+            keys.iter().for_each(|k| batch.remove(k));
+            t.apply_batch(batch)
+        })
+    }
+}
+
+/// Returns name of the segment tree which stores events written at `now`
+pub fn segment_tree_name(queue_name: &[u8], duration: Duration, now: SystemTime) -> Vec<u8> {
+    let now = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let duration = duration.as_secs().max(1);
+
+    let mut name = get_segment_prefix(queue_name);
+    name.extend_from_slice(&(now - now % duration).to_be_bytes());
+
+    name
+}
+
+pub fn get_segment_prefix(queue_name: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::from(SEGMENT_PREFIX);
+    prefix.extend_from_slice(queue_name);
+    prefix.push(0);
+
+    prefix
+}
+
+/// Splits segment tree name to the queue name and the segment start in seconds
+pub fn split_segment_name(name: &[u8]) -> Option<(&[u8], u64)> {
+    let name = name.strip_prefix(SEGMENT_PREFIX)?;
+    let split = name.len().checked_sub(9)?;
+    let (queue_name, start) = name.split_at(split);
+
+    Some((queue_name, u64::from_be_bytes(start[1..].try_into().ok()?)))
+}
+
+pub fn offsets_tree_name(queue_name: &[u8]) -> Vec<u8> {
+    let mut name = Vec::from(OFFSETS_PREFIX);
+    name.extend_from_slice(queue_name);
+
+    name
+}
+
+pub fn is_offsets_tree(name: &[u8]) -> bool {
+    name.starts_with(OFFSETS_PREFIX)
+}
+
+/// Returns the queue name of the queue, segment or offsets tree
+pub fn tree_queue_name(name: &[u8]) -> &[u8] {
+    match split_segment_name(name) {
+        Some((queue_name, _)) => queue_name,
+        None => name.strip_prefix(OFFSETS_PREFIX).unwrap_or(name),
+    }
+}
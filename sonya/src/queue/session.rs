@@ -0,0 +1,66 @@
+use crate::queue::map::{QueueMap, QueueResult};
+use sonya_meta::message::{RequestSequence, RequestSequenceId, SequenceId};
+use std::convert::TryInto;
+use std::time::Duration;
+
+/// How often connections store delivered sequences of their sessions
+pub const CURSOR_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Last sequence of the key delivered to the resumable session.
+/// Cursors are stored next to events, so sessions continue where they stopped
+/// after reconnects and server restarts.
+#[derive(Debug)]
+pub struct SessionCursor {
+    map: QueueMap,
+    key: Vec<u8>,
+    delivered: Option<SequenceId>,
+    saved: Option<SequenceId>,
+}
+
+impl SessionCursor {
+    pub(crate) fn load(map: QueueMap, key: Vec<u8>) -> QueueResult<Self> {
+        let saved = map
+            .get(&key)?
+            .and_then(|v| SequenceId::new(u64::from_be_bytes(v.as_ref().try_into().ok()?)));
+
+        Ok(Self {
+            map,
+            key,
+            delivered: saved,
+            saved,
+        })
+    }
+
+    /// Sequence to resume the session from, `None` if nothing was delivered yet
+    pub fn resume_sequence(&self) -> RequestSequence {
+        self.saved
+            .and_then(|s| s.checked_add(1))
+            .map(RequestSequenceId::Id)
+    }
+
+    pub fn deliver(&mut self, sequence: SequenceId) {
+        if self.delivered < Some(sequence) {
+            self.delivered = Some(sequence);
+        }
+    }
+
+    /// Stores the delivered sequence if it moved. Concurrent connections of the session
+    /// never move the stored cursor back.
+    pub fn save(&mut self) -> QueueResult<()> {
+        let delivered = match self.delivered {
+            Some(delivered) if self.saved != Some(delivered) => delivered,
+            _ => return Ok(()),
+        };
+
+        self.map.fetch_and_update(&self.key, |v| {
+            let stored = v
+                .and_then(|v| v.try_into().ok())
+                .map(u64::from_be_bytes)
+                .unwrap_or_default();
+            Some(stored.max(delivered.get()).to_be_bytes().to_vec())
+        })?;
+        self.saved = Some(delivered);
+
+        Ok(())
+    }
+}
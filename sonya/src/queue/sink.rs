@@ -0,0 +1,334 @@
+use crate::queue::executor::{StorageError, StorageExecutor};
+use crate::queue::failover::Failover;
+use crate::queue::map::{OffsetBatch, Queue, QueueError};
+use actix_web::error::BlockingError;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::http::StatusCode;
+use actix_web::web;
+use awc::error::{JsonPayloadError, SendRequestError};
+use derive_more::{Display, From};
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use serde::Deserialize;
+use serde_json::json;
+use sonya_meta::config::{Sink, SinkTarget};
+use sonya_meta::encoding::NDJSON_CONTENT_TYPE;
+use sonya_meta::message::{EventMessage, SequenceId};
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Limit of bulk responses, they list results of every written document
+const BULK_PAYLOAD_LIMIT: usize = 64 * 1024 * 1024;
+
+const SINK_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// External system which receives stored events of the queue in batches.
+/// Writes must be idempotent: the batch is written again after failures and restarts
+/// until its offset is checkpointed, so every event is applied exactly once.
+pub trait SinkWriter {
+    /// Writes events stored after the checkpoint offset
+    fn write<'a>(
+        &'a self,
+        queue_name: &'a str,
+        checkpoint: u64,
+        events: &'a [EventMessage],
+    ) -> LocalBoxFuture<'a, Result<(), SinkError>>;
+}
+
+/// Creates the writer of the configured target
+pub fn sink_writer(target: SinkTarget) -> Result<Box<dyn SinkWriter>, SinkError> {
+    match target {
+        #[cfg(feature = "postgres")]
+        SinkTarget::Postgres { url, table } => Ok(Box::new(PostgresSink::new(url, table))),
+        #[cfg(not(feature = "postgres"))]
+        SinkTarget::Postgres { .. } => Err(SinkError::Target(
+            "postgres sinks require the postgres feature".into(),
+        )),
+        SinkTarget::Elasticsearch {
+            url,
+            index,
+            authorization,
+        } => Ok(Box::new(ElasticsearchSink::new(url, index, authorization))),
+        SinkTarget::Files { path } => Ok(Box::new(FilesSink::new(path)?)),
+    }
+}
+
+/// Pushes events of the queue to the sink until the server stops. Standby servers don't push,
+/// checkpoints are local, so promoted standbys push the replicated history from the start.
+pub async fn run_sink(
+    queue: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    failover: web::Data<Failover>,
+    sink: Sink,
+    writer: Box<dyn SinkWriter>,
+) {
+    let interval = Duration::from_secs(sink.interval);
+    info!(sink = %sink.name, queue = %sink.queue, "sink started");
+
+    loop {
+        if failover.is_standby() {
+            actix_web::rt::time::sleep(interval).await;
+            continue;
+        }
+
+        match push_batch(&queue, &executor, &sink, writer.as_ref()).await {
+            // the next batch is read right away while the sink is catching up
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => warn!(sink = %sink.name, error = %e, "sink write error"),
+        }
+        actix_web::rt::time::sleep(interval).await;
+    }
+}
+
+/// Writes events after the checkpoint and moves it, returns `false` if there were no events
+async fn push_batch(
+    queue: &web::Data<Queue<EventMessage>>,
+    executor: &web::Data<StorageExecutor>,
+    sink: &Sink,
+    writer: &dyn SinkWriter,
+) -> Result<bool, SinkError> {
+    let (checkpoint, batch) = executor
+        .run({
+            let (queue, sink) = (queue.clone(), sink.clone());
+            move || -> Result<(u64, OffsetBatch<EventMessage>), QueueError> {
+                let checkpoint = queue.sink_checkpoint(&sink.name)?;
+                let batch = queue.read_offsets(&sink.queue, checkpoint, sink.batch_size)?;
+                Ok((checkpoint, batch))
+            }
+        })
+        .await??;
+    let last = match batch.last {
+        None => return Ok(false),
+        Some(last) => last,
+    };
+
+    if !batch.events.is_empty() {
+        writer.write(&sink.queue, checkpoint, &batch.events).await?;
+    }
+
+    executor
+        .run({
+            let (queue, name) = (queue.clone(), sink.name.clone());
+            move || queue.save_sink_checkpoint(&name, last)
+        })
+        .await??;
+
+    Ok(true)
+}
+
+#[derive(Debug, Display, From)]
+pub enum SinkError {
+    Storage(StorageError),
+    Queue(QueueError),
+    Request(SendRequestError),
+    Payload(JsonPayloadError),
+    Encode(serde_json::Error),
+    Io(std::io::Error),
+    Blocking(BlockingError),
+    #[display(fmt = "sink responded with {}", _0)]
+    Status(StatusCode),
+    #[display(fmt = "sink rejected documents")]
+    #[from(ignore)]
+    Rejected,
+    #[from(ignore)]
+    Target(String),
+}
+
+/// Indexes events as documents with `{key}:{sequence}` ids, so rewritten events replace
+/// their documents
+pub struct ElasticsearchSink {
+    client: awc::Client,
+    url: String,
+    index: String,
+    authorization: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BulkResponse {
+    errors: bool,
+}
+
+impl ElasticsearchSink {
+    pub fn new(url: String, index: String, authorization: Option<String>) -> Self {
+        Self {
+            client: awc::Client::builder()
+                .timeout(SINK_REQUEST_TIMEOUT)
+                .finish(),
+            url: format!("{}/_bulk", url.trim_end_matches('/')),
+            index,
+            authorization,
+        }
+    }
+}
+
+impl SinkWriter for ElasticsearchSink {
+    fn write<'a>(
+        &'a self,
+        _queue_name: &'a str,
+        _checkpoint: u64,
+        events: &'a [EventMessage],
+    ) -> LocalBoxFuture<'a, Result<(), SinkError>> {
+        async move {
+            let mut body = Vec::new();
+            for event in events {
+                let id = format!("{}:{}", event.id, event.sequence.map_or(0, SequenceId::get));
+                let action = json!({ "index": { "_index": self.index, "_id": id } });
+                serde_json::to_writer(&mut body, &action)?;
+                body.push(b'\n');
+                serde_json::to_writer(&mut body, event)?;
+                body.push(b'\n');
+            }
+
+            let mut request = self
+                .client
+                .post(self.url.as_str())
+                .content_type(NDJSON_CONTENT_TYPE);
+            if let Some(authorization) = &self.authorization {
+                request = request.insert_header((AUTHORIZATION, authorization.as_str()));
+            }
+            let mut response = request.send_body(body).await?;
+            if !response.status().is_success() {
+                return Err(SinkError::Status(response.status()));
+            }
+            let bulk: BulkResponse = response.json().limit(BULK_PAYLOAD_LIMIT).await?;
+            match bulk.errors {
+                true => Err(SinkError::Rejected),
+                false => Ok(()),
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// Writes every batch as a newline-delimited JSON file named by the queue and the checkpoint,
+/// so rewritten batches replace their files. Directories may be mounted object storages.
+pub struct FilesSink {
+    path: PathBuf,
+}
+
+impl FilesSink {
+    pub fn new(path: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+}
+
+impl SinkWriter for FilesSink {
+    fn write<'a>(
+        &'a self,
+        queue_name: &'a str,
+        checkpoint: u64,
+        events: &'a [EventMessage],
+    ) -> LocalBoxFuture<'a, Result<(), SinkError>> {
+        async move {
+            let mut body = Vec::new();
+            for event in events {
+                serde_json::to_writer(&mut body, event)?;
+                body.push(b'\n');
+            }
+
+            let name = format!("{}-{:020}.ndjson", queue_name, checkpoint);
+            let (tmp, path) = (
+                self.path.join(format!("{}.tmp", name)),
+                self.path.join(name),
+            );
+            // the file becomes visible only when it is fully written
+            web::block(move || {
+                std::fs::write(&tmp, body)?;
+                std::fs::rename(tmp, path)
+            })
+            .await??;
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
+
+/// Inserts events as rows of the table with the unique `(queue, key, sequence)` constraint,
+/// rewritten rows are skipped. Tombstones delete earlier rows of their keys.
+#[cfg(feature = "postgres")]
+pub struct PostgresSink {
+    url: String,
+    table: String,
+    client: tokio::sync::Mutex<Option<tokio_postgres::Client>>,
+}
+
+#[cfg(feature = "postgres")]
+impl PostgresSink {
+    pub fn new(url: String, table: String) -> Self {
+        Self {
+            url,
+            table,
+            client: Default::default(),
+        }
+    }
+
+    async fn insert(
+        &self,
+        client: &mut tokio_postgres::Client,
+        queue_name: &str,
+        events: &[EventMessage],
+    ) -> Result<(), tokio_postgres::Error> {
+        let transaction = client.transaction().await?;
+        let insert = transaction
+            .prepare(&format!(
+                "INSERT INTO {} (queue, key, sequence, payload) VALUES ($1, $2, $3, $4) \
+                ON CONFLICT DO NOTHING",
+                self.table
+            ))
+            .await?;
+        let delete = transaction
+            .prepare(&format!(
+                "DELETE FROM {} WHERE queue = $1 AND key = $2 AND sequence < $3",
+                self.table
+            ))
+            .await?;
+
+        for event in events {
+            let key = event.id.clone();
+            let sequence = event.sequence.map_or(0, SequenceId::get) as i64;
+            let payload = &event.payload;
+            match event.tombstone {
+                true => transaction.execute(&delete, &[&queue_name, &key, &sequence]),
+                false => transaction.execute(&insert, &[&queue_name, &key, &sequence, payload]),
+            }
+            .await?;
+        }
+
+        transaction.commit().await
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl SinkWriter for PostgresSink {
+    fn write<'a>(
+        &'a self,
+        queue_name: &'a str,
+        _checkpoint: u64,
+        events: &'a [EventMessage],
+    ) -> LocalBoxFuture<'a, Result<(), SinkError>> {
+        async move {
+            let mut client = self.client.lock().await;
+            if client.as_ref().map_or(true, |c| c.is_closed()) {
+                let (connected, connection) =
+                    tokio_postgres::connect(&self.url, tokio_postgres::NoTls)
+                        .await
+                        .map_err(|e| SinkError::Target(e.to_string()))?;
+                actix_web::rt::spawn(async move {
+                    if let Err(e) = connection.await {
+                        warn!(error = %e, "postgres sink connection error");
+                    }
+                });
+                *client = Some(connected);
+            }
+
+            let connected = client.as_mut().expect("client is connected");
+            self.insert(connected, queue_name, events)
+                .await
+                .map_err(|e| SinkError::Target(e.to_string()))
+        }
+        .boxed_local()
+    }
+}
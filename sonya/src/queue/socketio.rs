@@ -0,0 +1,609 @@
+use crate::queue::admission::Admission;
+use crate::queue::connection::BroadcastMessage;
+use crate::queue::executor::StorageExecutor;
+use crate::queue::failover::Failover;
+use crate::queue::map::{Queue, QueueError};
+use actix::prelude::*;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use actix_web_actors::ws::{CloseCode, CloseReason};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sonya_meta::api::{authorize_key_subscription, extract_any_data_from_query};
+use sonya_meta::close::QueueCloseReason;
+use sonya_meta::config::{Config, Secure, SocketIo};
+use sonya_meta::limit::{ConnectionGuard, ConnectionLimiter};
+use sonya_meta::message::{ControlMessage, EventMessage, RequestSequence};
+use sonya_meta::response::{BaseQueueResponse, SendResponse};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, instrument, warn, Span};
+
+/// Largest Engine.IO packet accepted from clients
+const MAX_PAYLOAD: usize = 1024 * 1024;
+
+/// Shared state of Socket.IO connections
+pub struct SocketIoState {
+    pub queue: web::Data<Queue<EventMessage>>,
+    pub executor: web::Data<StorageExecutor>,
+    pub admission: web::Data<Admission>,
+    pub limiter: web::Data<ConnectionLimiter>,
+    pub failover: web::Data<Failover>,
+    pub options: SocketIo,
+}
+
+#[derive(Deserialize)]
+struct EngineQuery {
+    #[serde(rename = "EIO")]
+    eio: Option<String>,
+    transport: Option<String>,
+}
+
+/// Accepts Engine.IO 4 websocket connections, the polling transport is not supported,
+/// so clients must connect with the websocket transport only
+#[instrument(skip_all)]
+pub async fn connect_socket_io(
+    req: HttpRequest,
+    stream: web::Payload,
+    state: web::Data<SocketIoState>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, Error> {
+    let query: Option<EngineQuery> = extract_any_data_from_query(req.head());
+    match query {
+        Some(EngineQuery {
+            eio: Some(eio),
+            transport: Some(transport),
+        }) if eio == "4" && transport == "websocket" => {}
+        _ => {
+            return Err(actix_web::error::ErrorBadRequest(
+                "Only the websocket transport of Engine.IO 4 is supported",
+            ))
+        }
+    }
+
+    let connection = SocketIoConnection {
+        state: state.into_inner(),
+        secure: config.secure.clone(),
+        peer: req.peer_addr().map(|a| a.ip()),
+        max_skipped_messages: config.queue.max_skipped_messages,
+        skipped_messages: 0,
+        last_pong: Instant::now(),
+        namespaces: HashMap::new(),
+        subscriptions: HashMap::new(),
+        span: info_span!("socket_io", peer = ?req.peer_addr()),
+    };
+    ws::WsResponseBuilder::new(connection, &req, stream)
+        .frame_size(MAX_PAYLOAD)
+        .start()
+}
+
+/// Engine.IO connection, every Socket.IO namespace is the queue with the same name
+pub struct SocketIoConnection {
+    state: std::sync::Arc<SocketIoState>,
+    secure: Option<Secure>,
+    peer: Option<IpAddr>,
+    max_skipped_messages: Option<u64>,
+    skipped_messages: u64,
+    last_pong: Instant,
+    /// Connected namespaces with access tokens of their connect packets
+    namespaces: HashMap<String, Option<String>>,
+    /// Subscriptions by namespace and key, the stream handle is set when the preload starts
+    subscriptions: HashMap<(String, String), KeySubscription>,
+    span: Span,
+}
+
+struct KeySubscription {
+    handle: Option<SpawnHandle>,
+    /// The subscription is counted by limits until it ends
+    _connection_guard: ConnectionGuard,
+}
+
+/// Event of the key subscription, `None` when the subscription stream has ended
+struct Delivery {
+    namespace: String,
+    id: String,
+    message: Option<BroadcastMessage<EventMessage>>,
+}
+
+/// Socket.IO packet `<type>[<namespace>,][<ack id>][<data>]`, binary packets are not supported
+struct Packet<'a> {
+    kind: u8,
+    namespace: &'a str,
+    ack: Option<u64>,
+    data: &'a str,
+}
+
+const CONNECT: u8 = 0;
+const DISCONNECT: u8 = 1;
+const EVENT: u8 = 2;
+const ACK: u8 = 3;
+const CONNECT_ERROR: u8 = 4;
+
+impl<'a> Packet<'a> {
+    fn parse(s: &'a str) -> Option<Self> {
+        let kind = s.chars().next()?.to_digit(10)? as u8;
+        let rest = &s[1..];
+        let (namespace, rest) = match rest.starts_with('/') {
+            true => rest.split_once(',').unwrap_or((rest, "")),
+            false => ("/", rest),
+        };
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        Some(Self {
+            kind,
+            namespace,
+            ack: rest[..digits].parse().ok(),
+            data: &rest[digits..],
+        })
+    }
+
+    /// Encodes the packet into the Engine.IO message
+    fn encode(&self) -> String {
+        let mut packet = format!("4{}", self.kind);
+        if self.namespace != "/" {
+            packet.push_str(self.namespace);
+            packet.push(',');
+        }
+        if let Some(ack) = self.ack {
+            packet.push_str(&ack.to_string());
+        }
+        packet.push_str(self.data);
+        packet
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct ConnectAuth {
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SubscribeArgs {
+    id: String,
+    #[serde(default)]
+    sequence: RequestSequence,
+    max_preload: Option<usize>,
+    /// Identity of subscriptions without secure mode
+    identity: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UnsubscribeArgs {
+    id: String,
+}
+
+fn error_ack(error: impl ToString) -> Value {
+    json!({ "success": false, "error": error.to_string() })
+}
+
+impl SocketIoConnection {
+    fn send_packet(&self, packet: Packet, ctx: &mut <Self as Actor>::Context) {
+        ctx.text(packet.encode())
+    }
+
+    fn emit(&self, namespace: &str, args: Value, ctx: &mut <Self as Actor>::Context) {
+        let data = args.to_string();
+        self.send_packet(
+            Packet {
+                kind: EVENT,
+                namespace,
+                ack: None,
+                data: &data,
+            },
+            ctx,
+        )
+    }
+
+    fn ack(
+        &self,
+        namespace: &str,
+        ack: Option<u64>,
+        response: Value,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        if ack.is_none() {
+            return;
+        }
+        let data = json!([response]).to_string();
+        self.send_packet(
+            Packet {
+                kind: ACK,
+                namespace,
+                ack,
+                data: &data,
+            },
+            ctx,
+        )
+    }
+
+    fn close(&self, reason: QueueCloseReason, ctx: &mut <Self as Actor>::Context) {
+        info!(parent: &self.span, reason = %reason, "closing connection");
+        ctx.close(Some(CloseReason {
+            code: CloseCode::from(reason.code()),
+            description: Some(reason.to_string()),
+        }));
+        ctx.stop()
+    }
+
+    fn handle_packet(&mut self, packet: Packet, ctx: &mut <Self as Actor>::Context) {
+        match packet.kind {
+            CONNECT => self.connect(packet.namespace, packet.data, ctx),
+            DISCONNECT => {
+                self.namespaces.remove(packet.namespace);
+                let ids: Vec<_> = self
+                    .subscriptions
+                    .keys()
+                    .filter(|(namespace, _)| namespace == packet.namespace)
+                    .cloned()
+                    .collect();
+                for key in ids {
+                    self.unsubscribe(key, ctx);
+                }
+            }
+            EVENT if self.namespaces.contains_key(packet.namespace) => {
+                match serde_json::from_str::<Vec<Value>>(packet.data) {
+                    Ok(args) => self.handle_event(packet.namespace, packet.ack, args, ctx),
+                    Err(e) => warn!(parent: &self.span, error = %e, "invalid socket.io event"),
+                }
+            }
+            kind => warn!(parent: &self.span, kind, "unsupported socket.io packet"),
+        }
+    }
+
+    fn connect(&mut self, namespace: &str, data: &str, ctx: &mut <Self as Actor>::Context) {
+        let ConnectAuth { token } = serde_json::from_str(data).unwrap_or_default();
+        let error = match (namespace, &self.secure, &token) {
+            ("/", _, _) => Some("Namespace must be the queue name"),
+            (_, Some(_), None) => Some("Access token is required"),
+            _ => None,
+        };
+        if let Some(error) = error {
+            let data = json!({ "message": error }).to_string();
+            return self.send_packet(
+                Packet {
+                    kind: CONNECT_ERROR,
+                    namespace,
+                    ack: None,
+                    data: &data,
+                },
+                ctx,
+            );
+        }
+
+        self.namespaces.insert(namespace.to_string(), token);
+        let data = json!({ "sid": uuid::Uuid::new_v4().to_string() }).to_string();
+        self.send_packet(
+            Packet {
+                kind: CONNECT,
+                namespace,
+                ack: None,
+                data: &data,
+            },
+            ctx,
+        )
+    }
+
+    fn handle_event(
+        &mut self,
+        namespace: &str,
+        ack: Option<u64>,
+        mut args: Vec<Value>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        if args.is_empty() {
+            warn!(parent: &self.span, "socket.io event without name");
+            return;
+        }
+        let name = args.remove(0);
+        let arg = match args.is_empty() {
+            true => Value::Null,
+            false => args.remove(0),
+        };
+        match name.as_str() {
+            Some("subscribe") => match serde_json::from_value(arg) {
+                Ok(args) => self.subscribe(namespace, ack, args, ctx),
+                Err(e) => self.ack(namespace, ack, error_ack(e), ctx),
+            },
+            Some("unsubscribe") => match serde_json::from_value::<UnsubscribeArgs>(arg) {
+                Ok(UnsubscribeArgs { id }) => {
+                    self.unsubscribe((namespace.to_string(), id), ctx);
+                    self.ack(
+                        namespace,
+                        ack,
+                        json!(BaseQueueResponse { success: true }),
+                        ctx,
+                    )
+                }
+                Err(e) => self.ack(namespace, ack, error_ack(e), ctx),
+            },
+            Some("publish") => match serde_json::from_value(arg) {
+                Ok(event) => self.publish(namespace, ack, event, ctx),
+                Err(e) => self.ack(namespace, ack, error_ack(e), ctx),
+            },
+            _ => self.ack(namespace, ack, error_ack("Unknown event"), ctx),
+        }
+    }
+
+    fn subscribe(
+        &mut self,
+        namespace: &str,
+        ack: Option<u64>,
+        args: SubscribeArgs,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let queue_name = namespace.trim_start_matches('/').to_string();
+        let key = (namespace.to_string(), args.id.clone());
+        if self.subscriptions.contains_key(&key) {
+            return self.ack(namespace, ack, error_ack("Key is already subscribed"), ctx);
+        }
+
+        let identity = match &self.secure {
+            None => args.identity,
+            Some(secure) => {
+                let token = self.namespaces.get(namespace).and_then(Option::as_deref);
+                match token
+                    .and_then(|t| authorize_key_subscription(secure, t, &queue_name, &args.id))
+                {
+                    Some(identity) => identity,
+                    None => {
+                        let error = error_ack("Subscription is not authorized");
+                        return self.ack(namespace, ack, error, ctx);
+                    }
+                }
+            }
+        };
+        let connection_guard = match self
+            .state
+            .limiter
+            .clone()
+            .into_inner()
+            .acquire(&queue_name, self.peer)
+        {
+            Ok(guard) => guard,
+            Err(e) => return self.ack(namespace, ack, error_ack(e), ctx),
+        };
+        self.subscriptions.insert(
+            key.clone(),
+            KeySubscription {
+                handle: None,
+                _connection_guard: connection_guard,
+            },
+        );
+
+        let state = self.state.clone();
+        let subscription = async move {
+            let permit = state.admission.admit().await.map_err(|e| e.to_string())?;
+            let queue = state.queue.clone();
+            let subscription = state
+                .executor
+                .run_subscribe(move || {
+                    queue.subscribe_queue_by_id(
+                        queue_name,
+                        args.id,
+                        args.sequence,
+                        args.max_preload,
+                        identity,
+                    )
+                })
+                .await
+                .map_err(|e| e.to_string())?
+                .map_err(|e| e.to_string())?;
+            permit
+                .hold(subscription)
+                .stream
+                .ok_or_else(|| "Queue does not exist".to_string())
+        };
+
+        ctx.spawn(subscription.into_actor(self).map(move |result, act, ctx| {
+            let namespace = key.0.clone();
+            // the key was unsubscribed while the preload was waiting
+            if !act.subscriptions.contains_key(&key) {
+                return;
+            }
+            match result {
+                Ok(stream) => {
+                    let (delivered_namespace, id) = key.clone();
+                    let handle = ctx.add_stream(
+                        stream
+                            .map(Some)
+                            .chain(futures::stream::once(async { None }))
+                            .map(move |message| Delivery {
+                                namespace: delivered_namespace.clone(),
+                                id: id.clone(),
+                                message,
+                            }),
+                    );
+                    if let Some(subscription) = act.subscriptions.get_mut(&key) {
+                        subscription.handle = Some(handle);
+                    }
+                    act.ack(
+                        &namespace,
+                        ack,
+                        json!(BaseQueueResponse { success: true }),
+                        ctx,
+                    )
+                }
+                Err(e) => {
+                    act.subscriptions.remove(&key);
+                    act.ack(&namespace, ack, error_ack(e), ctx)
+                }
+            }
+        }));
+    }
+
+    fn unsubscribe(&mut self, key: (String, String), ctx: &mut <Self as Actor>::Context) {
+        if let Some(handle) = self.subscriptions.remove(&key).and_then(|s| s.handle) {
+            ctx.cancel_future(handle);
+        }
+    }
+
+    fn publish(
+        &mut self,
+        namespace: &str,
+        ack: Option<u64>,
+        event: EventMessage,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let token = self.namespaces.get(namespace).and_then(Option::as_deref);
+        if matches!(&self.secure, Some(secure) if token != Some(secure.service_token.as_str())) {
+            return self.ack(namespace, ack, error_ack("Publish is not authorized"), ctx);
+        }
+        if self.state.failover.is_standby() {
+            return self.ack(
+                namespace,
+                ack,
+                error_ack("Standby server is read only"),
+                ctx,
+            );
+        }
+
+        let queue_name = namespace.trim_start_matches('/').to_string();
+        let namespace = namespace.to_string();
+        let state = self.state.clone();
+        let published = async move {
+            let queue = state.queue.clone();
+            state
+                .executor
+                .run_publish(move || queue.publish(queue_name, event))
+                .await
+        };
+
+        ctx.spawn(published.into_actor(self).map(move |result, act, ctx| {
+            let response = match result {
+                Ok(Ok(sequences)) => json!(SendResponse {
+                    success: sequences.is_some(),
+                    sequence: sequences.and_then(|s| s.last().copied()),
+                }),
+                Ok(Err(QueueError::Rejected(e))) => error_ack(e),
+                Ok(Err(e)) => {
+                    error!(parent: &act.span, error = %e, "sending message error");
+                    error_ack("Message was not sent")
+                }
+                Err(e) => error_ack(e),
+            };
+            act.ack(&namespace, ack, response, ctx)
+        }));
+    }
+
+    fn deliver(&mut self, delivery: Delivery, ctx: &mut <Self as Actor>::Context) {
+        let Delivery {
+            namespace,
+            id,
+            message,
+        } = delivery;
+        let message = match message {
+            Some(message) => message,
+            None => return self.unsubscribe((namespace, id), ctx),
+        };
+
+        match &message {
+            BroadcastMessage::Message(event) => {
+                info!(parent: &self.span, sequence = ?event.sequence, "accepted message");
+                self.emit(&namespace, json!(["message", event]), ctx)
+            }
+            BroadcastMessage::Draining => {
+                let retry_after = self.state.admission.retry_hint().map(|h| h.jittered());
+                let control = ControlMessage::Draining { retry_after };
+                self.emit(&namespace, json!(["control", control, id]), ctx);
+                return self.close(QueueCloseReason::Draining, ctx);
+            }
+            control => self.emit(&namespace, json!(["control", control.control(), id]), ctx),
+        }
+
+        match message {
+            BroadcastMessage::Lagged(skipped) => {
+                self.skipped_messages += skipped;
+                warn!(
+                    parent: &self.span,
+                    skipped,
+                    total_skipped = self.skipped_messages,
+                    "slow consumer"
+                );
+                if self
+                    .max_skipped_messages
+                    .map_or(false, |max| self.skipped_messages > max)
+                {
+                    self.close(QueueCloseReason::SlowConsumer, ctx)
+                }
+            }
+            BroadcastMessage::QueueClosed
+            | BroadcastMessage::KeyDeleted
+            | BroadcastMessage::Moved(_)
+            | BroadcastMessage::PreloadFailed => self.unsubscribe((namespace, id), ctx),
+            _ => {}
+        }
+    }
+}
+
+impl Actor for SocketIoConnection {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let options = &self.state.options;
+        let (ping_interval, ping_timeout) = (
+            Duration::from_secs(options.ping_interval),
+            Duration::from_secs(options.ping_timeout),
+        );
+        let open = json!({
+            "sid": uuid::Uuid::new_v4().to_string(),
+            "upgrades": [],
+            "pingInterval": ping_interval.as_millis() as u64,
+            "pingTimeout": ping_timeout.as_millis() as u64,
+            "maxPayload": MAX_PAYLOAD,
+        });
+        ctx.text(format!("0{}", open));
+
+        // clients which didn't answer the previous ping are gone
+        let reaper = self.state.queue.reaper(ping_interval + ping_timeout);
+        ctx.run_interval(ping_interval, move |act, ctx| {
+            if reaper.reap(act.last_pong) {
+                warn!(parent: &act.span, "reaped dead connection");
+                ctx.stop();
+                return;
+            }
+            ctx.text("2");
+        });
+
+        info!(parent: &self.span, "created connection");
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        info!(parent: &self.span, "closed connection");
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for SocketIoConnection {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(ws::Message::Text(text)) => match text.chars().next() {
+                Some('3') => self.last_pong = Instant::now(),
+                Some('1') => ctx.stop(),
+                Some('4') => match Packet::parse(&text[1..]) {
+                    Some(packet) => self.handle_packet(packet, ctx),
+                    None => warn!(parent: &self.span, "invalid socket.io packet"),
+                },
+                _ => warn!(parent: &self.span, "unsupported engine.io packet"),
+            },
+            Err(ws::ProtocolError::Overflow) => self.close(QueueCloseReason::MessageTooLarge, ctx),
+            Err(_) => ctx.stop(),
+            _ => (),
+        }
+    }
+}
+
+impl StreamHandler<Delivery> for SocketIoConnection {
+    fn handle(&mut self, delivery: Delivery, ctx: &mut Self::Context) {
+        self.deliver(delivery, ctx)
+    }
+
+    /// Subscription streams end on their own, the connection stays open
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
+}
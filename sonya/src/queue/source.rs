@@ -0,0 +1,294 @@
+use crate::queue::executor::{StorageError, StorageExecutor};
+use crate::queue::failover::Failover;
+use crate::queue::map::{Queue, QueueError};
+use crate::queue::route::key_to_string;
+use actix_web::web;
+use derive_more::{Display, From};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use sonya_meta::config::{Capture, Source};
+use sonya_meta::message::EventMessage;
+use std::time::Duration;
+use tokio_postgres::{Client, NoTls};
+use tracing::{info, warn};
+
+/// Publishes row changes of the Postgres database until the server stops.
+/// Changes are acknowledged in the database after they are published with dedup ids,
+/// so changes published again after failures are stored once during the dedup window.
+/// Standby servers don't capture changes.
+pub async fn run_source(
+    queue: web::Data<Queue<EventMessage>>,
+    executor: web::Data<StorageExecutor>,
+    failover: web::Data<Failover>,
+    source: Source,
+) {
+    let interval = Duration::from_secs(source.interval);
+    let mut client: Option<Client> = None;
+    info!(source = %source.name, "source started");
+
+    loop {
+        if failover.is_standby() {
+            actix_web::rt::time::sleep(interval).await;
+            continue;
+        }
+
+        if client.as_ref().map_or(true, |c| c.is_closed()) {
+            match connect(&source.url).await {
+                Ok(connected) => client = Some(connected),
+                Err(e) => {
+                    warn!(source = %source.name, error = %e, "connecting to source error");
+                    actix_web::rt::time::sleep(interval).await;
+                    continue;
+                }
+            }
+        }
+
+        let connected = client.as_ref().expect("client is connected");
+        match capture(&queue, &executor, &source, connected).await {
+            // the next changes are read right away while the source is catching up
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => warn!(source = %source.name, error = %e, "source capture error"),
+        }
+        actix_web::rt::time::sleep(interval).await;
+    }
+}
+
+async fn connect(url: &str) -> Result<Client, tokio_postgres::Error> {
+    let (client, connection) = tokio_postgres::connect(url, NoTls).await?;
+    actix_web::rt::spawn(async move {
+        if let Err(e) = connection.await {
+            warn!(error = %e, "source connection error");
+        }
+    });
+    Ok(client)
+}
+
+/// Publishes the next changes and acknowledges them, returns `false` if there were no changes
+async fn capture(
+    queue: &web::Data<Queue<EventMessage>>,
+    executor: &web::Data<StorageExecutor>,
+    source: &Source,
+    client: &Client,
+) -> Result<bool, SourceError> {
+    let changes = match &source.capture {
+        Capture::Logical { slot } => logical_changes(client, slot, source.batch_size).await?,
+        Capture::Outbox { table } => outbox_changes(client, table, source.batch_size).await?,
+    };
+    let ack = match changes.ack {
+        None => return Ok(false),
+        Some(ack) => ack,
+    };
+
+    for change in changes.rows {
+        let queue_name = match source.tables.get(&change.table) {
+            None => continue,
+            Some(queue_name) => queue_name.clone(),
+        };
+        let dedup_id = format!("{}:{}", source.name, change.position);
+        let event = EventMessage::new(
+            change.key,
+            json!({
+                "operation": change.operation,
+                "table": change.table,
+                "row": change.row,
+            }),
+        );
+
+        let published = executor
+            .run_publish({
+                let queue = queue.clone();
+                let queue_name = queue_name.clone();
+                move || queue.send_deduplicated(queue_name, event, &dedup_id)
+            })
+            .await??;
+        if published.is_none() {
+            warn!(source = %source.name, queue = %queue_name, "source queue does not exist");
+        }
+    }
+
+    match ack {
+        Ack::Lsn { slot, lsn } => {
+            client
+                .execute(
+                    "SELECT pg_replication_slot_advance($1, $2::text::pg_lsn)",
+                    &[&slot, &lsn],
+                )
+                .await?;
+        }
+        Ack::Ids { table, ids } => {
+            client
+                .execute(
+                    &format!("DELETE FROM {} WHERE id = ANY($1)", table),
+                    &[&ids],
+                )
+                .await?;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Captured changes and their acknowledgement, `None` if there were no changes
+struct Changes<'a> {
+    rows: Vec<RowChange>,
+    ack: Option<Ack<'a>>,
+}
+
+enum Ack<'a> {
+    /// Position of the replication slot after the changes
+    Lsn { slot: &'a str, lsn: String },
+    /// Ids of the outbox rows
+    Ids { table: &'a str, ids: Vec<i64> },
+}
+
+struct RowChange {
+    /// Unique position of the change in the database, used in dedup ids
+    position: String,
+    table: String,
+    operation: String,
+    key: String,
+    row: Value,
+}
+
+/// Change of the `wal2json` plugin with the `format-version` 2
+#[derive(Deserialize)]
+struct Wal2JsonChange {
+    action: String,
+    #[serde(default)]
+    schema: String,
+    #[serde(default)]
+    table: String,
+    #[serde(default)]
+    columns: Vec<Wal2JsonColumn>,
+    /// Old key columns of updates and deletes
+    #[serde(default)]
+    identity: Vec<Wal2JsonColumn>,
+    #[serde(default)]
+    pk: Vec<Wal2JsonColumn>,
+}
+
+#[derive(Deserialize)]
+struct Wal2JsonColumn {
+    name: String,
+    #[serde(default)]
+    value: Value,
+}
+
+/// Peeks changes of the slot, the slot is advanced after they are published
+async fn logical_changes<'a>(
+    client: &Client,
+    slot: &'a str,
+    limit: usize,
+) -> Result<Changes<'a>, SourceError> {
+    let rows = client
+        .query(
+            "SELECT lsn::text, data FROM pg_logical_slot_peek_changes($1, NULL, $2, \
+            'format-version', '2', 'include-pk', 'true')",
+            &[&slot, &(limit.min(i32::MAX as usize) as i32)],
+        )
+        .await?;
+
+    let mut changes = Changes {
+        rows: Vec::new(),
+        ack: None,
+    };
+    for row in rows {
+        let (lsn, data): (String, String) = (row.try_get(0)?, row.try_get(1)?);
+        changes.ack = Some(Ack::Lsn {
+            slot,
+            lsn: lsn.clone(),
+        });
+
+        let change: Wal2JsonChange = serde_json::from_str(&data)?;
+        let operation = match change.action.as_str() {
+            "I" => "insert",
+            "U" => "update",
+            "D" => "delete",
+            // transaction boundaries, truncates and messages aren't row changes
+            _ => continue,
+        };
+        let columns = match operation {
+            "delete" => change.identity,
+            _ => change.columns,
+        };
+        let row: Map<String, Value> = columns.into_iter().map(|c| (c.name, c.value)).collect();
+        let table = format!("{}.{}", change.schema, change.table);
+
+        let key = match primary_key(&row, change.pk.iter().map(|c| c.name.as_str())) {
+            None => {
+                warn!(table = %table, "captured table has no primary key");
+                continue;
+            }
+            Some(key) => key,
+        };
+        changes.rows.push(RowChange {
+            position: lsn,
+            table,
+            operation: operation.into(),
+            key,
+            row: Value::Object(row),
+        });
+    }
+
+    Ok(changes)
+}
+
+/// Reads the oldest rows of the outbox, they are deleted after they are published
+async fn outbox_changes<'a>(
+    client: &Client,
+    table: &'a str,
+    limit: usize,
+) -> Result<Changes<'a>, SourceError> {
+    let rows = client
+        .query(
+            &format!(
+                "SELECT id, table_name, operation, key, row FROM {} ORDER BY id LIMIT $1",
+                table
+            ),
+            &[&(limit as i64)],
+        )
+        .await?;
+
+    let mut changes = Changes {
+        rows: Vec::with_capacity(rows.len()),
+        ack: None,
+    };
+    let mut ids = Vec::with_capacity(rows.len());
+    for row in rows {
+        let id: i64 = row.try_get(0)?;
+        let key: String = row.try_get(3)?;
+        ids.push(id);
+        changes.rows.push(RowChange {
+            position: id.to_string(),
+            table: row.try_get(1)?,
+            operation: row.try_get(2)?,
+            key: key.into(),
+            row: row.try_get::<_, Option<Value>>(4)?.unwrap_or_default(),
+        });
+    }
+    if !ids.is_empty() {
+        changes.ack = Some(Ack::Ids { table, ids });
+    }
+
+    Ok(changes)
+}
+
+/// Key of the row, values of composite keys are joined with `:`
+fn primary_key<'a>(
+    row: &Map<String, Value>,
+    mut names: impl Iterator<Item = &'a str>,
+) -> Option<String> {
+    let first = key_to_string(row.get(names.next()?)?);
+    names.try_fold(first, |key, name| {
+        Some(format!("{}:{}", key, key_to_string(row.get(name)?)))
+    })
+}
+
+#[derive(Debug, Display, From)]
+pub enum SourceError {
+    Postgres(tokio_postgres::Error),
+    Decode(serde_json::Error),
+    Storage(StorageError),
+    Queue(QueueError),
+}
@@ -0,0 +1,181 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Internal counters of the queue server
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    published: AtomicU64,
+    rejected: AtomicU64,
+    duplicates: AtomicU64,
+    dropped: AtomicU64,
+    slow_preloads: AtomicU64,
+    cached_preloads: AtomicU64,
+    quarantined: AtomicU64,
+    dead_lettered: AtomicU64,
+    reaped: AtomicU64,
+    compressed_responses: AtomicU64,
+    compression_saved: AtomicU64,
+}
+
+impl QueueStats {
+    pub fn add_published(&self, count: u64) {
+        self.published.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn add_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_duplicate(&self) {
+        self.duplicates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_dropped(&self, count: u64) {
+        self.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn add_slow_preload(&self) {
+        self.slow_preloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_cached_preload(&self) {
+        self.cached_preloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_quarantined(&self) {
+        self.quarantined.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_dead_lettered(&self) {
+        self.dead_lettered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_reaped(&self) {
+        self.reaped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_compressed(&self, saved: u64) {
+        self.compressed_responses.fetch_add(1, Ordering::Relaxed);
+        self.compression_saved.fetch_add(saved, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            published: self.published.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            duplicates: self.duplicates.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            slow_preloads: self.slow_preloads.load(Ordering::Relaxed),
+            cached_preloads: self.cached_preloads.load(Ordering::Relaxed),
+            quarantined: self.quarantined.load(Ordering::Relaxed),
+            dead_lettered: self.dead_lettered.load(Ordering::Relaxed),
+            reaped: self.reaped.load(Ordering::Relaxed),
+            compressed_responses: self.compressed_responses.load(Ordering::Relaxed),
+            compression_saved: self.compression_saved.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct StatsSnapshot {
+    pub published: u64,
+    pub rejected: u64,
+    pub duplicates: u64,
+    pub dropped: u64,
+    pub slow_preloads: u64,
+    pub cached_preloads: u64,
+    pub quarantined: u64,
+    pub dead_lettered: u64,
+    pub reaped: u64,
+    pub compressed_responses: u64,
+    pub compression_saved: u64,
+}
+
+/// Converts counters snapshots into per interval reports
+#[derive(Debug)]
+pub struct StatsReporter {
+    interval: u64,
+    prev: StatsSnapshot,
+}
+
+impl StatsReporter {
+    pub fn new(interval: u64) -> Self {
+        Self {
+            interval,
+            prev: Default::default(),
+        }
+    }
+
+    pub fn report(
+        &mut self,
+        snapshot: StatsSnapshot,
+        subscribers: HashMap<String, usize>,
+        skipped_by_clients: HashMap<String, u64>,
+        formats: HashMap<String, usize>,
+    ) -> StatsReport {
+        let published = snapshot.published - self.prev.published;
+
+        let report = StatsReport {
+            publish_rate: published as f64 / self.interval as f64,
+            published,
+            rejected: snapshot.rejected - self.prev.rejected,
+            duplicates: snapshot.duplicates - self.prev.duplicates,
+            dropped: snapshot.dropped - self.prev.dropped,
+            slow_preloads: snapshot.slow_preloads - self.prev.slow_preloads,
+            cached_preloads: snapshot.cached_preloads - self.prev.cached_preloads,
+            quarantined: snapshot.quarantined - self.prev.quarantined,
+            dead_lettered: snapshot.dead_lettered - self.prev.dead_lettered,
+            reaped: snapshot.reaped - self.prev.reaped,
+            compressed_responses: snapshot.compressed_responses - self.prev.compressed_responses,
+            compression_saved: snapshot.compression_saved - self.prev.compression_saved,
+            formats,
+            subscribers: subscribers.values().sum(),
+            queues: subscribers,
+            skipped_by_clients,
+            total: snapshot,
+        };
+
+        self.prev = snapshot;
+
+        report
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatsReport {
+    /// Published events per second during the interval
+    pub publish_rate: f64,
+    /// Published events during the interval
+    pub published: u64,
+    /// Rejected events during the interval
+    pub rejected: u64,
+    /// Retried publishes ignored by the dedup window during the interval
+    pub duplicates: u64,
+    /// Events skipped by slow subscribers during the interval
+    pub dropped: u64,
+    /// Subscriptions with slow preload during the interval
+    pub slow_preloads: u64,
+    /// Subscriptions preloaded from the cache during the interval
+    pub cached_preloads: u64,
+    /// Stored entries which repeatedly failed to decode and were quarantined during the interval
+    pub quarantined: u64,
+    /// Journaled publishes which couldn't be applied and were dead lettered during the interval
+    pub dead_lettered: u64,
+    /// Dead websocket subscriptions closed without a close frame during the interval
+    pub reaped: u64,
+    /// Longpoll responses compressed during the interval
+    pub compressed_responses: u64,
+    /// Bytes saved by compression of longpoll responses during the interval
+    pub compression_saved: u64,
+    /// Open websocket subscriptions per negotiated delivery format
+    pub formats: HashMap<String, usize>,
+    /// Active subscribers
+    pub subscribers: usize,
+    /// Active subscribers per queue
+    pub queues: HashMap<String, usize>,
+    /// Messages skipped by open subscriptions per client id
+    pub skipped_by_clients: HashMap<String, u64>,
+    /// Counters since the server start
+    pub total: StatsSnapshot,
+}
@@ -0,0 +1,182 @@
+use sonya_meta::encoding::DeliveryFormat;
+use sonya_meta::response::SubscriberResponse;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::Notify;
+
+/// Close descriptions are limited by the websocket control frame size
+const MAX_REASON_LEN: usize = 120;
+
+/// Client id and labels attached by the subscriber at connect time, e.g. app name and version
+#[derive(Debug, Clone, Default)]
+pub struct ClientLabels {
+    pub client_id: Option<String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Open websocket subscriptions, so operators can tell which clients are lagging
+#[derive(Debug, Default)]
+pub struct SubscriberRegistry {
+    subscribers: Mutex<HashMap<String, Arc<Subscriber>>>,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    queue_name: String,
+    key: Option<String>,
+    client: ClientLabels,
+    ip: Option<IpAddr>,
+    format: DeliveryFormat,
+    connected: SystemTime,
+    skipped: AtomicU64,
+    disconnect: Notify,
+    disconnect_reason: Mutex<Option<String>>,
+}
+
+impl SubscriberRegistry {
+    /// Registers the subscription, it will be removed when returned handle is dropped
+    pub fn register(
+        self: Arc<Self>,
+        queue_name: String,
+        key: Option<String>,
+        client: ClientLabels,
+        ip: Option<IpAddr>,
+        format: DeliveryFormat,
+    ) -> SubscriberHandle {
+        // ids are random, so proxies can look for the connection on every shard
+        let id = uuid::Uuid::new_v4().to_string();
+        let subscriber = Arc::new(Subscriber {
+            queue_name,
+            key,
+            client,
+            ip,
+            format,
+            connected: SystemTime::now(),
+            skipped: AtomicU64::new(0),
+            disconnect: Notify::new(),
+            disconnect_reason: Mutex::new(None),
+        });
+        self.subscribers
+            .lock()
+            .unwrap()
+            .insert(id.clone(), subscriber.clone());
+
+        SubscriberHandle {
+            registry: self,
+            id,
+            subscriber,
+        }
+    }
+
+    /// Lists subscribers of the queue, the earliest connected first
+    pub fn list(&self, queue_name: &str) -> Vec<SubscriberResponse> {
+        let mut subscribers: Vec<_> = self
+            .subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, s)| s.queue_name == queue_name)
+            .map(|(id, s)| (id.clone(), s.clone()))
+            .collect();
+        subscribers.sort_by_key(|(_, s)| s.connected);
+
+        subscribers
+            .into_iter()
+            .map(|(id, s)| SubscriberResponse {
+                connection_id: id,
+                key: s.key.clone(),
+                client_id: s.client.client_id.clone(),
+                labels: s.client.labels.clone(),
+                ip: s.ip.map(|ip| ip.to_string()),
+                format: s.format.to_string(),
+                connected: s
+                    .connected
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                skipped: s.skipped.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Asks the connection to close itself, returns false if it's not open anymore
+    pub fn disconnect(&self, connection_id: &str, reason: Option<String>) -> bool {
+        let subscriber = match self.subscribers.lock().unwrap().get(connection_id) {
+            None => return false,
+            Some(s) => s.clone(),
+        };
+
+        *subscriber.disconnect_reason.lock().unwrap() = reason.map(|mut reason| {
+            let mut len = reason.len().min(MAX_REASON_LEN);
+            while !reason.is_char_boundary(len) {
+                len -= 1;
+            }
+            reason.truncate(len);
+            reason
+        });
+        subscriber.disconnect.notify_one();
+        true
+    }
+
+    /// Open subscriptions grouped by negotiated delivery formats
+    pub fn formats(&self) -> HashMap<String, usize> {
+        let mut formats: HashMap<String, usize> = HashMap::new();
+        for s in self.subscribers.lock().unwrap().values() {
+            *formats.entry(s.format.to_string()).or_default() += 1;
+        }
+        formats
+    }
+
+    /// Messages skipped by open subscriptions grouped by client ids, subscribers without id
+    /// are counted under the empty one
+    pub fn skipped_by_clients(&self) -> HashMap<String, u64> {
+        let mut skipped: HashMap<String, u64> = HashMap::new();
+        for s in self.subscribers.lock().unwrap().values() {
+            let count = s.skipped.load(Ordering::Relaxed);
+            if count > 0 {
+                *skipped
+                    .entry(s.client.client_id.clone().unwrap_or_default())
+                    .or_default() += count;
+            }
+        }
+        skipped
+    }
+}
+
+/// Keeps the subscription listed until dropped
+#[derive(Debug)]
+pub struct SubscriberHandle {
+    registry: Arc<SubscriberRegistry>,
+    id: String,
+    subscriber: Arc<Subscriber>,
+}
+
+impl SubscriberHandle {
+    pub fn client(&self) -> &ClientLabels {
+        &self.subscriber.client
+    }
+
+    pub fn add_skipped(&self, count: u64) {
+        self.subscriber.skipped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Resolves with the given reason when the subscriber is disconnected by the admin api
+    pub fn disconnected(&self) -> impl Future<Output = Option<String>> {
+        let subscriber = self.subscriber.clone();
+        async move {
+            subscriber.disconnect.notified().await;
+            let reason = subscriber.disconnect_reason.lock().unwrap().take();
+            reason
+        }
+    }
+}
+
+impl Drop for SubscriberHandle {
+    fn drop(&mut self) {
+        self.registry.subscribers.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,39 @@
+use crate::queue::filter::SubscriberInfo;
+use crate::queue::interceptor::DeliveryInterceptor;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use sonya_meta::message::{EventMessage, SequenceId};
+
+/// Delivery of the stored event to one subscription
+#[derive(Debug, Clone)]
+pub struct TracedDelivery {
+    pub queue: String,
+    pub id: String,
+    pub sequence: SequenceId,
+}
+
+/// Reports deliveries of stored events to subscriptions, so they are counted in message traces.
+/// Must be registered after filters, so hidden events are not counted.
+#[derive(Debug, Clone)]
+pub struct TraceInterceptor {
+    sender: UnboundedSender<TracedDelivery>,
+}
+
+impl TraceInterceptor {
+    pub fn new() -> (Self, UnboundedReceiver<TracedDelivery>) {
+        let (sender, receiver) = unbounded();
+        (Self { sender }, receiver)
+    }
+}
+
+impl DeliveryInterceptor<EventMessage> for TraceInterceptor {
+    fn on_deliver(&self, subscriber: &SubscriberInfo, event: EventMessage) -> Option<EventMessage> {
+        if let (true, Some(sequence)) = (subscriber.subscription, event.sequence) {
+            let _ = self.sender.unbounded_send(TracedDelivery {
+                queue: subscriber.queue_name.clone(),
+                id: event.id.clone(),
+                sequence,
+            });
+        }
+        Some(event)
+    }
+}
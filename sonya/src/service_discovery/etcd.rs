@@ -1,9 +1,9 @@
 use actix::clock::sleep;
 use etcd_client::{Client, PutOptions};
-use log::{error, info};
 use sonya_meta::api::{sleep_between_reconnects, MAX_RECONNECT_ATTEMPTS};
 use sonya_meta::config::Shards;
 use std::time::Duration;
+use tracing::{error, info};
 
 const DEFAULT_TTL: i64 = 5;
 const DEFAULT_SLEEP: Duration = Duration::from_secs(2);
@@ -33,7 +33,7 @@ pub async fn register_instance(
                 attempts = 0
             }
             Err(e) => {
-                error!("connection error: {}", e);
+                error!(error = %e, "connection error");
                 attempts += 1
             }
         }
@@ -46,7 +46,7 @@ async fn register_instance_impl(mut client: Client, key: &str, value: &str) {
     let cli = match client.lease_grant(DEFAULT_TTL, None).await {
         Ok(cli) => cli,
         Err(e) => {
-            error!("lease grant error: {}", e);
+            error!(error = %e, "lease grant error");
             return;
         }
     };
@@ -59,15 +59,15 @@ async fn register_instance_impl(mut client: Client, key: &str, value: &str) {
         )
         .await
     {
-        error!("putting instance addr error: {}", e);
+        error!(error = %e, "putting instance addr error");
         return;
     }
 
-    info!("register instance in etcd, key: {}, value: {}", key, value);
+    info!(key = %key, value = %value, "register instance in etcd");
 
     loop {
         if let Err(e) = client.lease_keep_alive(cli.id()).await {
-            error!("lease keep alive error: {}", e);
+            error!(error = %e, "lease keep alive error");
             return;
         }
 